@@ -0,0 +1,320 @@
+// Server-side ban list.
+//
+// A ban is keyed by pubkey and may also cover the IP the user had when it
+// was made; guests have no key, so theirs is on the IP alone.  The IRC
+// server refuses banned IPs on connect and banned keys at SASL login.  The
+// relay has no keys and its nicks are only claimed, so it checks its own
+// IP and whoever the IRC server last registered under that nick, key and
+// address.  Bans can expire and are kept in a TOML file so they survive
+// restarts.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+use crate::config::Role;
+use crate::moderation::format_duration;
+
+/// Shared by the IRC server and the relay.
+pub type SharedBans = Arc<RwLock<BanList>>;
+
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Ban {
+    /// Empty for a guest, banned by `ip` only.
+    pub pubkey: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    /// Nick at the time of the ban, for display.
+    pub nick: String,
+    #[serde(default)]
+    pub reason: String,
+    pub by: String,
+    /// Rank of `by` when they made it; lifting it takes the same or higher.
+    /// Bans saved before this was kept count as the host's.
+    #[serde(default = "host_role")]
+    pub by_role: Role,
+    /// Unix seconds.
+    pub created: u64,
+    /// Unix seconds; `None` is permanent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+fn host_role() -> Role {
+    Role::Host
+}
+
+impl Ban {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires.is_none_or(|t| t > now)
+    }
+
+    /// One line for /listbanned.
+    pub fn describe(&self, now: u64) -> String {
+        let mut line = if self.pubkey.is_empty() {
+            format!("{} (guest)", self.nick)
+        } else {
+            format!("{} ({}…)", self.nick, &self.pubkey[..8.min(self.pubkey.len())])
+        };
+        if let Some(ip) = self.ip {
+            line.push_str(&format!(" ip {}", ip));
+        }
+        line.push_str(&format!(" by {}", self.by));
+        if !self.reason.is_empty() {
+            line.push_str(&format!(": {}", self.reason));
+        }
+        match self.expires {
+            Some(t) => line.push_str(&format!(" [{} left]", format_duration(t.saturating_sub(now)))),
+            None => line.push_str(" [permanent]"),
+        }
+        line
+    }
+
+    /// Whether adding this ban replaces `other`: the same key, or for a
+    /// guest, the same address.
+    fn replaces(&self, other: &Ban) -> bool {
+        other.pubkey == self.pubkey && (!self.pubkey.is_empty() || other.ip == self.ip)
+    }
+
+    fn matches_query(&self, query: &str) -> bool {
+        self.nick == query
+            || (!self.pubkey.is_empty() && self.pubkey == query)
+            || (query.len() >= 8 && self.pubkey.starts_with(query))
+            || self.ip.is_some_and(|ip| ip.to_string() == query)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct BanFile {
+    #[serde(default)]
+    bans: Vec<Ban>,
+}
+
+/// Who the IRC server last registered under a nick.
+struct Holder {
+    /// Only if they logged in with it.
+    pubkey: Option<String>,
+    ip: IpAddr,
+}
+
+pub struct BanList {
+    /// `None` keeps the list in memory only.
+    path: Option<PathBuf>,
+    bans: Vec<Ban>,
+    /// Nick → its last holder on the IRC server, for the relay.  Not saved.
+    holders: HashMap<String, Holder>,
+}
+
+impl BanList {
+    #[cfg(test)]
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path, bans: Vec::new(), holders: HashMap::new() }
+    }
+
+    /// Read the list at `path`, which is also where changes are saved.  A
+    /// missing file is an empty list.
+    pub fn load(path: &Path) -> Self {
+        let bans = match std::fs::read_to_string(path) {
+            Ok(content) => match toml::from_str::<BanFile>(&content) {
+                Ok(file) => file.bans,
+                Err(e) => {
+                    warn!("Failed to parse {:?}: {}", path, e);
+                    Vec::new()
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        Self { path: Some(path.to_path_buf()), bans, holders: HashMap::new() }
+    }
+
+    pub fn shared(self) -> SharedBans {
+        Arc::new(RwLock::new(self))
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = BanFile { bans: self.bans.clone() };
+        std::fs::write(path, toml::to_string_pretty(&file)?)?;
+        Ok(())
+    }
+
+    /// Add a ban, replacing any earlier one on the same key (or, for a
+    /// guest, the same address).
+    pub fn add(&mut self, ban: Ban) -> Result<()> {
+        self.bans.retain(|b| !ban.replaces(b));
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// The active ban `add`ing `ban` would replace, if any.
+    pub fn replaced_by(&self, ban: &Ban, now: u64) -> Option<&Ban> {
+        self.active(now).find(|b| ban.replaces(b))
+    }
+
+    /// Bans whose nick, pubkey (or an 8+ char prefix) or IP is `query`.
+    pub fn matching<'a>(&'a self, query: &'a str) -> impl Iterator<Item = &'a Ban> {
+        self.bans.iter().filter(move |b| b.matches_query(query))
+    }
+
+    /// Lift every ban `matching` `query`.  Returns what was removed.
+    pub fn remove(&mut self, query: &str) -> Result<Vec<Ban>> {
+        let (removed, kept) = self.bans.drain(..).partition(|b| b.matches_query(query));
+        self.bans = kept;
+        if !removed.is_empty() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Drop expired bans; saves if anything changed.
+    pub fn prune(&mut self, now: u64) {
+        let before = self.bans.len();
+        self.bans.retain(|b| b.is_active(now));
+        if self.bans.len() != before {
+            if let Err(e) = self.save() {
+                warn!("Failed to save ban list: {}", e);
+            }
+        }
+    }
+
+    pub fn active(&self, now: u64) -> impl Iterator<Item = &Ban> {
+        self.bans.iter().filter(move |b| b.is_active(now))
+    }
+
+    /// The ban covering this key or address, if any.
+    pub fn find(&self, pubkey: Option<&str>, ip: Option<IpAddr>, now: u64) -> Option<&Ban> {
+        self.active(now).find(|b| {
+            pubkey.is_some_and(|pk| !b.pubkey.is_empty() && pk == b.pubkey) || (ip.is_some() && b.ip == ip)
+        })
+    }
+
+    /// The IRC server registered `nick` to this key, if they logged in,
+    /// and address.  They stay its holder until someone else registers it.
+    pub fn set_holder(&mut self, nick: &str, pubkey: Option<&str>, ip: IpAddr) {
+        self.holders.insert(nick.to_string(), Holder { pubkey: pubkey.map(str::to_string), ip });
+    }
+
+    /// Relay connections carry only a claimed nick and an address: banned
+    /// if the address is, or if the nick's holder on the IRC server is.
+    pub fn find_relay(&self, nick: &str, ip: IpAddr, now: u64) -> Option<&Ban> {
+        let holder = self.holders.get(nick);
+        self.find(holder.and_then(|h| h.pubkey.as_deref()), Some(ip), now)
+            .or_else(|| holder.and_then(|h| self.find(None, Some(h.ip), now)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn ban(nick: &str, pubkey: &str, ip: Option<&str>, expires: Option<u64>) -> Ban {
+        Ban {
+            pubkey: pubkey.to_string(),
+            ip: ip.map(|s| s.parse().unwrap()),
+            nick: nick.to_string(),
+            reason: "spam".to_string(),
+            by: "alice".to_string(),
+            by_role: Role::Host,
+            created: 100,
+            expires,
+        }
+    }
+
+    #[test]
+    fn test_find_by_key_ip_and_expiry() {
+        let mut list = BanList::new(None);
+        list.add(ban("bob", "aaaa1111bbbb", Some("10.0.0.2"), None)).unwrap();
+        list.add(ban("carol", "cccc2222dddd", None, Some(200))).unwrap();
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert!(list.find(Some("aaaa1111bbbb"), None, 150).is_some());
+        assert!(list.find(Some("other"), Some(ip), 150).is_some());
+        assert!(list.find(Some("other"), None, 150).is_none());
+        assert!(list.find(Some("cccc2222dddd"), None, 150).is_some());
+        assert!(list.find(Some("cccc2222dddd"), None, 200).is_none());
+
+        assert!(list.find_relay("dave", ip, 150).is_some());
+
+        list.prune(250);
+        assert_eq!(list.active(0).count(), 1);
+    }
+
+    #[test]
+    fn test_relay_goes_by_the_nicks_holder() {
+        let mut list = BanList::new(None);
+        list.add(ban("bob", "aaaa1111bbbb", None, None)).unwrap();
+        let (home, elsewhere): (IpAddr, IpAddr) = ("10.0.0.2".parse().unwrap(), "10.9.9.9".parse().unwrap());
+
+        // Nobody has held "bob" yet: the banned nick alone proves nothing.
+        assert!(list.find_relay("bob", elsewhere, 0).is_none());
+        list.set_holder("bob", Some("aaaa1111bbbb"), home);
+        assert!(list.find_relay("bob", elsewhere, 0).is_some());
+
+        // Someone else has the nick now.
+        list.set_holder("bob", None, elsewhere);
+        assert!(list.find_relay("bob", elsewhere, 0).is_none());
+
+        // A guest banned by address is found through the nick too.
+        list.add(ban("eve", "", Some("10.0.0.7"), None)).unwrap();
+        list.set_holder("eve", None, "10.0.0.7".parse().unwrap());
+        assert!(list.find_relay("eve", elsewhere, 0).is_some());
+    }
+
+    #[test]
+    fn test_rebanning_replaces_and_unban_matches() {
+        let mut list = BanList::new(None);
+        list.add(ban("bob", "aaaa1111bbbb", None, None)).unwrap();
+        list.add(ban("bob2", "aaaa1111bbbb", None, Some(500))).unwrap();
+        assert_eq!(list.active(0).count(), 1);
+        assert_eq!(list.active(0).next().unwrap().nick, "bob2");
+
+        assert!(list.remove("aaaa").unwrap().is_empty(), "short prefixes don't match");
+        assert_eq!(list.remove("aaaa1111").unwrap().len(), 1);
+        assert_eq!(list.active(0).count(), 0);
+
+        list.add(ban("eve", "eeee", Some("192.168.1.5"), None)).unwrap();
+        assert_eq!(list.remove("192.168.1.5").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_persisted_across_load() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("bans.toml");
+        let mut list = BanList::load(&path);
+        list.add(ban("bob", "aaaa1111bbbb", Some("::1"), Some(900))).unwrap();
+        list.add(ban("carol", "cccc2222dddd", None, None)).unwrap();
+
+        let reloaded = BanList::load(&path);
+        assert_eq!(reloaded.bans, list.bans);
+    }
+
+    #[test]
+    fn test_describe() {
+        let b = ban("bob", "aaaa1111bbbb", Some("10.0.0.2"), Some(100 + 7200));
+        assert_eq!(b.describe(100), "bob (aaaa1111…) ip 10.0.0.2 by alice: spam [2h left]");
+        assert!(ban("bob", "aaaa", None, None).describe(0).ends_with("[permanent]"));
+        assert!(ban("dave", "", Some("10.0.0.3"), None).describe(0).starts_with("dave (guest) ip 10.0.0.3"));
+    }
+}
@@ -0,0 +1,2173 @@
+// Call engine shared by the frontends.
+//
+// Everything that happens once we're "in a room" lives here: audio device
+// setup, the IRC connection, WebRTC peer management, the relay fallback and
+// slash-command handling.  A `CallSession` is driven with `CallCommand`s and
+// reports back through `CallEvent`s (plus the shared `AppState`), so the GUI,
+// the TUI, bots and tests all use the same code path.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock as StdRwLock};
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tracing::{error, info, warn};
+
+use crate::config::{AudioProcessing, CodecProfile, ConnState, PeerAudioSettings, Role, TransmitSettings, TurnServer, UserConfig, VoiceStatus};
+use crate::contacts;
+use crate::e2e;
+use crate::irc_client::{IrcClient, IrcEvent};
+use crate::irc_server::SERVER_NAME;
+use crate::magic_link::ConnectionInfo;
+use crate::moderation::{self, format_duration, ModAction, MuteKind};
+use crate::persistence::{self, Identity, MessageEvent, SignedMessage, SyncMessage};
+use crate::relay::RelayConnection;
+use crate::search::SearchQuery;
+use crate::state::{AppState, ChatEntry, Verification};
+use crate::topology;
+use crate::jitter_buffer::{self, AudioPacket};
+use crate::voice_mixer::{ActiveDevices, AudioIo, VoiceControls, VoiceMixer};
+use crate::webrtc_peer::{InternalSignal, ReceivedFile, WebRtcPeer, WebRtcSignal};
+
+pub enum CallCommand {
+    /// Chat text or a slash command, exactly as typed.
+    SendMessage(String),
+    SwitchChannel(String),
+    CreateChannel(String),
+    SendFile { name: String, data: Vec<u8> },
+    /// Chat in the current channel answering signed message `reply_to`.
+    SendReply { reply_to: String, text: String },
+    /// End-to-end encrypted message to `to`; needs their verified key.
+    SendDirect { to: String, text: String },
+    /// Stop/resume sending our mic and tell the channel.
+    SetMuted(bool),
+    /// Stop/resume playing everyone else (implies muted) and tell the channel.
+    SetDeafened(bool),
+    Shutdown,
+}
+
+/// Hits `/search` lists in the channel; the GUI's search panel shows more.
+const SEARCH_RESULT_LINES: usize = 20;
+
+/// What `send_chat` sends along with the text.
+enum Outgoing {
+    Chat,
+    /// An answer to this message id.
+    Reply(String),
+    Event(MessageEvent),
+}
+
+impl Outgoing {
+    fn sign(
+        self,
+        identity: &Identity,
+        nickname: &str,
+        ch: &str,
+        content: &str,
+        sealed: bool,
+        parents: &[String],
+    ) -> anyhow::Result<SignedMessage> {
+        match self {
+            Outgoing::Chat if sealed => SignedMessage::create_sealed(identity, nickname, ch, content, parents),
+            Outgoing::Chat => SignedMessage::create(identity, nickname, ch, content, parents),
+            Outgoing::Reply(to) => SignedMessage::create_reply(identity, nickname, ch, &to, content, sealed, parents),
+            Outgoing::Event(event) => SignedMessage::create_event(identity, nickname, ch, event, content, sealed, parents),
+        }
+    }
+}
+
+/// Which event `/edit`, `/delete` or `/react` sends.
+#[derive(Clone, Copy, PartialEq)]
+enum EventCommand {
+    Edit,
+    Delete,
+    React,
+}
+
+/// What a session reports to whoever is driving it.  Every line that lands
+/// in the chat scrollback also arrives as `Line`, so a frontend that only
+/// cares about text can ignore the rest.
+#[derive(Clone, Debug)]
+pub enum CallEvent {
+    /// IRC is up and the event loop is running.
+    Connected { nick: String, channel: String },
+    ConnectFailed(String),
+    /// A line appended to a channel's scrollback (chat or system).
+    Line { channel: String, text: String },
+    /// Chat from another user.
+    Chat { channel: String, from: String, text: String },
+    /// A signed message was edited, deleted or reacted to; `text` is how
+    /// it reads now.
+    MessageUpdated { channel: String, id: String, from: String, text: String },
+    /// A decrypted DM; it is also in `AppState::direct_messages`.
+    DirectMessage { from: String, text: String },
+    /// `nick` came back with a key other than the one we knew.
+    IdentityChanged { nick: String },
+    ChannelChanged(String),
+    PeerJoined { nick: String, role: Role },
+    PeerLeft(String),
+    PeerConnState { nick: String, state: ConnState },
+    PeerVoice { nick: String, status: VoiceStatus },
+    /// The sound devices were reopened (unplugged, replugged or switched).
+    AudioDevicesChanged(ActiveDevices),
+    FileReceived { from: String, name: String, path: PathBuf },
+    ModAction { from: String, action: String, target: String },
+    PowRequirementChanged { bits: u8 },
+    /// The event loop exited (Shutdown, or the connection was lost).
+    Ended,
+}
+
+impl std::fmt::Display for CallEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallEvent::Connected { nick, channel } => write!(f, "Connected to {} as {}", channel, nick),
+            CallEvent::ConnectFailed(e) => write!(f, "Connection failed: {}", e),
+            CallEvent::Line { channel, text } => write!(f, "{}: {}", channel, text),
+            CallEvent::Chat { channel, from, text } => write!(f, "{}: <{}> {}", channel, from, text),
+            CallEvent::MessageUpdated { channel, id, from, text } => {
+                write!(f, "{}: {}'s message {} now reads {}", channel, from, id, text)
+            }
+            CallEvent::DirectMessage { from, text } => write!(f, "DM from {}: {}", from, text),
+            CallEvent::IdentityChanged { nick } => write!(f, "{} has a different key than before", nick),
+            CallEvent::ChannelChanged(channel) => write!(f, "Now in {}", channel),
+            CallEvent::PeerJoined { nick, role } => write!(f, "{} joined ({})", nick, role.as_str()),
+            CallEvent::PeerLeft(nick) => write!(f, "{} left", nick),
+            CallEvent::PeerConnState { nick, state } => write!(f, "{}: {}", nick, state),
+            CallEvent::PeerVoice { nick, status } => {
+                let what = if status.deafened { "deafened" } else if status.muted { "muted" } else { "unmuted" };
+                write!(f, "{} {}", nick, what)
+            }
+            CallEvent::AudioDevicesChanged(active) => write!(
+                f,
+                "Audio devices changed - mic: {}, speakers: {}",
+                active.input.as_deref().unwrap_or("none"),
+                active.output.as_deref().unwrap_or("none"),
+            ),
+            CallEvent::FileReceived { from, name, path } => {
+                write!(f, "Received {} from {}, saved to {}", name, from, path.display())
+            }
+            CallEvent::ModAction { from, action, target } => write!(f, "{}: {} {}", from, action, target),
+            CallEvent::PowRequirementChanged { bits } => write!(f, "Nicks now need {} bits of PoW", bits),
+            CallEvent::Ended => write!(f, "Call ended"),
+        }
+    }
+}
+
+/// How to start a `CallSession`.  `from_config` covers the frontends; the
+/// `with_*` setters are for scripts and tests.
+pub struct SessionOptions {
+    pub conn_info: ConnectionInfo,
+    pub nickname: String,
+    pub role: Role,
+    pub turn_servers: Vec<TurnServer>,
+    /// Saved per-peer mix settings, keyed by pubkey hex.
+    pub peer_audio: HashMap<String, PeerAudioSettings>,
+    /// Starting VAD/push-to-talk/always-on choice; change it live through
+    /// `CallSession::voice`.
+    pub transmit: TransmitSettings,
+    /// Noise suppression, AGC and high-pass settings for the mic.
+    pub processing: AudioProcessing,
+    /// Preferred devices by name; `None` is the system default.
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    pub identity: Option<Identity>,
+    /// Open the sound card.  Without it the session is chat/signaling only.
+    pub audio: bool,
+    /// Where logs go.  `None` uses the platform data dir.
+    pub data_dir: Option<PathBuf>,
+    pub invite_link: Arc<RwLock<Option<String>>>,
+    pub connect_timeout: std::time::Duration,
+}
+
+impl SessionOptions {
+    pub fn new(nickname: String, conn_info: ConnectionInfo) -> Self {
+        Self {
+            conn_info,
+            nickname,
+            role: Role::Peer,
+            turn_servers: Vec::new(),
+            peer_audio: HashMap::new(),
+            transmit: TransmitSettings::default(),
+            processing: AudioProcessing::default(),
+            input_device: None,
+            output_device: None,
+            identity: None,
+            audio: true,
+            data_dir: None,
+            invite_link: Arc::new(RwLock::new(None)),
+            connect_timeout: std::time::Duration::from_secs(10),
+        }
+    }
+
+    pub fn from_config(config: &UserConfig, conn_info: ConnectionInfo, is_host: bool) -> Self {
+        let identity = Identity::load_or_generate(&UserConfig::tls_cert_dir()).ok();
+        let role = if is_host { Role::Host } else { Role::Peer };
+        let mut opts = Self::new(config.display_name.clone(), conn_info)
+            .with_role(role)
+            .with_identity(identity);
+        opts.turn_servers = config.turn_servers.clone();
+        opts.peer_audio = config.peer_audio.clone();
+        opts.transmit = config.transmit;
+        opts.processing = config.processing;
+        opts.input_device = config.input_device.clone();
+        opts.output_device = config.output_device.clone();
+        opts
+    }
+
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    pub fn with_identity(mut self, identity: Option<Identity>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_audio(mut self, audio: bool) -> Self {
+        self.audio = audio;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_data_dir(mut self, dir: PathBuf) -> Self {
+        self.data_dir = Some(dir);
+        self
+    }
+
+    /// Share the invite-link slot with the frontend (the GUI fills it in
+    /// once the external IP is known).
+    pub fn with_invite_link(mut self, link: Arc<RwLock<Option<String>>>) -> Self {
+        self.invite_link = link;
+        self
+    }
+}
+
+/// A running call.  Dropping it stops the audio streams; send
+/// `CallCommand::Shutdown` first to leave the room cleanly.
+pub struct CallSession {
+    pub state: Arc<AppState>,
+    pub command_tx: mpsc::UnboundedSender<CallCommand>,
+    pub channels: Arc<RwLock<Vec<String>>>,
+    pub current_channel: Arc<RwLock<String>>,
+    pub nickname: String,
+    pub invite_link: Arc<RwLock<Option<String>>>,
+    /// Mute/deafen/push-to-talk switches.  Change mute and deafen through
+    /// `CallCommand` so peers are told; push-to-talk is local and can be
+    /// flipped here directly from a key handler.
+    pub voice: Arc<VoiceControls>,
+    /// `SessionOptions::peer_audio` plus changes made during the call.
+    saved_levels: Arc<StdRwLock<HashMap<String, PeerAudioSettings>>>,
+    audio: Option<AudioIo>,
+}
+
+impl CallSession {
+    /// Open the audio devices (if requested) and connect to the room in the
+    /// background.  Progress is reported through `subscribe()` and as chat
+    /// lines in `state`.
+    pub fn start(opts: SessionOptions) -> anyhow::Result<Self> {
+        let SessionOptions {
+            conn_info, nickname, role: our_role, turn_servers, peer_audio,
+            transmit, processing, input_device, output_device, identity, audio, data_dir, invite_link, connect_timeout,
+        } = opts;
+
+        let state = match &data_dir {
+            Some(dir) => AppState::with_data_dir(identity, dir),
+            None => AppState::new(identity),
+        };
+        let is_host = our_role == Role::Host;
+        let codec = conn_info.codec;
+        let channels_vec = conn_info.channels.clone();
+        let default_channel = conn_info.default_channel().to_string();
+
+        let cert_fingerprint = conn_info.cert_fingerprint.clone();
+        let use_tls = cert_fingerprint.is_some();
+
+        let relay_port = conn_info.relay_port;
+        let relay_addr = relay_port.map(|rp| format!("{}:{}", conn_info.host, rp));
+
+        // Update diagnostics
+        {
+            let s = Arc::clone(&state);
+            let turn_count = turn_servers.len();
+            let has_relay = relay_port.is_some();
+            tokio::spawn(async move {
+                let mut d = s.diagnostics.write().await;
+                d.turn_configured = turn_count;
+                d.relay_enabled = has_relay;
+                d.relay_port = relay_port;
+            });
+        }
+
+        let (mic_tx, mic_rx) = mpsc::unbounded_channel();
+        let (mix_tx, mut mix_rx) = mpsc::unbounded_channel::<(String, AudioPacket)>();
+
+        let current_channel = Arc::new(RwLock::new(default_channel.clone()));
+
+        let voice = Arc::new(VoiceControls::default());
+        voice.apply_transmit(transmit);
+        let (audio_io, mixer) = if audio {
+            let mixer = Arc::new(VoiceMixer::new(Arc::clone(&voice), &processing, codec));
+            let rt = tokio::runtime::Handle::current();
+            let state_dev = Arc::clone(&state);
+            let cur_ch_dev = Arc::clone(&current_channel);
+            let on_change = move |active: &ActiveDevices| {
+                state_dev.emit(CallEvent::AudioDevicesChanged(active.clone()));
+                let note = format!(
+                    "Audio devices changed - mic: {}, speakers: {}",
+                    active.input.as_deref().unwrap_or("none"),
+                    active.output.as_deref().unwrap_or("none"),
+                );
+                let state_dev = Arc::clone(&state_dev);
+                let cur_ch_dev = Arc::clone(&cur_ch_dev);
+                rt.spawn(async move {
+                    let ch = cur_ch_dev.read().await.clone();
+                    state_dev.add_message(&ch, note).await;
+                });
+            };
+            let io = AudioIo::start(Arc::clone(&mixer), mic_tx, input_device, output_device, on_change)?;
+            (Some(io), Some(mixer))
+        } else {
+            info!("Audio disabled for this session");
+            (None, None)
+        };
+
+        let has_mic = audio_io.as_ref().is_some_and(|a| a.active().input.is_some());
+        if audio && !has_mic {
+            info!("Running in listen-only mode (no microphone)");
+        }
+
+        let saved_levels = Arc::new(StdRwLock::new(peer_audio));
+        let state_mix = Arc::clone(&state);
+        let state_fwd = Arc::clone(&state);
+        let voice_mix = Arc::clone(&voice);
+        let levels_mix = Arc::clone(&saved_levels);
+        let (forward_tx, forward_rx) = mpsc::unbounded_channel::<(String, AudioPacket)>();
+        let our_nick = nickname.clone();
+        tokio::spawn(async move {
+            // nick -> pubkey whose saved levels we last applied
+            let mut applied: HashMap<String, String> = HashMap::new();
+            while let Some((via, packet)) = mix_rx.recv().await {
+                // Packets forwarded by a superpeer name their speaker by CSRC.
+                // Anyone else setting one is speaking under another nick.
+                let nick = match packet.source {
+                    None => via.clone(),
+                    Some(_) if !state_mix.get_peer_role(&via).await.is_superpeer() => continue,
+                    Some(id) => match speaker_for_source(&state_mix, id).await {
+                        Some(n) => n,
+                        None if id == jitter_buffer::source_id(&our_nick) => continue,
+                        None => format!("{}~{:08x}", via, id),
+                    },
+                };
+                if nick == our_nick {
+                    continue;
+                }
+                // Voice-muted speakers aren't played, whoever handed us the
+                // packet; the forward task drops them too.
+                let voice_muted = state_mix.is_voice_muted(&nick).await
+                    || state_mix.is_voice_muted(&via).await;
+                if let Some(mixer) = mixer.as_ref().filter(|_| !voice_muted) {
+                    if let Some(pk) = state_mix.pubkey_for_nick(&nick).await {
+                        if applied.get(&nick) != Some(&pk) {
+                            if let Some(saved) = levels_mix.read().unwrap().get(&pk) {
+                                voice_mix.set_peer(&nick, *saved);
+                            }
+                            applied.insert(nick.clone(), pk);
+                        }
+                    }
+                    state_mix.mark_speaking(&nick).await;
+                    mixer.queue_packet(&nick, packet.clone());
+                }
+                // Only forward first-hop audio, so two superpeers never bounce
+                // a packet between them.
+                let our_role = state_fwd.our_role().await;
+                if our_role.is_superpeer() && packet.source.is_none() {
+                    let source = Some(jitter_buffer::source_id(&via));
+                    let _ = forward_tx.send((via, AudioPacket { source, ..packet }));
+                }
+            }
+        });
+
+        let (command_tx, command_rx) = mpsc::unbounded_channel::<CallCommand>();
+        let (file_tx, file_rx) = mpsc::unbounded_channel::<ReceivedFile>();
+
+        let channels = Arc::new(RwLock::new(channels_vec));
+
+        let state_c = Arc::clone(&state);
+        let nick_c = nickname.clone();
+        let conn_c = conn_info.clone();
+        let cur_ch = Arc::clone(&current_channel);
+        let channels_for_loop = Arc::clone(&channels);
+
+        let state_role = Arc::clone(&state);
+        tokio::spawn(async move {
+            state_role.set_our_role(our_role).await;
+        });
+
+        let invite_link_c = Arc::clone(&invite_link);
+        let voice_c = Arc::clone(&voice);
+
+        tokio::spawn(async move {
+            let timeout = tokio::time::timeout(
+                connect_timeout,
+                IrcClient::connect(
+                    conn_c.server_address(),
+                    nick_c.clone(),
+                    default_channel.clone(),
+                    state_c.clone(),
+                    cert_fingerprint,
+                ),
+            );
+
+            match timeout.await {
+                Ok(Ok((irc_client, irc_stream, irc_events))) => {
+                    let irc = Arc::new(irc_client);
+
+                    let irc_c = Arc::clone(&irc);
+                    let state_s = Arc::clone(&state_c);
+                    let cur_ch_s = Arc::clone(&cur_ch);
+                    tokio::spawn(async move {
+                        if let Err(e) = irc_c.run(irc_stream).await {
+                            error!("IRC stream: {}", e);
+                            let ch = cur_ch_s.read().await.clone();
+                            state_s.add_message(&ch, format!("IRC error: {}", e)).await;
+                        }
+                    });
+
+                    let _ = irc.announce_role(&default_channel, our_role);
+
+                    let ch = cur_ch.read().await.clone();
+                    let tls_note = if use_tls { " (TLS)" } else { "" };
+                    state_c.add_message(&ch, format!("Connected as {}{}", nick_c, tls_note)).await;
+                    if is_host {
+                        state_c.add_message(&ch, "You are hosting this room".to_string()).await;
+                        if relay_addr.is_some() {
+                            state_c.add_message(&ch, "Audio relay is active for NAT-challenged peers".to_string()).await;
+                        }
+                    }
+                    if audio && !has_mic {
+                        state_c.add_message(&ch, "No microphone detected - listen-only mode. Chat still works!".to_string()).await;
+                    }
+
+                    state_c.load_history(&ch, 100).await;
+                    state_c.emit(CallEvent::Connected { nick: nick_c.clone(), channel: ch });
+
+                    let custom_commands = moderation::CustomCommands::load();
+
+                    event_loop(
+                        irc, irc_events, Arc::clone(&state_c), nick_c, our_role,
+                        mix_tx, mic_rx, forward_rx,
+                        command_rx, file_tx, file_rx,
+                        cur_ch, channels_for_loop, voice_c,
+                        turn_servers,
+                        custom_commands, invite_link_c,
+                        relay_addr, codec,
+                    ).await;
+                    state_c.emit(CallEvent::Ended);
+                }
+                Ok(Err(e)) => {
+                    let ch = cur_ch.read().await.clone();
+                    state_c.add_message(&ch, format!("Connection failed: {}", e)).await;
+                    state_c.emit(CallEvent::ConnectFailed(e.to_string()));
+                }
+                Err(_) => {
+                    let ch = cur_ch.read().await.clone();
+                    state_c.add_message(&ch, "Connection timed out".to_string()).await;
+                    state_c.emit(CallEvent::ConnectFailed("timed out".to_string()));
+                }
+            }
+        });
+
+        if !is_host {
+            if let Ok(link) = conn_info.to_magic_link() {
+                let il = Arc::clone(&invite_link);
+                tokio::spawn(async move {
+                    let mut guard = il.write().await;
+                    if guard.is_none() {
+                        *guard = Some(link);
+                    }
+                });
+            }
+        }
+
+        Ok(Self {
+            state,
+            command_tx,
+            channels,
+            current_channel,
+            nickname,
+            invite_link,
+            voice,
+            saved_levels,
+            audio: audio_io,
+        })
+    }
+
+    pub fn send(&self, cmd: CallCommand) {
+        let _ = self.command_tx.send(cmd);
+    }
+
+    /// Change how `nick` is mixed into our speakers.  With `pubkey` the
+    /// setting is also reapplied if they leave and rejoin this call; saving
+    /// it to `UserConfig::peer_audio` is up to the frontend.
+    pub fn set_peer_audio(&self, nick: &str, pubkey: Option<&str>, settings: PeerAudioSettings) {
+        self.voice.set_peer(nick, settings);
+        if let Some(pk) = pubkey {
+            self.saved_levels.write().unwrap().insert(pk.to_string(), settings);
+        }
+    }
+
+    /// Apply new mic processing settings mid-call.  A no-op without audio;
+    /// saving them to `UserConfig::processing` is up to the frontend.
+    pub fn set_audio_processing(&self, settings: &AudioProcessing) {
+        if let Some(io) = &self.audio {
+            io.mixer().set_processing(settings);
+        }
+    }
+
+    /// Switch sound devices without leaving the call.  `None` is the system
+    /// default; saving the choice to `UserConfig` is up to the frontend.
+    pub fn set_audio_devices(&self, input: Option<String>, output: Option<String>) {
+        if let Some(io) = &self.audio {
+            io.select(input, output);
+        }
+    }
+
+    /// The devices in use right now, or `None` without audio.
+    pub fn active_audio_devices(&self) -> Option<ActiveDevices> {
+        self.audio.as_ref().map(AudioIo::active)
+    }
+
+    /// A new receiver for this session's events.  Subscribe before sending
+    /// commands whose outcome you want to observe.
+    pub fn subscribe(&self) -> broadcast::Receiver<CallEvent> {
+        self.state.events.subscribe()
+    }
+}
+
+/// The peer whose `source_id` matches a forwarded packet's CSRC.
+async fn speaker_for_source(state: &AppState, id: u32) -> Option<String> {
+    state.peer_states.read().await
+        .keys()
+        .find(|nick| jitter_buffer::source_id(nick) == id)
+        .cloned()
+}
+
+/// Add the room to the config's recent-servers list.
+pub fn remember_server(config: &mut UserConfig, conn_info: &ConnectionInfo, is_host: bool) {
+    let name = if is_host {
+        format!("My Server ({})", conn_info.channels.join(", "))
+    } else {
+        format!("{} ({})", conn_info.host, conn_info.channels.join(", "))
+    };
+    if let Ok(link) = conn_info.to_magic_link() {
+        config.add_recent_server(name, link);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn event_loop(
+    irc: Arc<IrcClient>,
+    mut irc_events: mpsc::UnboundedReceiver<IrcEvent>,
+    state: Arc<AppState>,
+    nickname: String,
+    mut our_role: Role,
+    mix_tx: mpsc::UnboundedSender<(String, AudioPacket)>,
+    mut mic_rx: mpsc::UnboundedReceiver<AudioPacket>,
+    mut forward_rx: mpsc::UnboundedReceiver<(String, AudioPacket)>,
+    mut command_rx: mpsc::UnboundedReceiver<CallCommand>,
+    file_tx: mpsc::UnboundedSender<ReceivedFile>,
+    mut file_rx: mpsc::UnboundedReceiver<ReceivedFile>,
+    current_channel: Arc<RwLock<String>>,
+    channels: Arc<RwLock<Vec<String>>>,
+    voice: Arc<VoiceControls>,
+    turn_servers: Vec<TurnServer>,
+    mut custom_commands: moderation::CustomCommands,
+    invite_link: Arc<RwLock<Option<String>>>,
+    relay_addr: Option<String>,
+    codec: CodecProfile,
+) {
+    let (ice_out_tx, mut ice_out_rx) = mpsc::unbounded_channel::<InternalSignal>();
+    let peers: Arc<RwLock<HashMap<String, Arc<WebRtcPeer>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+
+    let reconnect_attempts: Arc<RwLock<HashMap<String, u32>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+
+    // Optional relay connection for fallback
+    let relay_conn: Arc<RwLock<Option<RelayConnection>>> = Arc::new(RwLock::new(None));
+    if let Some(ref addr) = relay_addr {
+        let mix_tx_relay = mix_tx.clone();
+        let addr_c = addr.clone();
+        let nick_c = nickname.clone();
+        let rc = Arc::clone(&relay_conn);
+        tokio::spawn(async move {
+            match RelayConnection::connect(&addr_c, &nick_c, mix_tx_relay).await {
+                Ok(conn) => {
+                    info!("Relay connection established");
+                    *rc.write().await = Some(conn);
+                }
+                Err(e) => {
+                    warn!("Could not connect to relay: {}", e);
+                }
+            }
+        });
+    }
+
+    let irc_c = Arc::clone(&irc);
+    let (reconnect_tx, mut reconnect_rx) = mpsc::unbounded_channel::<String>();
+    let (conn_failed_tx, mut conn_failed_rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(sig) = ice_out_rx.recv().await {
+            match sig {
+                InternalSignal::WebRtc(target, ws) => {
+                    if let Ok(json) = serde_json::to_string(&ws) {
+                        let _ = irc_c.send_webrtc_signal(&target, &json);
+                    }
+                }
+                InternalSignal::Reconnect(nick) => {
+                    let _ = reconnect_tx.send(nick);
+                }
+                InternalSignal::ConnFailed(nick) => {
+                    let _ = conn_failed_tx.send(nick);
+                }
+            }
+        }
+    });
+
+    let peers_mic = Arc::clone(&peers);
+    let relay_mic = Arc::clone(&relay_conn);
+    tokio::spawn(async move {
+        while let Some(pkt) = mic_rx.recv().await {
+            let r = peers_mic.read().await;
+            for p in r.values() {
+                let _ = p.send_audio(&pkt).await;
+            }
+            // Also send via relay if connected
+            if let Some(rc) = relay_mic.read().await.as_ref() {
+                let _ = rc.send_audio(&pkt).await;
+            }
+        }
+    });
+
+    let peers_fwd = Arc::clone(&peers);
+    let state_fwd = Arc::clone(&state);
+    tokio::spawn(async move {
+        while let Some((sender_nick, packet)) = forward_rx.recv().await {
+            if state_fwd.is_voice_muted(&sender_nick).await {
+                continue;
+            }
+            let r = peers_fwd.read().await;
+            for (nick, peer) in r.iter() {
+                if *nick != sender_nick {
+                    let _ = peer.send_audio(&packet).await;
+                }
+            }
+        }
+    });
+
+    async fn maybe_create_peer(
+        nick: &str,
+        nickname: &str,
+        our_role: Role,
+        state: &Arc<AppState>,
+        peers: &Arc<RwLock<HashMap<String, Arc<WebRtcPeer>>>>,
+        irc: &Arc<IrcClient>,
+        mix_tx: &mpsc::UnboundedSender<(String, AudioPacket)>,
+        ice_out_tx: &mpsc::UnboundedSender<InternalSignal>,
+        file_tx: &mpsc::UnboundedSender<ReceivedFile>,
+        turn_servers: &[TurnServer],
+        codec: CodecProfile,
+        current_channel: &Arc<RwLock<String>>,
+    ) {
+        let target_role = state.get_peer_role(nick).await;
+
+        if !topology::should_connect_to(state, nickname, our_role, nick, target_role).await {
+            info!("Topology: skipping connection to {} (not our superpeer)", nick);
+            return;
+        }
+
+        if nickname >= nick {
+            return;
+        }
+
+        state.set_peer_connecting(nick).await;
+
+        match WebRtcPeer::new(
+            nick.to_string(), Arc::clone(state), mix_tx.clone(),
+            ice_out_tx.clone(), file_tx.clone(), turn_servers.to_vec(), codec,
+        ).await {
+            Ok(peer) => {
+                if let Ok(offer) = peer.create_offer().await {
+                    peers.write().await.insert(nick.to_string(), Arc::new(peer));
+                    let _ = irc.send_webrtc_signal(nick, &offer);
+                    let ch = current_channel.read().await.clone();
+                    state.add_message(&ch, format!("-> Calling {}", nick)).await;
+                }
+            }
+            Err(e) => error!("Peer create: {}", e),
+        }
+    }
+
+    /// Leave the current channel (closing every peer connection) and join
+    /// `new_ch`, with `key` if it is +k.
+    #[allow(clippy::too_many_arguments)]
+    async fn change_channel(
+        new_ch: &str,
+        key: Option<&str>,
+        our_role: Role,
+        state: &Arc<AppState>,
+        peers: &Arc<RwLock<HashMap<String, Arc<WebRtcPeer>>>>,
+        irc: &Arc<IrcClient>,
+        current_channel: &Arc<RwLock<String>>,
+        voice: &VoiceControls,
+    ) {
+        let old_ch = current_channel.read().await.clone();
+        info!("Switching {} -> {}", old_ch, new_ch);
+
+        let mut pw = peers.write().await;
+        for (_, p) in pw.drain() {
+            p.close().await;
+        }
+        drop(pw);
+        state.clear_peers().await;
+        state.group_keys.write().await.rotate(&old_ch);
+
+        let _ = irc.part_channel(&old_ch);
+        let _ = match key {
+            Some(k) => irc.join_channel_with_key(new_ch, k),
+            None => irc.join_channel(new_ch),
+        };
+        *current_channel.write().await = new_ch.to_string();
+
+        state.load_history(new_ch, 100).await;
+        let _ = irc.announce_role(new_ch, our_role);
+        if voice.status() != VoiceStatus::default() {
+            let _ = irc.announce_voice(new_ch, voice.status());
+        }
+        state.emit(CallEvent::ChannelChanged(new_ch.to_string()));
+    }
+
+    async fn apply_mod_action(
+        action: &ModAction,
+        ch: &str,
+        our_role: Role,
+        state: &Arc<AppState>,
+        irc: &Arc<IrcClient>,
+    ) {
+        if let Err(msg) = moderation::check_permission(our_role, action) {
+            state.add_message(ch, format!("Permission denied: {}", msg)).await;
+            return;
+        }
+        // The server checks these again against its own role table and
+        // answers with the KICK / VOIRC_ROLE broadcast, or a refusal.
+        match action {
+            ModAction::Kick(target) => {
+                let _ = irc.kick(ch, target);
+            }
+            ModAction::Ban { target, duration, reason, ip } => {
+                let _ = irc.ban(target, *duration, *ip, reason.as_deref());
+            }
+            ModAction::Unban(query) => {
+                let _ = irc.unban(query);
+            }
+            ModAction::Promote(target) => {
+                if let Err(e) = irc.grant_role(target, Role::Mod) {
+                    state.add_message(ch, format!("Cannot promote {}: {}", target, e)).await;
+                }
+            }
+            ModAction::Demote(target) => {
+                if let Err(e) = irc.grant_role(target, Role::Peer) {
+                    state.add_message(ch, format!("Cannot demote {}: {}", target, e)).await;
+                }
+            }
+            ModAction::Mute { target, kind, duration } => {
+                let _ = irc.mute(target, *kind, *duration);
+            }
+            ModAction::Unmute { target, kind } => {
+                let _ = irc.unmute(target, *kind);
+            }
+            ModAction::SlowMode { interval, duration } => {
+                let _ = irc.set_slow_mode(ch, *interval, *duration);
+            }
+        }
+    }
+
+    /// Encrypt and send a DM.  The conversation gets our line, or why it
+    /// wasn't sent; `false` on failure.
+    async fn send_direct(to: &str, text: &str, nickname: &str, state: &Arc<AppState>, irc: &Arc<IrcClient>) -> bool {
+        match irc.send_direct_message(to, text).await {
+            Ok(()) => {
+                state.add_direct_message(to, format!("<{}> {}", nickname, text)).await;
+                true
+            }
+            Err(e) => {
+                state.add_direct_message(to, format!("Not sent: {}", e)).await;
+                false
+            }
+        }
+    }
+
+    /// Channels in `channels` that `nick` has been in with us this session,
+    /// going by where their key was announced.
+    async fn shared_channels(nick: &str, state: &Arc<AppState>, channels: &Arc<RwLock<Vec<String>>>) -> Vec<String> {
+        let Some(pubkey) = state.pubkey_for_nick(nick).await else { return Vec::new() };
+        let mut shared = Vec::new();
+        for ch in channels.read().await.iter() {
+            if state.was_channel_member(ch, &pubkey).await {
+                shared.push(ch.clone());
+            }
+        }
+        shared
+    }
+
+    /// One step of the history sync with `from`, for channels they've been
+    /// in with us; see `persistence` for the exchange.
+    async fn handle_sync(
+        from: &str,
+        msg: SyncMessage,
+        state: &Arc<AppState>,
+        irc: &Arc<IrcClient>,
+        channels: &Arc<RwLock<Vec<String>>>,
+    ) {
+        // A channel being in our list isn't enough: someone who was never
+        // let into a +i or +k channel mustn't get its history from us.
+        let ours = shared_channels(from, state, channels).await;
+        let log = &state.message_log;
+        match msg {
+            SyncMessage::Digest { channels: digests } => {
+                for theirs in digests.into_iter().filter(|d| ours.contains(&d.channel)) {
+                    let days = persistence::differing_days(&log.digest(&theirs.channel).await, &theirs.buckets);
+                    if days.is_empty() {
+                        continue;
+                    }
+                    let ids = log.ids_on_days(&theirs.channel, &days).await;
+                    let _ = irc.send_sync(from, &SyncMessage::Inventory { channel: theirs.channel, days, ids });
+                }
+            }
+            SyncMessage::Inventory { channel, days, ids } if ours.contains(&channel) => {
+                let mine: HashSet<String> = log.ids_on_days(&channel, &days).await.into_iter().collect();
+                let theirs: HashSet<String> = ids.into_iter().collect();
+                let want: Vec<String> = theirs.difference(&mine).take(persistence::SYNC_MAX_WANT).cloned().collect();
+                if !want.is_empty() {
+                    let _ = irc.send_sync(from, &SyncMessage::Want { channel: channel.clone(), ids: want });
+                }
+                // Bounded like a Want, so a short inventory can't make us
+                // push the whole log; the rest goes out on the next digest.
+                let extra: Vec<String> = mine.difference(&theirs).take(persistence::SYNC_MAX_WANT).cloned().collect();
+                for batch in persistence::batches(&channel, log.messages_by_id(&channel, &extra).await) {
+                    let _ = irc.send_sync(from, &batch);
+                }
+            }
+            SyncMessage::Want { channel, mut ids } if ours.contains(&channel) => {
+                ids.truncate(persistence::SYNC_MAX_WANT);
+                for batch in persistence::batches(&channel, log.messages_by_id(&channel, &ids).await) {
+                    let _ = irc.send_sync(from, &batch);
+                }
+            }
+            SyncMessage::Batch { channel, messages } if ours.contains(&channel) => {
+                let trusted = state.trusted_keys_snapshot().await;
+                let moderators = state.moderator_keys().await;
+                let (accepted, rejected, flagged) = persistence::process_sync_batch(log, &channel, messages, &trusted, &moderators).await;
+                info!(
+                    "Sync {} from {}: {} accepted, {} rejected, {} parents still missing",
+                    channel, from, accepted, rejected, log.missing_parents(&channel).await
+                );
+                if flagged > 0 {
+                    state.add_message(&channel, format!(
+                        "[?] {} message(s) synced from {} were reordered or fork their author's history", flagged, from
+                    )).await;
+                }
+            }
+            _ => warn!("Ignoring sync for a channel we don't share with {}", from),
+        }
+    }
+
+    /// `/verify`: print the safety number with `who`, or mark their
+    /// current key checked once the user has compared it.
+    async fn verify_identity(who: &str, confirm: bool, ch: &str, state: &Arc<AppState>) {
+        let (Some(identity), Some(theirs)) = (&state.identity, state.pubkey_for_nick(who).await) else {
+            state.add_message(ch, format!("No key announced for {} yet", who)).await;
+            return;
+        };
+        if confirm {
+            state.contacts.write().await.set_verified(&theirs, true);
+            // Our sender key here went out before they were verified; the
+            // next message gets a new one they're given too.
+            state.group_keys.write().await.rotate(ch);
+            state.add_message(ch, format!("Marked {}'s key {} as verified", who, e2e::short_fingerprint(&theirs))).await;
+        } else {
+            let number = match contacts::safety_number(&identity.pubkey_hex, &theirs) {
+                Ok(number) => number,
+                Err(e) => {
+                    state.add_message(ch, format!("No safety number for {}'s key: {}", who, e)).await;
+                    return;
+                }
+            };
+            state.add_message(ch, format!("Safety number with {}: {}", who, number)).await;
+            state.add_message(ch, format!(
+                "Compare it with {} in person or on a call; if it matches, /verify {} confirm", who, who
+            )).await;
+        }
+    }
+
+    /// Send `text` as chat, a reply or an event, signed when we have an
+    /// identity and sealed in `+E` channels.  Returns the signed message's
+    /// id; `None` if it went out unsigned.
+    async fn send_chat(
+        ch: &str,
+        text: &str,
+        what: Outgoing,
+        nickname: &str,
+        state: &Arc<AppState>,
+        irc: &Arc<IrcClient>,
+    ) -> anyhow::Result<Option<String>> {
+        if state.is_channel_encrypted(ch).await {
+            return send_sealed(ch, text, what, nickname, state, irc).await.map(Some);
+        }
+        let Some(identity) = &state.identity else {
+            if !matches!(what, Outgoing::Chat) {
+                anyhow::bail!("Replies, edits, deletions and reactions need an identity key");
+            }
+            irc.send_message(ch, text)?;
+            return Ok(None);
+        };
+        let parents = state.message_log.heads(ch).await;
+        let signed = what.sign(identity, nickname, ch, text, false, &parents)?;
+        let id = signed.id.clone();
+        let json = serde_json::to_string(&signed)?;
+        state.message_log.append(signed).await.ok();
+        irc.send_message(ch, &format!("SIGNED:{}", json))?;
+        Ok(Some(id))
+    }
+
+    /// Chat for a `+E` channel: if our sender key is new, share it with
+    /// every member whose key we've verified, then send the text sealed
+    /// under it.  Only the ciphertext is signed, logged and relayed.
+    async fn send_sealed(
+        ch: &str,
+        text: &str,
+        what: Outgoing,
+        nickname: &str,
+        state: &Arc<AppState>,
+        irc: &Arc<IrcClient>,
+    ) -> anyhow::Result<String> {
+        let identity = state.identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("{} is end-to-end encrypted and you have no identity key", ch))?;
+        let (share, fresh) = state.group_keys.write().await.sender_key(ch);
+        if fresh {
+            let members: Vec<String> = state.peer_states.read().await.keys().cloned().collect();
+            for member in members {
+                if let Err(e) = irc.send_key_share(&member, &share).await {
+                    state.add_message(ch, format!("{} can't read your messages here: {}", member, e)).await;
+                }
+            }
+        }
+        let sealed = state.group_keys.write().await.seal(ch, text)?;
+        let parents = state.message_log.heads(ch).await;
+        let signed = what.sign(identity, nickname, ch, &sealed, true, &parents)?;
+        let id = signed.id.clone();
+        let json = serde_json::to_string(&signed)?;
+        state.message_log.append(signed).await.ok();
+        irc.send_message(ch, &format!("SIGNED:{}", json))?;
+        Ok(id)
+    }
+
+    /// Send chat (answering `reply_to` if set) and put it in our own
+    /// scrollback.
+    async fn post_chat(
+        ch: &str,
+        text: &str,
+        reply_to: Option<String>,
+        nickname: &str,
+        state: &Arc<AppState>,
+        irc: &Arc<IrcClient>,
+    ) {
+        let what = reply_to.clone().map_or(Outgoing::Chat, Outgoing::Reply);
+        match send_chat(ch, text, what, nickname, state, irc).await {
+            Ok(id) => {
+                let status = if id.is_some() { Verification::Verified } else { Verification::Unsigned };
+                state.add_entry(ch, ChatEntry {
+                    id,
+                    author: Some(nickname.to_string()),
+                    text: text.to_string(),
+                    timestamp: chrono::Utc::now().timestamp(),
+                    status,
+                    reply_to,
+                }).await;
+            }
+            Err(e) => {
+                error!("Send: {}", e);
+                state.add_message(ch, format!("Could not send: {}", e)).await;
+            }
+        }
+    }
+
+    /// `/search`: list the newest matches from every channel's log.
+    async fn search_log(query: &SearchQuery, ch: &str, state: &Arc<AppState>) {
+        use chrono::TimeZone;
+        let results = state.message_log.search(query, SEARCH_RESULT_LINES).await;
+        let shown = if results.total > results.hits.len() {
+            format!("newest {} of {} matches", results.hits.len(), results.total)
+        } else {
+            format!("{} match{}", results.total, if results.total == 1 { "" } else { "es" })
+        };
+        state.add_message(ch, format!("── Search: {} ──", shown)).await;
+        for hit in results.hits {
+            let when = chrono::Local.timestamp_opt(hit.timestamp, 0).single()
+                .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            state.add_message(ch, format!("  {} {} {}: {}", when, hit.channel, hit.author, hit.text)).await;
+        }
+    }
+
+    /// `/reply`: answer `nick`'s last message here.
+    async fn reply_to_last(who: &str, text: &str, ch: &str, nickname: &str, state: &Arc<AppState>, irc: &Arc<IrcClient>) {
+        let key = match who == nickname {
+            true => state.identity.as_ref().map(|i| i.pubkey_hex.clone()),
+            false => state.pubkey_for_nick(who).await,
+        };
+        let target = match key {
+            Some(key) => state.message_log.last_message(ch, Some(&key)).await,
+            None => None,
+        };
+        match target {
+            Some(target) => post_chat(ch, text, Some(target.id), nickname, state, irc).await,
+            None => state.add_message(ch, format!("No signed message from {} to reply to here", who)).await,
+        }
+    }
+
+    /// `/edit`, `/delete` and `/react`: find the message meant (our last
+    /// one, or `nick`'s, or anyone's), send the event and redraw it.
+    async fn send_event(
+        kind: EventCommand,
+        nick: Option<&str>,
+        text: &str,
+        ch: &str,
+        nickname: &str,
+        state: &Arc<AppState>,
+        irc: &Arc<IrcClient>,
+    ) {
+        let ours = state.identity.as_ref().map(|i| i.pubkey_hex.clone());
+        let author = match nick {
+            Some(n) if n != nickname => match state.pubkey_for_nick(n).await {
+                Some(key) => Some(key),
+                None => {
+                    state.add_message(ch, format!("No signed messages from {} to go by", n)).await;
+                    return;
+                }
+            },
+            // Reactions go to the last message of anyone's by default.
+            None if kind == EventCommand::React => None,
+            _ => ours.clone(),
+        };
+        if kind == EventCommand::Delete && author != ours && !state.our_role().await.can_moderate() {
+            state.add_message(ch, "Only host and mods can delete other people's messages".to_string()).await;
+            return;
+        }
+        let Some(target) = state.message_log.last_message(ch, author.as_deref()).await else {
+            state.add_message(ch, "No message to act on here".to_string()).await;
+            return;
+        };
+        let target = target.id;
+        let event = match kind {
+            EventCommand::Edit => MessageEvent::Edit { target: target.clone() },
+            EventCommand::Delete => MessageEvent::Redact { target: target.clone() },
+            EventCommand::React => MessageEvent::React { target: target.clone() },
+        };
+        match send_chat(ch, text, Outgoing::Event(event), nickname, state, irc).await {
+            Ok(_) => refresh_signed(ch, &target, state).await,
+            Err(e) => state.add_message(ch, format!("Could not send: {}", e)).await,
+        }
+    }
+
+    /// Redraw signed message `id` after something changed it.
+    async fn refresh_signed(ch: &str, id: &str, state: &Arc<AppState>) {
+        if let Some((from, text)) = state.render_signed(ch, id).await {
+            state.update_signed_message(ch, id, text.clone()).await;
+            state.emit(CallEvent::MessageUpdated { channel: ch.to_string(), id: id.to_string(), from, text });
+        }
+    }
+
+    async fn set_pow(bits: u8, ch: &str, our_role: Role, state: &Arc<AppState>, irc: &Arc<IrcClient>) {
+        if !our_role.can_moderate() {
+            state.add_message(ch, "Only host/mod can change PoW difficulty.".to_string()).await;
+        } else {
+            let _ = irc.send_pow_set(bits);
+            // Confirmation arrives via PowRequirementChanged broadcast from server.
+        }
+    }
+
+    loop {
+        tokio::select! {
+            Some(cmd) = command_rx.recv() => {
+                match cmd {
+                    CallCommand::SendMessage(text) => {
+                        let ch = current_channel.read().await.clone();
+
+                        let peer_nicks: Vec<String> = state.peer_states.read().await
+                            .values()
+                            .filter(|ps| ps.connected)
+                            .map(|ps| ps.nickname.clone())
+                            .collect();
+                        let ctx = moderation::CommandContext {
+                            nick: nickname.clone(),
+                            channel: ch.clone(),
+                            role: our_role,
+                            peers: peer_nicks,
+                        };
+
+                        if let Some(command) = moderation::parse_command(&text, &custom_commands, &ctx) {
+                            match command {
+                                moderation::Command::Help => {
+                                    for line in moderation::help_text(our_role, &custom_commands) {
+                                        state.add_message(&ch, line).await;
+                                    }
+                                }
+                                moderation::Command::ShowRole => {
+                                    state.add_message(&ch, format!("Your role: {}", our_role.as_str())).await;
+                                    // Needed for `host_pubkey` when running a headless server.
+                                    if let Some(id) = state.identity.as_ref() {
+                                        state.add_message(&ch, format!("Your key: {}", id.pubkey_hex)).await;
+                                    }
+                                }
+                                moderation::Command::ListPeers => {
+                                    let peers_list = state.peer_states.read().await;
+                                    if peers_list.is_empty() {
+                                        state.add_message(&ch, "No peers connected".to_string()).await;
+                                    } else {
+                                        state.add_message(&ch, "-- Connected Peers --".to_string()).await;
+                                        for ps in peers_list.values() {
+                                            state.add_message(&ch, format!(
+                                                "  {} [{}] ({})", ps.nickname, ps.role.as_str(), ps.conn_state
+                                            )).await;
+                                        }
+                                    }
+                                }
+                                moderation::Command::ListBanned => {
+                                    if !our_role.can_moderate() {
+                                        state.add_message(&ch, "Only host and mods can view ban list".to_string()).await;
+                                    } else {
+                                        // Answered with ServerInfo lines.
+                                        let _ = irc.request_ban_list();
+                                    }
+                                }
+                                moderation::Command::Diag => {
+                                    let d = state.diagnostics.read().await;
+                                    state.add_message(&ch, "-- Connection Diagnostics --".to_string()).await;
+                                    state.add_message(&ch, format!("  Local IP: {}", d.local_ip.as_deref().unwrap_or("unknown"))).await;
+                                    state.add_message(&ch, format!("  External IP: {}", d.external_ip.as_deref().unwrap_or("unknown"))).await;
+                                    state.add_message(&ch, format!("  UPnP: {}", d.upnp_status.as_deref().unwrap_or("not checked"))).await;
+                                    state.add_message(&ch, format!("  TURN servers: {}", d.turn_configured)).await;
+                                    state.add_message(&ch, format!("  Relay: {}", if d.relay_enabled { "enabled" } else { "disabled" })).await;
+                                    if let Some(rp) = d.relay_port {
+                                        state.add_message(&ch, format!("  Relay port: {}", rp)).await;
+                                    }
+                                    state.add_message(&ch, format!("  Port open: {}", match d.port_open {
+                                        Some(true) => "yes",
+                                        Some(false) => "no",
+                                        None => "unknown",
+                                    })).await;
+
+                                    // Show peer connection states
+                                    let peers_list = state.peer_states.read().await;
+                                    if !peers_list.is_empty() {
+                                        state.add_message(&ch, "  Peer connections:".to_string()).await;
+                                        for ps in peers_list.values() {
+                                            state.add_message(&ch, format!("    {} - {}", ps.nickname, ps.conn_state)).await;
+                                        }
+                                    }
+                                }
+                                moderation::Command::EditCommands => {
+                                    let path = moderation::CustomCommands::commands_path();
+                                    state.add_message(&ch, format!("Commands file: {}", path.display())).await;
+                                    let opened = std::process::Command::new("xdg-open")
+                                        .arg(&path)
+                                        .spawn()
+                                        .or_else(|_| std::process::Command::new("open").arg(&path).spawn())
+                                        .or_else(|_| std::process::Command::new("notepad").arg(&path).spawn());
+                                    match opened {
+                                        Ok(_) => {
+                                            state.add_message(&ch, "Opened in editor. Use /reload when done.".to_string()).await;
+                                        }
+                                        Err(_) => {
+                                            state.add_message(&ch, "Could not open editor - edit the file manually, then /reload".to_string()).await;
+                                        }
+                                    }
+                                }
+                                moderation::Command::Invite => {
+                                    let link = invite_link.read().await;
+                                    if let Some(l) = link.as_ref() {
+                                        state.add_message(&ch, format!("Invite link: {}", l)).await;
+                                    } else {
+                                        state.add_message(&ch, "No invite link available yet (external IP still resolving)".to_string()).await;
+                                    }
+                                }
+                                moderation::Command::InviteUser(nick) => {
+                                    let _ = irc.invite_user(&nick, &ch);
+                                }
+                                moderation::Command::Topic(None) => {
+                                    let _ = irc.request_topic(&ch);
+                                }
+                                moderation::Command::Topic(Some(text)) => {
+                                    let _ = irc.set_topic(&ch, &text);
+                                }
+                                moderation::Command::Mode(modes) => {
+                                    let _ = irc.mode(&ch, &modes);
+                                }
+                                moderation::Command::ListChannels => {
+                                    state.add_message(&ch, "── Channels ──".to_string()).await;
+                                    let _ = irc.list_channels();
+                                }
+                                moderation::Command::Join { channel: new_ch, key } => {
+                                    if new_ch == ch {
+                                        state.add_message(&ch, format!("Already in {}", ch)).await;
+                                    } else {
+                                        let created = {
+                                            let mut ch_list = channels.write().await;
+                                            let is_new = !ch_list.contains(&new_ch);
+                                            if is_new {
+                                                ch_list.push(new_ch.clone());
+                                            }
+                                            is_new
+                                        };
+                                        change_channel(&new_ch, key.as_deref(), our_role, &state, &peers, &irc, &current_channel, &voice).await;
+                                        let verb = if created { "Created and joined" } else { "Joined" };
+                                        state.add_message(&new_ch, format!("{} {}", verb, new_ch)).await;
+                                    }
+                                }
+                                moderation::Command::DirectMessage { to, text } => {
+                                    let line = if send_direct(&to, &text, &nickname, &state, &irc).await {
+                                        format!("Encrypted DM sent to {}", to)
+                                    } else {
+                                        format!("Could not send an encrypted DM to {} (no verified key)", to)
+                                    };
+                                    state.add_message(&ch, line).await;
+                                }
+                                moderation::Command::Verify { nick: who, confirm } => {
+                                    verify_identity(&who, confirm, &ch, &state).await;
+                                }
+                                moderation::Command::Edit(text) => {
+                                    send_event(EventCommand::Edit, None, &text, &ch, &nickname, &state, &irc).await;
+                                }
+                                moderation::Command::Delete(who) => {
+                                    send_event(EventCommand::Delete, who.as_deref(), "", &ch, &nickname, &state, &irc).await;
+                                }
+                                moderation::Command::Reply { nick: who, text } => {
+                                    reply_to_last(&who, &text, &ch, &nickname, &state, &irc).await;
+                                }
+                                moderation::Command::React { reaction, nick: who } => {
+                                    send_event(EventCommand::React, who.as_deref(), &reaction, &ch, &nickname, &state, &irc).await;
+                                }
+                                moderation::Command::Search(query) => {
+                                    search_log(&query, &ch, &state).await;
+                                }
+                                moderation::Command::Reload => {
+                                    custom_commands = moderation::CustomCommands::load();
+                                    state.add_message(&ch, "Custom commands reloaded".to_string()).await;
+                                }
+                                moderation::Command::Mod(action) => {
+                                    apply_mod_action(&action, &ch, our_role, &state, &irc).await;
+                                }
+                                moderation::Command::Custom { response, broadcast } => {
+                                    if broadcast {
+                                        if let Err(e) = irc.send_message(&ch, &response) {
+                                            error!("Send: {}", e);
+                                        } else {
+                                            state.add_message(&ch, format!("<{}> {}", nickname, response)).await;
+                                        }
+                                    } else {
+                                        state.add_message(&ch, response).await;
+                                    }
+                                }
+                                moderation::Command::Unknown(cmd) => {
+                                    state.add_message(&ch, format!("Unknown command: {}. Type /help for commands.", cmd)).await;
+                                }
+                              moderation::Command::SetPow(bits) => {
+                                  set_pow(bits, &ch, our_role, &state, &irc).await;
+                              }
+                              moderation::Command::MineNick { bits } => {
+                                  // /mine lets you pre-emptively grind a stronger nick while you're
+                                  // already in the room, ready for when you next reconnect or join
+                                  // a room with stricter requirements.
+                                  let base = crate::pow::base_name(&nickname).to_string();
+                                  let pubkey = state.identity.as_ref()
+                                      .map(|id| id.pubkey_hex.clone())
+                                      .unwrap_or_default();
+                                  if pubkey.is_empty() {
+                                      state.add_message(&ch, "No identity key found.".to_string()).await;
+                                  } else {
+                                      let already_ok = crate::pow::check_difficulty(&nickname, &pubkey, bits);
+                                      if already_ok {
+                                          let actual = crate::pow::leading_zero_bits(
+                                              &crate::pow::nick_hash(&nickname, &pubkey)
+                                          );
+                                          state.add_message(&ch, format!(
+                                              "Your current nick '{}' already has {} bits — no need to re-mine for {} bits.",
+                                              nickname, actual, bits
+                                          )).await;
+                                      } else {
+                                          state.add_message(&ch, format!(
+                                              "Mining '{}' at {} bits (~{})…",
+                                              base, bits, crate::pow::time_estimate(bits)
+                                          )).await;
+                                          let state_c = Arc::clone(&state);
+                                          let ch_c = ch.clone();
+                                          tokio::task::spawn_blocking(move || {
+                                              let result = crate::pow::mine_nick(&base, &pubkey, bits, 200_000_000);
+                                              let handle = tokio::runtime::Handle::current();
+                                              handle.block_on(async move {
+                                                  match result {
+                                                      Some(m) => state_c.add_message(&ch_c, format!(
+                                                          "✓ New nick ready: '{}' ({} bits, {} attempts). \
+                                                           Go to Settings → Display Name, paste it, then reconnect.",
+                                                          m.nick, m.bits, m.attempts
+                                                      )).await,
+                                                      None => state_c.add_message(&ch_c,
+                                                          "Mining failed (limit reached). Try a lower difficulty.".to_string()
+                                                      ).await,
+                                                  }
+                                              });
+                                          });
+                                      }
+                                  }
+                              }
+                            }
+                        } else {
+                            post_chat(&ch, &text, None, &nickname, &state, &irc).await;
+                        }
+                    }
+                    CallCommand::SwitchChannel(new_ch) => {
+                        change_channel(&new_ch, None, our_role, &state, &peers, &irc, &current_channel, &voice).await;
+                        state.add_message(&new_ch, format!("Joined {}", new_ch)).await;
+                    }
+                    CallCommand::CreateChannel(new_ch) => {
+                        let mut ch_list = channels.write().await;
+                        if !ch_list.contains(&new_ch) {
+                            ch_list.push(new_ch.clone());
+                            drop(ch_list);
+
+                            change_channel(&new_ch, None, our_role, &state, &peers, &irc, &current_channel, &voice).await;
+                            state.add_message(&new_ch, format!("Created and joined {}", new_ch)).await;
+                        }
+                    }
+                    CallCommand::SendFile { name, data } => {
+                        let ch = current_channel.read().await.clone();
+                        let size = data.len();
+                        let r = peers.read().await;
+                        let mut ok = 0usize;
+                        for p in r.values() {
+                            if p.send_file(&name, &data).await.is_ok() {
+                                ok += 1;
+                            }
+                        }
+                        let kb = size / 1024;
+                        state.add_message(&ch, format!(
+                            "You shared {} ({} KB) -> {} peers", name, kb, ok
+                        )).await;
+                    }
+                    CallCommand::SendReply { reply_to, text } => {
+                        let ch = current_channel.read().await.clone();
+                        post_chat(&ch, &text, Some(reply_to), &nickname, &state, &irc).await;
+                    }
+                    CallCommand::SendDirect { to, text } => {
+                        send_direct(&to, &text, &nickname, &state, &irc).await;
+                    }
+                    CallCommand::SetMuted(on) => {
+                        voice.set_muted(on);
+                        let ch = current_channel.read().await.clone();
+                        let _ = irc.announce_voice(&ch, voice.status());
+                    }
+                    CallCommand::SetDeafened(on) => {
+                        voice.set_deafened(on);
+                        let ch = current_channel.read().await.clone();
+                        let _ = irc.announce_voice(&ch, voice.status());
+                    }
+                    CallCommand::Shutdown => {
+                        info!("Shutdown");
+                        break;
+                    }
+                }
+            }
+
+            Some(file) = file_rx.recv() => {
+                let ch = current_channel.read().await.clone();
+                let size = file.data.len();
+                let kb = size / 1024;
+
+                let save_dir = dirs::download_dir().unwrap_or_else(|| std::path::PathBuf::from("."));
+                let save_path = save_dir.join(&file.name);
+                match std::fs::write(&save_path, &file.data) {
+                    Ok(_) => {
+                        state.add_received_file(
+                            file.from.clone(), file.name.clone(), size, save_path.clone(),
+                        ).await;
+                        state.emit(CallEvent::FileReceived {
+                            from: file.from.clone(), name: file.name.clone(), path: save_path.clone(),
+                        });
+                        state.add_message(&ch, format!(
+                            "{} shared {} ({} KB)",
+                            file.from, file.name, kb,
+                        )).await;
+                    }
+                    Err(e) => {
+                        state.add_message(&ch, format!(
+                            "{} shared {} ({} KB) - save failed: {}",
+                            file.from, file.name, kb, e
+                        )).await;
+                    }
+                }
+            }
+
+            // Connection failure - suggest relay
+            Some(nick) = conn_failed_rx.recv() => {
+                let ch = current_channel.read().await.clone();
+                if relay_addr.is_some() {
+                    state.add_message(&ch, format!(
+                        "WebRTC to {} failed - audio will route through relay", nick
+                    )).await;
+                    state.set_peer_conn_state(&nick, ConnState::Relayed).await;
+                } else {
+                    state.add_message(&ch, format!(
+                        "Connection to {} failed. Ask the host to enable relay mode.", nick
+                    )).await;
+                }
+            }
+
+            Some(nick) = reconnect_rx.recv() => {
+                info!("Reconnect: {}", nick);
+                peers.write().await.remove(&nick);
+
+                if !state.peer_states.read().await.contains_key(&nick) { continue; }
+
+                let mut attempts = reconnect_attempts.write().await;
+                let attempt = attempts.entry(nick.clone()).or_insert(0);
+                *attempt += 1;
+                let delay = std::cmp::min(2u64.pow(*attempt), 30);
+                drop(attempts);
+
+                info!("Reconnect backoff for {}: {}s", nick, delay);
+                tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+
+                if nickname < nick {
+                    let target_role = state.get_peer_role(&nick).await;
+                    if !topology::should_connect_to(&state, &nickname, our_role, &nick, target_role).await {
+                        continue;
+                    }
+
+                    state.set_peer_connecting(&nick).await;
+
+                    match WebRtcPeer::new(
+                        nick.clone(), Arc::clone(&state), mix_tx.clone(),
+                        ice_out_tx.clone(), file_tx.clone(), turn_servers.clone(), codec,
+                    ).await {
+                        Ok(peer) => {
+                            if let Ok(offer) = peer.create_offer().await {
+                                peers.write().await.insert(nick.clone(), Arc::new(peer));
+                                let _ = irc.send_webrtc_signal(&nick, &offer);
+                                let ch = current_channel.read().await.clone();
+                                state.add_message(&ch, format!("-> Reconnecting to {}", nick)).await;
+                            }
+                        }
+                        Err(e) => error!("Reconnect peer: {}", e),
+                    }
+                }
+            }
+
+            Some(event) = irc_events.recv() => {
+                match event {
+                    IrcEvent::UserJoined { nick, role } => {
+                        info!("Joined: {} (role: {:?})", nick, role);
+
+                        state.update_peer_state(nick.clone(), false, false).await;
+                        state.set_peer_role(&nick, role).await;
+                        // Whatever we say next needs a key they didn't see the past under.
+                        state.group_keys.write().await.rotate(&current_channel.read().await);
+                        state.emit(CallEvent::PeerJoined { nick: nick.clone(), role });
+
+                        reconnect_attempts.write().await.remove(&nick);
+
+                        // Newcomers assume everyone is unmuted.
+                        if voice.status() != VoiceStatus::default() {
+                            let ch = current_channel.read().await.clone();
+                            let _ = irc.announce_voice(&ch, voice.status());
+                        }
+
+                        // Offer the joining peer a digest of every channel we share.
+                        {
+                            let mut digests = Vec::new();
+                            for ch in shared_channels(&nick, &state, &channels).await.iter() {
+                                digests.push(state.message_log.digest(ch).await);
+                            }
+                            let _ = irc.send_sync(&nick, &SyncMessage::Digest { channels: digests });
+                        }
+
+                        maybe_create_peer(
+                            &nick, &nickname, our_role, &state, &peers,
+                            &irc, &mix_tx, &ice_out_tx, &file_tx,
+                            &turn_servers, codec, &current_channel,
+                        ).await;
+                    }
+
+                    IrcEvent::UserLeft(nick) => {
+                        if let Some(p) = peers.write().await.remove(&nick) {
+                            p.close().await;
+                        }
+                        state.remove_peer(&nick).await;
+                        state.group_keys.write().await.rotate(&current_channel.read().await);
+                        reconnect_attempts.write().await.remove(&nick);
+                        state.emit(CallEvent::PeerLeft(nick));
+                    }
+
+                   IrcEvent::PowRequirementChanged { bits } => {
+                       state.emit(CallEvent::PowRequirementChanged { bits });
+                       let ch = current_channel.read().await.clone();
+                       if bits == 0 {
+                           state.add_message(&ch,
+                               "PoW requirement removed — anyone can now register a nick.".to_string()
+                           ).await;
+                       } else {
+                           state.add_message(&ch, format!(
+                               "⚡ Nick PoW requirement changed to {} bits. \
+                                Reconnecting users whose nick has < {} bits will need to /mine first (~{}).",
+                               bits, bits, crate::pow::time_estimate(bits)
+                           )).await;
+                       }
+                   }
+
+                    IrcEvent::WebRtcSignal { from, payload } => {
+                        match serde_json::from_str::<WebRtcSignal>(&payload) {
+                            Ok(WebRtcSignal::Offer { sdp }) => {
+                                let from_role = state.get_peer_role(&from).await;
+                                if !topology::should_connect_to(&state, &nickname, our_role, &from, from_role).await {
+                                    info!("Topology: rejecting offer from {}", from);
+                                    continue;
+                                }
+
+                                state.set_peer_connecting(&from).await;
+
+                                match WebRtcPeer::new(
+                                    from.clone(), Arc::clone(&state), mix_tx.clone(),
+                                    ice_out_tx.clone(), file_tx.clone(), turn_servers.clone(), codec,
+                                ).await {
+                                    Ok(peer) => {
+                                        if let Ok(answer) = peer.handle_offer(sdp).await {
+                                            peers.write().await.insert(from.clone(), Arc::new(peer));
+                                            let _ = irc.send_webrtc_signal(&from, &answer);
+                                            reconnect_attempts.write().await.remove(&from);
+                                        }
+                                    }
+                                    Err(e) => error!("Peer for offer: {}", e),
+                                }
+                            }
+                            Ok(WebRtcSignal::Answer { sdp }) => {
+                                if let Some(p) = peers.read().await.get(&from) {
+                                    let _ = p.handle_answer(sdp).await;
+                                    reconnect_attempts.write().await.remove(&from);
+                                }
+                            }
+                            Ok(WebRtcSignal::IceCandidate { candidate, sdp_mid, sdp_mline_index }) => {
+                                if let Some(p) = peers.read().await.get(&from) {
+                                    let _ = p.add_ice_candidate(candidate, sdp_mid, sdp_mline_index).await;
+                                }
+                            }
+                            Err(e) => error!("Parse signal: {}", e),
+                        }
+                    }
+
+                    IrcEvent::ChatMessage { channel, entry } => {
+                        let (from, text) = (entry.author.clone().unwrap_or_default(), entry.text.clone());
+                        state.add_entry(&channel, entry).await;
+                        state.emit(CallEvent::Chat { channel, from, text });
+                    }
+
+                    IrcEvent::MessageUpdated { channel, id, from, text } => {
+                        state.update_signed_message(&channel, &id, text.clone()).await;
+                        state.emit(CallEvent::MessageUpdated { channel, id, from, text });
+                    }
+
+                    IrcEvent::DirectMessage { from, text } => {
+                        state.add_direct_message(&from, format!("<{}> {}", from, text)).await;
+                        let ch = current_channel.read().await.clone();
+                        state.add_message(&ch, format!("Encrypted DM from {}", from)).await;
+                        state.emit(CallEvent::DirectMessage { from, text });
+                    }
+
+                    IrcEvent::Sync { from, msg } => {
+                        handle_sync(&from, msg, &state, &irc, &channels).await;
+                    }
+
+                    IrcEvent::IdentityChanged { nick, was_verified } => {
+                        let ch = current_channel.read().await.clone();
+                        let previous = if was_verified { "the one you verified" } else { "the one you knew" };
+                        state.add_message(&ch, format!(
+                            "Warning: {} is using a different key than {}. Compare safety numbers (/verify {}) before trusting them.",
+                            nick, previous, nick
+                        )).await;
+                        state.emit(CallEvent::IdentityChanged { nick });
+                    }
+
+                    IrcEvent::DirectMessageFailed { from } => {
+                        state.add_direct_message(&from, "A message that failed to decrypt or was a replay was dropped".to_string()).await;
+                    }
+
+                    IrcEvent::ModAction { from, action, target } => {
+                        let from_role = state.get_peer_role(&from).await;
+                        if !from_role.can_moderate() {
+                            warn!("Non-mod {} tried to use mod action", from);
+                            continue;
+                        }
+                        state.emit(CallEvent::ModAction {
+                            from: from.clone(), action: action.clone(), target: target.clone(),
+                        });
+
+                        let ch = current_channel.read().await.clone();
+                        match action.as_str() {
+                            "kick" => {
+                                if target == nickname {
+                                    state.add_message(&ch, format!("You were kicked by {}", from)).await;
+                                } else {
+                                    state.add_message(&ch, format!("{} was kicked by {}", target, from)).await;
+                                }
+                            }
+                            "ban" => {
+                                if target == nickname {
+                                    state.add_message(&ch, format!("You were banned by {}", from)).await;
+                                } else {
+                                    state.add_message(&ch, format!("{} was banned by {}", target, from)).await;
+                                }
+                            }
+                            // Role changes arrive as RoleChanged from the server.
+                            _ => {}
+                        }
+                    }
+
+                    IrcEvent::RoleChanged { nick, role, by } => {
+                        if nick == nickname {
+                            our_role = role;
+                            state.set_our_role(role).await;
+                        } else {
+                            state.set_peer_role(&nick, role).await;
+                        }
+                        let Some(by) = by else { continue };
+                        let action = if role == Role::Mod { "promote" } else { "demote" };
+                        state.emit(CallEvent::ModAction {
+                            from: by.clone(), action: action.to_string(), target: nick.clone(),
+                        });
+                        let ch = current_channel.read().await.clone();
+                        let line = match (nick == nickname, role) {
+                            (true, Role::Mod) => format!("You were promoted to mod by {}", by),
+                            (true, _) => "You were demoted from mod".to_string(),
+                            (false, Role::Mod) => format!("{} was promoted to mod by {}", nick, by),
+                            (false, _) => format!("{} was demoted from mod", nick),
+                        };
+                        state.add_message(&ch, line).await;
+                    }
+
+                    IrcEvent::ModRejected(reason) => {
+                        let ch = current_channel.read().await.clone();
+                        state.add_message(&ch, format!("Server refused: {}", reason)).await;
+                    }
+
+                    IrcEvent::ServerInfo(text) => {
+                        let ch = current_channel.read().await.clone();
+                        state.add_message(&ch, text).await;
+                    }
+
+                    IrcEvent::Topic { channel, topic, by } => {
+                        state.set_topic(&channel, topic.clone()).await;
+                        let text = match (topic, by) {
+                            (Some(t), Some(by)) => format!("{} set the topic: {}", by, t),
+                            (None, Some(by)) => format!("{} cleared the topic", by),
+                            (Some(t), None) => format!("Topic: {}", t),
+                            (None, None) => "No topic is set".to_string(),
+                        };
+                        state.add_message(&channel, text).await;
+                    }
+
+                    IrcEvent::ChannelMode { channel, change, by } => {
+                        state.add_message(&channel, format!("{} sets mode {}", by, change)).await;
+                    }
+
+                    IrcEvent::ChannelEncryption { channel, on, by } => {
+                        state.set_channel_encrypted(&channel, on).await;
+                        let text = match (on, by) {
+                            (true, Some(by)) => format!("{} made {} end-to-end encrypted", by, channel),
+                            (false, Some(by)) => format!("{} turned off end-to-end encryption in {}", by, channel),
+                            (true, None) => format!("Messages in {} are end-to-end encrypted", channel),
+                            (false, None) => format!("Messages in {} are no longer end-to-end encrypted", channel),
+                        };
+                        state.add_message(&channel, text).await;
+                    }
+
+                    IrcEvent::Invited { channel, by } => {
+                        let ch = current_channel.read().await.clone();
+                        state.add_message(&ch, format!("{} invited you to {} (/join {})", by, channel, channel)).await;
+                    }
+
+                    IrcEvent::JoinFailed { channel, reason } => {
+                        // We already switched over; go back to a channel we can be in.
+                        let fallback = {
+                            let mut ch_list = channels.write().await;
+                            ch_list.retain(|c| *c != channel);
+                            ch_list.first().cloned().unwrap_or_else(|| "#general".to_string())
+                        };
+                        if *current_channel.read().await == channel {
+                            change_channel(&fallback, None, our_role, &state, &peers, &irc, &current_channel, &voice).await;
+                        }
+                        state.add_message(&fallback, format!("Cannot join {}: {}", channel, reason)).await;
+                    }
+
+                    IrcEvent::Muted { nick, kind, secs, by } => {
+                        if kind == MuteKind::Voice {
+                            state.set_voice_muted(&nick, true).await;
+                        }
+                        let (what, action) = match kind {
+                            MuteKind::Chat => ("muted", "mute"),
+                            MuteKind::Voice => ("voice-muted", "voicemute"),
+                        };
+                        let left = secs.map_or(String::new(), |s| format!(" for {}", format_duration(s)));
+                        let ch = current_channel.read().await.clone();
+                        match by {
+                            Some(by) => {
+                                state.emit(CallEvent::ModAction {
+                                    from: by.clone(),
+                                    action: action.to_string(),
+                                    target: nick.clone(),
+                                });
+                                let who = if nick == nickname { "You were".to_string() } else { format!("{} was", nick) };
+                                state.add_message(&ch, format!("{} {} by {}{}", who, what, by, left)).await;
+                            }
+                            None if nick == nickname => {
+                                state.add_message(&ch, format!("You are {}{}", what, left)).await;
+                            }
+                            None => {}
+                        }
+                    }
+
+                    IrcEvent::Unmuted { nick, kind, by } => {
+                        if kind == MuteKind::Voice {
+                            state.set_voice_muted(&nick, false).await;
+                        }
+                        let (what, action) = match kind {
+                            MuteKind::Chat => ("mute", "unmute"),
+                            MuteKind::Voice => ("voice mute", "voiceunmute"),
+                        };
+                        let whose = if nick == nickname { "Your".to_string() } else { format!("{}'s", nick) };
+                        let ch = current_channel.read().await.clone();
+                        if by == SERVER_NAME {
+                            state.add_message(&ch, format!("{} {} has expired", whose, what)).await;
+                        } else {
+                            state.emit(CallEvent::ModAction {
+                                from: by.clone(),
+                                action: action.to_string(),
+                                target: nick.clone(),
+                            });
+                            state.add_message(&ch, format!("{} {} was lifted by {}", whose, what, by)).await;
+                        }
+                    }
+
+                    IrcEvent::SlowMode { channel, interval, secs, by } => {
+                        let text = if interval == 0 {
+                            match by.as_deref() {
+                                Some(SERVER_NAME) => format!("Slow mode in {} has ended", channel),
+                                Some(by) => format!("Slow mode in {} turned off by {}", channel, by),
+                                None => continue,
+                            }
+                        } else {
+                            let left = secs.map_or(String::new(), |s| format!(" for {}", format_duration(s)));
+                            let by = by.map_or(String::new(), |b| format!(" (set by {})", b));
+                            format!("Slow mode in {}: one message every {}{}{}", channel, format_duration(interval), left, by)
+                        };
+                        state.add_message(&channel, text).await;
+                    }
+                    
+                    // FIX: Added handler for PowTooWeak
+                    IrcEvent::PowTooWeak { required_bits } => {
+                        let ch = current_channel.read().await.clone();
+                        state.add_message(&ch, format!(
+                            "❌ Connection Rejected: Your nick's Proof-of-Work is too weak. \
+                             Server requires {} bits. Please run '/mine {}' to upgrade your nick.",
+                            required_bits, required_bits
+                        )).await;
+                    }
+                }
+            }
+
+            else => break,
+        }
+    }
+
+    info!("Event loop exiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bans::BanList;
+    use crate::contacts::KeyTrust;
+    use crate::irc_server::EmbeddedServer;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    async fn next_matching<F>(rx: &mut broadcast::Receiver<CallEvent>, mut pred: F) -> CallEvent
+    where
+        F: FnMut(&CallEvent) -> bool,
+    {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match rx.recv().await {
+                    Ok(ev) if pred(&ev) => return ev,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(e) => panic!("event stream closed: {}", e),
+                }
+            }
+        })
+        .await
+        .expect("timed out waiting for event")
+    }
+
+    fn session(nick: &str, port: u16, dir: &TempDir) -> CallSession {
+        let info = ConnectionInfo::new("127.0.0.1".to_string(), port, vec!["#test".to_string()]);
+        let identity = Identity::load_or_generate(dir.path()).ok();
+        let opts = SessionOptions::new(nick.to_string(), info)
+            .with_audio(false)
+            .with_identity(identity)
+            .with_data_dir(dir.path().to_path_buf());
+        CallSession::start(opts).unwrap()
+    }
+
+    async fn start_server(host_pubkey: Option<String>) -> u16 {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        tokio::spawn(EmbeddedServer::run(port, 0, host_pubkey, BanList::new(None).shared()));
+        eventually(move || async move { tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_ok() }).await;
+        port
+    }
+
+    /// Poll `check` until it holds; fails the test after ten seconds.
+    async fn eventually<F, Fut>(mut check: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !check().await {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for condition")
+    }
+
+    /// Wait until `a` and `b` have each seen the other's key announced.
+    async fn exchange_keys(a: &CallSession, b: &CallSession) {
+        eventually(move || async move {
+            a.state.pubkey_for_nick(&b.nickname).await.is_some() && b.state.pubkey_for_nick(&a.nickname).await.is_some()
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_session_chat_roundtrip() {
+        let port = start_server(None).await;
+
+        let (dir_a, dir_b) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let alice = session("alice", port, &dir_a);
+        let mut alice_rx = alice.subscribe();
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+
+        let bob = session("bob", port, &dir_b);
+        let mut bob_rx = bob.subscribe();
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::PeerJoined { nick, .. } if nick == "bob")).await;
+
+        alice.send(CallCommand::SendMessage("hello bob".to_string()));
+        let ev = next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Chat { .. })).await;
+        match ev {
+            CallEvent::Chat { channel, from, text } => {
+                assert_eq!(channel, "#test");
+                assert_eq!(from, "alice");
+                assert_eq!(text, "hello bob");
+            }
+            _ => unreachable!(),
+        }
+
+        bob.send(CallCommand::Shutdown);
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Ended)).await;
+        alice.send(CallCommand::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn test_mute_is_announced_to_peers() {
+        let port = start_server(None).await;
+
+        let (dir_a, dir_b) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let alice = session("alice", port, &dir_a);
+        let mut alice_rx = alice.subscribe();
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+
+        let bob = session("bob", port, &dir_b);
+        let mut bob_rx = bob.subscribe();
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::PeerJoined { nick, .. } if nick == "bob")).await;
+        // Bob needs alice's key before her signaling is accepted.
+        exchange_keys(&alice, &bob).await;
+
+        alice.send(CallCommand::SetMuted(true));
+        let ev = next_matching(&mut bob_rx, |e| matches!(e, CallEvent::PeerVoice { .. })).await;
+        match ev {
+            CallEvent::PeerVoice { nick, status } => {
+                assert_eq!(nick, "alice");
+                assert_eq!(status, VoiceStatus { muted: true, deafened: false });
+            }
+            _ => unreachable!(),
+        }
+        assert!(alice.voice.is_muted());
+        assert!(!alice.voice.transmitting());
+
+        alice.send(CallCommand::Shutdown);
+        bob.send(CallCommand::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_is_end_to_end() {
+        let port = start_server(None).await;
+
+        let (dir_a, dir_b) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let alice = session("alice", port, &dir_a);
+        let mut alice_rx = alice.subscribe();
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+
+        let bob = session("bob", port, &dir_b);
+        let mut bob_rx = bob.subscribe();
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::PeerJoined { nick, .. } if nick == "bob")).await;
+        exchange_keys(&alice, &bob).await;
+
+        // Unverified keys are refused; each side checks the other's first.
+        alice.send(CallCommand::SendDirect { to: "bob".to_string(), text: "hi".to_string() });
+        alice.send(CallCommand::SendMessage("/verify bob confirm".to_string()));
+        bob.send(CallCommand::SendMessage("/verify alice confirm".to_string()));
+
+        // Long enough to need several E2E: fragments.
+        let text = "meet at 10 ".repeat(60);
+        alice.send(CallCommand::SendDirect { to: "bob".to_string(), text: text.clone() });
+        let ev = next_matching(&mut bob_rx, |e| matches!(e, CallEvent::DirectMessage { .. })).await;
+        match ev {
+            CallEvent::DirectMessage { from, text: got } => {
+                assert_eq!(from, "alice");
+                assert_eq!(got, text);
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(bob.state.direct_messages.read().await["alice"], vec![format!("<alice> {}", text)]);
+        let sent = alice.state.direct_messages.read().await["bob"].clone();
+        assert!(sent[0].starts_with("Not sent: bob's key isn't verified"));
+        assert_eq!(sent[1..], [format!("<alice> {}", text)]);
+
+        bob.send(CallCommand::SendMessage("/msg alice ok".to_string()));
+        let ev = next_matching(&mut alice_rx, |e| matches!(e, CallEvent::DirectMessage { .. })).await;
+        assert!(matches!(ev, CallEvent::DirectMessage { from, text } if from == "bob" && text == "ok"));
+
+        alice.send(CallCommand::Shutdown);
+        bob.send(CallCommand::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_channel_chat() {
+        let (dir_a, dir_b) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let host_key = Identity::load_or_generate(dir_a.path()).unwrap().pubkey_hex;
+        let port = start_server(Some(host_key)).await;
+
+        let alice = session("alice", port, &dir_a);
+        let mut alice_rx = alice.subscribe();
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+
+        let bob = session("bob", port, &dir_b);
+        let mut bob_rx = bob.subscribe();
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::PeerJoined { nick, .. } if nick == "bob")).await;
+        exchange_keys(&alice, &bob).await;
+        alice.send(CallCommand::SendMessage("/verify bob confirm".to_string()));
+        bob.send(CallCommand::SendMessage("/verify alice confirm".to_string()));
+        let (a, b) = (&alice, &bob);
+        eventually(move || async move {
+            let (bobs, alices) = (a.state.pubkey_for_nick("bob").await.unwrap(), b.state.pubkey_for_nick("alice").await.unwrap());
+            a.state.contacts.read().await.trust("bob", &bobs) == KeyTrust::Verified
+                && b.state.contacts.read().await.trust("alice", &alices) == KeyTrust::Verified
+        })
+        .await;
+
+        // Nobody has verified carol, so she's sent no keys.
+        let dir_c = TempDir::new().unwrap();
+        let carol = session("carol", port, &dir_c);
+        let mut carol_rx = carol.subscribe();
+        next_matching(&mut carol_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        exchange_keys(&alice, &carol).await;
+        exchange_keys(&bob, &carol).await;
+
+        alice.send(CallCommand::SendMessage("/cmode +E".to_string()));
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Line { text, .. } if text.contains("end-to-end encrypted"))).await;
+        next_matching(&mut carol_rx, |e| matches!(e, CallEvent::Line { text, .. } if text.contains("end-to-end encrypted"))).await;
+        assert!(alice.state.is_channel_encrypted("#test").await);
+
+        bob.send(CallCommand::SendMessage("psst".to_string()));
+        let ev = next_matching(&mut alice_rx, |e| matches!(e, CallEvent::Chat { .. })).await;
+        assert!(matches!(ev, CallEvent::Chat { from, text, .. } if from == "bob" && text == "psst"));
+        next_matching(&mut bob_rx, |e| {
+            matches!(e, CallEvent::Line { text, .. } if text.starts_with("carol can't read your messages here: carol's key isn't verified"))
+        })
+        .await;
+        // What got signed and logged is the ciphertext.
+        let logged = alice.state.message_log.get_messages("#test").await;
+        assert!(logged.iter().all(|m| m.encrypted && !m.content.contains("psst")));
+
+        let ev = next_matching(&mut carol_rx, |e| matches!(e, CallEvent::Chat { .. })).await;
+        assert!(matches!(&ev, CallEvent::Chat { from, text, .. } if from == "bob" && text.starts_with("[encrypted")));
+        let sealed = &logged.last().unwrap().content;
+        let bob_key = carol.state.pubkey_for_nick("bob").await.unwrap();
+        assert!(carol.state.group_keys.read().await.open("#test", &bob_key, sealed).is_err());
+
+        alice.send(CallCommand::SendMessage("heard".to_string()));
+        let ev = next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Chat { .. })).await;
+        assert!(matches!(ev, CallEvent::Chat { from, text, .. } if from == "alice" && text == "heard"));
+
+        alice.send(CallCommand::Shutdown);
+        bob.send(CallCommand::Shutdown);
+        carol.send(CallCommand::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn test_edits_reactions_and_deletions() {
+        let (dir_a, dir_b) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let host_key = Identity::load_or_generate(dir_a.path()).unwrap().pubkey_hex;
+        let port = start_server(Some(host_key)).await;
+
+        let alice = session("alice", port, &dir_a);
+        let mut alice_rx = alice.subscribe();
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        let bob = session("bob", port, &dir_b);
+        let mut bob_rx = bob.subscribe();
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::PeerJoined { nick, .. } if nick == "bob")).await;
+        exchange_keys(&alice, &bob).await;
+
+        alice.send(CallCommand::SendMessage("helo".to_string()));
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Chat { .. })).await;
+        alice.send(CallCommand::SendMessage("/edit hello".to_string()));
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::MessageUpdated { text, .. } if text == "hello (edited)")).await;
+        assert!(bob.state.messages.read().await["#test"].iter().any(|e| e.line() == "<alice> hello (edited)"));
+
+        bob.send(CallCommand::SendMessage("/react 👍".to_string()));
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::MessageUpdated { text, .. } if text.ends_with("[👍 1]"))).await;
+        // Search finds the corrected text.
+        bob.send(CallCommand::SendMessage("/search hel from:alice".to_string()));
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Line { text, .. } if text.ends_with("#test alice: hello"))).await;
+        // Only the author or a mod may take it back.
+        bob.send(CallCommand::SendMessage("/delete alice".to_string()));
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Line { text, .. } if text.starts_with("Only host and mods"))).await;
+        alice.send(CallCommand::SendMessage("/delete".to_string()));
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::MessageUpdated { text, .. } if text == "[message deleted]")).await;
+
+        // Retracted messages drop out of search.
+        alice.send(CallCommand::SendMessage("/search hel from:alice".to_string()));
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::Line { text, .. } if text == "── Search: 0 matches ──")).await;
+
+        alice.send(CallCommand::Shutdown);
+        bob.send(CallCommand::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn test_history_syncs_for_every_joined_channel() {
+        let port = start_server(None).await;
+
+        let (dir_a, dir_b, dir_c) = (TempDir::new().unwrap(), TempDir::new().unwrap(), TempDir::new().unwrap());
+        let alice = session("alice", port, &dir_a);
+        let mut alice_rx = alice.subscribe();
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        let bob = session("bob", port, &dir_b);
+        let mut bob_rx = bob.subscribe();
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::PeerJoined { nick, .. } if nick == "bob")).await;
+
+        // Bob is in #ops with alice once, then leaves before she writes.
+        alice.send(CallCommand::CreateChannel("#ops".to_string()));
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::ChannelChanged(ch) if ch == "#ops")).await;
+        bob.send(CallCommand::CreateChannel("#ops".to_string()));
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::PeerJoined { nick, .. } if nick == "bob")).await;
+        let alice_key = alice.state.identity.as_ref().unwrap().pubkey_hex.clone();
+        eventually(|| bob.state.was_channel_member("#ops", &alice_key)).await;
+        bob.send(CallCommand::SwitchChannel("#test".to_string()));
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::PeerLeft(nick) if nick == "bob")).await;
+
+        // History in a channel other than the one bob will meet alice in,
+        // more than one batch of it.
+        let count = persistence::SYNC_BATCH_SIZE + 10;
+        for i in 0..count {
+            alice.send(CallCommand::SendMessage(format!("note {}", i)));
+        }
+        alice.send(CallCommand::SwitchChannel("#test".to_string()));
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::ChannelChanged(ch) if ch == "#test")).await;
+        assert_eq!(alice.state.message_log.get_messages("#ops").await.len(), count);
+
+        // Carol lists #ops too, but never shared it with either of them.
+        let carol = session("carol", port, &dir_c);
+        let mut carol_rx = carol.subscribe();
+        next_matching(&mut carol_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        carol.send(CallCommand::CreateChannel("#ops".to_string()));
+        next_matching(&mut carol_rx, |e| matches!(e, CallEvent::ChannelChanged(ch) if ch == "#ops")).await;
+        let carol_key = carol.state.identity.as_ref().unwrap().pubkey_hex.clone();
+        eventually(|| alice.state.was_channel_member("#test", &carol_key)).await;
+        // Carol can only get this through the sync she's offered on her
+        // way back, the same exchange that would carry #ops.
+        alice.send(CallCommand::SendMessage("while you were out".to_string()));
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Chat { text, .. } if text == "while you were out")).await;
+        carol.send(CallCommand::SwitchChannel("#test".to_string()));
+
+        eventually(|| async { bob.state.message_log.get_messages("#ops").await.len() == count }).await;
+        eventually(|| async {
+            carol.state.message_log.get_messages("#test").await.iter().any(|m| m.content == "while you were out")
+        })
+        .await;
+        assert!(carol.state.message_log.get_messages("#ops").await.is_empty());
+
+        alice.send(CallCommand::Shutdown);
+        bob.send(CallCommand::Shutdown);
+        carol.send(CallCommand::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn test_only_bound_host_can_promote() {
+        let (dir_a, dir_b) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let host_key = Identity::load_or_generate(dir_a.path()).unwrap().pubkey_hex;
+        let port = start_server(Some(host_key)).await;
+
+        let alice = session("alice", port, &dir_a);
+        let mut alice_rx = alice.subscribe();
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+
+        let bob = session("bob", port, &dir_b);
+        let mut bob_rx = bob.subscribe();
+        next_matching(&mut bob_rx, |e| matches!(e, CallEvent::Connected { .. })).await;
+        next_matching(&mut alice_rx, |e| matches!(e, CallEvent::PeerJoined { nick, .. } if nick == "bob")).await;
+        exchange_keys(&alice, &bob).await;
+
+        // Bob is a peer as far as the server is concerned, so his
+        // self-promotion never reaches anyone.
+        bob.send(CallCommand::SendMessage("/mod bob".to_string()));
+
+        alice.send(CallCommand::SendMessage("/mod bob".to_string()));
+        let ev = next_matching(&mut bob_rx, |e| matches!(e, CallEvent::ModAction { .. })).await;
+        match ev {
+            CallEvent::ModAction { from, action, target } => {
+                assert_eq!(from, "alice");
+                assert_eq!(action, "promote");
+                assert_eq!(target, "bob");
+            }
+            _ => unreachable!(),
+        }
+
+        alice.send(CallCommand::Shutdown);
+        bob.send(CallCommand::Shutdown);
+    }
+}
@@ -1,8 +1,8 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -45,8 +45,6 @@ pub struct UserConfig {
     #[serde(default)]
     pub turn_servers: Vec<TurnServer>,
     #[serde(default)]
-    pub banned_users: HashSet<String>,
-    #[serde(default)]
     pub pubkey_hex: Option<String>,
 
     /// Default PoW difficulty (leading zero bits) to require when hosting.
@@ -54,6 +52,220 @@ pub struct UserConfig {
     /// Can also be changed at runtime by mods via /powset.
     #[serde(default)]
     pub pow_required_bits: u8,
+
+    /// Volume, local mute and pan per peer, keyed by pubkey hex so it
+    /// follows the person rather than the nick.
+    #[serde(default)]
+    pub peer_audio: HashMap<String, PeerAudioSettings>,
+
+    #[serde(default)]
+    pub transmit: TransmitSettings,
+
+    #[serde(default)]
+    pub processing: AudioProcessing,
+
+    /// Preferred sound devices by cpal name; `None` follows the system
+    /// default.  A missing device falls back to the default until it's back.
+    #[serde(default)]
+    pub input_device: Option<String>,
+    #[serde(default)]
+    pub output_device: Option<String>,
+
+    /// Codec profile last picked on the Host screen.
+    #[serde(default)]
+    pub host_codec: CodecProfile,
+}
+
+/// Opus settings for a whole room.  The host picks one; it travels in the
+/// magic link and shapes both the encoder and the SDP every peer offers.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CodecProfile {
+    /// Mono speech at 16 kbps for poor connections.
+    LowBandwidth,
+    /// Mono speech at 32 kbps.
+    #[default]
+    Voice,
+    /// Stereo 128 kbps with the general-audio encoder mode.  Echo
+    /// cancellation and noise suppression are bypassed since they are
+    /// tuned for mono speech; use headphones.
+    Music,
+}
+
+impl CodecProfile {
+    pub const ALL: [CodecProfile; 3] = [Self::LowBandwidth, Self::Voice, Self::Music];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::LowBandwidth => "Low bandwidth voice",
+            Self::Voice => "High quality voice",
+            Self::Music => "Stereo music",
+        }
+    }
+
+    pub fn channels(self) -> u16 {
+        match self {
+            Self::Music => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn bitrate(self) -> i32 {
+        match self {
+            Self::LowBandwidth => 16_000,
+            Self::Voice => 32_000,
+            Self::Music => 128_000,
+        }
+    }
+
+    pub fn complexity(self) -> i32 {
+        match self {
+            Self::LowBandwidth => 5,
+            _ => 10,
+        }
+    }
+
+    pub fn is_music(self) -> bool {
+        self == Self::Music
+    }
+
+    /// Opus fmtp parameters for the SDP (RFC 7587).
+    pub fn fmtp(self) -> String {
+        let stereo = if self.channels() == 2 { 1 } else { 0 };
+        format!(
+            "minptime=10;useinbandfec=1;stereo={};sprop-stereo={};maxaveragebitrate={}",
+            stereo, stereo, self.bitrate()
+        )
+    }
+}
+
+/// When the mic is sent.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransmitMode {
+    /// Sent while the voice activity detector hears speech.
+    #[default]
+    VoiceActivity,
+    /// Sent only while the talk key is held.
+    PushToTalk,
+    /// Every frame is sent; Opus DTX keeps silence cheap.
+    AlwaysOn,
+}
+
+impl TransmitMode {
+    pub const ALL: [TransmitMode; 3] = [Self::VoiceActivity, Self::PushToTalk, Self::AlwaysOn];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::VoiceActivity => "Voice activity",
+            Self::PushToTalk => "Push-to-talk",
+            Self::AlwaysOn => "Always on",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct TransmitSettings {
+    pub mode: TransmitMode,
+    /// VAD threshold in dBFS.  The gate also stays a margin above the
+    /// measured noise floor, so this is the minimum level that counts as
+    /// speech in a quiet room.
+    pub vad_threshold_db: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoiseSuppressionSetting {
+    Off,
+    Low,
+    Moderate,
+    #[default]
+    High,
+    VeryHigh,
+}
+
+impl NoiseSuppressionSetting {
+    pub const ALL: [NoiseSuppressionSetting; 5] =
+        [Self::Off, Self::Low, Self::Moderate, Self::High, Self::VeryHigh];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Off => "Off",
+            Self::Low => "Low",
+            Self::Moderate => "Moderate",
+            Self::High => "High",
+            Self::VeryHigh => "Very high",
+        }
+    }
+}
+
+/// Mic clean-up stages run alongside echo cancellation.  Echo cancellation
+/// itself is always on.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct AudioProcessing {
+    pub noise_suppression: NoiseSuppressionSetting,
+    /// Adaptive digital gain control, for quiet or distant mics.
+    pub gain_control: bool,
+    /// AGC target peak level, in dB below full scale (0-31).
+    pub agc_target_dbfs: i32,
+    /// Most gain the AGC may add, in dB (0-90).
+    pub agc_max_gain_db: i32,
+    /// Cuts rumble and DC offset below ~80Hz.
+    pub high_pass_filter: bool,
+}
+
+impl Default for AudioProcessing {
+    fn default() -> Self {
+        Self {
+            noise_suppression: NoiseSuppressionSetting::High,
+            gain_control: true,
+            agc_target_dbfs: 3,
+            agc_max_gain_db: 9,
+            high_pass_filter: true,
+        }
+    }
+}
+
+impl Default for TransmitSettings {
+    fn default() -> Self {
+        Self { mode: TransmitMode::VoiceActivity, vad_threshold_db: -45.0 }
+    }
+}
+
+/// How one peer is mixed into our speakers.  Only affects what we hear.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct PeerAudioSettings {
+    /// Linear gain, 0.0-2.0.
+    pub gain: f32,
+    pub muted: bool,
+    /// -1.0 hard left, 0.0 centre, 1.0 hard right.
+    pub pan: f32,
+}
+
+impl Default for PeerAudioSettings {
+    fn default() -> Self {
+        Self { gain: 1.0, muted: false, pan: 0.0 }
+    }
+}
+
+impl PeerAudioSettings {
+    pub const MAX_GAIN: f32 = 2.0;
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// (left, right) multipliers.  Balance law rather than constant power so
+    /// a centred peer plays at exactly `gain` on both sides.
+    pub fn channel_gains(&self) -> (f32, f32) {
+        if self.muted {
+            return (0.0, 0.0);
+        }
+        let gain = self.gain.clamp(0.0, Self::MAX_GAIN);
+        let pan = self.pan.clamp(-1.0, 1.0);
+        (gain * (1.0 - pan).min(1.0), gain * (1.0 + pan).min(1.0))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -70,6 +282,103 @@ pub struct TurnServer {
     pub credential: String,
 }
 
+/// Settings for the headless `voirc serve` mode, read from a TOML file.
+/// Every field is optional; a missing file section falls back to the same
+/// defaults the GUI's Host screen uses.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub port: u16,
+    /// Audio relay port. Defaults to `port + 1`.
+    pub relay_port: Option<u16>,
+    pub channels: Vec<String>,
+    pub pow_required_bits: u8,
+    /// Serve IRC over TLS with a pinned self-signed cert. Disable only for
+    /// testing; the magic link will carry no fingerprint.
+    pub tls: bool,
+    /// Where cert.der/key.der live. Defaults to `UserConfig::tls_cert_dir()`.
+    pub cert_dir: Option<PathBuf>,
+    /// Host to put in the magic link. When unset the external IP is looked
+    /// up via UPnP / HTTP, same as the GUI.
+    pub public_host: Option<String>,
+    /// Try to forward the IRC and relay ports via UPnP.
+    pub upnp: bool,
+    /// Opus profile advertised to joiners in the magic link.
+    pub codec: CodecProfile,
+    /// Hex pubkey of the room owner, who gets host rights (promote mods,
+    /// kick, PoW).  `/role` shows yours.  The TUI `--host` mode fills in
+    /// the local identity when unset; a bare `serve` has no host.
+    pub host_pubkey: Option<String>,
+    /// Where bans are kept between runs. Defaults to `bans.toml` in the
+    /// voirc config directory.
+    pub ban_file: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: 6667,
+            relay_port: None,
+            channels: vec!["#general".to_string()],
+            pow_required_bits: 0,
+            tls: true,
+            cert_dir: None,
+            public_host: None,
+            upnp: true,
+            codec: CodecProfile::default(),
+            host_pubkey: None,
+            ban_file: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Cannot read {}: {}", path.display(), e))?;
+        let config: Self = toml::from_str(&content)?;
+        if config.pow_required_bits > 28 {
+            anyhow::bail!("pow_required_bits must be 0-28");
+        }
+        Ok(config)
+    }
+
+    pub fn relay_port(&self) -> u16 {
+        self.relay_port.unwrap_or(self.port.wrapping_add(1))
+    }
+
+    pub fn cert_dir(&self) -> PathBuf {
+        match &self.cert_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir).ok();
+                dir.clone()
+            }
+            None => UserConfig::tls_cert_dir(),
+        }
+    }
+
+    pub fn ban_file(&self) -> PathBuf {
+        self.ban_file.clone().unwrap_or_else(|| {
+            let mut path = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+            path.push("voirc");
+            path.push("bans.toml");
+            path
+        })
+    }
+
+    /// Channel names with a leading '#', empty entries dropped.
+    pub fn channel_list(&self) -> Vec<String> {
+        self.channels
+            .iter()
+            .map(|s| {
+                let s = s.trim().to_string();
+                if s.starts_with('#') { s } else { format!("#{}", s) }
+            })
+            .filter(|s| s.len() > 1)
+            .collect()
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Role {
     Host,
@@ -107,6 +416,47 @@ impl Role {
     }
 }
 
+/// Our mic/speaker state as announced to the channel with `VOIRC_VOICE:`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VoiceStatus {
+    pub muted: bool,
+    pub deafened: bool,
+}
+
+impl VoiceStatus {
+    /// Comma-separated flags, empty when neither is set.  Unknown flags are
+    /// ignored on parse so new ones can be added later.
+    pub fn to_wire(self) -> String {
+        let mut flags = Vec::new();
+        if self.muted { flags.push("muted"); }
+        if self.deafened { flags.push("deafened"); }
+        flags.join(",")
+    }
+
+    pub fn from_wire(s: &str) -> Self {
+        let mut status = Self::default();
+        for flag in s.split(',').map(str::trim) {
+            match flag {
+                "muted" => status.muted = true,
+                "deafened" => status.deafened = true,
+                _ => {}
+            }
+        }
+        status
+    }
+
+    /// Suffix for peer lists; deafened wins since it implies muted.
+    pub fn tag(self) -> &'static str {
+        if self.deafened {
+            " [deaf]"
+        } else if self.muted {
+            " [muted]"
+        } else {
+            ""
+        }
+    }
+}
+
 impl Default for UserConfig {
     fn default() -> Self {
         Self {
@@ -114,9 +464,14 @@ impl Default for UserConfig {
             display_name: "User".to_string(),
             recent_servers: Vec::new(),
             turn_servers: Vec::new(),
-            banned_users: HashSet::new(),
             pubkey_hex: None,
             pow_required_bits: 0,
+            peer_audio: HashMap::new(),
+            transmit: TransmitSettings::default(),
+            processing: AudioProcessing::default(),
+            input_device: None,
+            output_device: None,
+            host_codec: CodecProfile::default(),
         }
     }
 }
@@ -181,6 +536,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn test_pow_required_bits_serialization() {
         let mut config = UserConfig::default();
         config.pow_required_bits = 16;
@@ -202,6 +558,34 @@ mod tests {
         assert_eq!(config.pow_required_bits, 0);
     }
 
+    #[test]
+    fn test_server_config_defaults() {
+        let config: ServerConfig = toml::from_str("").unwrap();
+        assert_eq!(config.port, 6667);
+        assert_eq!(config.relay_port(), 6668);
+        assert!(config.tls);
+        assert!(config.upnp);
+        assert_eq!(config.channel_list(), vec!["#general".to_string()]);
+    }
+
+    #[test]
+    fn test_server_config_parse() {
+        let toml = r##"
+            port = 7000
+            relay_port = 7100
+            channels = ["general", "#ops", " "]
+            pow_required_bits = 12
+            public_host = "voice.example.org"
+            upnp = false
+        "##;
+        let config: ServerConfig = toml::from_str(toml).unwrap();
+        assert_eq!(config.relay_port(), 7100);
+        assert_eq!(config.channel_list(), vec!["#general".to_string(), "#ops".to_string()]);
+        assert_eq!(config.pow_required_bits, 12);
+        assert_eq!(config.public_host.as_deref(), Some("voice.example.org"));
+        assert!(!config.upnp);
+    }
+
     #[test]
     fn test_role_is_superpeer() {
         assert!(Role::Host.is_superpeer());
@@ -223,6 +607,74 @@ mod tests {
         assert!(!Role::Peer.can_promote());
     }
 
+    #[test]
+    fn test_peer_audio_channel_gains() {
+        let centre = PeerAudioSettings::default();
+        assert_eq!(centre.channel_gains(), (1.0, 1.0));
+
+        let left = PeerAudioSettings { gain: 0.5, pan: -1.0, ..Default::default() };
+        assert_eq!(left.channel_gains(), (0.5, 0.0));
+
+        let muted = PeerAudioSettings { muted: true, ..Default::default() };
+        assert_eq!(muted.channel_gains(), (0.0, 0.0));
+
+        let loud = PeerAudioSettings { gain: 9.0, pan: 0.5, ..Default::default() };
+        assert_eq!(loud.channel_gains(), (1.0, 2.0));
+    }
+
+    #[test]
+    fn test_peer_audio_saved_by_pubkey() {
+        let mut config = UserConfig::default();
+        config.peer_audio.insert(
+            "ab".repeat(32),
+            PeerAudioSettings { gain: 0.25, muted: true, pan: -0.5 },
+        );
+        let toml = toml::to_string_pretty(&config).unwrap();
+        let reloaded: UserConfig = toml::from_str(&toml).unwrap();
+        assert_eq!(reloaded.peer_audio, config.peer_audio);
+    }
+
+    #[test]
+    fn test_audio_settings_default_when_missing() {
+        let mut config = UserConfig::default();
+        config.processing.noise_suppression = NoiseSuppressionSetting::Off;
+        config.processing.agc_target_dbfs = 12;
+        config.transmit.mode = TransmitMode::AlwaysOn;
+        let toml = toml::to_string_pretty(&config).unwrap();
+        let reloaded: UserConfig = toml::from_str(&toml).unwrap();
+        assert_eq!(reloaded.processing, config.processing);
+        assert_eq!(reloaded.transmit, config.transmit);
+
+        // Configs written before these settings existed keep today's behavior.
+        let old = "user_id = \"u\"\ndisplay_name = \"n\"\nrecent_servers = []\n";
+        let old: UserConfig = toml::from_str(old).unwrap();
+        assert_eq!(old.processing, AudioProcessing::default());
+        assert_eq!(old.transmit, TransmitSettings::default());
+    }
+
+    #[test]
+    fn test_codec_profile_fmtp() {
+        assert_eq!(
+            CodecProfile::Voice.fmtp(),
+            "minptime=10;useinbandfec=1;stereo=0;sprop-stereo=0;maxaveragebitrate=32000"
+        );
+        let music = CodecProfile::Music.fmtp();
+        assert!(music.contains("stereo=1;sprop-stereo=1"));
+        assert!(music.contains("maxaveragebitrate=128000"));
+        assert_eq!(CodecProfile::Music.channels(), 2);
+        assert_eq!(CodecProfile::LowBandwidth.channels(), 1);
+    }
+
+    #[test]
+    fn test_voice_status_wire_roundtrip() {
+        for (muted, deafened) in [(false, false), (true, false), (false, true), (true, true)] {
+            let status = VoiceStatus { muted, deafened };
+            assert_eq!(VoiceStatus::from_wire(&status.to_wire()), status);
+        }
+        assert_eq!(VoiceStatus { muted: true, deafened: true }.to_wire(), "muted,deafened");
+        assert_eq!(VoiceStatus::from_wire("deafened,loud"), VoiceStatus { muted: false, deafened: true });
+    }
+
     #[test]
     fn test_conn_state_display() {
         assert_eq!(ConnState::Connecting.to_string(), "connecting...");
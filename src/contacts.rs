@@ -0,0 +1,269 @@
+// Known identities, trust on first use.
+//
+// Every key we learn for a nick (from the server's VOIRC_PUBKEY or a signed
+// hello) is remembered with the nicks it has gone by, when we first saw it
+// and whether the user has checked it out of band.  The server binds nicks
+// to keys only while it runs, so a nick can come back with another key after
+// a restart or on another server; the store is what notices.  Such a nick
+// isn't refused, it's flagged until the user compares safety numbers with
+// that person and marks the new key verified.
+//
+// A safety number is 60 digits derived from both keys, the same on both
+// ends, so two people can read it to each other on a call or in person.
+
+use anyhow::Result;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Contact {
+    pub pubkey: String,
+    /// Nicks seen with this key, oldest first.
+    pub nicks: Vec<String>,
+    /// Unix seconds.
+    pub first_seen: u64,
+    /// The user compared safety numbers and confirmed this key.
+    #[serde(default)]
+    pub verified: bool,
+}
+
+/// What `observe` made of a nick/key pair.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sighting {
+    /// A key we hadn't seen before, under a nick nobody else used.
+    New,
+    /// Seen before with this nick.
+    Known,
+    /// The nick belonged to `previous` before.  `was_verified` if the user
+    /// had verified that key.
+    KeyChanged { previous: String, was_verified: bool },
+}
+
+/// How far to trust the key a nick has now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyTrust {
+    /// The user compared safety numbers and confirmed it.
+    Verified,
+    /// Trusted on first use only.
+    Unverified,
+    /// The nick had another key before and this one isn't verified.
+    Changed,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ContactFile {
+    #[serde(default)]
+    contacts: Vec<Contact>,
+}
+
+pub struct KnownIdentities {
+    path: PathBuf,
+    contacts: Vec<Contact>,
+}
+
+impl KnownIdentities {
+    /// Read the store at `path`, which is also where changes are saved.  A
+    /// missing file is an empty store.
+    pub fn load(path: &Path) -> Self {
+        let contacts = match std::fs::read_to_string(path) {
+            Ok(content) => match toml::from_str::<ContactFile>(&content) {
+                Ok(file) => file.contacts,
+                Err(e) => {
+                    warn!("Failed to parse {:?}: {}", path, e);
+                    Vec::new()
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        Self { path: path.to_path_buf(), contacts }
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = ContactFile { contacts: self.contacts.clone() };
+        std::fs::write(&self.path, toml::to_string_pretty(&file)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, pubkey: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|c| c.pubkey == pubkey)
+    }
+
+    /// The most recently seen other key that went by `nick` before we first
+    /// saw `pubkey`.  Keys that came along later don't count against it:
+    /// the first key for a nick is the one trusted on first use.
+    fn previous_holder(&self, nick: &str, pubkey: &str) -> Option<&Contact> {
+        let since = self.get(pubkey).map(|c| c.first_seen);
+        self.contacts.iter()
+            .filter(|c| c.pubkey != pubkey && c.nicks.iter().any(|n| n == nick))
+            .filter(|c| since.is_none_or(|t| c.first_seen < t))
+            .max_by_key(|c| c.first_seen)
+    }
+
+    /// Record that `nick` is using `pubkey`; saves when anything is new.
+    pub fn observe(&mut self, nick: &str, pubkey: &str, now: u64) -> Sighting {
+        let sighting = match self.get(pubkey) {
+            Some(c) if c.nicks.iter().any(|n| n == nick) => return Sighting::Known,
+            _ => match self.previous_holder(nick, pubkey) {
+                Some(prev) => Sighting::KeyChanged { previous: prev.pubkey.clone(), was_verified: prev.verified },
+                None => Sighting::New,
+            },
+        };
+        match self.contacts.iter_mut().find(|c| c.pubkey == pubkey) {
+            Some(c) => c.nicks.push(nick.to_string()),
+            None => self.contacts.push(Contact {
+                pubkey: pubkey.to_string(),
+                nicks: vec![nick.to_string()],
+                first_seen: now,
+                verified: false,
+            }),
+        }
+        if let Err(e) = self.save() {
+            warn!("Failed to save known identities: {}", e);
+        }
+        sighting
+    }
+
+    /// Mark `pubkey` checked (or not).  `false` if we don't know the key.
+    pub fn set_verified(&mut self, pubkey: &str, verified: bool) -> bool {
+        let Some(c) = self.contacts.iter_mut().find(|c| c.pubkey == pubkey) else { return false };
+        c.verified = verified;
+        if let Err(e) = self.save() {
+            warn!("Failed to save known identities: {}", e);
+        }
+        true
+    }
+
+    pub fn is_verified(&self, pubkey: &str) -> bool {
+        self.get(pubkey).is_some_and(|c| c.verified)
+    }
+
+    /// `nick` used to have another key and the user hasn't verified this
+    /// one yet.
+    pub fn needs_attention(&self, nick: &str, pubkey: &str) -> bool {
+        !self.is_verified(pubkey) && self.previous_holder(nick, pubkey).is_some()
+    }
+
+    pub fn trust(&self, nick: &str, pubkey: &str) -> KeyTrust {
+        if self.is_verified(pubkey) {
+            KeyTrust::Verified
+        } else if self.needs_attention(nick, pubkey) {
+            KeyTrust::Changed
+        } else {
+            KeyTrust::Unverified
+        }
+    }
+
+    /// Nick → the keys seen with it that its history may be signed with.
+    /// A key flagged by `needs_attention` is left out until verified; the
+    /// nick stays in the map even if that leaves it no keys.
+    pub fn nick_keys(&self) -> HashMap<String, HashSet<String>> {
+        let mut map: HashMap<String, HashSet<String>> = HashMap::new();
+        for c in &self.contacts {
+            for nick in &c.nicks {
+                let keys = map.entry(nick.clone()).or_default();
+                if !self.needs_attention(nick, &c.pubkey) {
+                    keys.insert(c.pubkey.clone());
+                }
+            }
+        }
+        map
+    }
+}
+
+/// 60 digits in groups of five for the pair of keys; either side gets the
+/// same number.  Fails if either key isn't hex.
+pub fn safety_number(ours: &str, theirs: &str) -> Result<String> {
+    let (first, second) = if ours <= theirs { (ours, theirs) } else { (theirs, ours) };
+    let mut groups = key_digits(first)?;
+    groups.extend(key_digits(second)?);
+    Ok(groups.join(" "))
+}
+
+/// Six five-digit groups from a hash of one key.
+fn key_digits(pubkey_hex: &str) -> Result<Vec<String>> {
+    let mut ctx = digest::Context::new(&digest::SHA512);
+    ctx.update(b"voirc-safety-v1\0");
+    ctx.update(&hex::decode(pubkey_hex)?);
+    let hash = ctx.finish();
+    Ok(hash.as_ref()[..30]
+        .chunks(5)
+        .map(|c| {
+            let n = c.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+            format!("{:05}", n % 100_000)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_key_change_is_flagged_until_verified() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("known_identities.toml");
+        let mut store = KnownIdentities::load(&path);
+        assert_eq!(store.observe("bob", "aa11", 100), Sighting::New);
+        assert_eq!(store.observe("bob", "aa11", 200), Sighting::Known);
+        assert!(store.set_verified("aa11", true));
+
+        // Same nick, new key: flagged, and stays flagged across restarts.
+        assert_eq!(
+            store.observe("bob", "bb22", 300),
+            Sighting::KeyChanged { previous: "aa11".to_string(), was_verified: true }
+        );
+        let mut store = KnownIdentities::load(&path);
+        assert_eq!(store.observe("bob", "bb22", 400), Sighting::Known);
+        assert!(store.needs_attention("bob", "bb22"));
+        assert!(!store.needs_attention("bob", "aa11"));
+        assert_eq!(store.trust("bob", "bb22"), KeyTrust::Changed);
+        assert_eq!(store.trust("bob", "aa11"), KeyTrust::Verified);
+        // History under the unverified key isn't trusted yet.
+        assert_eq!(store.nick_keys()["bob"], HashSet::from(["aa11".to_string()]));
+        assert_eq!(store.get("bb22").unwrap().first_seen, 300);
+
+        store.set_verified("bb22", true);
+        assert!(!store.needs_attention("bob", "bb22"));
+        assert_eq!(store.nick_keys()["bob"].len(), 2);
+
+        // A known key under a new nick is no warning; it's nick history.
+        assert_eq!(store.observe("robert", "bb22", 500), Sighting::New);
+        assert_eq!(store.get("bb22").unwrap().nicks, vec!["bob", "robert"]);
+    }
+
+    #[test]
+    fn test_first_key_for_a_nick_stays_trusted() {
+        let dir = TempDir::new().unwrap();
+        let mut store = KnownIdentities::load(&dir.path().join("known_identities.toml"));
+        assert_eq!(store.observe("bob", "aa11", 100), Sighting::New);
+
+        // An impostor under bob's nick is flagged; the real bob isn't.
+        assert_eq!(
+            store.observe("bob", "cc33", 200),
+            Sighting::KeyChanged { previous: "aa11".to_string(), was_verified: false }
+        );
+        assert!(store.needs_attention("bob", "cc33"));
+        assert!(!store.needs_attention("bob", "aa11"));
+        assert_eq!(store.trust("bob", "aa11"), KeyTrust::Unverified);
+        assert_eq!(store.observe("bob", "aa11", 300), Sighting::Known);
+        assert_eq!(store.nick_keys()["bob"], HashSet::from(["aa11".to_string()]));
+    }
+
+    #[test]
+    fn test_safety_number_is_symmetric() {
+        let (a, b) = ("11".repeat(32), "22".repeat(32));
+        let number = safety_number(&a, &b).unwrap();
+        assert_eq!(number, safety_number(&b, &a).unwrap());
+        assert_eq!(number.split(' ').count(), 12);
+        assert!(number.split(' ').all(|g| g.len() == 5 && g.chars().all(|c| c.is_ascii_digit())));
+        assert_ne!(number, safety_number(&a, &"33".repeat(32)).unwrap());
+        assert!(safety_number(&a, "not hex").is_err());
+    }
+}
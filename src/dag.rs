@@ -0,0 +1,293 @@
+// Per-channel message DAG.
+//
+// Every signed message names the hashes of the channel's heads (messages
+// nothing references yet) as its author saw them, so a channel's history
+// is a DAG, as in Matrix or Scuttlebutt, rather than a list sorted by
+// self-reported timestamps.  The links let a verifier tell:
+//
+//   omissions   a parent we don't hold is history we were never given;
+//   reordering  a parent claiming to be newer than its child;
+//   forks       an author's message that doesn't build on their own
+//               previous one (two versions of their history).
+//
+// Display order is a topological sort with ties broken by (timestamp,
+// hash), so everyone holding the same messages sees the same order, and a
+// message can't be placed before what it replied to by lying about time.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+
+use crate::persistence::SignedMessage;
+
+/// Most parents a new message names; the newest heads win.
+pub const MAX_PARENTS: usize = 4;
+/// A parent may be this much newer than its child (clock skew).
+const REORDER_TOLERANCE_SECS: i64 = 120;
+/// Ancestors walked looking for an author's previous message before
+/// giving up on the fork check.
+const FORK_SEARCH_LIMIT: usize = 10_000;
+
+/// What inserting a message revealed about its place in the history.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DagCheck {
+    /// Parents we don't hold (yet).
+    pub missing_parents: usize,
+    /// A parent is newer than the message.
+    pub reordered: bool,
+    /// The author's previous message isn't among its ancestors.
+    pub fork: bool,
+}
+
+impl DagCheck {
+    /// Reordering and forks are tampering or a broken client; missing
+    /// parents are normal until sync fills them in.
+    pub fn is_suspicious(&self) -> bool {
+        self.reordered || self.fork
+    }
+}
+
+#[derive(Default)]
+pub struct ChannelDag {
+    messages: Vec<SignedMessage>,
+    /// `hashes[i]` is `messages[i].hash()`.
+    hashes: Vec<String>,
+    /// Hash → position in `messages`.
+    index: HashMap<String, usize>,
+    /// Id → hash.
+    ids: HashMap<String, String>,
+    /// Target id → hashes of the events naming it.
+    targets: HashMap<String, Vec<String>>,
+    /// Every parent hash some message names.
+    referenced: HashSet<String>,
+    /// Pubkey → (timestamp, hash) of their messages.
+    by_author: HashMap<String, BTreeSet<(i64, String)>>,
+    /// `messages` is in display order.
+    ordered: bool,
+}
+
+impl ChannelDag {
+    /// Build from a stored log, without judging what's in it.
+    pub fn from_messages(messages: Vec<SignedMessage>) -> Self {
+        let mut dag = Self::default();
+        for msg in messages {
+            if !dag.ids.contains_key(&msg.id) {
+                dag.push(msg);
+            }
+        }
+        dag
+    }
+
+    pub fn contains_id(&self, id: &str) -> bool {
+        self.ids.contains_key(id)
+    }
+
+    pub fn by_id(&self, id: &str) -> Option<&SignedMessage> {
+        self.ids.get(id).map(|hash| &self.messages[self.index[hash]])
+    }
+
+    /// Edits, redactions and reactions naming `id`.
+    pub fn events_for(&self, id: &str) -> impl Iterator<Item = &SignedMessage> {
+        self.targets.get(id).into_iter().flatten().map(|hash| &self.messages[self.index[hash]])
+    }
+
+    /// Add `msg`; `None` if we already hold it.
+    pub fn insert(&mut self, msg: SignedMessage) -> Option<DagCheck> {
+        if self.ids.contains_key(&msg.id) {
+            return None;
+        }
+        let check = self.check(&msg);
+        self.push(msg);
+        Some(check)
+    }
+
+    fn push(&mut self, msg: SignedMessage) {
+        let hash = msg.hash();
+        self.index.insert(hash.clone(), self.messages.len());
+        self.ids.insert(msg.id.clone(), hash.clone());
+        if let Some(event) = &msg.event {
+            self.targets.entry(event.target().to_string()).or_default().push(hash.clone());
+        }
+        self.referenced.extend(msg.parents.iter().cloned());
+        self.by_author.entry(msg.pubkey.clone()).or_default().insert((msg.timestamp, hash.clone()));
+        self.hashes.push(hash);
+        self.messages.push(msg);
+        self.ordered = false;
+    }
+
+    fn check(&self, msg: &SignedMessage) -> DagCheck {
+        let mut check = DagCheck::default();
+        for parent in &msg.parents {
+            match self.index.get(parent) {
+                Some(&i) if self.messages[i].timestamp > msg.timestamp + REORDER_TOLERANCE_SECS => check.reordered = true,
+                Some(_) => {}
+                None => check.missing_parents += 1,
+            }
+        }
+        // Messages from before the DAG have no links to check.
+        if !msg.is_legacy() {
+            let previous = self.by_author.get(&msg.pubkey)
+                .and_then(|own| own.range(..(msg.timestamp, String::new())).next_back())
+                .filter(|(_, hash)| !self.messages[self.index[hash]].is_legacy());
+            if let Some((_, previous)) = previous {
+                check.fork = self.lacks_ancestor(&msg.parents, previous) == Some(true);
+            }
+        }
+        check
+    }
+
+    /// Whether `target` is missing from the ancestry reachable from
+    /// `parents`; `None` if a gap or the search limit leaves it open.
+    fn lacks_ancestor(&self, parents: &[String], target: &str) -> Option<bool> {
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut queue: VecDeque<&str> = parents.iter().map(String::as_str).collect();
+        while let Some(hash) = queue.pop_front() {
+            if hash == target {
+                return Some(false);
+            }
+            if !seen.insert(hash) {
+                continue;
+            }
+            if seen.len() > FORK_SEARCH_LIMIT {
+                return None;
+            }
+            let &i = self.index.get(hash)?;
+            queue.extend(self.messages[i].parents.iter().map(String::as_str));
+        }
+        Some(true)
+    }
+
+    /// Hashes for a new message's `parents`: the newest heads.
+    pub fn heads(&self) -> Vec<String> {
+        let mut heads: Vec<(i64, &String)> = self.hashes.iter()
+            .zip(&self.messages)
+            .filter(|(hash, _)| !self.referenced.contains(*hash))
+            .map(|(hash, m)| (m.timestamp, hash))
+            .collect();
+        heads.sort_unstable_by(|a, b| b.cmp(a));
+        let mut heads: Vec<String> = heads.into_iter().take(MAX_PARENTS).map(|(_, h)| h.clone()).collect();
+        heads.sort_unstable();
+        heads
+    }
+
+    /// Parent hashes named in this channel that we don't hold.
+    pub fn missing(&self) -> usize {
+        self.referenced.iter().filter(|h| !self.index.contains_key(*h)).count()
+    }
+
+    /// In no particular order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &SignedMessage> {
+        self.messages.iter()
+    }
+
+    /// In display order.
+    pub fn ordered(&mut self) -> &[SignedMessage] {
+        if !self.ordered {
+            self.sort();
+        }
+        &self.messages
+    }
+
+    /// Kahn's algorithm, taking the smallest (timestamp, hash) that's ready.
+    fn sort(&mut self) {
+        let n = self.messages.len();
+        let mut pending = vec![0usize; n];
+        let mut children: Vec<Vec<usize>> = vec![Vec::new(); n];
+        for (i, msg) in self.messages.iter().enumerate() {
+            let parents: HashSet<&String> = msg.parents.iter().collect();
+            for p in parents.into_iter().filter_map(|p| self.index.get(p)) {
+                children[*p].push(i);
+                pending[i] += 1;
+            }
+        }
+        let key = |i: usize| Reverse((self.messages[i].timestamp, self.hashes[i].clone(), i));
+        let mut ready: BinaryHeap<_> = (0..n).filter(|&i| pending[i] == 0).map(key).collect();
+        let mut order = Vec::with_capacity(n);
+        while let Some(Reverse((_, _, i))) = ready.pop() {
+            order.push(i);
+            for &c in &children[i] {
+                pending[c] -= 1;
+                if pending[c] == 0 {
+                    ready.push(key(c));
+                }
+            }
+        }
+
+        let mut slots: Vec<Option<(SignedMessage, String)>> = self.messages.drain(..)
+            .zip(self.hashes.drain(..))
+            .map(Some)
+            .collect();
+        for i in order {
+            let (msg, hash) = slots[i].take().expect("each message is ordered once");
+            self.index.insert(hash.clone(), self.messages.len());
+            self.hashes.push(hash);
+            self.messages.push(msg);
+        }
+        self.ordered = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::Identity;
+    use tempfile::tempdir;
+
+    fn write(dag: &ChannelDag, identity: &Identity, text: &str, timestamp: i64) -> SignedMessage {
+        let mut msg = SignedMessage::create(identity, "alice", "#general", text, &dag.heads()).unwrap();
+        // Unsigned from here on, which the DAG doesn't check.
+        msg.timestamp = timestamp;
+        msg
+    }
+
+    #[test]
+    fn test_order_follows_links_not_clocks() {
+        let dir = tempdir().unwrap();
+        let alice = Identity::load_or_generate(dir.path()).unwrap();
+        let mut dag = ChannelDag::default();
+
+        let first = write(&dag, &alice, "first", 1000);
+        assert_eq!(dag.insert(first.clone()), Some(DagCheck::default()));
+        // Claims to be older than what it answers.
+        let reply = write(&dag, &alice, "reply", 500);
+        assert!(dag.insert(reply).unwrap().is_suspicious());
+        assert_eq!(dag.insert(first), None);
+
+        let texts: Vec<&str> = dag.ordered().iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(texts, vec!["first", "reply"]);
+        assert_eq!(dag.heads().len(), 1);
+    }
+
+    #[test]
+    fn test_gaps_and_forks_are_detected() {
+        let dir = tempdir().unwrap();
+        let alice = Identity::load_or_generate(dir.path()).unwrap();
+        let mut full = ChannelDag::default();
+        let one = write(&full, &alice, "one", 1000);
+        full.insert(one.clone());
+        let two = write(&full, &alice, "two", 1010);
+        full.insert(two.clone());
+
+        // Someone handing over "two" without "one" leaves a visible gap.
+        let mut partial = ChannelDag::default();
+        assert_eq!(partial.insert(two.clone()).unwrap().missing_parents, 1);
+        assert_eq!(partial.missing(), 1);
+        partial.insert(one.clone());
+        assert_eq!(partial.missing(), 0);
+
+        // A message built on "one" alone, after alice already wrote "two".
+        let mut stale = ChannelDag::default();
+        stale.insert(one);
+        let forked = write(&stale, &alice, "other two", 1020);
+        let check = full.insert(forked).unwrap();
+        assert!(check.fork);
+        assert_eq!(full.heads().len(), 2);
+
+        // The same order regardless of arrival order.
+        let mut reversed: Vec<SignedMessage> = full.iter().cloned().collect();
+        reversed.reverse();
+        let mut a = ChannelDag::from_messages(full.iter().cloned().collect());
+        let mut b = ChannelDag::from_messages(reversed);
+        let ids = |d: &mut ChannelDag| d.ordered().iter().map(|m| m.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&mut a), ids(&mut b));
+    }
+}
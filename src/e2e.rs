@@ -0,0 +1,228 @@
+// End-to-end encryption for direct messages.
+//
+// There are no extra keys to exchange: each side's ed25519 identity is
+// mapped to X25519 (the birational map, as age does for ssh-ed25519
+// recipients), and the recipient's key is the one the server bound to their
+// nick and `known_pubkeys` holds.
+//
+// Every message gets a fresh ephemeral X25519 key.  The AEAD key is
+// HKDF-SHA256 over DH(ephemeral, recipient) || DH(sender, recipient), salted
+// with the ephemeral key and both identities, so only the recipient can read
+// it and only the sender could have written it.  Each key seals one message,
+// so ChaCha20-Poly1305 runs with a zero nonce.
+//
+// The server can still play a captured message back.  So the plaintext
+// starts with a random id and the time it was sent, and the recipient's
+// `ReplayGuard` refuses ids it has had and anything outside `REPLAY_WINDOW_MS`.
+//
+// Sealed: ephemeral public key (32 bytes) || ciphertext || tag (16 bytes).
+// Plaintext: id (16 bytes) || sent, unix ms (8 bytes BE) || message.
+
+use anyhow::{anyhow, Result};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use ed25519_dalek::VerifyingKey;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use std::collections::HashMap;
+
+use crate::bans::now_ms;
+use crate::persistence::Identity;
+
+const INFO: &[u8] = b"voirc-dm-v2";
+const EPHEMERAL_LEN: usize = 32;
+const ID_LEN: usize = 16;
+const HEADER_LEN: usize = ID_LEN + 8;
+
+/// How far a message's send time may be from our clock.  Ids are kept as
+/// long as that, so a replay is either remembered or too old.
+pub const REPLAY_WINDOW_MS: u64 = 10 * 60 * 1000;
+
+/// A message `open`ed, with the id and send time `seal` put in it.
+pub struct Opened {
+    pub id: [u8; ID_LEN],
+    pub sent_ms: u64,
+    pub body: Vec<u8>,
+}
+
+/// Ids of the sealed messages opened lately.
+#[derive(Default)]
+pub struct ReplayGuard {
+    /// Id → when it was sent.
+    seen: HashMap<[u8; ID_LEN], u64>,
+}
+
+impl ReplayGuard {
+    /// Take `opened` if it was sent recently and we haven't had it before.
+    pub fn check(&mut self, opened: &Opened, now_ms: u64) -> Result<()> {
+        if opened.sent_ms.abs_diff(now_ms) > REPLAY_WINDOW_MS {
+            return Err(anyhow!("message is too old or from the future"));
+        }
+        self.seen.retain(|_, sent| sent.abs_diff(now_ms) <= REPLAY_WINDOW_MS);
+        if self.seen.insert(opened.id, opened.sent_ms).is_some() {
+            return Err(anyhow!("message was replayed"));
+        }
+        Ok(())
+    }
+}
+
+fn verifying_key(pubkey_hex: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(pubkey_hex)?
+        .try_into()
+        .map_err(|_| anyhow!("pubkey must be 32 bytes"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+/// Both DH outputs through HKDF.  `sender_hex` and `recipient_hex` are the
+/// ed25519 identities, so the key also says who it is between.
+fn message_key(
+    ephemeral_dh: [u8; 32],
+    static_dh: [u8; 32],
+    ephemeral_pub: &[u8; 32],
+    sender_hex: &str,
+    recipient_hex: &str,
+) -> Result<LessSafeKey> {
+    // A low-order point gives an all-zero secret; refuse it.
+    if ephemeral_dh == [0; 32] || static_dh == [0; 32] {
+        return Err(anyhow!("degenerate key exchange"));
+    }
+    let mut salt = ephemeral_pub.to_vec();
+    salt.extend_from_slice(sender_hex.as_bytes());
+    salt.extend_from_slice(recipient_hex.as_bytes());
+    let ikm = [ephemeral_dh, static_dh].concat();
+    let prk = Salt::new(HKDF_SHA256, &salt).extract(&ikm);
+    let okm = prk
+        .expand(&[INFO], &CHACHA20_POLY1305)
+        .map_err(|_| anyhow!("key derivation failed"))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/// Encrypt `plaintext` from `identity` to the holder of `recipient_hex`.
+pub fn seal(identity: &Identity, recipient_hex: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let recipient = verifying_key(recipient_hex)?.to_montgomery();
+    let ephemeral: [u8; 32] = rand::random();
+    let ephemeral_pub = MontgomeryPoint::mul_base_clamped(ephemeral).to_bytes();
+
+    let key = message_key(
+        recipient.mul_clamped(ephemeral).to_bytes(),
+        recipient.mul_clamped(identity.signing_key.to_scalar_bytes()).to_bytes(),
+        &ephemeral_pub,
+        &identity.pubkey_hex,
+        recipient_hex,
+    )?;
+    let id: [u8; ID_LEN] = rand::random();
+    let mut buf = id.to_vec();
+    buf.extend_from_slice(&now_ms().to_be_bytes());
+    buf.extend_from_slice(plaintext);
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key([0; 12]), Aad::from(INFO), &mut buf)
+        .map_err(|_| anyhow!("encryption failed"))?;
+
+    let mut sealed = ephemeral_pub.to_vec();
+    sealed.extend_from_slice(&buf);
+    Ok(sealed)
+}
+
+/// Decrypt a message `seal`ed to `identity` by the holder of `sender_hex`.
+/// Fails if it was tampered with or came from anyone else; whether it's a
+/// replay is for a `ReplayGuard` to say.
+pub fn open(identity: &Identity, sender_hex: &str, sealed: &[u8]) -> Result<Opened> {
+    if sealed.len() < EPHEMERAL_LEN + CHACHA20_POLY1305.tag_len() {
+        return Err(anyhow!("message too short"));
+    }
+    let sender = verifying_key(sender_hex)?.to_montgomery();
+    let ephemeral_pub: [u8; 32] = sealed[..EPHEMERAL_LEN].try_into()?;
+    let our_scalar = identity.signing_key.to_scalar_bytes();
+
+    let key = message_key(
+        MontgomeryPoint(ephemeral_pub).mul_clamped(our_scalar).to_bytes(),
+        sender.mul_clamped(our_scalar).to_bytes(),
+        &ephemeral_pub,
+        sender_hex,
+        &identity.pubkey_hex,
+    )?;
+    let mut buf = sealed[EPHEMERAL_LEN..].to_vec();
+    let plaintext = key
+        .open_in_place(Nonce::assume_unique_for_key([0; 12]), Aad::from(INFO), &mut buf)
+        .map_err(|_| anyhow!("decryption failed"))?;
+    if plaintext.len() < HEADER_LEN {
+        return Err(anyhow!("message too short"));
+    }
+    Ok(Opened {
+        id: plaintext[..ID_LEN].try_into()?,
+        sent_ms: u64::from_be_bytes(plaintext[ID_LEN..HEADER_LEN].try_into()?),
+        body: plaintext[HEADER_LEN..].to_vec(),
+    })
+}
+
+/// Short, readable form of a key for the DM header: `1a2b 3c4d 5e6f 7a8b`.
+pub fn short_fingerprint(pubkey_hex: &str) -> String {
+    let hex = &pubkey_hex[..16.min(pubkey_hex.len())];
+    hex.as_bytes()
+        .chunks(4)
+        .map(|c| String::from_utf8_lossy(c).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn identity(dir: &TempDir, name: &str) -> Identity {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        Identity::load_or_generate(&path).unwrap()
+    }
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let (alice, bob) = (identity(&dir, "a"), identity(&dir, "b"));
+        let sealed = seal(&alice, &bob.pubkey_hex, b"meet at 10").unwrap();
+        assert_eq!(open(&bob, &alice.pubkey_hex, &sealed).unwrap().body, b"meet at 10");
+
+        // Fresh ephemeral key each time.
+        assert_ne!(seal(&alice, &bob.pubkey_hex, b"meet at 10").unwrap(), sealed);
+    }
+
+    #[test]
+    fn test_only_recipient_reads_and_sender_is_bound() {
+        let dir = TempDir::new().unwrap();
+        let (alice, bob, eve) = (identity(&dir, "a"), identity(&dir, "b"), identity(&dir, "e"));
+        let sealed = seal(&alice, &bob.pubkey_hex, b"secret").unwrap();
+
+        assert!(open(&eve, &alice.pubkey_hex, &sealed).is_err());
+        // Bob won't take it as coming from eve.
+        assert!(open(&bob, &eve.pubkey_hex, &sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open(&bob, &alice.pubkey_hex, &tampered).is_err());
+        assert!(open(&bob, &alice.pubkey_hex, &sealed[..40]).is_err());
+    }
+
+    #[test]
+    fn test_replayed_message_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let (alice, bob) = (identity(&dir, "a"), identity(&dir, "b"));
+        let mut guard = ReplayGuard::default();
+        let sealed = seal(&alice, &bob.pubkey_hex, b"send the file").unwrap();
+        let now = now_ms();
+
+        let opened = open(&bob, &alice.pubkey_hex, &sealed).unwrap();
+        assert!(guard.check(&opened, now).is_ok());
+        // The same bytes again, as a server playing it back would send them.
+        let replayed = open(&bob, &alice.pubkey_hex, &sealed).unwrap();
+        assert!(guard.check(&replayed, now + 1000).is_err());
+        // Once the id is forgotten, the send time gives it away.
+        assert!(guard.check(&replayed, now + REPLAY_WINDOW_MS + 1).is_err());
+
+        let other = open(&bob, &alice.pubkey_hex, &seal(&alice, &bob.pubkey_hex, b"send the file").unwrap()).unwrap();
+        assert!(guard.check(&other, now).is_ok());
+    }
+
+    #[test]
+    fn test_short_fingerprint() {
+        assert_eq!(short_fingerprint("1a2b3c4d5e6f7a8b9c0d"), "1a2b 3c4d 5e6f 7a8b");
+    }
+}
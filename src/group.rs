@@ -0,0 +1,186 @@
+// End-to-end encryption for channels (+E).
+//
+// Sender keys, as in Signal groups: everyone has their own random key per
+// channel, seals what they send with it, and hands it to each verified
+// member over a pairwise `e2e::seal`ed message before first use.  Nobody has
+// to agree on a shared key, and the host only ever relays ciphertext.
+//
+// A key is dropped whenever someone joins, parts or is kicked, so the next
+// message goes out under a new one that only the current members receive:
+// a newcomer can't read what was said before, and someone who left can't
+// read what comes after.
+//
+// Sealed content: `<key id>:<base64 of nonce (12) || ciphertext || tag>`,
+// with the channel and key id as associated data.
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Old keys kept per sender, for messages that were in flight or come in
+/// by sync after a rotation.
+const KEPT_KEYS: usize = 4;
+
+/// A sender key as it travels inside a sealed `E2EKEY:` message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KeyShare {
+    pub channel: String,
+    pub id: String,
+    /// Hex-encoded 32-byte ChaCha20-Poly1305 key.
+    pub key: String,
+}
+
+/// A sender's keys by id, oldest first.
+type SenderKeys = Vec<(String, [u8; 32])>;
+
+#[derive(Default)]
+pub struct GroupKeys {
+    /// Channel → our current sender key there.
+    ours: HashMap<String, KeyShare>,
+    /// (channel, sender pubkey) → their keys.
+    theirs: HashMap<(String, String), SenderKeys>,
+}
+
+impl GroupKeys {
+    /// Our key for `channel`, and whether it was just made and still has to
+    /// be shared with the members.
+    pub fn sender_key(&mut self, channel: &str) -> (KeyShare, bool) {
+        if let Some(share) = self.ours.get(channel) {
+            return (share.clone(), false);
+        }
+        let key: [u8; 32] = rand::random();
+        let id: [u8; 4] = rand::random();
+        let share = KeyShare { channel: channel.to_string(), id: hex::encode(id), key: hex::encode(key) };
+        self.ours.insert(channel.to_string(), share.clone());
+        (share, true)
+    }
+
+    /// Membership changed: the next message in `channel` gets a new key.
+    pub fn rotate(&mut self, channel: &str) {
+        self.ours.remove(channel);
+    }
+
+    /// Take a key `sender_pubkey` shared with us.
+    pub fn accept(&mut self, sender_pubkey: &str, share: &KeyShare) -> Result<()> {
+        let key = parse_key(&share.key)?;
+        let keys = self.theirs.entry((share.channel.clone(), sender_pubkey.to_string())).or_default();
+        keys.retain(|(id, _)| id != &share.id);
+        keys.push((share.id.clone(), key));
+        if keys.len() > KEPT_KEYS {
+            keys.remove(0);
+        }
+        Ok(())
+    }
+
+    /// Seal `plaintext` under our current key for `channel`.  Call
+    /// `sender_key` first so the members have it.
+    pub fn seal(&mut self, channel: &str, plaintext: &str) -> Result<String> {
+        let (share, _) = self.sender_key(channel);
+        let key = parse_key(&share.key)?;
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut buf = plaintext.as_bytes().to_vec();
+        aead_key(&key)
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), aad(channel, &share.id), &mut buf)
+            .map_err(|_| anyhow!("encryption failed"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&buf);
+        Ok(format!("{}:{}", share.id, general_purpose::STANDARD.encode(sealed)))
+    }
+
+    /// Open what `sender_pubkey` sealed in `channel`.  Fails if they never
+    /// shared that key with us.
+    pub fn open(&self, channel: &str, sender_pubkey: &str, content: &str) -> Result<String> {
+        let (id, body) = content.split_once(':').ok_or_else(|| anyhow!("not a sealed message"))?;
+        let key = self.theirs.get(&(channel.to_string(), sender_pubkey.to_string()))
+            .and_then(|keys| keys.iter().find(|(k, _)| k == id))
+            .map(|(_, key)| *key)
+            .ok_or_else(|| anyhow!("no key {} from this sender", id))?;
+        let sealed = general_purpose::STANDARD.decode(body)?;
+        if sealed.len() < NONCE_LEN + CHACHA20_POLY1305.tag_len() {
+            return Err(anyhow!("message too short"));
+        }
+        let nonce: [u8; NONCE_LEN] = sealed[..NONCE_LEN].try_into()?;
+        let mut buf = sealed[NONCE_LEN..].to_vec();
+        let plaintext = aead_key(&key)
+            .open_in_place(Nonce::assume_unique_for_key(nonce), aad(channel, id), &mut buf)
+            .map_err(|_| anyhow!("decryption failed"))?;
+        Ok(String::from_utf8_lossy(plaintext).into_owned())
+    }
+}
+
+fn parse_key(hex_key: &str) -> Result<[u8; 32]> {
+    hex::decode(hex_key)?
+        .try_into()
+        .map_err(|_| anyhow!("group key must be 32 bytes"))
+}
+
+fn aead_key(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("32-byte key"))
+}
+
+fn aad(channel: &str, id: &str) -> Aad<Vec<u8>> {
+    Aad::from(format!("voirc-group-v1\0{}\0{}", channel, id).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "aa";
+
+    /// Bob's view after alice shared her key for #ops.
+    fn shared(alice: &mut GroupKeys) -> GroupKeys {
+        let mut bob = GroupKeys::default();
+        let (share, fresh) = alice.sender_key("#ops");
+        assert!(fresh);
+        bob.accept(ALICE, &share).unwrap();
+        bob
+    }
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let mut alice = GroupKeys::default();
+        let bob = shared(&mut alice);
+        let sealed = alice.seal("#ops", "deploy at 5").unwrap();
+        assert!(!sealed.contains("deploy"));
+        assert_eq!(bob.open("#ops", ALICE, &sealed).unwrap(), "deploy at 5");
+
+        // Bound to the channel and the sender.
+        assert!(bob.open("#general", ALICE, &sealed).is_err());
+        assert!(bob.open("#ops", "ee", &sealed).is_err());
+        assert!(GroupKeys::default().open("#ops", ALICE, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_rotation_shuts_out_old_key_holders() {
+        let mut alice = GroupKeys::default();
+        let bob = shared(&mut alice);
+        let (old, _) = alice.sender_key("#ops");
+        assert!(!alice.sender_key("#ops").1);
+
+        alice.rotate("#ops");
+        let (new, fresh) = alice.sender_key("#ops");
+        assert!(fresh);
+        assert_ne!(new.id, old.id);
+        // Bob didn't get the new key, so he can't read what follows.
+        assert!(bob.open("#ops", ALICE, &alice.seal("#ops", "after").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_recent_keys_are_kept() {
+        let mut alice = GroupKeys::default();
+        let mut bob = shared(&mut alice);
+        let first = alice.seal("#ops", "first").unwrap();
+        for _ in 0..KEPT_KEYS - 1 {
+            alice.rotate("#ops");
+            bob.accept(ALICE, &alice.sender_key("#ops").0).unwrap();
+        }
+        assert_eq!(bob.open("#ops", ALICE, &first).unwrap(), "first");
+
+        alice.rotate("#ops");
+        bob.accept(ALICE, &alice.sender_key("#ops").0).unwrap();
+        assert!(bob.open("#ops", ALICE, &first).is_err());
+    }
+}
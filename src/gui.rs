@@ -1,22 +1,257 @@
 use egui::{CentralPanel, Context, RichText, ScrollArea, TextEdit, TopBottomPanel};
-use std::collections::HashMap;
+use chrono::TimeZone;
 use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
-use tracing::{error, info, warn};
-
-use crate::config::{ConnState, Role, TurnServer, UserConfig};
-use crate::irc_client::{IrcClient, IrcEvent};
-use crate::irc_server::EmbeddedServer;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{error, warn};
+
+use crate::call::{self, CallCommand, CallSession, SessionOptions};
+use crate::contacts;
+use crate::e2e;
+use crate::config::{
+    AudioProcessing, CodecProfile, ConnState, NoiseSuppressionSetting, PeerAudioSettings, Role, ServerConfig,
+    TransmitMode, TurnServer, UserConfig,
+};
+use crate::headless::HostedRoom;
 use crate::magic_link::ConnectionInfo;
-use crate::moderation;
-use crate::relay::{AudioRelay, RelayConnection};
-use crate::state::AppState;
-use crate::tls;
-use crate::topology;
-use crate::upnp::PortForwarder;
-use crate::voice_mixer::{PeerDecoders, VoiceMixer};
-use crate::webrtc_peer::{InternalSignal, ReceivedFile, WebRtcPeer, WebRtcSignal};
+use crate::search::{SearchHit, SearchQuery, SearchResults};
+use crate::state::{ChatEntry, Verification};
+use crate::voice_mixer;
+
+/// Bottom of the mic level meter.
+const METER_MIN_DB: f32 = -70.0;
+
+fn meter_fraction(level_db: f32) -> f32 {
+    ((level_db - METER_MIN_DB) / -METER_MIN_DB).clamp(0.0, 1.0)
+}
+
+/// Noise suppression / AGC / high-pass controls, shared by Settings and the
+/// in-call Mic menu.  Returns (changed, done editing) so callers can apply
+/// live but only save once a slider is released.
+fn processing_controls(ui: &mut egui::Ui, p: &mut AudioProcessing) -> (bool, bool) {
+    let mut changed = false;
+    let mut done = false;
+    egui::ComboBox::from_label("Noise suppression")
+        .selected_text(p.noise_suppression.label())
+        .show_ui(ui, |ui| {
+            for level in NoiseSuppressionSetting::ALL {
+                if ui.selectable_value(&mut p.noise_suppression, level, level.label()).clicked() {
+                    changed = true;
+                    done = true;
+                }
+            }
+        });
+    if ui.checkbox(&mut p.gain_control, "Automatic gain control")
+        .on_hover_text("Evens out your volume for quiet or distant mics")
+        .changed()
+    {
+        changed = true;
+        done = true;
+    }
+    for slider in [
+        egui::Slider::new(&mut p.agc_target_dbfs, 0..=31).text("Target").prefix("-").suffix(" dBFS"),
+        egui::Slider::new(&mut p.agc_max_gain_db, 0..=30).text("Max gain").suffix(" dB"),
+    ] {
+        let r = ui.add_enabled(p.gain_control, slider);
+        changed |= r.changed();
+        done |= r.drag_stopped() || r.lost_focus();
+    }
+    if ui.checkbox(&mut p.high_pass_filter, "High-pass filter")
+        .on_hover_text("Cuts desk thumps, rumble and hum")
+        .changed()
+    {
+        changed = true;
+        done = true;
+    }
+    (changed, done)
+}
+
+/// Device picker; `None` is the system default.  Returns true on change.
+/// A saved device that's currently unplugged stays listed so the choice
+/// isn't silently lost.
+fn device_combo(ui: &mut egui::Ui, label: &str, names: &[String], selected: &mut Option<String>) -> bool {
+    let mut changed = false;
+    let current = selected.clone();
+    egui::ComboBox::from_label(label)
+        .selected_text(current.as_deref().unwrap_or("System default"))
+        .width(260.0)
+        .show_ui(ui, |ui| {
+            changed |= ui.selectable_value(selected, None, "System default").clicked();
+            let missing = current.as_ref().filter(|c| !names.contains(c));
+            for name in names.iter().chain(missing) {
+                let text = if Some(name) == missing { format!("{} (not connected)", name) } else { name.clone() };
+                changed |= ui.selectable_value(selected, Some(name.clone()), text).clicked();
+            }
+        });
+    changed
+}
+
+/// `<nick> text` as chat; anything else as a grey system line.
+fn chat_line(ui: &mut egui::Ui, msg: &str) {
+    if let Some(rest) = msg.strip_prefix('<') {
+        if let Some((nick, text)) = rest.split_once('>') {
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(nick).strong().color(egui::Color32::from_rgb(100, 150, 255)));
+                ui.label(RichText::new(text.trim()));
+            });
+        } else {
+            ui.label(msg);
+        }
+    } else {
+        ui.label(RichText::new(msg).color(egui::Color32::GRAY).italics());
+    }
+}
+
+/// How long a message jumped to stays highlighted.
+const JUMP_HIGHLIGHT: Duration = Duration::from_millis(1500);
+/// How long to wait for a message jumped to to show up (after switching
+/// channel, say) before giving up.
+const JUMP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Hits the search window lists.
+const SEARCH_LIMIT: usize = 200;
+/// Messages shown either side of a search hit.
+const SEARCH_CONTEXT: usize = 6;
+
+/// A message to scroll the chat to.
+struct Jump {
+    id: String,
+    asked: Instant,
+    /// When it was scrolled into view.
+    found: Option<Instant>,
+}
+
+impl Jump {
+    fn to(id: String) -> Self {
+        Self { id, asked: Instant::now(), found: None }
+    }
+
+    fn expired(&self) -> bool {
+        match self.found {
+            Some(at) => at.elapsed() > JUMP_HIGHLIGHT,
+            None => self.asked.elapsed() > JUMP_TIMEOUT,
+        }
+    }
+}
+
+/// A search hit and the messages around it.
+type HitContext = (SearchHit, Vec<ChatEntry>);
+
+/// The search window: the query as typed, and what came back.
+#[derive(Default)]
+struct SearchPanel {
+    words: String,
+    author: String,
+    /// Empty searches every channel.
+    channel: String,
+    after: String,
+    before: String,
+    error: Option<String>,
+    results: Option<SearchResults>,
+    /// The hit shown in context, with the messages around it.
+    context: Option<HitContext>,
+    /// Scroll the context to the hit on the next frame.
+    scroll_to_hit: bool,
+    /// Where the background search and context lookups land.
+    pending_results: Arc<std::sync::Mutex<Option<SearchResults>>>,
+    pending_context: Arc<std::sync::Mutex<Option<HitContext>>>,
+}
+
+impl SearchPanel {
+    /// The fields in `/search` syntax.
+    fn query(&self) -> String {
+        let mut parts = vec![self.words.trim().to_string()];
+        for (filter, value) in [("from", &self.author), ("in", &self.channel), ("after", &self.after), ("before", &self.before)] {
+            let value = value.trim();
+            if !value.is_empty() {
+                parts.push(format!("{}:{}", filter, value));
+            }
+        }
+        parts.join(" ")
+    }
+}
+
+/// What the user asked of a chat entry.
+enum ChatAction {
+    Reply,
+    Quote,
+    /// Scroll to the message with this id.
+    Jump(String),
+}
+
+/// The start of `text`, for quoting.
+fn snippet(text: &str) -> String {
+    match text.char_indices().nth(60) {
+        Some((i, _)) => format!("{}…", &text[..i]),
+        None => text.to_string(),
+    }
+}
+
+/// One scrollback entry: what it answers, then time, author, whether it
+/// verified, the text and how many replies it has.  If `interactive`,
+/// signed entries have Reply and Quote on right-click and the reply links
+/// jump.
+fn chat_entry(ui: &mut egui::Ui, entry: &ChatEntry, all: &[ChatEntry], highlighted: bool, interactive: bool) -> (egui::Response, Option<ChatAction>) {
+    let Some(author) = &entry.author else {
+        return (ui.label(RichText::new(&entry.text).color(egui::Color32::GRAY).italics()), None);
+    };
+    let mut action = None;
+    let fill = if highlighted { egui::Color32::from_rgb(70, 70, 45) } else { egui::Color32::TRANSPARENT };
+    let response = egui::Frame::none().fill(fill).show(ui, |ui| {
+        if let Some(target) = &entry.reply_to {
+            let quoted = all.iter().find(|e| e.id.as_ref() == Some(target));
+            let text = match quoted {
+                Some(q) => format!("↪ {}: {}", q.author.as_deref().unwrap_or("?"), snippet(&q.text)),
+                None => "↪ an earlier message".to_string(),
+            };
+            let label = ui.add(egui::Label::new(RichText::new(text).size(11.0).color(egui::Color32::GRAY)).sense(egui::Sense::click()));
+            if interactive && quoted.is_some() && label.on_hover_text("Jump to this message").clicked() {
+                action = Some(ChatAction::Jump(target.clone()));
+            }
+        }
+        ui.horizontal(|ui| {
+            let time = chrono::Local.timestamp_opt(entry.timestamp, 0).single()
+                .map(|t| t.format("%H:%M").to_string())
+                .unwrap_or_default();
+            ui.label(RichText::new(time).size(10.0).color(egui::Color32::DARK_GRAY));
+            ui.label(RichText::new(author).strong().color(egui::Color32::from_rgb(100, 150, 255)));
+            match entry.status {
+                Verification::Verified => {
+                    ui.label(RichText::new("✔").size(10.0).color(egui::Color32::from_rgb(90, 180, 90)))
+                        .on_hover_text("Signed and verified");
+                }
+                Verification::Suspicious => {
+                    ui.label(RichText::new("⚠").size(10.0).color(egui::Color32::from_rgb(255, 165, 0)))
+                        .on_hover_text("Signed, but its time or place in the history doesn't add up");
+                }
+                Verification::Unsigned => {}
+            }
+            ui.label(RichText::new(&entry.text));
+            let mut replies = all.iter().filter(|e| e.reply_to.is_some() && e.reply_to == entry.id);
+            if let Some(first) = replies.next().filter(|_| interactive) {
+                let count = 1 + replies.count();
+                let label = if count == 1 { "1 reply".to_string() } else { format!("{} replies", count) };
+                let link = ui.add(egui::Label::new(RichText::new(label).size(10.0).color(egui::Color32::from_rgb(100, 150, 255))).sense(egui::Sense::click()));
+                if link.on_hover_text("Jump to the first reply").clicked() {
+                    action = first.id.clone().map(ChatAction::Jump);
+                }
+            }
+        });
+    }).response;
+    if interactive && entry.id.is_some() {
+        response.context_menu(|ui| {
+            if ui.button("Reply").clicked() {
+                action = Some(ChatAction::Reply);
+                ui.close_menu();
+            }
+            if ui.button("Quote").clicked() {
+                action = Some(ChatAction::Quote);
+                ui.close_menu();
+            }
+        });
+    }
+    (response, action)
+}
 
 fn open_path(path: &Path) {
     #[cfg(target_os = "linux")]
@@ -37,26 +272,6 @@ enum Screen {
     InCall,
 }
 
-enum CallCommand {
-    SendMessage(String),
-    SwitchChannel(String),
-    CreateChannel(String),
-    SendFile { name: String, data: Vec<u8> },
-    Shutdown,
-}
-
-struct CallState {
-    state: Arc<AppState>,
-    command_tx: mpsc::UnboundedSender<CallCommand>,
-    channels: Arc<RwLock<Vec<String>>>,
-    current_channel: Arc<RwLock<String>>,
-    nickname: String,
-    invite_link: Arc<RwLock<Option<String>>>,
-    _mixer: Arc<VoiceMixer>,
-    _input_stream: Option<cpal::Stream>,
-    _output_stream: cpal::Stream,
-}
-
 pub struct VoircApp {
     config: UserConfig,
     screen: Screen,
@@ -67,6 +282,12 @@ pub struct VoircApp {
     host_link: Option<String>,
     host_link_external: Arc<RwLock<Option<String>>>,
     link_copied: bool,
+    /// The room we're serving, once started.
+    hosted: Option<HostedRoom>,
+    /// True while `HostedRoom::start` is running in the background.
+    host_starting: bool,
+    /// Storage for the background start's result.
+    host_result: Arc<std::sync::Mutex<Option<Result<HostedRoom, String>>>>,
 
     join_input: String,
     join_error: Option<String>,
@@ -75,11 +296,25 @@ pub struct VoircApp {
     settings_turn_url: String,
     settings_turn_user: String,
     settings_turn_cred: String,
+    settings_processing: AudioProcessing,
+    settings_input_device: Option<String>,
+    settings_output_device: Option<String>,
+    /// (inputs, outputs), refreshed when Settings or a call opens.
+    device_lists: (Vec<String>, Vec<String>),
     selected_recent: usize,
 
     chat_input: String,
+    /// Channel and signed message the next chat line answers.
+    reply_to: Option<(String, ChatEntry)>,
+    jump_to: Option<Jump>,
+    search: Option<SearchPanel>,
     new_channel_input: String,
-    call_state: Option<Arc<CallState>>,
+    /// Nick whose DM window is open.
+    dm_peer: Option<String>,
+    dm_input: String,
+    /// Whose safety number the verify window shows.
+    verify_peer: Option<String>,
+    call_state: Option<CallSession>,
     file_status: Option<String>,
 
     upnp_warning: Option<String>,
@@ -112,15 +347,28 @@ impl VoircApp {
             host_link: None,
             host_link_external: Arc::new(RwLock::new(None)),
             link_copied: false,
+            hosted: None,
+            host_starting: false,
+            host_result: Arc::new(std::sync::Mutex::new(None)),
             join_input: String::new(),
             join_error: None,
             settings_name: String::new(),
             settings_turn_url: String::new(),
             settings_turn_user: String::new(),
             settings_turn_cred: String::new(),
+            settings_processing: AudioProcessing::default(),
+            settings_input_device: None,
+            settings_output_device: None,
+            device_lists: (Vec::new(), Vec::new()),
             selected_recent: 0,
             chat_input: String::new(),
+            reply_to: None,
+            jump_to: None,
+            search: None,
             new_channel_input: String::new(),
+            dm_peer: None,
+            dm_input: String::new(),
+            verify_peer: None,
             call_state: None,
             file_status: None,
             upnp_warning: None,
@@ -165,6 +413,10 @@ impl VoircApp {
                         self.settings_turn_user = ts.username.clone();
                         self.settings_turn_cred = ts.credential.clone();
                     }
+                    self.settings_processing = self.config.processing;
+                    self.settings_input_device = self.config.input_device.clone();
+                    self.settings_output_device = self.config.output_device.clone();
+                    self.device_lists = voice_mixer::list_devices();
                     self.screen = Screen::Settings;
                 }
             });
@@ -205,6 +457,20 @@ impl VoircApp {
                              ui.label(RichText::new(hint).size(11.0)
                                  .color(egui::Color32::GRAY));
                          });
+                         ui.add_space(15.0);
+
+                         ui.label(RichText::new("Audio Quality").size(14.0));
+                         egui::ComboBox::from_id_salt("host_codec")
+                             .selected_text(self.config.host_codec.label())
+                             .show_ui(ui, |ui| {
+                                 for profile in CodecProfile::ALL {
+                                     ui.selectable_value(&mut self.config.host_codec, profile, profile.label());
+                                 }
+                             });
+                         if self.config.host_codec.is_music() {
+                             ui.label(RichText::new("Stereo, no echo cancellation or noise suppression — everyone should use headphones.")
+                                 .size(11.0).color(egui::Color32::GRAY));
+                         }
                          ui.add_space(20.0);
 
                          if let Some(err) = &self.host_error {
@@ -212,7 +478,12 @@ impl VoircApp {
                             ui.add_space(10.0);
                         }
 
-                        if ui.add_sized([400.0, 40.0], egui::Button::new(RichText::new("Start Server").size(16.0))).clicked() {
+                        if self.host_starting {
+                            ui.horizontal(|ui| {
+                                ui.spinner();
+                                ui.label(RichText::new("  Starting server...").size(14.0).color(egui::Color32::GRAY));
+                            });
+                        } else if ui.add_sized([400.0, 40.0], egui::Button::new(RichText::new("Start Server").size(16.0))).clicked() {
                             self.start_hosting();
                         }
                     });
@@ -277,6 +548,7 @@ impl VoircApp {
 
                 ui.add_space(20.0);
                 if ui.button("<- Back").clicked() {
+                    self.stop_hosting();
                     self.screen = Screen::Dashboard;
                     self.host_error = None;
                     self.host_link = None;
@@ -364,10 +636,32 @@ impl VoircApp {
                 ui.add(TextEdit::singleline(&mut self.settings_turn_cred).password(true));
             });
 
+            ui.add_space(20.0);
+            ui.separator();
+            ui.add_space(10.0);
+            ui.label(RichText::new("Audio devices").size(14.0).strong());
+            ui.add_space(10.0);
+            device_combo(ui, "Microphone", &self.device_lists.0, &mut self.settings_input_device);
+            device_combo(ui, "Speakers", &self.device_lists.1, &mut self.settings_output_device);
+            if ui.small_button("Refresh list").clicked() {
+                self.device_lists = voice_mixer::list_devices();
+            }
+
+            ui.add_space(20.0);
+            ui.separator();
+            ui.add_space(10.0);
+            ui.label(RichText::new("Microphone processing").size(14.0).strong());
+            ui.label(RichText::new("Echo cancellation is always on. These can also be changed from the Mic menu during a call.").size(12.0).color(egui::Color32::GRAY));
+            ui.add_space(10.0);
+            processing_controls(ui, &mut self.settings_processing);
+
             ui.add_space(20.0);
             ui.horizontal(|ui| {
                 if ui.button("Save").clicked() {
                     self.config.display_name = self.settings_name.clone();
+                    self.config.processing = self.settings_processing;
+                    self.config.input_device = self.settings_input_device.clone();
+                    self.config.output_device = self.settings_output_device.clone();
                     self.config.turn_servers.clear();
                     if !self.settings_turn_url.is_empty() {
                         self.config.turn_servers.push(TurnServer {
@@ -423,6 +717,10 @@ impl VoircApp {
     fn render_in_call(&mut self, ctx: &Context) {
         let mut disconnect = false;
         let mut send_msg = false;
+        let mut peer_audio_change: Option<(String, PeerAudioSettings, bool)> = None;
+        let mut open_dm: Option<String> = None;
+        let mut open_verify: Option<String> = None;
+        let mut open_search = false;
 
         if let Some(call_state) = &self.call_state {
             let nickname = call_state.nickname.clone();
@@ -437,6 +735,12 @@ impl VoircApp {
                 .try_read()
                 .map(|g| g.clone())
                 .unwrap_or_default();
+            let topic = state.topics.try_read()
+                .ok()
+                .and_then(|t| t.get(&current_channel).cloned());
+            let encrypted = state.encrypted_channels.try_read()
+                .map(|e| e.contains(&current_channel))
+                .unwrap_or(false);
 
             {
                 let s = Arc::clone(&state);
@@ -458,6 +762,19 @@ impl VoircApp {
                 }
             }
 
+            // Push-to-talk: hold ` (outside a text field) or the Talk button.
+            let voice = Arc::clone(&call_state.voice);
+            let ptt_key = !ctx.wants_keyboard_input() && ctx.input(|i| i.key_down(egui::Key::Backtick));
+            let mut ptt_button = false;
+            let mut save_transmit = false;
+            let mut processing = self.config.processing;
+            let mut devices = (self.config.input_device.clone(), self.config.output_device.clone());
+            let mut devices_changed = false;
+            let mut refresh_devices = false;
+            let active_devices = call_state.active_audio_devices();
+            let mut processing_changed = false;
+            let mut save_processing = false;
+
             let cmd_tx_top = cmd_tx.clone();
             let our_role = state.our_role.try_read().map(|r| *r).unwrap_or(Role::Peer);
             let invite_link_ref = call_state.invite_link.try_read()
@@ -471,6 +788,14 @@ impl VoircApp {
                         ui.horizontal(|ui| {
                             ui.label(RichText::new("V").size(20.0));
                             ui.label(RichText::new(&current_channel).size(16.0).strong());
+                            if encrypted {
+                                ui.label(RichText::new("[E2E]").size(12.0).color(egui::Color32::LIGHT_GREEN))
+                                    .on_hover_text("Messages here are end-to-end encrypted; the host only relays ciphertext");
+                            }
+                            if let Some(topic) = &topic {
+                                ui.label(RichText::new(topic).size(13.0).color(egui::Color32::GRAY))
+                                    .on_hover_text(topic);
+                            }
 
                             let badge = match our_role {
                                 Role::Host => RichText::new(" [HOST]").size(12.0).color(egui::Color32::GOLD),
@@ -485,12 +810,84 @@ impl VoircApp {
                                     disconnect = true;
                                 }
 
+                                if ui.button("Search").on_hover_text("Search every channel's history").clicked() {
+                                    open_search = true;
+                                }
                                 if let Some(link) = &invite_link_ref {
                                     if ui.button("Invite").clicked() {
                                         ui.output_mut(|o| o.copied_text = link.clone());
                                     }
                                 }
 
+                                let deafened = voice.is_deafened();
+                                if ui.selectable_label(deafened, "Deafen").clicked() {
+                                    let _ = cmd_tx_top.send(CallCommand::SetDeafened(!deafened));
+                                }
+                                let muted = voice.is_muted();
+                                if ui.selectable_label(muted, "Mute").clicked() {
+                                    let _ = cmd_tx_top.send(CallCommand::SetMuted(!muted));
+                                }
+                                let mode = voice.mode();
+                                if mode == TransmitMode::PushToTalk {
+                                    let talk = ui.add(egui::Button::new("Talk").sense(egui::Sense::click_and_drag()))
+                                        .on_hover_text("Hold ` or this button to send your mic");
+                                    ptt_button = talk.is_pointer_button_down_on();
+                                }
+                                ui.menu_button("Mic", |ui| {
+                                    for m in TransmitMode::ALL {
+                                        if ui.radio(mode == m, m.label()).clicked() {
+                                            voice.set_mode(m);
+                                            save_transmit = true;
+                                        }
+                                    }
+                                    ui.separator();
+                                    ui.label(RichText::new("Input level").size(12.0));
+                                    let level = voice.input_level_db();
+                                    let color = if voice.voice_detected() {
+                                        egui::Color32::from_rgb(80, 200, 120)
+                                    } else {
+                                        egui::Color32::GRAY
+                                    };
+                                    ui.add(egui::ProgressBar::new(meter_fraction(level))
+                                        .fill(color)
+                                        .text(format!("{:.0} dB", level))
+                                        .desired_width(200.0));
+                                    let mut threshold = voice.vad_threshold_db();
+                                    let slider = ui.add_enabled(
+                                        mode == TransmitMode::VoiceActivity,
+                                        egui::Slider::new(&mut threshold, METER_MIN_DB..=-10.0)
+                                            .text("Threshold")
+                                            .suffix(" dB"),
+                                    ).on_hover_text("Speech must be louder than this (and than the background noise) to be sent");
+                                    if slider.changed() {
+                                        voice.set_vad_threshold_db(threshold);
+                                    }
+                                    if slider.drag_stopped() || slider.lost_focus() {
+                                        save_transmit = true;
+                                    }
+                                    ui.separator();
+                                    if device_combo(ui, "Microphone", &self.device_lists.0, &mut devices.0) {
+                                        devices_changed = true;
+                                    }
+                                    if device_combo(ui, "Speakers", &self.device_lists.1, &mut devices.1) {
+                                        devices_changed = true;
+                                    }
+                                    if let Some(active) = &active_devices {
+                                        ui.label(RichText::new(format!(
+                                            "In use: {} / {}",
+                                            active.input.as_deref().unwrap_or("no mic"),
+                                            active.output.as_deref().unwrap_or("no speakers"),
+                                        )).size(11.0).color(egui::Color32::GRAY));
+                                    }
+                                    if ui.small_button("Refresh devices").clicked() {
+                                        refresh_devices = true;
+                                    }
+                                    ui.separator();
+                                    let (changed, done) = processing_controls(ui, &mut processing);
+                                    processing_changed |= changed;
+                                    save_processing |= done;
+                                });
+
                                 if let Some(status) = &self.file_status {
                                     ui.label(RichText::new(status).size(11.0).color(egui::Color32::LIGHT_GREEN));
                                 }
@@ -499,12 +896,42 @@ impl VoircApp {
                     });
             });
 
+            voice.set_ptt_held(ptt_key || ptt_button);
+            if processing_changed {
+                call_state.set_audio_processing(&processing);
+                self.config.processing = processing;
+            }
+            if refresh_devices {
+                self.device_lists = voice_mixer::list_devices();
+            }
+            if devices_changed {
+                call_state.set_audio_devices(devices.0.clone(), devices.1.clone());
+                (self.config.input_device, self.config.output_device) = devices;
+            }
+            if save_transmit || save_processing || devices_changed {
+                self.config.transmit = voice.transmit_settings();
+                if let Err(e) = self.config.save() {
+                    warn!("Could not save audio settings: {}", e);
+                }
+            }
+
             let chat_id = egui::Id::new("chat_input_field");
             TopBottomPanel::bottom("call_input").show(ctx, |ui| {
                 egui::Frame::none()
                     .fill(egui::Color32::from_rgb(30, 30, 35))
                     .inner_margin(10.0)
                     .show(ui, |ui| {
+                        let replying = self.reply_to.as_ref()
+                            .filter(|(ch, _)| *ch == current_channel)
+                            .map(|(_, entry)| format!("Replying to {}: {}", entry.author.as_deref().unwrap_or("?"), snippet(&entry.text)));
+                        if let Some(banner) = replying {
+                            ui.horizontal(|ui| {
+                                ui.label(RichText::new(banner).size(11.0).color(egui::Color32::GRAY));
+                                if ui.small_button("✕").on_hover_text("Cancel reply").clicked() {
+                                    self.reply_to = None;
+                                }
+                            });
+                        }
                         ui.horizontal(|ui| {
                             let resp = ui.add(
                                 TextEdit::singleline(&mut self.chat_input)
@@ -601,6 +1028,7 @@ impl VoircApp {
                                         Role::Peer => "",
                                     };
                                     ui.label(RichText::new(format!("{} (you){}", nickname, role_tag)).size(14.0));
+                                    ui.label(RichText::new(voice.status().tag()).size(11.0).color(egui::Color32::GRAY));
                                 });
                                 ui.add_space(5.0);
 
@@ -631,7 +1059,61 @@ impl VoircApp {
                                                 Role::Peer => "",
                                             };
                                             let color = if peer.connected { egui::Color32::WHITE } else { egui::Color32::GRAY };
-                                            ui.label(RichText::new(format!("{}{}", peer.nickname, role_tag)).size(14.0).color(color));
+                                            let name = ui.add(
+                                                egui::Label::new(RichText::new(format!("{}{}", peer.nickname, role_tag)).size(14.0).color(color))
+                                                    .sense(egui::Sense::click()),
+                                            ).on_hover_text("Right-click for volume or a DM");
+                                            ui.label(RichText::new(peer.voice.tag()).size(11.0).color(egui::Color32::GRAY));
+                                            let pubkey = state_sb.known_pubkeys.try_read().ok().and_then(|k| k.get(&peer.nickname).cloned());
+                                            if let (Some(pk), Ok(contacts)) = (&pubkey, state_sb.contacts.try_read()) {
+                                                if contacts.is_verified(pk) {
+                                                    ui.label(RichText::new("[verified]").size(11.0).color(egui::Color32::LIGHT_GREEN));
+                                                } else if contacts.needs_attention(&peer.nickname, pk) {
+                                                    ui.label(RichText::new("[new key]").size(11.0).color(egui::Color32::from_rgb(255, 165, 0)))
+                                                        .on_hover_text("This nick used a different key before. Compare safety numbers.");
+                                                }
+                                            }
+
+                                            let mut mix = voice.peer(&peer.nickname);
+                                            if mix.muted {
+                                                ui.label(RichText::new("[off]").size(11.0).color(egui::Color32::from_rgb(255, 165, 0)));
+                                            } else if mix.gain != 1.0 {
+                                                ui.label(RichText::new(format!("{:.0}%", mix.gain * 100.0)).size(11.0).color(egui::Color32::GRAY));
+                                            }
+                                            name.context_menu(|ui| {
+                                                ui.label(RichText::new(&peer.nickname).strong());
+                                                let volume = ui.add(
+                                                    egui::Slider::new(&mut mix.gain, 0.0..=PeerAudioSettings::MAX_GAIN)
+                                                        .text("Volume")
+                                                        .custom_formatter(|v, _| format!("{:.0}%", v * 100.0)),
+                                                );
+                                                let pan = ui.add(egui::Slider::new(&mut mix.pan, -1.0..=1.0).text("Pan"));
+                                                let mut changed = volume.changed() || pan.changed();
+                                                // Save once the slider is let go, not every frame of the drag.
+                                                let mut persist = volume.drag_stopped() || pan.drag_stopped()
+                                                    || (changed && !volume.dragged() && !pan.dragged());
+                                                if ui.checkbox(&mut mix.muted, "Mute for me").changed() {
+                                                    changed = true;
+                                                    persist = true;
+                                                }
+                                                if ui.button("Reset").clicked() {
+                                                    mix = PeerAudioSettings::default();
+                                                    changed = true;
+                                                    persist = true;
+                                                }
+                                                if changed || persist {
+                                                    peer_audio_change = Some((peer.nickname.clone(), mix, persist));
+                                                }
+                                                ui.separator();
+                                                if ui.button("Direct message").clicked() {
+                                                    open_dm = Some(peer.nickname.clone());
+                                                    ui.close_menu();
+                                                }
+                                                if ui.add_enabled(pubkey.is_some(), egui::Button::new("Verify identity...")).clicked() {
+                                                    open_verify = Some(peer.nickname.clone());
+                                                    ui.close_menu();
+                                                }
+                                            });
                                         });
 
                                         // Show status text for non-connected peers
@@ -698,20 +1180,31 @@ impl VoircApp {
                                 .auto_shrink([false, false])
                                 .stick_to_bottom(true)
                                 .show(ui, |ui| {
+                                    if self.jump_to.as_ref().is_some_and(Jump::expired) {
+                                        self.jump_to = None;
+                                    }
                                     if let Ok(messages) = state.messages.try_read() {
                                         if let Some(msgs) = messages.get(&current_channel) {
-                                            for msg in msgs.iter() {
-                                                if let Some(rest) = msg.strip_prefix('<') {
-                                                    if let Some((nick, text)) = rest.split_once('>') {
-                                                        ui.horizontal(|ui| {
-                                                            ui.label(RichText::new(nick).strong().color(egui::Color32::from_rgb(100, 150, 255)));
-                                                            ui.label(RichText::new(text.trim()));
-                                                        });
-                                                    } else {
-                                                        ui.label(msg);
+                                            for entry in msgs.iter() {
+                                                let target = self.jump_to.as_mut().filter(|j| entry.id.as_ref() == Some(&j.id));
+                                                let (response, action) = chat_entry(ui, entry, msgs, target.is_some(), true);
+                                                // Scroll there the first time it's on screen.
+                                                if let Some(jump) = target.filter(|j| j.found.is_none()) {
+                                                    response.scroll_to_me(Some(egui::Align::Center));
+                                                    jump.found = Some(Instant::now());
+                                                }
+                                                match action {
+                                                    Some(ChatAction::Reply) => {
+                                                        self.reply_to = Some((current_channel.clone(), entry.clone()));
+                                                        ctx.memory_mut(|m| m.request_focus(chat_id));
+                                                    }
+                                                    Some(ChatAction::Quote) => {
+                                                        self.reply_to = Some((current_channel.clone(), entry.clone()));
+                                                        self.chat_input = format!("> {} ", snippet(&entry.text));
+                                                        ctx.memory_mut(|m| m.request_focus(chat_id));
                                                     }
-                                                } else {
-                                                    ui.label(RichText::new(msg).color(egui::Color32::GRAY).italics());
+                                                    Some(ChatAction::Jump(id)) => self.jump_to = Some(Jump::to(id)),
+                                                    None => {}
                                                 }
                                                 ui.add_space(5.0);
                                             }
@@ -723,8 +1216,27 @@ impl VoircApp {
             });
         }
 
+        if let Some((nick, settings, persist)) = peer_audio_change {
+            self.set_peer_audio(&nick, settings, persist);
+        }
+        if open_dm.is_some() {
+            self.dm_peer = open_dm;
+        }
+        if open_verify.is_some() {
+            self.verify_peer = open_verify;
+        }
+        if open_search && self.search.is_none() {
+            self.search = Some(SearchPanel::default());
+        }
+        self.render_direct_messages(ctx);
+        self.render_verify_identity(ctx);
+        self.render_search(ctx);
         if disconnect {
             self.call_state = None;
+            self.dm_peer = None;
+            self.verify_peer = None;
+            self.search = None;
+            self.jump_to = None;
             self.screen = Screen::Dashboard;
         }
         if send_msg {
@@ -732,137 +1244,404 @@ impl VoircApp {
         }
     }
 
-    fn start_hosting(&mut self) {
-        let port: u16 = match self.host_port.parse() {
-            Ok(p) => p,
-            Err(_) => {
-                self.host_error = Some("Invalid port number".to_string());
-                return;
-            }
-        };
+    /// The open DM conversation.  The header says whether the peer's key is
+    /// verified; without one nothing can be encrypted, so input is off.
+    fn render_direct_messages(&mut self, ctx: &Context) {
+        let (Some(peer), Some(cs)) = (self.dm_peer.clone(), &self.call_state) else { return };
+        let pubkey = cs.state.known_pubkeys.try_read().ok().and_then(|k| k.get(&peer).cloned());
+        let trust = pubkey.as_ref().and_then(|pk| {
+            cs.state.contacts.try_read().ok().map(|c| c.trust(&peer, pk))
+        });
+        let lines = cs.state.direct_messages.try_read()
+            .ok()
+            .and_then(|d| d.get(&peer).cloned())
+            .unwrap_or_default();
+
+        let mut open = true;
+        let mut send = false;
+        let mut verify = false;
+        egui::Window::new(format!("DM - {}", peer))
+            .id(egui::Id::new("dm_window"))
+            .open(&mut open)
+            .default_size([360.0, 320.0])
+            .show(ctx, |ui| {
+                let orange = egui::Color32::from_rgb(255, 165, 0);
+                let (text, color) = match (&pubkey, trust) {
+                    (Some(pk), Some(contacts::KeyTrust::Verified)) => (
+                        format!("End-to-end encrypted - verified key {}", e2e::short_fingerprint(pk)),
+                        egui::Color32::LIGHT_GREEN,
+                    ),
+                    (Some(pk), Some(contacts::KeyTrust::Changed)) => (
+                        format!("{} used a different key before - compare safety numbers before sending to {}",
+                            peer, e2e::short_fingerprint(pk)),
+                        orange,
+                    ),
+                    (Some(pk), _) => (
+                        format!("Key {} not verified yet - compare safety numbers to send", e2e::short_fingerprint(pk)),
+                        orange,
+                    ),
+                    (None, _) => ("No key for this nick yet, so messages can't be encrypted".to_string(), orange),
+                };
+                ui.horizontal(|ui| {
+                    let label = ui.label(RichText::new(text).size(11.0).color(color));
+                    if let Some(pk) = &pubkey {
+                        label.on_hover_text(pk);
+                        if trust != Some(contacts::KeyTrust::Verified) && ui.small_button("Verify...").clicked() {
+                            verify = true;
+                        }
+                    }
+                });
+                ui.separator();
+                ScrollArea::vertical()
+                    .id_salt("dm_scroll")
+                    .auto_shrink([false, false])
+                    .stick_to_bottom(true)
+                    .max_height(220.0)
+                    .show(ui, |ui| {
+                        for line in &lines {
+                            chat_line(ui, line);
+                            ui.add_space(4.0);
+                        }
+                    });
+                ui.separator();
+                ui.add_enabled_ui(trust == Some(contacts::KeyTrust::Verified), |ui| {
+                    let resp = ui.add(
+                        TextEdit::singleline(&mut self.dm_input)
+                            .hint_text("Encrypted message...")
+                            .desired_width(f32::INFINITY),
+                    );
+                    if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) && !self.dm_input.is_empty() {
+                        send = true;
+                        resp.request_focus();
+                    }
+                });
+            });
 
-        let channels: Vec<String> = self.host_channels
-            .split(',')
-            .map(|s| {
-                let s = s.trim().to_string();
-                if s.starts_with('#') { s } else { format!("#{}", s) }
-            })
-            .filter(|s| s.len() > 1)
-            .collect();
+        if send {
+            let text = std::mem::take(&mut self.dm_input);
+            let _ = cs.command_tx.send(CallCommand::SendDirect { to: peer.clone(), text });
+        }
+        if verify {
+            self.verify_peer = Some(peer);
+        }
+        if !open {
+            self.dm_peer = None;
+        }
+    }
 
-        if channels.is_empty() {
-            self.host_error = Some("Need at least one channel".to_string());
+    /// Safety number with a peer, what we know of their key, and the switch
+    /// that marks it checked.
+    fn render_verify_identity(&mut self, ctx: &Context) {
+        let (Some(peer), Some(cs)) = (self.verify_peer.clone(), &self.call_state) else { return };
+        let pubkey = cs.state.known_pubkeys.try_read().ok().and_then(|k| k.get(&peer).cloned());
+        let (Some(identity), Some(theirs)) = (&cs.state.identity, pubkey) else {
+            self.verify_peer = None;
+            return;
+        };
+        let contact = cs.state.contacts.try_read().ok().and_then(|c| {
+            c.get(&theirs).cloned().map(|contact| (contact, c.needs_attention(&peer, &theirs)))
+        });
+        let Ok(number) = contacts::safety_number(&identity.pubkey_hex, &theirs) else {
+            self.verify_peer = None;
             return;
+        };
+        let groups: Vec<&str> = number.split(' ').collect();
+
+        let mut open = true;
+        let mut set_verified = None;
+        egui::Window::new(format!("Verify {}", peer))
+            .id(egui::Id::new("verify_window"))
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "Compare this number with the one {} sees for you, in person or on a call. If they match, nobody is in between.",
+                    peer
+                ));
+                ui.add_space(8.0);
+                for row in groups.chunks(4) {
+                    ui.label(RichText::new(row.join("  ")).size(18.0).monospace());
+                }
+                ui.add_space(8.0);
+                ui.label(RichText::new(format!("Key {}", e2e::short_fingerprint(&theirs))).size(11.0).color(egui::Color32::GRAY))
+                    .on_hover_text(&theirs);
+                if let Some((contact, changed)) = &contact {
+                    let first_seen = chrono::DateTime::from_timestamp(contact.first_seen as i64, 0)
+                        .map(|t| t.format("%Y-%m-%d").to_string())
+                        .unwrap_or_default();
+                    ui.label(RichText::new(format!("First seen {} as {}", first_seen, contact.nicks.join(", ")))
+                        .size(11.0)
+                        .color(egui::Color32::GRAY));
+                    if *changed {
+                        ui.label(RichText::new(format!("{} used a different key before.", peer))
+                            .size(11.0)
+                            .color(egui::Color32::from_rgb(255, 165, 0)));
+                    }
+                    ui.separator();
+                    let mut verified = contact.verified;
+                    if ui.checkbox(&mut verified, "The numbers match - mark as verified").changed() {
+                        set_verified = Some(verified);
+                    }
+                }
+            });
+
+        if let Some(verified) = set_verified {
+            let state = Arc::clone(&cs.state);
+            tokio::spawn(async move { state.contacts.write().await.set_verified(&theirs, verified) });
+        }
+        if !open {
+            self.verify_peer = None;
         }
+    }
 
-        // Generate TLS cert
-        let cert_dir = UserConfig::tls_cert_dir();
-        let cert_info = match tls::load_or_generate(&cert_dir) {
-            Ok(ci) => ci,
-            Err(e) => {
-                warn!("TLS cert generation failed, falling back to plaintext: {}", e);
-                // Fall back to plaintext, using 0 bits for fallback PoW (or self.host_pow_bits if safe)
-                let pow_bits = 0; 
-                tokio::spawn(async move {
-                    if let Err(e) = EmbeddedServer::run(port, pow_bits).await {
-                        error!("Server error: {}", e);
+    /// Search across every channel's signed log, with the messages around
+    /// the hit picked and a way to jump to it in the chat.
+    fn render_search(&mut self, ctx: &Context) {
+        let (Some(panel), Some(cs)) = (self.search.as_mut(), &self.call_state) else { return };
+        if let Some(results) = panel.pending_results.lock().ok().and_then(|mut r| r.take()) {
+            panel.results = Some(results);
+        }
+        if let Some(context) = panel.pending_context.lock().ok().and_then(|mut c| c.take()) {
+            panel.context = Some(context);
+            panel.scroll_to_hit = true;
+        }
+        let channels = cs.channels.try_read().map(|c| c.clone()).unwrap_or_default();
+
+        let mut open = true;
+        let mut run = false;
+        let mut show: Option<SearchHit> = None;
+        let mut jump: Option<(String, String)> = None;
+        egui::Window::new("Search")
+            .id(egui::Id::new("search_window"))
+            .open(&mut open)
+            .default_size([520.0, 520.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    let resp = ui.add(
+                        TextEdit::singleline(&mut panel.words)
+                            .hint_text("Words...")
+                            .desired_width(ui.available_width() - 70.0),
+                    );
+                    if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                        run = true;
+                    }
+                    if ui.button("Search").clicked() {
+                        run = true;
                     }
                 });
+                ui.horizontal(|ui| {
+                    ui.add(TextEdit::singleline(&mut panel.author).hint_text("From nick").desired_width(90.0));
+                    let selected = if panel.channel.is_empty() { "All channels".to_string() } else { panel.channel.clone() };
+                    egui::ComboBox::from_id_salt("search_channel")
+                        .selected_text(selected)
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut panel.channel, String::new(), "All channels");
+                            for ch in &channels {
+                                ui.selectable_value(&mut panel.channel, ch.clone(), ch);
+                            }
+                        });
+                    ui.add(TextEdit::singleline(&mut panel.after).hint_text("After YYYY-MM-DD").desired_width(115.0));
+                    ui.add(TextEdit::singleline(&mut panel.before).hint_text("Before YYYY-MM-DD").desired_width(115.0));
+                });
+                if let Some(error) = &panel.error {
+                    ui.label(RichText::new(error).size(11.0).color(egui::Color32::from_rgb(255, 165, 0)));
+                }
 
-                let conn_info = ConnectionInfo::new("127.0.0.1".to_string(), port, channels.clone());
-                self.host_link = conn_info.to_magic_link().ok();
-                self.link_copied = false;
+                if let Some(results) = &panel.results {
+                    ui.separator();
+                    let summary = match results.total {
+                        0 => "No matches".to_string(),
+                        n if n > results.hits.len() => format!("Newest {} of {} matches", results.hits.len(), n),
+                        n => format!("{} match{}", n, if n == 1 { "" } else { "es" }),
+                    };
+                    ui.label(RichText::new(summary).size(11.0).color(egui::Color32::GRAY));
+                    ScrollArea::vertical()
+                        .id_salt("search_results")
+                        .auto_shrink([false, true])
+                        .max_height(200.0)
+                        .show(ui, |ui| {
+                            for hit in &results.hits {
+                                let when = chrono::Local.timestamp_opt(hit.timestamp, 0).single()
+                                    .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                                    .unwrap_or_default();
+                                let picked = panel.context.as_ref().is_some_and(|(h, _)| h == hit);
+                                let text = format!("{}  {}  {}: {}", when, hit.channel, hit.author, snippet(&hit.text));
+                                if ui.selectable_label(picked, text).clicked() {
+                                    show = Some(hit.clone());
+                                }
+                            }
+                        });
+                }
+
+                if let Some((hit, entries)) = &panel.context {
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label(RichText::new(format!("In {}", hit.channel)).size(12.0).color(egui::Color32::GRAY));
+                        let in_chat = cs.state.messages.try_read().is_ok_and(|m| {
+                            m.get(&hit.channel).is_some_and(|list| list.iter().any(|e| e.id.as_ref() == Some(&hit.id)))
+                        });
+                        if in_chat {
+                            if ui.small_button("Show in chat").clicked() {
+                                jump = Some((hit.channel.clone(), hit.id.clone()));
+                            }
+                        } else {
+                            ui.label(RichText::new("(older than the chat's scrollback)").size(11.0).color(egui::Color32::DARK_GRAY));
+                        }
+                    });
+                    ScrollArea::vertical()
+                        .id_salt("search_context")
+                        .auto_shrink([false, false])
+                        .show(ui, |ui| {
+                            for entry in entries {
+                                let here = entry.id.as_ref() == Some(&hit.id);
+                                let (response, _) = chat_entry(ui, entry, entries, here, false);
+                                if here && panel.scroll_to_hit {
+                                    response.scroll_to_me(Some(egui::Align::Center));
+                                }
+                                ui.add_space(4.0);
+                            }
+                        });
+                    panel.scroll_to_hit = false;
+                }
+            });
 
-                let external = Arc::clone(&self.host_link_external);
-                let ch = channels;
-                tokio::spawn(async move {
-                    if let Ok(ip) = PortForwarder::get_external_ip(None).await {
-                        let info = ConnectionInfo::new(ip, port, ch);
-                        if let Ok(link) = info.to_magic_link() {
-                            *external.write().await = Some(link);
+        if run {
+            match SearchQuery::parse(&panel.query()) {
+                Ok(query) => {
+                    panel.error = None;
+                    let state = Arc::clone(&cs.state);
+                    let slot = Arc::clone(&panel.pending_results);
+                    tokio::spawn(async move {
+                        let results = state.message_log.search(&query, SEARCH_LIMIT).await;
+                        if let Ok(mut slot) = slot.lock() {
+                            *slot = Some(results);
                         }
-                    }
-                });
+                    });
+                }
+                Err(e) => panel.error = Some(format!("Can't search: {}", e)),
+            }
+        }
+        if let Some(hit) = show {
+            let state = Arc::clone(&cs.state);
+            let slot = Arc::clone(&panel.pending_context);
+            tokio::spawn(async move {
+                let entries = state.message_context(&hit.channel, &hit.id, SEARCH_CONTEXT).await;
+                if let Ok(mut slot) = slot.lock() {
+                    *slot = Some((hit, entries));
+                }
+            });
+        }
+        if let Some((channel, id)) = jump {
+            let current = cs.current_channel.try_read().map(|c| c.clone()).unwrap_or_default();
+            if channel != current {
+                let _ = cs.command_tx.send(CallCommand::SwitchChannel(channel));
+            }
+            self.jump_to = Some(Jump::to(id));
+        }
+        if !open {
+            self.search = None;
+        }
+    }
+
+    /// Apply a per-peer mix change now and remember it by pubkey.
+    fn set_peer_audio(&mut self, nick: &str, settings: PeerAudioSettings, persist: bool) {
+        let Some(cs) = &self.call_state else { return };
+        let pubkey = cs.state.known_pubkeys.try_read().ok().and_then(|k| k.get(nick).cloned());
+        cs.set_peer_audio(nick, pubkey.as_deref(), settings);
+        if let Some(pk) = pubkey.filter(|_| persist) {
+            if settings.is_default() {
+                self.config.peer_audio.remove(&pk);
+            } else {
+                self.config.peer_audio.insert(pk, settings);
+            }
+            if let Err(e) = self.config.save() {
+                warn!("Could not save peer audio settings: {}", e);
+            }
+        }
+    }
 
-                self.host_error = None;
+    fn start_hosting(&mut self) {
+        let port: u16 = match self.host_port.parse() {
+            Ok(p) => p,
+            Err(_) => {
+                self.host_error = Some("Invalid port number".to_string());
                 return;
             }
         };
 
-         let fingerprint = cert_info.fingerprint.clone();
-         let relay_port = port + 1;
-         let pow_bits = self.host_pow_bits;
-         self.config.pow_required_bits = pow_bits;
-         let _ = self.config.save();
-
-        // Start TLS IRC server
-        let cert_for_server = crate::tls::CertInfo {
-            cert_der: cert_info.cert_der.clone(),
-            key_der: cert_info.key_der.clone(),
-            fingerprint: cert_info.fingerprint.clone(),
+        let server_config = ServerConfig {
+            port,
+            channels: self.host_channels.split(',').map(str::to_string).collect(),
+            pow_required_bits: self.host_pow_bits,
+            codec: self.config.host_codec,
+            host_pubkey: Self::host_pubkey(),
+            ..Default::default()
         };
-        tokio::spawn(async move {
-            if let Err(e) = EmbeddedServer::run_tls(port, &cert_for_server, pow_bits).await {
-                error!("Server error: {}", e);
-            }
-        });
+        if server_config.channel_list().is_empty() {
+            self.host_error = Some("Need at least one channel".to_string());
+            return;
+        }
+
+        self.config.pow_required_bits = self.host_pow_bits;
+        let _ = self.config.save();
 
-        // Start relay
+        self.host_error = None;
+        self.host_starting = true;
+        let external = Arc::clone(&self.host_link_external);
+        let host_result = Arc::clone(&self.host_result);
         tokio::spawn(async move {
-            if let Err(e) = AudioRelay::run(relay_port).await {
-                error!("Relay error: {}", e);
+            let result = match HostedRoom::start(&server_config).await {
+                Ok(room) => {
+                    *external.write().await = Some(room.public_link.clone());
+                    Ok(room)
+                }
+                Err(e) => {
+                    error!("Could not start hosting: {}", e);
+                    Err(e.to_string())
+                }
+            };
+            if let Ok(mut guard) = host_result.lock() {
+                *guard = Some(result);
             }
         });
+    }
 
-        // UPnP with warning feedback
-        let upnp_warning: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
-        let uw = Arc::clone(&upnp_warning);
-        let port_c = port;
-        tokio::spawn(async move {
-            if let Err(e) = PortForwarder::forward_port(port_c, None).await {
-                info!("UPnP failed (not critical): {}", e);
-                *uw.write().await = Some(
+    /// Pick up a room `start_hosting` finished starting.
+    fn poll_hosting(&mut self) {
+        let Some(result) = self.host_result.lock().ok().and_then(|mut g| g.take()) else { return };
+        self.host_starting = false;
+        match result {
+            Ok(room) => {
+                self.host_link = room.local_info.to_magic_link().ok();
+                self.link_copied = false;
+                self.upnp_warning = room.upnp_failed.then(|| {
                     "Could not auto-forward port. Friends outside your network may not connect.".to_string()
-                );
-            }
-        });
-        // Check the warning after a brief delay
-        let uw2 = Arc::clone(&upnp_warning);
-        let upnp_warning_out = Arc::new(std::sync::Mutex::new(None::<String>));
-        let uwo = Arc::clone(&upnp_warning_out);
-        tokio::spawn(async move {
-            tokio::time::sleep(std::time::Duration::from_secs(3)).await;
-            if let Some(w) = uw2.read().await.clone() {
-                if let Ok(mut guard) = uwo.lock() {
-                    *guard = Some(w);
-                }
+                });
+                self.hosted = Some(room);
             }
-        });
+            Err(e) => self.host_error = Some(format!("Could not start the server: {}", e)),
+        }
+    }
 
-         let conn_info = ConnectionInfo::new("127.0.0.1".to_string(), port, channels.clone())
-             .with_tls(fingerprint.clone())
-             .with_relay(relay_port)
-             .with_pow(pow_bits);
-        self.host_link = conn_info.to_magic_link().ok();
-        self.link_copied = false;
+    /// Stop the room we're hosting, if any.
+    fn stop_hosting(&mut self) {
+        if let Some(room) = self.hosted.take() {
+            tokio::spawn(room.shutdown());
+        }
+        if let Ok(mut link) = self.host_link_external.try_write() {
+            *link = None;
+        }
+    }
 
-        let external = Arc::clone(&self.host_link_external);
-        let ch = channels;
-        let fp = fingerprint;
-        tokio::spawn(async move {
-            if let Ok(ip) = PortForwarder::get_external_ip(None).await {
-                 let info = ConnectionInfo::new(ip, port, ch)
-                      .with_tls(fp)
-                      .with_relay(relay_port)
-                      .with_pow(pow_bits);
-                if let Ok(link) = info.to_magic_link() {
-                    info!("External magic link: {}", link);
-                    *external.write().await = Some(link);
-                }
+    /// Our identity key, bound as the host of rooms we start.
+    fn host_pubkey() -> Option<String> {
+        match crate::persistence::Identity::load_or_generate(&UserConfig::tls_cert_dir()) {
+            Ok(id) => Some(id.pubkey_hex),
+            Err(e) => {
+                warn!("No identity for hosting, moderation disabled: {}", e);
+                None
             }
-        });
-
-        self.host_error = None;
+        }
     }
 
      fn join_room(&mut self) {
@@ -923,898 +1702,38 @@ impl VoircApp {
          });
      }
 
-fn connect_to_server(&mut self, conn_info: ConnectionInfo, is_host: bool) {
-        let identity = crate::persistence::Identity::load_or_generate(&UserConfig::tls_cert_dir()).ok();
-        let state = AppState::new(identity);
-        let nickname = self.config.display_name.clone();
-        let channels_vec = conn_info.channels.clone();
-        let default_channel = conn_info.default_channel().to_string();
-        let turn_servers = self.config.turn_servers.clone();
-        let banned_users = self.config.banned_users.clone();
-        
-        let cert_fingerprint = conn_info.cert_fingerprint.clone();
-        let use_tls = cert_fingerprint.is_some();
-
-        let relay_port = conn_info.relay_port;
-        let relay_addr = if let Some(rp) = relay_port {
-            Some(format!("{}:{}", conn_info.host, rp))
-        } else {
-            None
-        };
-
-        // Update diagnostics
-        {
-            let s = Arc::clone(&state);
-            let turn_count = turn_servers.len();
-            let has_relay = relay_port.is_some();
-            tokio::spawn(async move {
-                let mut d = s.diagnostics.write().await;
-                d.turn_configured = turn_count;
-                d.relay_enabled = has_relay;
-                d.relay_port = relay_port;
-            });
-        }
-
-        let mut config = self.config.clone();
-        let name = if is_host {
-            format!("My Server ({})", channels_vec.join(", "))
-        } else {
-            format!("{} ({})", conn_info.host, channels_vec.join(", "))
-        };
-        if let Ok(link) = conn_info.to_magic_link() {
-            config.add_recent_server(name, link);
-            self.config = config;
-        }
-
-        let mixer = match VoiceMixer::new() {
-            Ok(m) => Arc::new(m),
-            Err(e) => { error!("Mixer: {}", e); return; }
-        };
-
-        let (mic_tx, mic_rx) = mpsc::unbounded_channel();
-        let (mix_tx, mut mix_rx) = mpsc::unbounded_channel::<(String, Vec<u8>)>();
-
-        let input_stream = match mixer.start_input(mic_tx) {
-            Ok(s) => s,
-            Err(e) => { error!("Input stream: {}", e); None }
-        };
-        let output_stream = match mixer.start_output() {
-            Ok(o) => o,
-            Err(e) => { error!("Output stream: {}", e); return; }
-        };
-
-        if input_stream.is_none() {
-            info!("Running in listen-only mode (no microphone)");
-        }
-        let has_mic = input_stream.is_some();
-
-        let mixer_c = Arc::clone(&mixer);
-        let state_mix = Arc::clone(&state);
-        let state_fwd = Arc::clone(&state);
-        let (forward_tx, forward_rx) = mpsc::unbounded_channel::<(String, Vec<u8>)>();
-        tokio::spawn(async move {
-            let mut decoders = PeerDecoders::new();
-            while let Some((nick, packet)) = mix_rx.recv().await {
-                if let Some(pcm) = decoders.decode(&nick, &packet) {
-                    mixer_c.queue_audio(pcm);
-                    state_mix.mark_speaking(&nick).await;
-                }
-                let our_role = state_fwd.our_role().await;
-                if our_role.is_superpeer() {
-                    let _ = forward_tx.send((nick, packet));
-                }
-            }
-        });
-
-        let (command_tx, command_rx) = mpsc::unbounded_channel::<CallCommand>();
-        let (file_tx, file_rx) = mpsc::unbounded_channel::<ReceivedFile>();
-
-        let current_channel = Arc::new(RwLock::new(default_channel.clone()));
-        let channels = Arc::new(RwLock::new(channels_vec));
-
-        let state_c = Arc::clone(&state);
-        let nick_c = nickname.clone();
-        let conn_c = conn_info.clone();
-        let cur_ch = Arc::clone(&current_channel);
-        let channels_for_loop = Arc::clone(&channels);
-
-        let our_role = if is_host { Role::Host } else { Role::Peer };
-        let state_role = Arc::clone(&state);
-        tokio::spawn(async move {
-            state_role.set_our_role(our_role).await;
-        });
-
-        let invite_link_c = Arc::clone(&self.host_link_external);
-
-        tokio::spawn(async move {
-            let timeout = tokio::time::timeout(
-                std::time::Duration::from_secs(10),
-                IrcClient::connect(
-                    conn_c.server_address(),
-                    nick_c.clone(),
-                    default_channel.clone(),
-                    state_c.clone(),
-                    cert_fingerprint,
-                ),
-            );
-
-            match timeout.await {
-                Ok(Ok((irc_client, irc_stream, irc_events))) => {
-                    let irc = Arc::new(irc_client);
-
-                    let irc_c = Arc::clone(&irc);
-                    let state_s = Arc::clone(&state_c);
-                    let cur_ch_s = Arc::clone(&cur_ch);
-                    tokio::spawn(async move {
-                        if let Err(e) = irc_c.run(irc_stream).await {
-                            error!("IRC stream: {}", e);
-                            let ch = cur_ch_s.read().await.clone();
-                            state_s.add_message(&ch, format!("IRC error: {}", e)).await;
-                        }
-                    });
-
-                    let _ = irc.announce_role(&default_channel, our_role);
-
-                    let ch = cur_ch.read().await.clone();
-                    let tls_note = if use_tls { " (TLS)" } else { "" };
-                    state_c.add_message(&ch, format!("Connected as {}{}", nick_c, tls_note)).await;
-                    if is_host {
-                        state_c.add_message(&ch, "You are hosting this room".to_string()).await;
-                        if relay_addr.is_some() {
-                            state_c.add_message(&ch, "Audio relay is active for NAT-challenged peers".to_string()).await;
-                        }
-                    }
-                    if !has_mic {
-                        state_c.add_message(&ch, "No microphone detected - listen-only mode. Chat still works!".to_string()).await;
-                    }
-
-                    state_c.load_history(&ch, 100).await;
-
-                    let custom_commands = moderation::CustomCommands::load();
-
-                    Self::event_loop(
-                        irc, irc_events, state_c, nick_c, our_role,
-                        mix_tx, mic_rx, forward_rx,
-                        command_rx, file_tx, file_rx,
-                        cur_ch, channels_for_loop,
-                        turn_servers, banned_users,
-                        custom_commands, invite_link_c,
-                        relay_addr,
-                    ).await;
-                }
-                Ok(Err(e)) => {
-                    let ch = cur_ch.read().await.clone();
-                    state_c.add_message(&ch, format!("Connection failed: {}", e)).await;
-                }
-                Err(_) => {
-                    let ch = cur_ch.read().await.clone();
-                    state_c.add_message(&ch, "Connection timed out".to_string()).await;
-                }
-            }
-        });
+    fn connect_to_server(&mut self, conn_info: ConnectionInfo, is_host: bool) {
+        call::remember_server(&mut self.config, &conn_info, is_host);
 
         let invite_link = Arc::clone(&self.host_link_external);
-        if !is_host {
-            if let Ok(link) = conn_info.to_magic_link() {
-                let il = Arc::clone(&invite_link);
-                tokio::spawn(async move {
-                    let mut guard = il.write().await;
-                    if guard.is_none() {
-                        *guard = Some(link);
-                    }
-                });
-            }
-        }
-
-        self.call_state = Some(Arc::new(CallState {
-            state,
-            command_tx,
-            channels,
-            current_channel,
-            nickname,
-            invite_link,
-            _mixer: mixer,
-            _input_stream: input_stream,
-            _output_stream: output_stream,
-        }));
-        self.screen = Screen::InCall;
-        self.file_status = None;
-    }
-
-    #[allow(clippy::too_many_arguments)]
-    async fn event_loop(
-        irc: Arc<IrcClient>,
-        mut irc_events: mpsc::UnboundedReceiver<IrcEvent>,
-        state: Arc<AppState>,
-        nickname: String,
-        our_role: Role,
-        mix_tx: mpsc::UnboundedSender<(String, Vec<u8>)>,
-        mut mic_rx: mpsc::UnboundedReceiver<Vec<u8>>,
-        mut forward_rx: mpsc::UnboundedReceiver<(String, Vec<u8>)>,
-        mut command_rx: mpsc::UnboundedReceiver<CallCommand>,
-        file_tx: mpsc::UnboundedSender<ReceivedFile>,
-        mut file_rx: mpsc::UnboundedReceiver<ReceivedFile>,
-        current_channel: Arc<RwLock<String>>,
-        channels: Arc<RwLock<Vec<String>>>,
-        turn_servers: Vec<TurnServer>,
-        banned_users: std::collections::HashSet<String>,
-        mut custom_commands: moderation::CustomCommands,
-        invite_link: Arc<RwLock<Option<String>>>,
-        relay_addr: Option<String>,
-    ) {
-        let (ice_out_tx, mut ice_out_rx) = mpsc::unbounded_channel::<InternalSignal>();
-        let peers: Arc<RwLock<HashMap<String, Arc<WebRtcPeer>>>> =
-            Arc::new(RwLock::new(HashMap::new()));
-
-        let reconnect_attempts: Arc<RwLock<HashMap<String, u32>>> =
-            Arc::new(RwLock::new(HashMap::new()));
-
-        // Optional relay connection for fallback
-        let relay_conn: Arc<RwLock<Option<RelayConnection>>> = Arc::new(RwLock::new(None));
-        if let Some(ref addr) = relay_addr {
-            let mix_tx_relay = mix_tx.clone();
-            let addr_c = addr.clone();
-            let nick_c = nickname.clone();
-            let rc = Arc::clone(&relay_conn);
-            tokio::spawn(async move {
-                match RelayConnection::connect(&addr_c, &nick_c, mix_tx_relay).await {
-                    Ok(conn) => {
-                        info!("Relay connection established");
-                        *rc.write().await = Some(conn);
-                    }
-                    Err(e) => {
-                        warn!("Could not connect to relay: {}", e);
-                    }
-                }
-            });
-        }
-
-        let irc_c = Arc::clone(&irc);
-        let (reconnect_tx, mut reconnect_rx) = mpsc::unbounded_channel::<String>();
-        let (conn_failed_tx, mut conn_failed_rx) = mpsc::unbounded_channel::<String>();
-        tokio::spawn(async move {
-            while let Some(sig) = ice_out_rx.recv().await {
-                match sig {
-                    InternalSignal::WebRtc(target, ws) => {
-                        if let Ok(json) = serde_json::to_string(&ws) {
-                            let _ = irc_c.send_webrtc_signal(&target, &json);
-                        }
-                    }
-                    InternalSignal::Reconnect(nick) => {
-                        let _ = reconnect_tx.send(nick);
-                    }
-                    InternalSignal::ConnFailed(nick) => {
-                        let _ = conn_failed_tx.send(nick);
-                    }
-                }
-            }
-        });
-
-        let peers_mic = Arc::clone(&peers);
-        let relay_mic = Arc::clone(&relay_conn);
-        tokio::spawn(async move {
-            while let Some(pkt) = mic_rx.recv().await {
-                let r = peers_mic.read().await;
-                for p in r.values() {
-                    let _ = p.send_audio(&pkt).await;
-                }
-                // Also send via relay if connected
-                if let Some(rc) = relay_mic.read().await.as_ref() {
-                    let _ = rc.send_audio(&pkt).await;
-                }
-            }
-        });
-
-        let peers_fwd = Arc::clone(&peers);
-        tokio::spawn(async move {
-            while let Some((sender_nick, raw_packet)) = forward_rx.recv().await {
-                let r = peers_fwd.read().await;
-                for (nick, peer) in r.iter() {
-                    if *nick != sender_nick {
-                        let _ = peer.send_audio(&raw_packet).await;
-                    }
-                }
-            }
-        });
-
-        async fn maybe_create_peer(
-            nick: &str,
-            nickname: &str,
-            our_role: Role,
-            state: &Arc<AppState>,
-            peers: &Arc<RwLock<HashMap<String, Arc<WebRtcPeer>>>>,
-            irc: &Arc<IrcClient>,
-            mix_tx: &mpsc::UnboundedSender<(String, Vec<u8>)>,
-            ice_out_tx: &mpsc::UnboundedSender<InternalSignal>,
-            file_tx: &mpsc::UnboundedSender<ReceivedFile>,
-            turn_servers: &[TurnServer],
-            current_channel: &Arc<RwLock<String>>,
-        ) {
-            let target_role = state.get_peer_role(nick).await;
-
-            if !topology::should_connect_to(state, nickname, our_role, nick, target_role).await {
-                info!("Topology: skipping connection to {} (not our superpeer)", nick);
-                return;
-            }
-
-            if nickname >= nick {
-                return;
-            }
-
-            state.set_peer_connecting(nick).await;
-
-            match WebRtcPeer::new(
-                nick.to_string(), Arc::clone(state), mix_tx.clone(),
-                ice_out_tx.clone(), file_tx.clone(), turn_servers.to_vec(),
-            ).await {
-                Ok(peer) => {
-                    if let Ok(offer) = peer.create_offer().await {
-                        peers.write().await.insert(nick.to_string(), Arc::new(peer));
-                        let _ = irc.send_webrtc_signal(nick, &offer);
-                        let ch = current_channel.read().await.clone();
-                        state.add_message(&ch, format!("-> Calling {}", nick)).await;
-                    }
-                }
-                Err(e) => error!("Peer create: {}", e),
-            }
-        }
-
-        loop {
-            tokio::select! {
-                Some(cmd) = command_rx.recv() => {
-                    match cmd {
-                        CallCommand::SendMessage(text) => {
-                            let ch = current_channel.read().await.clone();
-
-                            let peer_nicks: Vec<String> = state.peer_states.read().await
-                                .values()
-                                .filter(|ps| ps.connected)
-                                .map(|ps| ps.nickname.clone())
-                                .collect();
-                            let ctx = moderation::CommandContext {
-                                nick: nickname.clone(),
-                                channel: ch.clone(),
-                                role: our_role,
-                                peers: peer_nicks,
-                            };
-
-                            if let Some(command) = moderation::parse_command(&text, &custom_commands, &ctx) {
-                                match command {
-                                    moderation::Command::Help => {
-                                        for line in moderation::help_text(our_role, &custom_commands) {
-                                            state.add_message(&ch, line).await;
-                                        }
-                                    }
-                                    moderation::Command::ShowRole => {
-                                        state.add_message(&ch, format!("Your role: {}", our_role.as_str())).await;
-                                    }
-                                    moderation::Command::ListPeers => {
-                                        let peers_list = state.peer_states.read().await;
-                                        if peers_list.is_empty() {
-                                            state.add_message(&ch, "No peers connected".to_string()).await;
-                                        } else {
-                                            state.add_message(&ch, "-- Connected Peers --".to_string()).await;
-                                            for ps in peers_list.values() {
-                                                state.add_message(&ch, format!(
-                                                    "  {} [{}] ({})", ps.nickname, ps.role.as_str(), ps.conn_state
-                                                )).await;
-                                            }
-                                        }
-                                    }
-                                    moderation::Command::ListBanned => {
-                                        if !our_role.can_moderate() {
-                                            state.add_message(&ch, "Only host and mods can view ban list".to_string()).await;
-                                        } else if banned_users.is_empty() {
-                                            state.add_message(&ch, "No banned users".to_string()).await;
-                                        } else {
-                                            state.add_message(&ch, "-- Banned Users --".to_string()).await;
-                                            for nick in &banned_users {
-                                                state.add_message(&ch, format!("  {}", nick)).await;
-                                            }
-                                        }
-                                    }
-                                    moderation::Command::Diag => {
-                                        let d = state.diagnostics.read().await;
-                                        state.add_message(&ch, "-- Connection Diagnostics --".to_string()).await;
-                                        state.add_message(&ch, format!("  Local IP: {}", d.local_ip.as_deref().unwrap_or("unknown"))).await;
-                                        state.add_message(&ch, format!("  External IP: {}", d.external_ip.as_deref().unwrap_or("unknown"))).await;
-                                        state.add_message(&ch, format!("  UPnP: {}", d.upnp_status.as_deref().unwrap_or("not checked"))).await;
-                                        state.add_message(&ch, format!("  TURN servers: {}", d.turn_configured)).await;
-                                        state.add_message(&ch, format!("  Relay: {}", if d.relay_enabled { "enabled" } else { "disabled" })).await;
-                                        if let Some(rp) = d.relay_port {
-                                            state.add_message(&ch, format!("  Relay port: {}", rp)).await;
-                                        }
-                                        state.add_message(&ch, format!("  Port open: {}", match d.port_open {
-                                            Some(true) => "yes",
-                                            Some(false) => "no",
-                                            None => "unknown",
-                                        })).await;
-
-                                        // Show peer connection states
-                                        let peers_list = state.peer_states.read().await;
-                                        if !peers_list.is_empty() {
-                                            state.add_message(&ch, "  Peer connections:".to_string()).await;
-                                            for ps in peers_list.values() {
-                                                state.add_message(&ch, format!("    {} - {}", ps.nickname, ps.conn_state)).await;
-                                            }
-                                        }
-                                    }
-                                    moderation::Command::EditCommands => {
-                                        let path = moderation::CustomCommands::commands_path();
-                                        state.add_message(&ch, format!("Commands file: {}", path.display())).await;
-                                        let opened = std::process::Command::new("xdg-open")
-                                            .arg(&path)
-                                            .spawn()
-                                            .or_else(|_| std::process::Command::new("open").arg(&path).spawn())
-                                            .or_else(|_| std::process::Command::new("notepad").arg(&path).spawn());
-                                        match opened {
-                                            Ok(_) => {
-                                                state.add_message(&ch, "Opened in editor. Use /reload when done.".to_string()).await;
-                                            }
-                                            Err(_) => {
-                                                state.add_message(&ch, "Could not open editor - edit the file manually, then /reload".to_string()).await;
-                                            }
-                                        }
-                                    }
-                                    moderation::Command::Invite => {
-                                        let link = invite_link.read().await;
-                                        if let Some(l) = link.as_ref() {
-                                            state.add_message(&ch, format!("Invite link: {}", l)).await;
-                                        } else {
-                                            state.add_message(&ch, "No invite link available yet (external IP still resolving)".to_string()).await;
-                                        }
-                                    }
-                                    moderation::Command::Reload => {
-                                        custom_commands = moderation::CustomCommands::load();
-                                        state.add_message(&ch, "Custom commands reloaded".to_string()).await;
-                                    }
-                                    moderation::Command::Mod(action) => {
-                                        match moderation::check_permission(our_role, &action) {
-                                            Ok(()) => {
-                                                match &action {
-                                                    moderation::ModAction::Kick(target) => {
-                                                        let _ = irc.send_mod_action(&ch, "kick", target);
-                                                        state.add_message(&ch, format!("Kicked {}", target)).await;
-                                                    }
-                                                    moderation::ModAction::Ban(target) => {
-                                                        let _ = irc.send_mod_action(&ch, "ban", target);
-                                                        state.add_message(&ch, format!("Banned {}", target)).await;
-                                                    }
-                                                    moderation::ModAction::Unban(target) => {
-                                                        state.add_message(&ch, format!("Unbanned {}", target)).await;
-                                                    }
-                                                    moderation::ModAction::Promote(target) => {
-                                                        let _ = irc.send_mod_action(&ch, "promote", target);
-                                                        state.set_peer_role(target, Role::Mod).await;
-                                                        state.add_message(&ch, format!("{} is now a mod (superpeer relay)", target)).await;
-                                                    }
-                                                    moderation::ModAction::Demote(target) => {
-                                                        let _ = irc.send_mod_action(&ch, "demote", target);
-                                                        state.set_peer_role(target, Role::Peer).await;
-                                                        state.add_message(&ch, format!("{} is no longer a mod", target)).await;
-                                                    }
-                                                }
-                                            }
-                                            Err(msg) => {
-                                                state.add_message(&ch, format!("Permission denied: {}", msg)).await;
-                                            }
-                                        }
-                                    }
-                                    moderation::Command::Custom { response, broadcast } => {
-                                        if broadcast {
-                                            if let Err(e) = irc.send_message(&ch, &response) {
-                                                error!("Send: {}", e);
-                                            } else {
-                                                state.add_message(&ch, format!("<{}> {}", nickname, response)).await;
-                                            }
-                                        } else {
-                                            state.add_message(&ch, response).await;
-                                        }
-                                    }
-                                    moderation::Command::Unknown(cmd) => {
-                                        state.add_message(&ch, format!("Unknown command: {}. Type /help for commands.", cmd)).await;
-                                    }
-                                  moderation::Command::SetPow(bits) => {
-                                      if !our_role.can_moderate() {
-                                          state.add_message(&ch, "Only host/mod can change PoW difficulty.".to_string()).await;
-                                      } else {
-                                          let _ = irc.send_pow_set(bits);
-                                          // Confirmation arrives via PowRequirementChanged broadcast from server.
-                                      }
-                                  }
-                                  moderation::Command::MineNick { bits } => {
-                                      // /mine lets you pre-emptively grind a stronger nick while you're
-                                      // already in the room, ready for when you next reconnect or join
-                                      // a room with stricter requirements.
-                                      let base = crate::pow::base_name(&nickname).to_string();
-                                      let pubkey = state.identity.as_ref()
-                                          .map(|id| id.pubkey_hex.clone())
-                                          .unwrap_or_default();
-                                      if pubkey.is_empty() {
-                                          state.add_message(&ch, "No identity key found.".to_string()).await;
-                                      } else {
-                                          let already_ok = crate::pow::check_difficulty(&nickname, &pubkey, bits);
-                                          if already_ok {
-                                              let actual = crate::pow::leading_zero_bits(
-                                                  &crate::pow::nick_hash(&nickname, &pubkey)
-                                              );
-                                              state.add_message(&ch, format!(
-                                                  "Your current nick '{}' already has {} bits — no need to re-mine for {} bits.",
-                                                  nickname, actual, bits
-                                              )).await;
-                                          } else {
-                                              state.add_message(&ch, format!(
-                                                  "Mining '{}' at {} bits (~{})…",
-                                                  base, bits, crate::pow::time_estimate(bits)
-                                              )).await;
-                                              let state_c = Arc::clone(&state);
-                                              let ch_c = ch.clone();
-                                              tokio::task::spawn_blocking(move || {
-                                                  let result = crate::pow::mine_nick(&base, &pubkey, bits, 200_000_000);
-                                                  let handle = tokio::runtime::Handle::current();
-                                                  handle.block_on(async move {
-                                                      match result {
-                                                          Some(m) => state_c.add_message(&ch_c, format!(
-                                                              "✓ New nick ready: '{}' ({} bits, {} attempts). \
-                                                               Go to Settings → Display Name, paste it, then reconnect.",
-                                                              m.nick, m.bits, m.attempts
-                                                          )).await,
-                                                          None => state_c.add_message(&ch_c,
-                                                              "Mining failed (limit reached). Try a lower difficulty.".to_string()
-                                                          ).await,
-                                                      }
-                                                  });
-                                              });
-                                          }
-                                      }
-                                  }
-                                }
-                            } else {
-                                // Sign the message if we have an identity
-                                let send_result = if let Some(ref identity) = state.identity {
-                                    let timestamps = state.message_log.recent_timestamps(&ch).await;
-                                    match crate::persistence::SignedMessage::create(
-                                        identity, &nickname, &ch, &text, &timestamps,
-                                    ) {
-                                        Ok(signed) => {
-                                            let json = serde_json::to_string(&signed)
-                                                .unwrap_or_default();
-                                            state.message_log.append(signed).await.ok();
-                                            irc.send_message(&ch, &format!("SIGNED:{}", json))
-                                        }
-                                        Err(e) => {
-                                            error!("Sign failed, sending plain: {}", e);
-                                            irc.send_message(&ch, &text)
-                                        }
-                                    }
-                                } else {
-                                    irc.send_message(&ch, &text)
-                                };
-
-                                if let Err(e) = send_result {
-                                    error!("Send: {}", e);
-                                } else {
-                                    state.add_message(&ch, format!("<{}> {}", nickname, text)).await;
-                                }
-                            }
-                        }
-                        CallCommand::SwitchChannel(new_ch) => {
-                            let old_ch = current_channel.read().await.clone();
-                            info!("Switching {} -> {}", old_ch, new_ch);
-
-                            let mut pw = peers.write().await;
-                            for (_, p) in pw.drain() {
-                                p.close().await;
-                            }
-                            drop(pw);
-                            state.clear_peers().await;
-
-                            let _ = irc.part_channel(&old_ch);
-                            let _ = irc.join_channel(&new_ch);
-                            *current_channel.write().await = new_ch.clone();
-
-                            state.load_history(&new_ch, 100).await;
-                            let _ = irc.announce_role(&new_ch, our_role);
-
-                            state.add_message(&new_ch, format!("Joined {}", new_ch)).await;
-                        }
-                        CallCommand::CreateChannel(new_ch) => {
-                            let mut ch_list = channels.write().await;
-                            if !ch_list.contains(&new_ch) {
-                                ch_list.push(new_ch.clone());
-                                drop(ch_list);
-
-                                let old_ch = current_channel.read().await.clone();
-                                let mut pw = peers.write().await;
-                                for (_, p) in pw.drain() {
-                                    p.close().await;
-                                }
-                                drop(pw);
-                                state.clear_peers().await;
-
-                                let _ = irc.part_channel(&old_ch);
-                                let _ = irc.join_channel(&new_ch);
-                                *current_channel.write().await = new_ch.clone();
-
-                                let _ = irc.announce_role(&new_ch, our_role);
-
-                                state.add_message(&new_ch, format!("Created and joined {}", new_ch)).await;
-                            }
-                        }
-                        CallCommand::SendFile { name, data } => {
-                            let ch = current_channel.read().await.clone();
-                            let size = data.len();
-                            let r = peers.read().await;
-                            let mut ok = 0usize;
-                            for p in r.values() {
-                                if p.send_file(&name, &data).await.is_ok() {
-                                    ok += 1;
-                                }
-                            }
-                            let kb = size / 1024;
-                            state.add_message(&ch, format!(
-                                "You shared {} ({} KB) -> {} peers", name, kb, ok
-                            )).await;
-                        }
-                        CallCommand::Shutdown => {
-                            info!("Shutdown");
-                            break;
-                        }
-                    }
-                }
-
-                Some(file) = file_rx.recv() => {
-                    let ch = current_channel.read().await.clone();
-                    let size = file.data.len();
-                    let kb = size / 1024;
-
-                    let save_dir = dirs::download_dir().unwrap_or_else(|| std::path::PathBuf::from("."));
-                    let save_path = save_dir.join(&file.name);
-                    match std::fs::write(&save_path, &file.data) {
-                        Ok(_) => {
-                            state.add_received_file(
-                                file.from.clone(), file.name.clone(), size, save_path.clone(),
-                            ).await;
-                            state.add_message(&ch, format!(
-                                "{} shared {} ({} KB)",
-                                file.from, file.name, kb,
-                            )).await;
-                        }
-                        Err(e) => {
-                            state.add_message(&ch, format!(
-                                "{} shared {} ({} KB) - save failed: {}",
-                                file.from, file.name, kb, e
-                            )).await;
-                        }
-                    }
-                }
-
-                // Connection failure - suggest relay
-                Some(nick) = conn_failed_rx.recv() => {
-                    let ch = current_channel.read().await.clone();
-                    if relay_addr.is_some() {
-                        state.add_message(&ch, format!(
-                            "WebRTC to {} failed - audio will route through relay", nick
-                        )).await;
-                        state.set_peer_conn_state(&nick, ConnState::Relayed).await;
-                    } else {
-                        state.add_message(&ch, format!(
-                            "Connection to {} failed. Ask the host to enable relay mode.", nick
-                        )).await;
-                    }
-                }
-
-                Some(nick) = reconnect_rx.recv() => {
-                    info!("Reconnect: {}", nick);
-                    peers.write().await.remove(&nick);
-
-                    if !state.peer_states.read().await.contains_key(&nick) { continue; }
-
-                    let mut attempts = reconnect_attempts.write().await;
-                    let attempt = attempts.entry(nick.clone()).or_insert(0);
-                    *attempt += 1;
-                    let delay = std::cmp::min(2u64.pow(*attempt), 30);
-                    drop(attempts);
-
-                    info!("Reconnect backoff for {}: {}s", nick, delay);
-                    tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
-
-                    if nickname < nick {
-                        let target_role = state.get_peer_role(&nick).await;
-                        if !topology::should_connect_to(&state, &nickname, our_role, &nick, target_role).await {
-                            continue;
-                        }
-
-                        state.set_peer_connecting(&nick).await;
-
-                        match WebRtcPeer::new(
-                            nick.clone(), Arc::clone(&state), mix_tx.clone(),
-                            ice_out_tx.clone(), file_tx.clone(), turn_servers.clone(),
-                        ).await {
-                            Ok(peer) => {
-                                if let Ok(offer) = peer.create_offer().await {
-                                    peers.write().await.insert(nick.clone(), Arc::new(peer));
-                                    let _ = irc.send_webrtc_signal(&nick, &offer);
-                                    let ch = current_channel.read().await.clone();
-                                    state.add_message(&ch, format!("-> Reconnecting to {}", nick)).await;
-                                }
-                            }
-                            Err(e) => error!("Reconnect peer: {}", e),
-                        }
-                    }
-                }
-
-                Some(event) = irc_events.recv() => {
-                    match event {
-                        IrcEvent::UserJoined { nick, role } => {
-                            info!("Joined: {} (role: {:?})", nick, role);
-
-                            if banned_users.contains(&nick) {
-                                warn!("Banned user {} tried to join, ignoring", nick);
-                                let ch = current_channel.read().await.clone();
-                                state.add_message(&ch, format!("Banned user {} tried to join", nick)).await;
-                                continue;
-                            }
-
-                            state.update_peer_state(nick.clone(), false, false).await;
-                            state.set_peer_role(&nick, role).await;
-
-                            reconnect_attempts.write().await.remove(&nick);
-
-                            // Request message sync from the joining peer
-                            {
-                                let ch = current_channel.read().await.clone();
-                                let since = state.message_log
-                                    .messages_since(&ch, 0).await
-                                    .last()
-                                    .map(|m| m.timestamp)
-                                    .unwrap_or(0);
-                                let req = crate::persistence::make_sync_request(&ch, since);
-                                let _ = irc.send_message(&nick, &format!("VOIRC_SYNC_REQ:{}", req));
-                            }
-
-                            maybe_create_peer(
-                                &nick, &nickname, our_role, &state, &peers,
-                                &irc, &mix_tx, &ice_out_tx, &file_tx,
-                                &turn_servers, &current_channel,
-                            ).await;
-                        }
-
-                        IrcEvent::UserLeft(nick) => {
-                            if let Some(p) = peers.write().await.remove(&nick) {
-                                p.close().await;
-                            }
-                            state.remove_peer(&nick).await;
-                            reconnect_attempts.write().await.remove(&nick);
-                        }
-
-                       IrcEvent::PowRequirementChanged { bits } => {
-                           let ch = current_channel.read().await.clone();
-                           if bits == 0 {
-                               state.add_message(&ch,
-                                   "PoW requirement removed — anyone can now register a nick.".to_string()
-                               ).await;
-                           } else {
-                               state.add_message(&ch, format!(
-                                   "⚡ Nick PoW requirement changed to {} bits. \
-                                    Reconnecting users whose nick has < {} bits will need to /mine first (~{}).",
-                                   bits, bits, crate::pow::time_estimate(bits)
-                               )).await;
-                           }
-                       }
-
-                        IrcEvent::WebRtcSignal { from, payload } => {
-                            match serde_json::from_str::<WebRtcSignal>(&payload) {
-                                Ok(WebRtcSignal::Offer { sdp }) => {
-                                    let from_role = state.get_peer_role(&from).await;
-                                    if !topology::should_connect_to(&state, &nickname, our_role, &from, from_role).await {
-                                        info!("Topology: rejecting offer from {}", from);
-                                        continue;
-                                    }
-
-                                    state.set_peer_connecting(&from).await;
-
-                                    match WebRtcPeer::new(
-                                        from.clone(), Arc::clone(&state), mix_tx.clone(),
-                                        ice_out_tx.clone(), file_tx.clone(), turn_servers.clone(),
-                                    ).await {
-                                        Ok(peer) => {
-                                            if let Ok(answer) = peer.handle_offer(sdp).await {
-                                                peers.write().await.insert(from.clone(), Arc::new(peer));
-                                                let _ = irc.send_webrtc_signal(&from, &answer);
-                                                reconnect_attempts.write().await.remove(&from);
-                                            }
-                                        }
-                                        Err(e) => error!("Peer for offer: {}", e),
-                                    }
-                                }
-                                Ok(WebRtcSignal::Answer { sdp }) => {
-                                    if let Some(p) = peers.read().await.get(&from) {
-                                        let _ = p.handle_answer(sdp).await;
-                                        reconnect_attempts.write().await.remove(&from);
-                                    }
-                                }
-                                Ok(WebRtcSignal::IceCandidate { candidate, sdp_mid, sdp_mline_index }) => {
-                                    if let Some(p) = peers.read().await.get(&from) {
-                                        let _ = p.add_ice_candidate(candidate, sdp_mid, sdp_mline_index).await;
-                                    }
-                                }
-                                Err(e) => error!("Parse signal: {}", e),
-                            }
-                        }
-
-                        IrcEvent::ChatMessage { channel, from, text } => {
-                            state.add_message(&channel, format!("<{}> {}", from, text)).await;
-                        }
-
-                        IrcEvent::ModAction { from, action, target } => {
-                            let from_role = state.get_peer_role(&from).await;
-                            if !from_role.can_moderate() {
-                                warn!("Non-mod {} tried to use mod action", from);
-                                continue;
-                            }
-
-                            let ch = current_channel.read().await.clone();
-                            match action.as_str() {
-                                "kick" => {
-                                    if target == nickname {
-                                        state.add_message(&ch, format!("You were kicked by {}", from)).await;
-                                    } else {
-                                        state.add_message(&ch, format!("{} was kicked by {}", target, from)).await;
-                                    }
-                                }
-                                "ban" => {
-                                    if target == nickname {
-                                        state.add_message(&ch, format!("You were banned by {}", from)).await;
-                                    } else {
-                                        state.add_message(&ch, format!("{} was banned by {}", target, from)).await;
-                                    }
-                                }
-                                "promote" => {
-                                    state.set_peer_role(&target, Role::Mod).await;
-                                    if target == nickname {
-                                        state.set_our_role(Role::Mod).await;
-                                        state.add_message(&ch, format!("You were promoted to mod by {}", from)).await;
-                                    } else {
-                                        state.add_message(&ch, format!("{} was promoted to mod by {}", target, from)).await;
-                                    }
-                                }
-                                "demote" => {
-                                    state.set_peer_role(&target, Role::Peer).await;
-                                    if target == nickname {
-                                        state.set_our_role(Role::Peer).await;
-                                        state.add_message(&ch, "You were demoted from mod".to_string()).await;
-                                    } else {
-                                        state.add_message(&ch, format!("{} was demoted from mod", target)).await;
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                        
-                        // FIX: Added handler for PowTooWeak
-                        IrcEvent::PowTooWeak { required_bits } => {
-                            let ch = current_channel.read().await.clone();
-                            state.add_message(&ch, format!(
-                                "❌ Connection Rejected: Your nick's Proof-of-Work is too weak. \
-                                 Server requires {} bits. Please run '/mine {}' to upgrade your nick.",
-                                required_bits, required_bits
-                            )).await;
-                        }
-                    }
-                }
-
-                else => break,
+        let opts = SessionOptions::from_config(&self.config, conn_info, is_host)
+            .with_invite_link(invite_link);
+        match CallSession::start(opts) {
+            Ok(handle) => {
+                self.call_state = Some(handle);
+                self.device_lists = voice_mixer::list_devices();
+                self.screen = Screen::InCall;
+                self.file_status = None;
             }
+            Err(e) => error!("Could not start call: {}", e),
         }
-
-        info!("Event loop exiting");
     }
 
     fn send_message(&mut self) {
         if let Some(cs) = &self.call_state {
             if !self.chat_input.is_empty() {
-                let _ = cs.command_tx.send(CallCommand::SendMessage(self.chat_input.clone()));
+                let channel = cs.current_channel.try_read().map(|c| c.clone()).ok();
+                let reply_to = self.reply_to.take()
+                    .filter(|(ch, _)| Some(ch) == channel.as_ref())
+                    .and_then(|(_, entry)| entry.id);
+                let command = match reply_to {
+                    Some(id) if !self.chat_input.starts_with('/') => CallCommand::SendReply {
+                        reply_to: id,
+                        text: self.chat_input.clone(),
+                    },
+                    _ => CallCommand::SendMessage(self.chat_input.clone()),
+                };
+                let _ = cs.command_tx.send(command);
                 self.chat_input.clear();
             }
         }
@@ -1824,6 +1743,7 @@ fn connect_to_server(&mut self, conn_info: ConnectionInfo, is_host: bool) {
 impl eframe::App for VoircApp {
     fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
         ctx.request_repaint_after(std::time::Duration::from_millis(100));
+        self.poll_hosting();
 
      // Poll for a completed nick mine.
      let mine_result = {
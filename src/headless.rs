@@ -0,0 +1,174 @@
+// Headless dedicated server.
+//
+// Runs the embedded IRCd and the audio relay without a window or sound card,
+// so an always-on room can live on a Linux box.  Started by `voirc serve`.
+// The magic link is the only thing printed to stdout; everything else goes
+// to the log file like the GUI.
+//
+// `HostedRoom` is also what the GUI's Host screen and the TUI's `--host`
+// mode start.
+
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::bans::BanList;
+use crate::config::ServerConfig;
+use crate::irc_server::EmbeddedServer;
+use crate::magic_link::ConnectionInfo;
+use crate::relay::AudioRelay;
+use crate::tls;
+use crate::upnp::PortForwarder;
+
+/// The IRC server and relay tasks for one room, plus the UPnP mappings to
+/// undo when it shuts down.
+pub struct HostedRoom {
+    server: JoinHandle<std::io::Result<()>>,
+    relay: JoinHandle<std::io::Result<()>>,
+    forwarded: Vec<u16>,
+    /// Connection info for joining from this machine.
+    pub local_info: ConnectionInfo,
+    /// Magic link to hand out (public host or external IP).
+    pub public_link: String,
+    /// UPnP was wanted but couldn't forward every port.
+    pub upnp_failed: bool,
+}
+
+impl HostedRoom {
+    pub async fn start(config: &ServerConfig) -> Result<Self> {
+        let port = config.port;
+        let relay_port = config.relay_port();
+        let pow_bits = config.pow_required_bits;
+        let host_pubkey = config.host_pubkey.clone();
+        let bans = BanList::load(&config.ban_file()).shared();
+        let channels = config.channel_list();
+        if channels.is_empty() {
+            anyhow::bail!("Need at least one channel");
+        }
+
+        let cert_info = if config.tls {
+            Some(tls::load_or_generate(&config.cert_dir())?)
+        } else {
+            warn!("TLS disabled in server config, running plaintext IRC");
+            None
+        };
+
+        // Everything that can fail comes before the tasks start, so an error
+        // leaves nothing running and no ports mapped.
+        let host = match &config.public_host {
+            Some(h) => h.clone(),
+            None => PortForwarder::get_external_ip(None).await?,
+        };
+        let fingerprint = cert_info.as_ref().map(|ci| ci.fingerprint.clone());
+        let build = |host: String| {
+            let info = ConnectionInfo::new(host, port, channels.clone())
+                .with_relay(relay_port)
+                .with_pow(pow_bits)
+                .with_codec(config.codec);
+            match &fingerprint {
+                Some(fp) => info.with_tls(fp.clone()),
+                None => info,
+            }
+        };
+        let public_link = build(host).to_magic_link()?;
+        let local_info = build("127.0.0.1".to_string());
+
+        let server_cert = cert_info;
+        let server_bans = Arc::clone(&bans);
+        let server = tokio::spawn(async move {
+            match server_cert {
+                Some(ci) => EmbeddedServer::run_tls(port, &ci, pow_bits, host_pubkey, server_bans).await,
+                None => EmbeddedServer::run(port, pow_bits, host_pubkey, server_bans).await,
+            }
+        });
+        let relay = tokio::spawn(async move { AudioRelay::run(relay_port, bans).await });
+
+        let mut forwarded = Vec::new();
+        let mut upnp_failed = false;
+        if config.upnp {
+            for p in [port, relay_port] {
+                match PortForwarder::forward_port(p, None).await {
+                    Ok(()) => forwarded.push(p),
+                    Err(e) => {
+                        warn!("UPnP failed for port {} (not critical): {}", p, e);
+                        upnp_failed = true;
+                    }
+                }
+            }
+        }
+        info!("Magic link: {}", public_link);
+
+        Ok(Self {
+            server,
+            relay,
+            forwarded,
+            local_info,
+            public_link,
+            upnp_failed,
+        })
+    }
+
+    /// Resolves only if the server or relay task dies.
+    pub async fn wait(&mut self) -> Result<()> {
+        tokio::select! {
+            r = &mut self.server => {
+                error!("IRC server stopped: {:?}", r);
+                Err(anyhow::anyhow!("IRC server stopped unexpectedly"))
+            }
+            r = &mut self.relay => {
+                error!("Relay stopped: {:?}", r);
+                Err(anyhow::anyhow!("Audio relay stopped unexpectedly"))
+            }
+        }
+    }
+
+    pub async fn shutdown(self) {
+        self.server.abort();
+        self.relay.abort();
+        for p in self.forwarded {
+            let _ = PortForwarder::remove_port(p).await;
+        }
+        info!("Server shut down");
+    }
+}
+
+pub async fn serve(config: ServerConfig) -> Result<()> {
+    let mut room = HostedRoom::start(&config).await?;
+    println!("{}", room.public_link);
+
+    let result = tokio::select! {
+        r = room.wait() => r,
+        _ = shutdown_signal() => {
+            info!("Shutdown signal received");
+            Ok(())
+        }
+    };
+
+    room.shutdown().await;
+    result
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where that exists (systemd, docker stop).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Cannot install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -1,32 +1,79 @@
 use anyhow::Result;
-use irc::proto::{Command, Message, Prefix};
+use irc::proto::{Command, Message, Prefix, Response};
 use std::collections::HashMap;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio_rustls::TlsConnector;
-use tracing::{error, info, warn};
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::config::Role;
-use crate::persistence::Identity;
-use crate::state::AppState;
+use crate::config::{Role, VoiceStatus};
+use crate::contacts::{KeyTrust, Sighting};
+use crate::bans::now_ms;
+use crate::e2e;
+use crate::group::KeyShare;
+use crate::irc_server::{self, AUTH_MAX_SKEW_MS, SASL_MECHANISM, SERVER_NAME};
+use crate::moderation::{self, MuteKind};
+use crate::persistence::{Identity, SyncMessage};
+use crate::state::{AppState, ChatEntry, Verification};
 use crate::tls;
 
 pub enum IrcEvent {
     UserJoined { nick: String, role: Role },
     UserLeft(String),
     WebRtcSignal { from: String, payload: String },
-    ChatMessage { channel: String, from: String, text: String },
+    ChatMessage { channel: String, entry: ChatEntry },
+    /// Signed message `id` was edited, retracted or reacted to; `text` is
+    /// how it reads now.
+    MessageUpdated { channel: String, id: String, from: String, text: String },
+    /// An end-to-end encrypted DM that opened under `from`'s verified key.
+    DirectMessage { from: String, text: String },
+    /// An `E2E:` message from `from` that didn't decrypt: not for our key,
+    /// tampered with, sent by someone else, or played back again.
+    DirectMessageFailed { from: String },
+    /// A step of the history sync from a verified peer.
+    Sync { from: String, msg: SyncMessage },
+    /// `nick` showed up with a different key than the one we knew it by.
+    /// `was_verified` if the user had checked the old one.
+    IdentityChanged { nick: String, was_verified: bool },
+    /// A kick or ban the server carried out.
     ModAction { from: String, action: String, target: String },
+    /// The server's role table changed or was reported to us.  `by` is the
+    /// host when this is the result of a grant, `None` for a plain sync.
+    RoleChanged { nick: String, role: Role, by: Option<String> },
+    /// The server refused a moderation command; the reason is its wording.
+    ModRejected(String),
+    /// Text from the server for the user: ban results, the ban list, or why
+    /// it is closing our connection.
+    ServerInfo(String),
+    /// A server mute on `nick`.  `secs` is the time left, `None` until
+    /// lifted; `by` is `None` when the server is just telling a joiner.
+    Muted { nick: String, kind: MuteKind, secs: Option<u64>, by: Option<String> },
+    /// `by` is the server's name when the mute ran out.
+    Unmuted { nick: String, kind: MuteKind, by: String },
+    /// `interval` 0 means slow mode is off.
+    SlowMode { channel: String, interval: u64, secs: Option<u64>, by: Option<String> },
+    /// A channel's topic, on join or when an op changes it.  `None` is no
+    /// topic; `by` is `None` when we're only being told what it is.
+    Topic { channel: String, topic: Option<String>, by: Option<String> },
+    /// An op changed channel modes; `change` is e.g. `+k secret`.
+    ChannelMode { channel: String, change: String, by: String },
+    /// The channel went `+E` (`on`) or back to plain chat.  `by` is `None`
+    /// when we're only being told on join.
+    ChannelEncryption { channel: String, on: bool, by: Option<String> },
+    /// The server refused our JOIN (invite-only, wrong key, full).
+    JoinFailed { channel: String, reason: String },
+    Invited { channel: String, by: String },
     /// Server requires at least `bits` leading zero bits in nick hash.
     /// Fired on initial connect and whenever a mod changes the difficulty.
     PowRequirementChanged { bits: u8 },
-    /// Our VOIRC_HELLO was rejected because our nick's PoW is too weak.
+    /// SASL login was refused because our nick's PoW is too weak.
     /// The UI should prompt the user to re-mine their nick.
     PowTooWeak { required_bits: u8 },
 }
@@ -39,7 +86,7 @@ struct FragmentBuffer {
 
 pub enum MaybeTlsStream {
     Plain(TcpStream),
-    Tls(tokio_rustls::client::TlsStream<TcpStream>),
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
 }
 
 impl AsyncRead for MaybeTlsStream {
@@ -76,7 +123,6 @@ pub struct IrcClient {
     tx: mpsc::UnboundedSender<String>,
     state: Arc<AppState>,
     event_tx: mpsc::UnboundedSender<IrcEvent>,
-    fragments: Mutex<HashMap<String, FragmentBuffer>>,
     nickname: String,
 }
 
@@ -98,14 +144,14 @@ impl IrcClient {
         info!("Connecting to {}...", addr);
         let tcp_stream = TcpStream::connect(addr).await?;
 
-        let stream = if let Some(fp) = cert_fingerprint {
+        let stream = if let Some(ref fp) = cert_fingerprint {
             info!("Upgrading to TLS (Pinned Fingerprint: {}...)", &fp[..8.min(fp.len())]);
-            let config = tls::client_config_pinned(&fp);
+            let config = tls::client_config_pinned(fp);
             let connector = TlsConnector::from(config);
             let domain = rustls::pki_types::ServerName::try_from("voirc.local")
                 .unwrap_or_else(|_| rustls::pki_types::ServerName::try_from("localhost").unwrap());
             let tls_stream = connector.connect(domain, tcp_stream).await?;
-            MaybeTlsStream::Tls(tls_stream)
+            MaybeTlsStream::Tls(Box::new(tls_stream))
         } else {
             MaybeTlsStream::Plain(tcp_stream)
         };
@@ -128,15 +174,24 @@ impl IrcClient {
             tx: out_tx.clone(),
             state,
             event_tx,
-            fragments: Mutex::new(HashMap::new()),
             nickname: nickname.clone(),
         };
 
+        // The `voirc` cap gets us signaling and protocol lines that the
+        // server hides from other IRC clients.  With a key we log in with
+        // SASL first; the handler sends CAP END once that's settled.
+        let identity = client.state.identity.clone();
+        let caps = if identity.is_some() { "voirc sasl" } else { "voirc" };
+        client.send_raw(format!("CAP REQ :{}", caps))?;
         client.send_raw(format!("NICK {}", nickname))?;
         client.send_raw(format!("USER {} 0 * :Voirc User", nickname))?;
+        if identity.is_some() {
+            client.send_raw(format!("AUTHENTICATE {}", SASL_MECHANISM))?;
+        } else {
+            client.send_raw("CAP END".to_string())?;
+        }
 
         let client_clone = Arc::new(client);
-        let identity = client_clone.state.identity.clone();
 
         let handler_ctx = HandlerContext {
             tx: out_tx.clone(),
@@ -144,22 +199,12 @@ impl IrcClient {
             state: client_clone.state.clone(),
             fragments: Arc::new(Mutex::new(HashMap::new())),
             nickname: nickname.clone(),
-            identity: identity.clone(),
+            identity,
+            autojoin: channel,
+            cert_fingerprint,
+            session: Mutex::new(None),
         };
 
-        // Send VOIRC_HELLO after a short delay so the server has processed
-        // NICK/USER.  The server will have already sent VOIRC_POW_REQUIRED in
-        // the welcome NOTICE; the handler below checks it before accepting.
-        if let Some(ref id) = identity {
-            if let Ok(hello) = build_hello_message(&nickname, id) {
-                let tx_hello = out_tx.clone();
-                tokio::spawn(async move {
-                    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-                    let _ = tx_hello.send(hello);
-                });
-            }
-        }
-
         tokio::spawn(async move {
             let mut line = String::new();
             while let Ok(n) = buf_reader.read_line(&mut line).await {
@@ -179,20 +224,11 @@ impl IrcClient {
             drop(done_tx);
         });
 
-        // Join channel after hello is processed
-        let tx_join = out_tx.clone();
-        let ch = channel.clone();
-        tokio::spawn(async move {
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-            let _ = tx_join.send(format!("JOIN {}", ch));
-        });
-
         Ok((
             Arc::try_unwrap(client_clone).unwrap_or_else(|c| IrcClient {
                 tx: c.tx.clone(),
                 state: c.state.clone(),
                 event_tx: c.event_tx.clone(),
-                fragments: Mutex::new(HashMap::new()),
                 nickname: c.nickname.clone(),
             }),
             done_rx,
@@ -209,8 +245,64 @@ impl IrcClient {
         self.send_raw(format!("PRIVMSG {} :VOIRC_ROLE:{}", channel, role.as_str()))
     }
 
-    pub fn send_mod_action(&self, channel: &str, action: &str, target: &str) -> Result<()> {
-        self.send_raw(format!("PRIVMSG {} :VOIRC_MOD:{}:{}", channel, action, target))
+    pub fn announce_voice(&self, channel: &str, status: VoiceStatus) -> Result<()> {
+        self.send_raw(format!("PRIVMSG {} :VOIRC_VOICE:{}", channel, status.to_wire()))
+    }
+
+    pub fn kick(&self, channel: &str, target: &str) -> Result<()> {
+        self.send_raw(format!("KICK {} {} :Kicked by {}", channel, target, self.nickname))
+    }
+
+    /// Ask the server to make `target` a mod (or a peer again).  Signed with
+    /// our identity; the server only honours it from the room's host key.
+    pub fn grant_role(&self, target: &str, role: Role) -> Result<()> {
+        let identity = self.state.identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No identity key to sign with"))?;
+        self.send_raw(build_role_grant(target, role, identity))
+    }
+
+    /// Ban `target` (an online nick or a full pubkey) from the server.
+    /// `None` duration is permanent; `ip` also bans their current address.
+    pub fn ban(&self, target: &str, duration: Option<Duration>, ip: bool, reason: Option<&str>) -> Result<()> {
+        self.send_raw(format!(
+            "PRIVMSG voirc :VOIRC_BAN:{}:{}:{}:{}",
+            target,
+            duration.map_or(0, |d| d.as_secs()),
+            ip as u8,
+            reason.unwrap_or(""),
+        ))
+    }
+
+    /// Lift bans matching a nick, pubkey prefix or IP.
+    pub fn unban(&self, query: &str) -> Result<()> {
+        self.send_raw(format!("PRIVMSG voirc :VOIRC_UNBAN:{}", query))
+    }
+
+    /// The reply comes back as `ServerInfo` lines.
+    pub fn request_ban_list(&self) -> Result<()> {
+        self.send_raw("PRIVMSG voirc :VOIRC_BANLIST".to_string())
+    }
+
+    /// Mute `target` server-wide.  `None` lasts until `unmute`.
+    pub fn mute(&self, target: &str, kind: MuteKind, duration: Option<Duration>) -> Result<()> {
+        self.send_raw(format!(
+            "PRIVMSG voirc :VOIRC_MUTE:{}:{}:{}",
+            kind.as_str(), target, duration.map_or(0, |d| d.as_secs()),
+        ))
+    }
+
+    pub fn unmute(&self, target: &str, kind: MuteKind) -> Result<()> {
+        self.send_raw(format!("PRIVMSG voirc :VOIRC_UNMUTE:{}:{}", kind.as_str(), target))
+    }
+
+    /// `interval` of `None` turns slow mode off.
+    pub fn set_slow_mode(&self, channel: &str, interval: Option<Duration>, duration: Option<Duration>) -> Result<()> {
+        self.send_raw(format!(
+            "PRIVMSG voirc :VOIRC_SLOWMODE:{}:{}:{}",
+            channel,
+            interval.map_or(0, |d| d.as_secs().max(1)),
+            duration.map_or(0, |d| d.as_secs()),
+        ))
     }
 
     /// Set the server-wide PoW difficulty.  Only works if our role permits it;
@@ -220,17 +312,77 @@ impl IrcClient {
     }
 
     pub fn send_webrtc_signal(&self, target: &str, payload: &str) -> Result<()> {
+        self.send_fragmented(target, "WRTC:", payload)
+    }
+
+    /// Encrypt `text` to `target`'s verified key and send it as `E2E:`
+    /// fragments.  The server relays the ciphertext; it can't read or alter
+    /// it.  The key comes from the server, so it has to have been checked
+    /// with `/verify` first.
+    pub async fn send_direct_message(&self, target: &str, text: &str) -> Result<()> {
+        use base64::{engine::general_purpose, Engine as _};
+        let identity = self.state.identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No identity key to encrypt with"))?;
+        let recipient = self.verified_key(target).await?;
+        let sealed = e2e::seal(identity, &recipient, text.as_bytes())?;
+        self.send_fragmented(target, "E2E:", &general_purpose::STANDARD.encode(sealed))
+    }
+
+    /// Hand our sender key for a `+E` channel to `target`, sealed to their
+    /// verified key like a DM, as `E2EKEY:` fragments.
+    pub async fn send_key_share(&self, target: &str, share: &KeyShare) -> Result<()> {
+        use base64::{engine::general_purpose, Engine as _};
+        let identity = self.state.identity.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No identity key to encrypt with"))?;
+        let recipient = self.verified_key(target).await?;
+        let sealed = e2e::seal(identity, &recipient, serde_json::to_string(share)?.as_bytes())?;
+        self.send_fragmented(target, "E2EKEY:", &general_purpose::STANDARD.encode(sealed))
+    }
+
+    /// The key the server announced for `target`, if we've checked it with
+    /// `/verify`: anything we seal to it is only as private as that key is
+    /// really theirs.
+    async fn verified_key(&self, target: &str) -> Result<String> {
+        let key = self.state.pubkey_for_nick(target).await
+            .ok_or_else(|| anyhow::anyhow!("No key announced for {} yet", target))?;
+        match self.state.contacts.read().await.trust(target, &key) {
+            KeyTrust::Verified => Ok(key),
+            KeyTrust::Unverified => anyhow::bail!(
+                "{}'s key isn't verified; compare safety numbers with /verify {} first", target, target
+            ),
+            KeyTrust::Changed => anyhow::bail!(
+                "{}'s key has changed and isn't verified; compare safety numbers with /verify {} first", target, target
+            ),
+        }
+    }
+
+    /// One step of the sync exchange (see `persistence`), fragmented.
+    pub fn send_sync(&self, target: &str, msg: &SyncMessage) -> Result<()> {
+        self.send_fragmented(target, "VOIRC_SYNC:", &serde_json::to_string(msg)?)
+    }
+
+    /// `<prefix>[seq/total|id]chunk` lines, small enough for the server's
+    /// line limit.  Chunks end on char boundaries, and not after a space,
+    /// which would be trimmed off the line on the way.
+    fn send_fragmented(&self, target: &str, prefix: &str, payload: &str) -> Result<()> {
         let chunk_size = 400;
-        let total_len = payload.len();
-        let total_chunks = (total_len + chunk_size - 1) / chunk_size;
+        let mut chunks = Vec::new();
+        let mut rest = payload;
+        while !rest.is_empty() {
+            let mut end = chunk_size.min(rest.len());
+            while end > 1 && (!rest.is_char_boundary(end) || (end < rest.len() && rest.as_bytes()[end - 1] == b' ')) {
+                end -= 1;
+            }
+            let (chunk, tail) = rest.split_at(end);
+            chunks.push(chunk);
+            rest = tail;
+        }
         let msg_id = Uuid::new_v4().to_string()[..8].to_string();
 
-        for (i, chunk) in payload.as_bytes().chunks(chunk_size).enumerate() {
-            let seq = i + 1;
-            let chunk_str = String::from_utf8_lossy(chunk);
+        for (i, chunk) in chunks.iter().enumerate() {
             self.send_raw(format!(
-                "PRIVMSG {} :WRTC:[{}/{}|{}]{}",
-                target, seq, total_chunks, msg_id, chunk_str
+                "PRIVMSG {} :{}[{}/{}|{}]{}",
+                target, prefix, i + 1, chunks.len(), msg_id, chunk
             ))?;
         }
         Ok(())
@@ -244,6 +396,35 @@ impl IrcClient {
         self.send_raw(format!("JOIN {}", channel))
     }
 
+    /// JOIN a channel that is +k.
+    pub fn join_channel_with_key(&self, channel: &str, key: &str) -> Result<()> {
+        self.send_raw(format!("JOIN {} {}", channel, key))
+    }
+
+    /// Empty `topic` clears it.
+    pub fn set_topic(&self, channel: &str, topic: &str) -> Result<()> {
+        self.send_raw(format!("TOPIC {} :{}", channel, topic))
+    }
+
+    /// Ask for the topic; it comes back as a `Topic` event.
+    pub fn request_topic(&self, channel: &str) -> Result<()> {
+        self.send_raw(format!("TOPIC {}", channel))
+    }
+
+    /// `modes` is as typed after /mode, e.g. `+ik secret`; empty queries.
+    pub fn mode(&self, channel: &str, modes: &str) -> Result<()> {
+        self.send_raw(format!("MODE {} {}", channel, modes).trim_end().to_string())
+    }
+
+    pub fn invite_user(&self, nick: &str, channel: &str) -> Result<()> {
+        self.send_raw(format!("INVITE {} {}", nick, channel))
+    }
+
+    /// The reply comes back as `ServerInfo` lines.
+    pub fn list_channels(&self) -> Result<()> {
+        self.send_raw("LIST".to_string())
+    }
+
     pub fn part_channel(&self, channel: &str) -> Result<()> {
         self.send_raw(format!("PART {}", channel))
     }
@@ -255,17 +436,42 @@ impl IrcClient {
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
-// VOIRC_HELLO construction
+// SASL response and VOIRC_HELLO construction
 // ─────────────────────────────────────────────────────────────────────────────
 
-fn build_hello_message(nick: &str, identity: &Identity) -> Result<String> {
+/// Answer the server's `AUTHENTICATE <nonce>` challenge.
+fn build_sasl_response(binding: &str, nick: &str, nonce_b64: &str, identity: &Identity) -> Result<String> {
+    use base64::{engine::general_purpose, Engine as _};
+    use ed25519_dalek::Signer;
+    let nonce = general_purpose::STANDARD.decode(nonce_b64)?;
+    let ts = now_ms();
+    let payload = irc_server::sasl_payload(binding, nick, &identity.pubkey_hex, &nonce, ts);
+    let signature = identity.signing_key.sign(payload.as_bytes());
+    let response = format!("{}:{}:{}", identity.pubkey_hex, ts, hex::encode(signature.to_bytes()));
+    Ok(format!("AUTHENTICATE {}", general_purpose::STANDARD.encode(response)))
+}
+
+/// Tells the people in `channel` which key is behind our nick.  The
+/// signature is bound to this server, its session and the channel.
+fn build_hello_message(binding: &str, session: &str, channel: &str, nick: &str, identity: &Identity) -> String {
     use ed25519_dalek::Signer;
-    let signature = identity.signing_key.sign(nick.as_bytes());
-    let sig_hex = hex::encode(signature.to_bytes());
-    Ok(format!(
-        "PRIVMSG voirc :VOIRC_HELLO:{}:{}:{}",
-        nick, identity.pubkey_hex, sig_hex
-    ))
+    let ts = now_ms();
+    let payload = irc_server::hello_payload(binding, session, channel, nick, &identity.pubkey_hex, ts);
+    let signature = identity.signing_key.sign(payload.as_bytes());
+    format!(
+        "PRIVMSG {} :VOIRC_HELLO:{}:{}:{}:{}",
+        channel, nick, identity.pubkey_hex, ts, hex::encode(signature.to_bytes())
+    )
+}
+
+fn build_role_grant(target: &str, role: Role, identity: &Identity) -> String {
+    use ed25519_dalek::Signer;
+    let ts = now_ms();
+    let signature = identity.signing_key.sign(moderation::role_grant_payload(target, role, ts).as_bytes());
+    format!(
+        "PRIVMSG {} :VOIRC_ROLE_SET:{}:{}:{}:{}",
+        SERVER_NAME, target, role.as_str(), ts, hex::encode(signature.to_bytes())
+    )
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
@@ -279,6 +485,12 @@ struct HandlerContext {
     fragments: Arc<Mutex<HashMap<String, FragmentBuffer>>>,
     nickname: String,
     identity: Option<Identity>,
+    /// Joined once the server welcomes us.
+    autojoin: String,
+    /// The server's pinned cert, which our signatures are bound to.
+    cert_fingerprint: Option<String>,
+    /// The server's `VOIRC_SESSION` nonce, as hex.
+    session: Mutex<Option<String>>,
 }
 
 impl HandlerContext {
@@ -287,6 +499,10 @@ impl HandlerContext {
         Ok(())
     }
 
+    fn binding(&self) -> &str {
+        irc_server::channel_binding(self.cert_fingerprint.as_deref())
+    }
+
     async fn handle_message(&self, message: Message) -> Result<()> {
         {
             let mut frags = self.fragments.lock().unwrap();
@@ -294,11 +510,40 @@ impl HandlerContext {
         }
 
         match message.command {
+            Command::AUTHENTICATE(ref nonce) => {
+                if let Some(ref id) = self.identity {
+                    match build_sasl_response(self.binding(), &self.nickname, nonce, id) {
+                        Ok(response) => { let _ = self.send_raw(response); }
+                        Err(e) => {
+                            warn!("Bad SASL challenge: {}", e);
+                            let _ = self.send_raw("AUTHENTICATE *".to_string());
+                        }
+                    }
+                }
+            }
+            Command::Response(Response::RPL_SASLSUCCESS, _) => {
+                info!("SASL login as {} accepted", self.nickname);
+                let _ = self.send_raw("CAP END".to_string());
+            }
+            Command::Response(
+                Response::ERR_SASLFAIL | Response::ERR_SASLTOOLONG
+                | Response::ERR_SASLABORT | Response::ERR_SASLALREADY,
+                ref args,
+            ) => {
+                if let Some(text) = args.last() {
+                    self.handle_sasl_failure(text);
+                }
+                let _ = self.send_raw("CAP END".to_string());
+            }
+            Command::Response(Response::RPL_WELCOME, _) => {
+                let _ = self.send_raw(format!("JOIN {}", self.autojoin));
+            }
             Command::NOTICE(ref _target, ref text) => {
-                // Parse server NOTICEs that carry structured data.
-                self.handle_notice(text).await?;
+                if let Some(Prefix::Nickname(ref nick, _, _)) = message.prefix {
+                    self.handle_notice(nick, text).await?;
+                }
             }
-            Command::JOIN(ref _channel, _, _) => {
+            Command::JOIN(ref channel, _, _) => {
                 if let Some(Prefix::Nickname(ref nick, _, _)) = message.prefix {
                     if nick != &self.nickname {
                         let _ = self.event_tx.send(IrcEvent::UserJoined {
@@ -306,18 +551,17 @@ impl HandlerContext {
                             role: Role::Peer,
                         });
                         // Re-announce our hello so the new peer learns our pubkey
-                        if let Some(ref id) = self.identity {
-                            if let Ok(hello) = build_hello_message(&self.nickname, id) {
-                                let _ = self.tx.send(hello);
-                            }
+                        let session = self.session.lock().unwrap().clone();
+                        if let (Some(id), Some(session)) = (&self.identity, session) {
+                            let _ = self.tx.send(build_hello_message(self.binding(), &session, channel, &self.nickname, id));
                         }
                     }
                 }
             }
-            Command::Response(irc::proto::Response::RPL_NAMREPLY, ref args) => {
+            Command::Response(Response::RPL_NAMREPLY, ref args) => {
                 if let Some(names) = args.last() {
                     for name in names.split_whitespace() {
-                        let clean_name = name.trim_start_matches(|c| c == '@' || c == '+');
+                        let clean_name = name.trim_start_matches(['@', '+']);
                         if clean_name != self.nickname && !clean_name.is_empty() {
                             let _ = self.event_tx.send(IrcEvent::UserJoined {
                                 nick: clean_name.to_string(),
@@ -327,8 +571,101 @@ impl HandlerContext {
                     }
                 }
             }
+            Command::KICK(_, ref target, ref comment) => {
+                // The server only relays kicks it allowed.  Bans show up as
+                // kicks with a "Banned" comment.
+                if let Some(Prefix::Nickname(ref nick, _, _)) = message.prefix {
+                    let banned = comment.as_deref().is_some_and(|c| c.starts_with("Banned"));
+                    let _ = self.event_tx.send(IrcEvent::ModAction {
+                        from: nick.clone(),
+                        action: if banned { "ban" } else { "kick" }.to_string(),
+                        target: target.clone(),
+                    });
+                }
+                if target != &self.nickname {
+                    let _ = self.event_tx.send(IrcEvent::UserLeft(target.clone()));
+                    self.state.remove_peer(target).await;
+                }
+            }
+            Command::Response(Response::ERR_CHANOPRIVSNEEDED, ref args) => {
+                if let Some(reason) = args.last() {
+                    let _ = self.event_tx.send(IrcEvent::ModRejected(reason.clone()));
+                }
+            }
+            Command::TOPIC(ref channel, ref topic) => {
+                if let Some(Prefix::Nickname(ref nick, _, _)) = message.prefix {
+                    let _ = self.event_tx.send(IrcEvent::Topic {
+                        channel: channel.clone(),
+                        topic: topic.clone().filter(|t| !t.is_empty()),
+                        by: Some(nick.clone()),
+                    });
+                }
+            }
+            Command::Response(Response::RPL_TOPIC, ref args) if args.len() >= 3 => {
+                let _ = self.event_tx.send(IrcEvent::Topic {
+                    channel: args[1].clone(),
+                    topic: Some(args[2].clone()),
+                    by: None,
+                });
+            }
+            Command::Response(Response::RPL_NOTOPIC, ref args) if args.len() >= 2 => {
+                let _ = self.event_tx.send(IrcEvent::Topic { channel: args[1].clone(), topic: None, by: None });
+            }
+            Command::ChannelMODE(ref channel, ref modes) => {
+                if let Some(Prefix::Nickname(ref nick, _, _)) = message.prefix {
+                    let change = modes.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(" ");
+                    let _ = self.event_tx.send(IrcEvent::ChannelMode {
+                        channel: channel.clone(),
+                        change,
+                        by: nick.clone(),
+                    });
+                }
+            }
+            Command::INVITE(_, ref channel) => {
+                if let Some(Prefix::Nickname(ref nick, _, _)) = message.prefix {
+                    let _ = self.event_tx.send(IrcEvent::Invited { channel: channel.clone(), by: nick.clone() });
+                }
+            }
+            Command::Response(
+                Response::ERR_INVITEONLYCHAN | Response::ERR_BADCHANNELKEY
+                | Response::ERR_CHANNELISFULL | Response::ERR_NOSUCHCHANNEL,
+                ref args,
+            ) if args.len() >= 3 => {
+                let _ = self.event_tx.send(IrcEvent::JoinFailed {
+                    channel: args[1].clone(),
+                    reason: args[2].clone(),
+                });
+            }
+            Command::Response(Response::RPL_CHANNELMODEIS, ref args) if args.len() >= 3 => {
+                let info = format!("{} modes: {}", args[1], args[2..].join(" "));
+                let _ = self.event_tx.send(IrcEvent::ServerInfo(info));
+            }
+            Command::Response(Response::RPL_INVITING, ref args) if args.len() >= 3 => {
+                let info = format!("Invited {} to {}", args[1], args[2]);
+                let _ = self.event_tx.send(IrcEvent::ServerInfo(info));
+            }
+            Command::Response(Response::RPL_LIST, ref args) if args.len() >= 4 => {
+                let info = format!("{} ({}) {}", args[1], args[2], args[3]);
+                let _ = self.event_tx.send(IrcEvent::ServerInfo(info.trim_end().to_string()));
+            }
+            Command::Response(
+                Response::ERR_CANNOTSENDTOCHAN | Response::ERR_NOSUCHNICK | Response::ERR_NOTONCHANNEL
+                | Response::ERR_USERONCHANNEL | Response::ERR_USERNOTINCHANNEL | Response::ERR_UNKNOWNMODE,
+                ref args,
+            ) if args.len() >= 2 => {
+                // "<nick> <subject...> :<reason>"; drop our own nick.
+                let _ = self.event_tx.send(IrcEvent::ServerInfo(args[1..].join(" ")));
+            }
+            Command::ERROR(ref reason) => {
+                warn!("Server closed the connection: {}", reason);
+                let _ = self.event_tx.send(IrcEvent::ServerInfo(reason.clone()));
+            }
             Command::PART(_, _) | Command::QUIT(_) => {
+                // The server echoes our own PART back to us.
                 if let Some(Prefix::Nickname(ref nick, _, _)) = message.prefix {
+                    if nick == &self.nickname {
+                        return Ok(());
+                    }
                     let _ = self.event_tx.send(IrcEvent::UserLeft(nick.clone()));
                     self.state.remove_peer(nick).await;
                 }
@@ -354,7 +691,16 @@ impl HandlerContext {
         Ok(())
     }
 
-    async fn handle_notice(&self, text: &str) -> Result<()> {
+    /// Server NOTICEs that carry structured data.  The server relays
+    /// users' NOTICEs too, so anything else is left alone.
+    async fn handle_notice(&self, from: &str, text: &str) -> Result<()> {
+        if from != SERVER_NAME {
+            if text.starts_with("VOIRC_") {
+                warn!("Ignoring {} from {}; only the server sends those", text.split(':').next().unwrap_or(text), from);
+            }
+            return Ok(());
+        }
+
         // VOIRC_POW_REQUIRED:<bits>  — server tells us the current requirement on welcome
         if let Some(rest) = text.strip_prefix("VOIRC_POW_REQUIRED:") {
             if let Ok(bits) = rest.trim().parse::<u8>() {
@@ -364,44 +710,64 @@ impl HandlerContext {
             return Ok(());
         }
 
-        // HELLO_OK pow_bits:<N>  — our HELLO was accepted
-        if text.starts_with("HELLO_OK") {
-            info!("VOIRC_HELLO accepted: {}", text);
+        // The server refused a moderation command of ours
+        for prefix in ["VOIRC_ROLE_SET_FAILED ", "VOIRC_POW_SET_FAILED ", "VOIRC_DENIED "] {
+            if let Some(reason) = text.strip_prefix(prefix) {
+                warn!("Server refused: {}", text);
+                let _ = self.event_tx.send(IrcEvent::ModRejected(reason.to_string()));
+                return Ok(());
+            }
+        }
+
+        if let Some(nonce) = text.strip_prefix("VOIRC_SESSION:") {
+            *self.session.lock().unwrap() = Some(nonce.trim().to_string());
             return Ok(());
         }
 
-        // HELLO_FAILED <reason>  — our HELLO was rejected
-        if let Some(reason) = text.strip_prefix("HELLO_FAILED ") {
-            if let Some(bits_str) = reason.strip_prefix("pow_too_weak:") {
-                if let Ok(required_bits) = bits_str.trim().parse::<u8>() {
-                    warn!(
-                        "Nick '{}' PoW too weak — server requires {} bits",
-                        self.nickname, required_bits
-                    );
-                    let _ = self.event_tx.send(IrcEvent::PowTooWeak { required_bits });
-                }
-            } else {
-                warn!("VOIRC_HELLO rejected: {}", reason);
-            }
+        if let Some(info) = text.strip_prefix("VOIRC_INFO ") {
+            let _ = self.event_tx.send(IrcEvent::ServerInfo(info.to_string()));
             return Ok(());
         }
 
         Ok(())
     }
 
+    /// 904 and friends: `SASL authentication failed: <reason>`.  We carry
+    /// on as a guest unless the server hangs up on us.
+    fn handle_sasl_failure(&self, text: &str) {
+        let reason = text.strip_prefix("SASL authentication failed: ").unwrap_or(text);
+        if let Some(bits_str) = reason.strip_prefix("pow_too_weak:") {
+            if let Ok(required_bits) = bits_str.trim().parse::<u8>() {
+                warn!(
+                    "Nick '{}' PoW too weak — server requires {} bits",
+                    self.nickname, required_bits
+                );
+                let _ = self.event_tx.send(IrcEvent::PowTooWeak { required_bits });
+            }
+        } else if let Some(why) = reason.strip_prefix("banned:") {
+            warn!("SASL login rejected: banned ({})", why);
+            let text = if why.is_empty() {
+                "You are banned from this server".to_string()
+            } else {
+                format!("You are banned from this server: {}", why)
+            };
+            let _ = self.event_tx.send(IrcEvent::ServerInfo(text));
+        } else {
+            warn!("SASL login rejected: {}", reason);
+        }
+    }
+
     async fn handle_privmsg(&self, nick: &str, target: &str, text: &str) -> Result<()> {
-        // VOIRC_PUBKEY broadcast from server
+        // VOIRC_PUBKEY broadcast from server.  Peers could name any nick
+        // here, so it only counts coming from the server's key table.
         if let Some(rest) = text.strip_prefix("VOIRC_PUBKEY:") {
+            if nick != SERVER_NAME {
+                warn!("Dropping VOIRC_PUBKEY from peer {}; keys come from the server or a signed hello", nick);
+                return Ok(());
+            }
             let parts: Vec<&str> = rest.splitn(2, ':').collect();
             if parts.len() == 2 {
-                let peer_nick = parts[0];
-                let pubkey_hex = parts[1];
-                // FIX: Added .to_string() to both arguments
-                if self.state.register_peer_pubkey(peer_nick.to_string(), pubkey_hex.to_string()).await {
-                    info!("Registered pubkey for {}: {}...", peer_nick, &pubkey_hex[..8.min(pubkey_hex.len())]);
-                } else {
-                    warn!("Pubkey conflict for {}", peer_nick);
-                }
+                self.learn_pubkey(parts[0], parts[1], target).await;
             }
             return Ok(());
         }
@@ -414,12 +780,20 @@ impl HandlerContext {
             return Ok(());
         }
 
-        // Peer-to-peer VOIRC_HELLO relay
+        // Peer-to-peer VOIRC_HELLO relay: VOIRC_HELLO:<nick>:<pubkey>:<ts>:<sig>,
+        // only for the sender's own nick and fresh.
         if let Some(rest) = text.strip_prefix("VOIRC_HELLO:") {
-            let parts: Vec<&str> = rest.splitn(3, ':').collect();
-            if parts.len() == 3 && verify_hello(parts[0], parts[1], parts[2]) {
-                // FIX: Added .to_string() to both arguments
-                let _ = self.state.register_peer_pubkey(parts[0].to_string(), parts[1].to_string()).await;
+            let parts: Vec<&str> = rest.splitn(4, ':').collect();
+            let Some(session) = self.session.lock().unwrap().clone() else { return Ok(()) };
+            if let [hello_nick, pubkey_hex, ts, sig_hex] = parts[..] {
+                let ts = ts.parse::<u64>().unwrap_or(0);
+                let payload = irc_server::hello_payload(self.binding(), &session, target, hello_nick, pubkey_hex, ts);
+                let fresh = ts.abs_diff(now_ms()) <= AUTH_MAX_SKEW_MS;
+                if hello_nick == nick && fresh && verify_hello(&payload, pubkey_hex, sig_hex) {
+                    self.learn_pubkey(hello_nick, pubkey_hex, target).await;
+                } else {
+                    warn!("Dropping stale or forged VOIRC_HELLO from {}", nick);
+                }
             }
             return Ok(());
         }
@@ -430,121 +804,283 @@ impl HandlerContext {
                 warn!("Dropping WRTC from unverified peer {}", nick);
                 return Ok(());
             }
-            self.handle_fragment(nick, text)?;
+            if let Some(payload) = self.handle_fragment(nick, "WRTC:", text) {
+                let _ = self.event_tx.send(IrcEvent::WebRtcSignal {
+                    from: nick.to_string(),
+                    payload,
+                });
+            }
             return Ok(());
         }
 
-        if let Some(role_str) = text.strip_prefix("VOIRC_ROLE:") {
-            if !self.is_verified(nick).await {
-                warn!("Dropping VOIRC_ROLE from unverified peer {}", nick);
+        // Encrypted DMs and channel key shares: only from a nick whose key
+        // we hold, since that key is what proves who wrote them.
+        if let Some(prefix) = ["E2E:", "E2EKEY:"].into_iter().find(|p| text.starts_with(p)) {
+            let Some(pubkey) = self.state.pubkey_for_nick(nick).await else {
+                warn!("Dropping {} from unverified peer {}", prefix, nick);
                 return Ok(());
+            };
+            if let Some(payload) = self.handle_fragment(nick, prefix, text) {
+                match (prefix, self.open_sealed(&pubkey, &payload).await) {
+                    ("E2E:", Ok(plain)) => {
+                        let _ = self.event_tx.send(IrcEvent::DirectMessage {
+                            from: nick.to_string(),
+                            text: String::from_utf8_lossy(&plain).into_owned(),
+                        });
+                    }
+                    (_, Ok(plain)) => {
+                        let accepted = match serde_json::from_slice::<KeyShare>(&plain) {
+                            Ok(share) => self.state.group_keys.write().await.accept(&pubkey, &share).map(|_| share),
+                            Err(e) => Err(e.into()),
+                        };
+                        match accepted {
+                            Ok(share) => info!("Got {}'s key {} for {}", nick, share.id, share.channel),
+                            Err(e) => warn!("Bad key share from {}: {}", nick, e),
+                        }
+                    }
+                    ("E2E:", Err(e)) => {
+                        warn!("Could not open DM from {}: {}", nick, e);
+                        let _ = self.event_tx.send(IrcEvent::DirectMessageFailed { from: nick.to_string() });
+                    }
+                    (_, Err(e)) => warn!("Could not open key share from {}: {}", nick, e),
+                }
             }
-            let role = Role::from_str(role_str.trim());
-            self.state.set_peer_role(nick, role).await;
             return Ok(());
         }
 
-        if let Some(rest) = text.strip_prefix("VOIRC_MOD:") {
-            if !self.is_verified(nick).await {
-                warn!("Dropping VOIRC_MOD from unverified peer {}", nick);
+        // Roles come only from the server's table: VOIRC_ROLE:<nick>:<role>[:<by>]
+        if let Some(rest) = text.strip_prefix("VOIRC_ROLE:") {
+            if nick != SERVER_NAME {
+                warn!("Dropping VOIRC_ROLE from peer {}; roles come from the server", nick);
                 return Ok(());
             }
-            if let Some((action, target_nick)) = rest.split_once(':') {
-                let _ = self.event_tx.send(IrcEvent::ModAction {
-                    from: nick.to_string(),
-                    action: action.to_string(),
-                    target: target_nick.to_string(),
+            let mut parts = rest.splitn(3, ':');
+            if let (Some(who), Some(role)) = (parts.next(), parts.next()) {
+                let _ = self.event_tx.send(IrcEvent::RoleChanged {
+                    nick: who.to_string(),
+                    role: Role::from_str(role.trim()),
+                    by: parts.next().map(|b| b.trim().to_string()),
                 });
             }
             return Ok(());
         }
 
-        if let Some(rest) = text.strip_prefix("VOIRC_SYNC_REQ:") {
-            if !self.is_verified(nick).await {
-                warn!("Dropping VOIRC_SYNC_REQ from unverified peer {}", nick);
+        // Mutes and slow mode, likewise only from the server.
+        for prefix in ["VOIRC_MUTE:", "VOIRC_UNMUTE:", "VOIRC_SLOWMODE:", "VOIRC_E2E:"] {
+            if text.starts_with(prefix) && nick != SERVER_NAME {
+                warn!("Dropping {} from peer {}", prefix, nick);
                 return Ok(());
             }
-            if let Ok(req) = serde_json::from_str::<crate::persistence::SyncRequest>(rest) {
-                let messages = self.state.message_log.messages_since(&req.channel, req.since).await;
-                let resp = crate::persistence::SyncResponse { channel: req.channel, messages };
-                if let Ok(json) = serde_json::to_string(&resp) {
-                    let _ = self.tx.send(format!("PRIVMSG {} :VOIRC_SYNC_RESP:{}\r\n", target, json));
+        }
+        if let Some(rest) = text.strip_prefix("VOIRC_MUTE:") {
+            let parts: Vec<&str> = rest.splitn(4, ':').collect();
+            if let [kind, who, secs, ..] = parts[..] {
+                if let (Some(kind), Ok(secs)) = (MuteKind::from_wire(kind), secs.trim().parse::<u64>()) {
+                    let _ = self.event_tx.send(IrcEvent::Muted {
+                        nick: who.to_string(),
+                        kind,
+                        secs: (secs > 0).then_some(secs),
+                        by: parts.get(3).map(|b| b.trim().to_string()),
+                    });
+                }
+            }
+            return Ok(());
+        }
+        if let Some(rest) = text.strip_prefix("VOIRC_UNMUTE:") {
+            let parts: Vec<&str> = rest.splitn(3, ':').collect();
+            if let [kind, who, by] = parts[..] {
+                if let Some(kind) = MuteKind::from_wire(kind) {
+                    let _ = self.event_tx.send(IrcEvent::Unmuted {
+                        nick: who.to_string(),
+                        kind,
+                        by: by.trim().to_string(),
+                    });
+                }
+            }
+            return Ok(());
+        }
+        if let Some(rest) = text.strip_prefix("VOIRC_SLOWMODE:") {
+            let parts: Vec<&str> = rest.splitn(3, ':').collect();
+            if let [interval, secs, ..] = parts[..] {
+                if let (Ok(interval), Ok(secs)) = (interval.parse::<u64>(), secs.trim().parse::<u64>()) {
+                    let _ = self.event_tx.send(IrcEvent::SlowMode {
+                        channel: target.to_string(),
+                        interval,
+                        secs: (secs > 0).then_some(secs),
+                        by: parts.get(2).map(|b| b.trim().to_string()),
+                    });
                 }
             }
             return Ok(());
         }
 
-        if let Some(rest) = text.strip_prefix("VOIRC_SYNC_RESP:") {
+        if let Some(rest) = text.strip_prefix("VOIRC_E2E:") {
+            let (on, by) = rest.split_once(':').map_or((rest, None), |(on, by)| (on, Some(by.trim().to_string())));
+            let _ = self.event_tx.send(IrcEvent::ChannelEncryption {
+                channel: target.to_string(),
+                on: on.trim() == "1",
+                by,
+            });
+            return Ok(());
+        }
+
+        if let Some(flags) = text.strip_prefix("VOIRC_VOICE:") {
             if !self.is_verified(nick).await {
-                warn!("Dropping VOIRC_SYNC_RESP from unverified peer {}", nick);
+                warn!("Dropping VOIRC_VOICE from unverified peer {}", nick);
                 return Ok(());
             }
-            if let Ok(resp) = serde_json::from_str::<crate::persistence::SyncResponse>(rest) {
-                let trusted = self.state.trusted_keys_snapshot().await;
-                let (accepted, rejected) = crate::persistence::process_sync_response(
-                    &self.state.message_log, resp, &trusted,
-                ).await;
-                info!("Sync: {} accepted, {} rejected", accepted, rejected);
+            self.state.set_peer_voice(nick, VoiceStatus::from_wire(flags)).await;
+            return Ok(());
+        }
+
+        if text.starts_with("VOIRC_SYNC:") {
+            if !self.is_verified(nick).await {
+                warn!("Dropping VOIRC_SYNC from unverified peer {}", nick);
+                return Ok(());
+            }
+            if let Some(payload) = self.handle_fragment(nick, "VOIRC_SYNC:", text) {
+                match serde_json::from_str::<SyncMessage>(&payload) {
+                    Ok(msg) => {
+                        let _ = self.event_tx.send(IrcEvent::Sync { from: nick.to_string(), msg });
+                    }
+                    Err(e) => warn!("Bad VOIRC_SYNC from {}: {}", nick, e),
+                }
             }
             return Ok(());
         }
 
         if let Some(rest) = text.strip_prefix("SIGNED:") {
             if let Ok(msg) = serde_json::from_str::<crate::persistence::SignedMessage>(rest) {
-                if let Some(kp) = self.state.pubkey_for_nick(nick).await {
-                    if kp != msg.pubkey {
-                        warn!("Dropping SIGNED from {}: pubkey mismatch", nick);
-                        return Ok(());
-                    }
+                // Signed for one channel, it goes in that channel's history
+                // only; don't let it ride in through another.
+                if msg.channel != target {
+                    warn!("Dropping SIGNED from {}: signed for {}, sent to {}", nick, msg.channel, target);
+                    return Ok(());
                 }
-                let result = msg.verify(None);
+                let bound = self.state.pubkey_for_nick(nick).await;
+                if bound.as_ref().is_some_and(|kp| *kp != msg.pubkey) {
+                    warn!("Dropping SIGNED from {}: pubkey mismatch", nick);
+                    return Ok(());
+                }
+                let result = msg.verify();
                 if result == crate::persistence::VerifyResult::InvalidSignature {
                     warn!("Dropping SIGNED with invalid sig from {}", nick);
                     return Ok(());
                 }
-                let display = if result.is_suspicious() {
-                    format!("[?] {}", msg.content)
-                } else {
-                    msg.content.clone()
+                // Without a key for the nick, a valid signature says nothing
+                // about who wrote it: show the text, but keep it out of the
+                // history.
+                if bound.is_none() {
+                    if msg.event.is_some() {
+                        warn!("Dropping signed event from {}: no key for that nick", nick);
+                        return Ok(());
+                    }
+                    let line = format!("<{}> {}", nick, self.state.open_content(&msg).await);
+                    let _ = self.event_tx.send(IrcEvent::ChatMessage {
+                        channel: target.to_string(),
+                        entry: ChatEntry::from_line(line),
+                    });
+                    return Ok(());
+                }
+                if let Some(event) = msg.event.clone() {
+                    let log = &self.state.message_log;
+                    let original = log.message(&msg.channel, event.target()).await;
+                    let moderators = self.state.moderator_keys().await;
+                    if msg.verify_event(original.as_ref(), &moderators) == crate::persistence::VerifyResult::Unauthorized {
+                        warn!("Dropping {:?} from {}: not theirs to make", event, nick);
+                        return Ok(());
+                    }
+                    let channel = msg.channel.clone();
+                    log.append(msg).await.ok();
+                    if let Some((from, text)) = self.state.render_signed(&channel, event.target()).await {
+                        let _ = self.event_tx.send(IrcEvent::MessageUpdated {
+                            channel, id: event.target().to_string(), from, text,
+                        });
+                    }
+                    return Ok(());
+                }
+                let mut entry = ChatEntry {
+                    id: Some(msg.id.clone()),
+                    author: Some(nick.to_string()),
+                    text: self.state.open_content(&msg).await,
+                    timestamp: msg.timestamp,
+                    status: Verification::Verified,
+                    reply_to: msg.reply_to.clone(),
                 };
-                self.state.message_log.append(msg).await.ok();
-                let _ = self.event_tx.send(IrcEvent::ChatMessage {
-                    channel: target.to_string(),
-                    from: nick.to_string(),
-                    text: display,
-                });
+                let check = self.state.message_log.append(msg).await.unwrap_or_default();
+                if result.is_suspicious() || check.is_suspicious() {
+                    entry.status = Verification::Suspicious;
+                }
+                let _ = self.event_tx.send(IrcEvent::ChatMessage { channel: target.to_string(), entry });
             }
             return Ok(());
         }
 
+        // Signaling we don't speak; it isn't chat either.
+        if text.starts_with("VOIRC_") {
+            warn!("Dropping unknown {} from {}", text.split(':').next().unwrap_or(text), nick);
+            return Ok(());
+        }
+
         let _ = self.event_tx.send(IrcEvent::ChatMessage {
             channel: target.to_string(),
-            from: nick.to_string(),
-            text: text.to_string(),
+            entry: ChatEntry::from_line(format!("<{}> {}", nick, text)),
         });
         Ok(())
     }
 
+    /// Record `nick`'s key, vouched for by the server or a signed hello in
+    /// `target`, and speak up if the nick used to have another one.  A
+    /// channel there also proves they're a member of it, which history sync
+    /// goes by.
+    async fn learn_pubkey(&self, nick: &str, pubkey_hex: &str, target: &str) {
+        let sighting = self.state.register_peer_pubkey(nick.to_string(), pubkey_hex.to_string()).await;
+        if sighting.is_some() && target.starts_with('#') {
+            self.state.note_channel_member(target, pubkey_hex).await;
+        }
+        match sighting {
+            Some(Sighting::KeyChanged { previous, was_verified }) => {
+                warn!("{} now has key {}..., was {}...", nick, &pubkey_hex[..8.min(pubkey_hex.len())], &previous[..8.min(previous.len())]);
+                let _ = self.event_tx.send(IrcEvent::IdentityChanged { nick: nick.to_string(), was_verified });
+            }
+            Some(Sighting::New) => info!("Registered pubkey for {}: {}...", nick, &pubkey_hex[..8.min(pubkey_hex.len())]),
+            Some(Sighting::Known) => {}
+            None => warn!("Pubkey conflict for {}", nick),
+        }
+    }
+
     async fn is_verified(&self, nick: &str) -> bool {
         self.state.pubkey_for_nick(nick).await.is_some()
     }
 
-    fn handle_fragment(&self, sender: &str, text: &str) -> Result<()> {
-        let content = text.strip_prefix("WRTC:").unwrap_or("");
+    /// Open an `E2E:` / `E2EKEY:` payload `pubkey` sealed to us, unless
+    /// we have had it before.
+    async fn open_sealed(&self, pubkey: &str, payload: &str) -> Result<Vec<u8>> {
+        use base64::{engine::general_purpose, Engine as _};
+        let identity = self.identity.as_ref().ok_or_else(|| anyhow::anyhow!("No identity key"))?;
+        let opened = e2e::open(identity, pubkey, &general_purpose::STANDARD.decode(payload)?)?;
+        self.state.sealed_seen.write().await.check(&opened, now_ms())?;
+        Ok(opened.body)
+    }
+
+    /// Collect one `<prefix>[seq/total|id]` fragment; returns the whole
+    /// payload once the last one is in.
+    fn handle_fragment(&self, sender: &str, prefix: &str, text: &str) -> Option<String> {
+        let content = text.strip_prefix(prefix).unwrap_or("");
         let end_bracket = content.find(']').unwrap_or(0);
-        if end_bracket == 0 || !content.starts_with('[') { return Ok(()); }
+        if end_bracket == 0 || !content.starts_with('[') { return None; }
 
         let header = &content[1..end_bracket];
         let payload = &content[end_bracket + 1..];
         let parts: Vec<&str> = header.split('|').collect();
-        if parts.len() != 2 { return Ok(()); }
+        if parts.len() != 2 { return None; }
 
         let counts: Vec<&str> = parts[0].split('/').collect();
         let msg_id = parts[1];
         let seq: usize = counts[0].parse().unwrap_or(0);
         let total: usize = counts.get(1).and_then(|s| s.parse().ok()).unwrap_or(0);
 
-        let key = format!("{}:{}", sender, msg_id);
+        let key = format!("{}:{}{}", sender, prefix, msg_id);
         let mut fragments = self.fragments.lock().unwrap();
         let entry = fragments.entry(key.clone()).or_insert(FragmentBuffer {
             parts: HashMap::new(),
@@ -560,12 +1096,9 @@ impl HandlerContext {
                 if let Some(p) = entry.parts.get(&i) { full.push_str(p); }
             }
             fragments.remove(&key);
-            let _ = self.event_tx.send(IrcEvent::WebRtcSignal {
-                from: sender.to_string(),
-                payload: full,
-            });
+            return Some(full);
         }
-        Ok(())
+        None
     }
 }
 
@@ -573,7 +1106,8 @@ impl HandlerContext {
 // Standalone HELLO signature verifier
 // ─────────────────────────────────────────────────────────────────────────────
 
-fn verify_hello(nick: &str, pubkey_hex: &str, sig_hex: &str) -> bool {
+/// `payload` is the `hello_payload` the receiver expects for this hello.
+fn verify_hello(payload: &str, pubkey_hex: &str, sig_hex: &str) -> bool {
     use ed25519_dalek::{Signature, Verifier, VerifyingKey};
     let pubkey_bytes = match hex::decode(pubkey_hex) {
         Ok(b) if b.len() == 32 => b, _ => return false,
@@ -584,5 +1118,103 @@ fn verify_hello(nick: &str, pubkey_hex: &str, sig_hex: &str) -> bool {
     let pk_arr: [u8; 32] = match pubkey_bytes.try_into() { Ok(a) => a, Err(_) => return false };
     let verifying_key = match VerifyingKey::from_bytes(&pk_arr) { Ok(k) => k, Err(_) => return false };
     let sig_arr: [u8; 64] = match sig_bytes.try_into() { Ok(a) => a, Err(_) => return false };
-    verifying_key.verify(nick.as_bytes(), &Signature::from_bytes(&sig_arr)).is_ok()
+    verifying_key.verify(payload.as_bytes(), &Signature::from_bytes(&sig_arr)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn handler(dir: &TempDir) -> (HandlerContext, mpsc::UnboundedReceiver<IrcEvent>) {
+        let (tx, _) = mpsc::unbounded_channel();
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let identity = Identity::load_or_generate(dir.path()).ok();
+        let ctx = HandlerContext {
+            tx,
+            event_tx,
+            state: AppState::with_data_dir(identity.clone(), dir.path()),
+            fragments: Arc::new(Mutex::new(HashMap::new())),
+            nickname: "me".to_string(),
+            identity,
+            autojoin: "#test".to_string(),
+            cert_fingerprint: None,
+            session: Mutex::new(None),
+        };
+        (ctx, event_rx)
+    }
+
+    #[tokio::test]
+    async fn test_pubkeys_only_from_the_server() {
+        let (dir, victim_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (ctx, _events) = handler(&dir);
+        let victim = Identity::load_or_generate(victim_dir.path()).unwrap();
+        let forged = "ab".repeat(32);
+
+        ctx.handle_privmsg("mallory", "#test", &format!("VOIRC_PUBKEY:alice:{}", forged)).await.unwrap();
+        assert_eq!(ctx.state.pubkey_for_nick("alice").await, None);
+
+        ctx.handle_privmsg(SERVER_NAME, "#test", &format!("VOIRC_PUBKEY:alice:{}", victim.pubkey_hex)).await.unwrap();
+        assert_eq!(ctx.state.pubkey_for_nick("alice").await, Some(victim.pubkey_hex));
+    }
+
+    #[tokio::test]
+    async fn test_hello_is_bound_to_the_server_session_and_channel() {
+        use ed25519_dalek::Signer;
+        let (dir, alice_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (ctx, _events) = handler(&dir);
+        let alice = Identity::load_or_generate(alice_dir.path()).unwrap();
+        let hello = |session: &str, channel: &str, ts: u64| {
+            let payload = irc_server::hello_payload(ctx.binding(), session, channel, "alice", &alice.pubkey_hex, ts);
+            let signature = alice.signing_key.sign(payload.as_bytes());
+            format!("VOIRC_HELLO:alice:{}:{}:{}", alice.pubkey_hex, ts, hex::encode(signature.to_bytes()))
+        };
+
+        // Only the server's NOTICE sets the session.
+        ctx.handle_notice("mallory", "VOIRC_SESSION:feed").await.unwrap();
+        assert_eq!(*ctx.session.lock().unwrap(), None);
+        ctx.handle_notice(SERVER_NAME, "VOIRC_SESSION:beef").await.unwrap();
+        assert_eq!(ctx.session.lock().unwrap().as_deref(), Some("beef"));
+
+        let stale = now_ms() - AUTH_MAX_SKEW_MS - 1000;
+        for text in [hello("feed", "#test", now_ms()), hello("beef", "#other", now_ms()), hello("beef", "#test", stale)] {
+            ctx.handle_privmsg("alice", "#test", &text).await.unwrap();
+            assert_eq!(ctx.state.pubkey_for_nick("alice").await, None);
+        }
+        // Someone else can't replay it as theirs.
+        ctx.handle_privmsg("mallory", "#test", &hello("beef", "#test", now_ms())).await.unwrap();
+        assert_eq!(ctx.state.pubkey_for_nick("alice").await, None);
+
+        ctx.handle_privmsg("alice", "#test", &hello("beef", "#test", now_ms())).await.unwrap();
+        assert_eq!(ctx.state.pubkey_for_nick("alice").await, Some(alice.pubkey_hex.clone()));
+    }
+
+    #[tokio::test]
+    async fn test_signed_chat_stays_in_its_channel_and_needs_a_key() {
+        let (dir, alice_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        let (ctx, mut events) = handler(&dir);
+        let alice = Identity::load_or_generate(alice_dir.path()).unwrap();
+        let signed = |channel: &str| {
+            let msg = crate::persistence::SignedMessage::create(&alice, "alice", channel, "hi", &[]).unwrap();
+            (msg.id.clone(), format!("SIGNED:{}", serde_json::to_string(&msg).unwrap()))
+        };
+
+        // No key for alice yet: shown, but unsigned and not logged.
+        let (id, text) = signed("#test");
+        ctx.handle_privmsg("alice", "#test", &text).await.unwrap();
+        assert!(matches!(events.try_recv(), Ok(IrcEvent::ChatMessage { entry, .. }) if entry.status == Verification::Unsigned));
+        assert!(ctx.state.message_log.message("#test", &id).await.is_none());
+
+        ctx.handle_privmsg(SERVER_NAME, "#test", &format!("VOIRC_PUBKEY:alice:{}", alice.pubkey_hex)).await.unwrap();
+        while events.try_recv().is_ok() {}
+        // Signed for #ops but sent to #test: dropped.
+        let (id, text) = signed("#ops");
+        ctx.handle_privmsg("alice", "#test", &text).await.unwrap();
+        assert!(ctx.state.message_log.message("#ops", &id).await.is_none());
+        assert!(events.try_recv().is_err());
+
+        let (id, text) = signed("#test");
+        ctx.handle_privmsg("alice", "#test", &text).await.unwrap();
+        assert!(ctx.state.message_log.message("#test", &id).await.is_some());
+    }
 }
@@ -1,27 +1,357 @@
 use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpListener;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, Notify, RwLock};
 use tokio_rustls::TlsAcceptor;
 use tracing::{error, info, warn};
 
+use crate::bans::{now_ms, now_secs, Ban, SharedBans};
+use crate::config::Role;
+use crate::moderation::{self, format_duration, ModAction, MuteKind};
+use crate::persistence::{MessageEvent, SignedMessage};
 use crate::pow;
 use crate::tls::CertInfo;
 
 const MAX_MSG_LEN: usize = 512;
+/// Clients with the `voirc` cap may send longer lines: a signed message
+/// carries its key, signature and parent hashes, which leaves too little
+/// of 512 bytes for the text.
+const MAX_VOIRC_MSG_LEN: usize = 2048;
+/// IRCv3 allows this much tag data in front of the 512 bytes.
+const MAX_TAGS_LEN: usize = 4096;
+const NICK_MAX_LEN: usize = 32;
+const VERSION: &str = concat!("voirc-", env!("CARGO_PKG_VERSION"));
+/// Offered in CAP LS.  `voirc` marks our own client, which gets the
+/// signaling and protocol traffic that plain IRC clients don't see.
+const CAPABILITIES: [&str; 4] = ["message-tags", "server-time", "voirc", "sasl"];
+/// Our only SASL mechanism: sign a server nonce with the identity key.
+pub const SASL_MECHANISM: &str = "VOIRC-ED25519";
+/// Longest AUTHENTICATE response we take; ours is about 260 characters.
+const SASL_MAX_RESPONSE: usize = 400;
+const ISUPPORT: &[&str] = &[
+    "CASEMAPPING=ascii",
+    "CHANTYPES=#",
+    "CHANMODES=b,k,l,Eim",
+    "PREFIX=(ov)@+",
+    "NETWORK=Voirc",
+    "NICKLEN=32",
+    "CHANNELLEN=50",
+    "TOPICLEN=300",
+    "AWAYLEN=200",
+    "MODES=6",
+];
 const MAX_CLIENTS_PER_IP: usize = 5;
 const MAX_TOTAL_CLIENTS: usize = 100;
+/// Prefix of server-originated lines; clients treat it as authoritative, so
+/// nobody may take it as a nick.
+pub const SERVER_NAME: &str = "voirc";
+/// How far a signed role grant's timestamp may be from our clock.
+const GRANT_MAX_SKEW_MS: u64 = 5 * 60 * 1000;
+/// Likewise for SASL responses and peer hellos.
+pub const AUTH_MAX_SKEW_MS: u64 = 5 * 60 * 1000;
 
 type Tx = mpsc::UnboundedSender<String>;
 
+/// IRCv3 capabilities a client has turned on.  Shared with its writer
+/// task, which applies them to every line on the way out.
+#[derive(Default)]
+struct Caps {
+    message_tags: AtomicBool,
+    server_time: AtomicBool,
+    voirc: AtomicBool,
+    sasl: AtomicBool,
+}
+
+impl Caps {
+    fn flag(&self, name: &str) -> Option<&AtomicBool> {
+        match name {
+            "message-tags" => Some(&self.message_tags),
+            "server-time" => Some(&self.server_time),
+            "voirc" => Some(&self.voirc),
+            "sasl" => Some(&self.sasl),
+            _ => None,
+        }
+    }
+
+    fn enabled(&self) -> Vec<&'static str> {
+        CAPABILITIES.into_iter()
+            .filter(|c| self.flag(c).is_some_and(|f| f.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
 struct Client {
     nick: Option<String>,
+    /// From USER; shown as `~user` in the mask.
+    user: Option<String>,
+    realname: String,
+    /// Cloaked host for the mask; the real address is never shown.
+    host: String,
+    /// NICK and USER are in and CAP negotiation (if any) has ended.
+    registered: bool,
+    cap_negotiating: bool,
+    caps: Arc<Caps>,
+    away: Option<String>,
+    /// Sent to the channels in the QUIT when the connection ends.
+    quit_reason: Option<String>,
     pubkey: Option<String>,
     authenticated: bool,
+    /// The challenge we sent after `AUTHENTICATE VOIRC-ED25519`.
+    sasl_nonce: Option<[u8; 32]>,
     tx: Tx,
     ip: std::net::IpAddr,
+    /// Signalled to make the connection task hang up (bans, QUIT).
+    closed: Arc<Notify>,
+    /// Channel → when they last posted, for slow mode.
+    last_chat: HashMap<String, u64>,
+}
+
+impl Client {
+    fn new(tx: Tx, ip: std::net::IpAddr, host: String) -> Self {
+        Self {
+            nick: None,
+            user: None,
+            realname: String::new(),
+            host,
+            registered: false,
+            cap_negotiating: false,
+            caps: Arc::new(Caps::default()),
+            away: None,
+            quit_reason: None,
+            pubkey: None,
+            authenticated: false,
+            sasl_nonce: None,
+            tx,
+            ip,
+            closed: Arc::new(Notify::new()),
+            last_chat: HashMap::new(),
+        }
+    }
+}
+
+/// RFC 2812 nicks, plus `#` after the first character for PoW-mined nicks.
+fn valid_nick(nick: &str) -> bool {
+    let mut chars = nick.chars();
+    let Some(first) = chars.next() else { return false };
+    nick.len() <= NICK_MAX_LEN
+        && (first.is_ascii_alphabetic() || "[]\\`_^{|}".contains(first))
+        && chars.all(|c| c.is_ascii_alphanumeric() || "[]\\`_^{|}-#".contains(c))
+}
+
+/// Split IRCv3 tags off a client line.  Only client-only (`+`) tags are
+/// kept; anything else a client sends is the server's to set.
+fn split_tags(line: &str) -> (Vec<String>, &str) {
+    let Some(tagged) = line.strip_prefix('@') else { return (Vec::new(), line) };
+    let (tags, rest) = tagged.split_once(' ').unwrap_or((tagged, ""));
+    let tags = tags.split(';').filter(|t| t.starts_with('+')).map(str::to_string).collect();
+    (tags, rest.trim_start())
+}
+
+/// The trailing parameter: after ` :`, or else the word at `index`.
+fn trailing<'a>(cmd: &'a str, parts: &[&'a str], index: usize) -> &'a str {
+    match cmd.split_once(" :") {
+        Some((_, t)) => t,
+        None => parts.get(index).copied().unwrap_or(""),
+    }
+}
+
+/// Shape an outgoing line for one client: drop tags it didn't ask for, add
+/// `server-time`, and turn Voirc traffic into something a plain IRC client
+/// can show, or nothing.
+fn render_line(line: &str, caps: &Caps, now: chrono::DateTime<chrono::Utc>) -> Option<String> {
+    let (mut tags, rest) = match line.strip_prefix('@') {
+        Some(tagged) => {
+            let (t, r) = tagged.split_once(' ')?;
+            (t.split(';').map(str::to_string).collect::<Vec<_>>(), r)
+        }
+        None => (Vec::new(), line),
+    };
+    let rest = rest.trim_end_matches(['\r', '\n']);
+    if !caps.message_tags.load(Ordering::Relaxed) {
+        if rest.split(' ').nth(1) == Some("TAGMSG") {
+            return None;
+        }
+        tags.clear();
+    }
+    let rest = if caps.voirc.load(Ordering::Relaxed) { rest.to_string() } else { render_for_irc(rest)? };
+    if caps.server_time.load(Ordering::Relaxed) {
+        tags.insert(0, format!("time={}", now.format("%Y-%m-%dT%H:%M:%S%.3fZ")));
+    }
+    Some(match tags.is_empty() {
+        true => format!("{}\r\n", rest),
+        false => format!("@{} {}\r\n", tags.join(";"), rest),
+    })
+}
+
+/// PRIVMSG/NOTICE lines carrying Voirc traffic, rewritten for a plain IRC
+/// client.  Signed chat shows as its text; the server's moderation notes
+/// become NOTICEs; signaling and the rest are dropped.
+fn render_for_irc(line: &str) -> Option<String> {
+    let Some((head, text)) = line.split_once(" :") else { return Some(line.to_string()) };
+    let mut words = head.split(' ');
+    let (Some(source), Some(command), Some(target)) = (words.next(), words.next(), words.next()) else {
+        return Some(line.to_string());
+    };
+    let voirc_traffic = ["VOIRC_", "WRTC:", "E2E:", "E2EKEY:", "SIGNED:"].iter().any(|p| text.starts_with(p));
+    if !matches!(command, "PRIVMSG" | "NOTICE") || !voirc_traffic {
+        return Some(line.to_string());
+    }
+
+    if let Some(json) = text.strip_prefix("SIGNED:") {
+        let msg: SignedMessage = serde_json::from_str(json).ok()?;
+        let text = match msg.encrypted {
+            true => "[end-to-end encrypted message]".to_string(),
+            false => msg.content.replace(['\r', '\n'], " "),
+        };
+        let text = match msg.event {
+            None => text,
+            Some(MessageEvent::Edit { .. }) => format!("[edit] {}", text),
+            Some(MessageEvent::Redact { .. }) => "[deleted a message]".to_string(),
+            Some(MessageEvent::React { .. }) => format!("[reacted {}]", text),
+        };
+        return Some(format!("{} {} {} :{}", source, command, target, text));
+    }
+    if source != format!(":{}", SERVER_NAME) {
+        return None;
+    }
+    let fields: Vec<&str> = text.split(':').collect();
+    let rendered = match fields.as_slice() {
+        _ if text.starts_with("VOIRC_INFO ") => text["VOIRC_INFO ".len()..].to_string(),
+        _ if text.starts_with("VOIRC_DENIED ") => format!("Permission denied ({})", &text["VOIRC_DENIED ".len()..]),
+        ["VOIRC_ROLE", nick, role, by] => {
+            let role = if *role == "peer" { "a regular user again" } else { "a moderator" };
+            format!("{} made {} {}", by, nick, role)
+        }
+        ["VOIRC_MUTE", kind, nick, secs, rest @ ..] => {
+            let what = if *kind == "voice" { "voice-muted" } else { "muted" };
+            let left = secs.parse::<u64>().ok().filter(|&s| s > 0)
+                .map_or(String::new(), |s| format!(" for {}", format_duration(s)));
+            let by = rest.first().map_or(String::new(), |b| format!(" by {}", b));
+            format!("{} is {}{}{}", nick, what, left, by)
+        }
+        ["VOIRC_UNMUTE", kind, nick, _by] => {
+            let what = if *kind == "voice" { "voice mute" } else { "mute" };
+            format!("{}'s {} has ended", nick, what)
+        }
+        ["VOIRC_E2E", "1", ..] => "Messages here are end-to-end encrypted; your client can't send or read them".to_string(),
+        ["VOIRC_E2E", "0", ..] => "Messages here are no longer end-to-end encrypted".to_string(),
+        ["VOIRC_SLOWMODE", "0", ..] => "Slow mode is off".to_string(),
+        ["VOIRC_SLOWMODE", interval, ..] => match interval.parse::<u64>() {
+            Ok(i) => format!("Slow mode: one message every {}", format_duration(i)),
+            Err(_) => return None,
+        },
+        _ => return None,
+    };
+    Some(format!("{} NOTICE {} :{}", source, target, rendered))
+}
+
+/// A chat or voice mute.  Times are unix seconds; no expiry lasts until
+/// lifted.
+struct Mute {
+    nick: String,
+    by: String,
+    /// Rank of `by` when they set it; see `may_lift`.
+    by_role: Role,
+    expires: Option<u64>,
+}
+
+/// Topic and modes of a channel.  Host and mods are its operators (`@`);
+/// they can set these and aren't held back by +i, +k or +l.
+#[derive(Default)]
+struct ChannelInfo {
+    topic: Option<Topic>,
+    invite_only: bool,
+    key: Option<String>,
+    moderated: bool,
+    /// +E: chat must be sealed with the members' channel keys.
+    encrypted: bool,
+    limit: Option<usize>,
+    /// Subjects let in past +i; used up when they join.
+    invited: HashSet<String>,
+    /// +v: may talk while the channel is +m.
+    voiced: HashSet<SocketAddr>,
+}
+
+struct Topic {
+    text: String,
+    by: String,
+    at: u64,
+}
+
+impl ChannelInfo {
+    /// `+ikl key 10` for RPL_CHANNELMODEIS; the key only for members.
+    fn mode_string(&self, show_key: bool) -> String {
+        let mut flags = String::from("+");
+        let mut args = Vec::new();
+        if self.invite_only {
+            flags.push('i');
+        }
+        if let Some(key) = &self.key {
+            flags.push('k');
+            args.push(if show_key { key.clone() } else { "*".to_string() });
+        }
+        if let Some(limit) = self.limit {
+            flags.push('l');
+            args.push(limit.to_string());
+        }
+        if self.moderated {
+            flags.push('m');
+        }
+        if self.encrypted {
+            flags.push('E');
+        }
+        std::iter::once(flags).chain(args).collect::<Vec<_>>().join(" ")
+    }
+}
+
+struct SlowMode {
+    interval: u64,
+    expires: Option<u64>,
+}
+
+fn is_active(expires: Option<u64>, now: u64) -> bool {
+    expires.is_none_or(|t| t > now)
+}
+
+/// Seconds left on the wire, 0 for no expiry.
+fn secs_left(expires: Option<u64>, now: u64) -> u64 {
+    expires.map_or(0, |t| t.saturating_sub(now).max(1))
+}
+
+/// Mods can act on peers; only the host can act on a mod; nobody acts on
+/// the host.
+fn may_act_on(actor: Role, target: Role) -> bool {
+    match target {
+        Role::Host => false,
+        Role::Mod => actor.can_promote(),
+        Role::Peer => actor.can_moderate(),
+    }
+}
+
+/// Lifting a mute or ban: whoever set it, anyone of the same rank, or
+/// someone `may_act_on` the setter.  A mod can't undo the host's.
+fn may_lift(actor: Role, setter: Role) -> bool {
+    actor == setter || may_act_on(actor, setter)
+}
+
+/// The signaling clients send to a channel on PRIVMSG.  Anything else
+/// there, `VOIRC_` text we don't know included, is someone talking and is
+/// held to mutes and slow mode.
+const CHANNEL_SIGNALING: [&str; 4] = ["VOIRC_HELLO:", "VOIRC_ROLE:", "VOIRC_VOICE:", "WRTC:"];
+
+fn is_signaling(text: &str) -> bool {
+    CHANNEL_SIGNALING.iter().any(|prefix| text.starts_with(prefix))
+}
+
+/// Signed chat whose content is sealed, the only kind a +E channel takes.
+fn is_sealed_chat(text: &str) -> bool {
+    text.strip_prefix("SIGNED:")
+        .and_then(|json| serde_json::from_str::<SignedMessage>(json).ok())
+        .is_some_and(|msg| msg.encrypted)
 }
 
 struct ServerState {
@@ -32,21 +362,502 @@ struct ServerState {
     /// Current PoW difficulty requirement (leading zero bits in nick hash).
     /// 0 = disabled.  Mods/host can change at runtime via VOIRC_POW_SET.
     pow_required_bits: u8,
+    /// The room owner, bound at startup.  Whoever authenticates with this
+    /// key is the host; nobody else can become one.
+    host_pubkey: Option<String>,
+    /// Pubkeys the host has granted mod, via signed VOIRC_ROLE_SET.
+    mods: HashSet<String>,
+    /// Timestamp of the last accepted grant, so a captured one can't be
+    /// replayed.
+    last_grant_ms: u64,
+    /// Shared with the relay; saved to disk on every change.
+    bans: SharedBans,
+    /// Room-wide mutes, keyed by kind and `subject`.
+    mutes: HashMap<(MuteKind, String), Mute>,
+    slow_modes: HashMap<String, SlowMode>,
+    /// Kept alongside `channels`, which only holds the members.
+    channel_info: HashMap<String, ChannelInfo>,
+    /// For 003.
+    created: String,
+    /// Mixed into the address hash behind each guest's cloaked host.
+    cloak_salt: [u8; 16],
+    /// Our TLS cert's, which clients pinned; signatures are bound to it.
+    cert_fingerprint: Option<String>,
+    /// Fresh each run and sent on welcome.  Peer hellos sign it, so they
+    /// can't be replayed after a restart.
+    session_nonce: [u8; 16],
 }
 
 impl ServerState {
-    fn new(pow_required_bits: u8) -> Self {
+    fn new(pow_required_bits: u8, host_pubkey: Option<String>, bans: SharedBans) -> Self {
         Self {
             clients: HashMap::new(),
             channels: HashMap::new(),
             nick_pubkeys: HashMap::new(),
             pow_required_bits,
+            host_pubkey,
+            mods: HashSet::new(),
+            last_grant_ms: 0,
+            bans,
+            mutes: HashMap::new(),
+            slow_modes: HashMap::new(),
+            channel_info: HashMap::new(),
+            created: chrono::Utc::now().format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            cloak_salt: rand::random(),
+            cert_fingerprint: None,
+            session_nonce: rand::random(),
+        }
+    }
+
+    /// Host shown for a guest: a salted hash, so addresses don't leak to
+    /// other users but one address keeps one host for the server's run.
+    fn cloak(&self, ip: std::net::IpAddr) -> String {
+        let mut data = self.cloak_salt.to_vec();
+        data.extend_from_slice(ip.to_string().as_bytes());
+        let digest = ring::digest::digest(&ring::digest::SHA256, &data);
+        format!("guest/{}", hex::encode(&digest.as_ref()[..4]))
+    }
+
+    /// `nick!~user@host`, the source of everything this client sends.
+    fn mask(&self, addr: &SocketAddr) -> String {
+        let Some(c) = self.clients.get(addr) else { return SERVER_NAME.to_string() };
+        format!(
+            "{}!~{}@{}",
+            c.nick.as_deref().unwrap_or("*"),
+            c.user.as_deref().unwrap_or("voirc"),
+            c.host,
+        )
+    }
+
+    /// Finish registration once NICK and USER are in and CAP is done.
+    fn try_register(&mut self, addr: &SocketAddr) {
+        let Some(c) = self.clients.get_mut(addr) else { return };
+        if c.registered || c.cap_negotiating || c.nick.is_none() || c.user.is_none() {
+            return;
+        }
+        // A nick bound to a key needs that key's SASL login first.
+        let nick = c.nick.clone().unwrap_or_default();
+        if self.nick_pubkeys.get(&nick).is_some_and(|pk| c.pubkey.as_ref() != Some(pk)) {
+            c.nick = None;
+            return self.reply(addr, "433", &format!("{} :Nickname is already in use", nick));
+        }
+        let Some(c) = self.clients.get_mut(addr) else { return };
+        c.registered = true;
+        info!("Registered {} from {}", self.mask(addr), addr);
+        self.publish_holder(addr);
+        self.welcome(addr);
+    }
+
+    /// Record who holds `addr`'s nick now, which the relay's bans go by.
+    fn publish_holder(&self, addr: &SocketAddr) {
+        let Some(c) = self.clients.get(addr) else { return };
+        if let Some(nick) = c.nick.as_deref().filter(|_| c.registered) {
+            let pubkey = c.pubkey.as_deref().filter(|_| c.authenticated);
+            self.bans.write().unwrap().set_holder(nick, pubkey, c.ip);
+        }
+    }
+
+    fn welcome(&self, addr: &SocketAddr) {
+        self.reply(addr, "001", &format!(":Welcome to the Voirc IRC network {}", self.mask(addr)));
+        self.reply(addr, "002", &format!(":Your host is {}, running version {}", SERVER_NAME, VERSION));
+        self.reply(addr, "003", &format!(":This server was created {}", self.created));
+        self.reply(addr, "004", &format!("{} {} i iklmv", SERVER_NAME, VERSION));
+        self.reply(addr, "005", &format!("{} :are supported by this server", ISUPPORT.join(" ")));
+        self.send_motd(addr);
+        // Voirc clients check their nick's PoW against this.
+        self.reply(addr, "NOTICE", &format!(":VOIRC_POW_REQUIRED:{}", self.pow_required_bits));
+        self.reply(addr, "NOTICE", &format!(":VOIRC_SESSION:{}", hex::encode(self.session_nonce)));
+    }
+
+    fn send_motd(&self, addr: &SocketAddr) {
+        let mut channels: Vec<String> = self.channels.iter()
+            .filter(|(ch, members)| !members.is_empty() && self.visible_to(ch, addr))
+            .map(|(ch, members)| format!("{} ({})", ch, members.len()))
+            .collect();
+        channels.sort();
+        self.reply(addr, "375", &format!(":- {} Message of the day -", SERVER_NAME));
+        self.reply(addr, "372", ":- Welcome to Voirc: voice chat with IRC text channels.");
+        self.reply(addr, "372", ":- Text works from any IRC client; voice needs the Voirc app.");
+        if !channels.is_empty() {
+            self.reply(addr, "372", &format!(":- Channels: {}", channels.join(", ")));
+        }
+        self.reply(addr, "376", ":End of /MOTD command.");
+    }
+
+    /// RPL_WHOREPLY for `member`, listed under `channel` (`*` for none).
+    fn who_line(&self, addr: &SocketAddr, channel: &str, member: &SocketAddr) {
+        let Some(c) = self.clients.get(member) else { return };
+        let Some(nick) = c.nick.as_deref() else { return };
+        let prefix = if channel == "*" { "" } else { self.member_prefix(channel, member) };
+        self.reply(addr, "352", &format!(
+            "{} ~{} {} {} {} {}{} :0 {}",
+            channel,
+            c.user.as_deref().unwrap_or("voirc"),
+            c.host,
+            SERVER_NAME,
+            nick,
+            if c.away.is_some() { 'G' } else { 'H' },
+            prefix,
+            c.realname,
+        ));
+    }
+
+    fn send_whois(&self, addr: &SocketAddr, nick: &str) {
+        let Some(target) = self.find_addr_by_nick(nick) else {
+            self.reply(addr, "401", &format!("{} :No such nick/channel", nick));
+            self.reply(addr, "318", &format!("{} :End of /WHOIS list.", nick));
+            return;
+        };
+        let c = &self.clients[&target];
+        let nick = c.nick.as_deref().unwrap_or(nick);
+        self.reply(addr, "311", &format!(
+            "{} ~{} {} * :{}", nick, c.user.as_deref().unwrap_or("voirc"), c.host, c.realname
+        ));
+        let mut channels: Vec<String> = self.channels.iter()
+            .filter(|(ch, members)| members.contains(&target) && self.visible_to(ch, addr))
+            .map(|(ch, _)| format!("{}{}", self.member_prefix(ch, &target), ch))
+            .collect();
+        channels.sort();
+        if !channels.is_empty() {
+            self.reply(addr, "319", &format!("{} :{}", nick, channels.join(" ")));
+        }
+        self.reply(addr, "312", &format!("{} {} :Voirc embedded server", nick, SERVER_NAME));
+        if let Some(away) = &c.away {
+            self.reply(addr, "301", &format!("{} :{}", nick, away));
+        }
+        match self.role_of(&target) {
+            Role::Host => self.reply(addr, "313", &format!("{} :is the room host", nick)),
+            Role::Mod => self.reply(addr, "313", &format!("{} :is a room moderator", nick)),
+            Role::Peer => {}
+        }
+        if let (true, Some(pk)) = (c.authenticated, &c.pubkey) {
+            self.reply(addr, "330", &format!("{} {} :is logged in as", nick, &pk[..16.min(pk.len())]));
+        }
+        if c.caps.voirc.load(Ordering::Relaxed) {
+            self.reply(addr, "320", &format!("{} :is using Voirc (voice)", nick));
+        }
+        self.reply(addr, "318", &format!("{} :End of /WHOIS list.", nick));
+    }
+
+    fn nick_of(&self, addr: &SocketAddr) -> String {
+        self.clients.get(addr).and_then(|c| c.nick.clone()).unwrap_or_else(|| "*".to_string())
+    }
+
+    /// Send a numeric reply (or any server line) to one client.
+    fn reply(&self, addr: &SocketAddr, numeric: &str, rest: &str) {
+        if let Some(c) = self.clients.get(addr) {
+            let _ = c.tx.send(format!(":{} {} {} {}\r\n", SERVER_NAME, numeric, self.nick_of(addr), rest));
+        }
+    }
+
+    fn is_member(&self, channel: &str, addr: &SocketAddr) -> bool {
+        self.channels.get(channel).is_some_and(|m| m.contains(addr))
+    }
+
+    /// `@` for host and mods, `+` for voiced, as in NAMES and WHO.
+    fn member_prefix(&self, channel: &str, addr: &SocketAddr) -> &'static str {
+        if self.role_of(addr).is_superpeer() {
+            "@"
+        } else if self.channel_info.get(channel).is_some_and(|i| i.voiced.contains(addr)) {
+            "+"
+        } else {
+            ""
+        }
+    }
+
+    fn send_names(&self, channel: &str, addr: &SocketAddr) {
+        let mut names: Vec<String> = self.channels.get(channel)
+            .into_iter()
+            .flatten()
+            .filter_map(|m| {
+                let nick = self.clients.get(m)?.nick.as_deref()?;
+                Some(format!("{}{}", self.member_prefix(channel, m), nick))
+            })
+            .collect();
+        names.sort();
+        // RPL_NAMREPLY lines are kept well under the 512 byte limit.
+        for chunk in names.chunks(20) {
+            self.reply(addr, "353", &format!("= {} :{}", channel, chunk.join(" ")));
+        }
+        self.reply(addr, "366", &format!("{} :End of /NAMES list.", channel));
+    }
+
+    fn send_topic(&self, channel: &str, addr: &SocketAddr) {
+        match self.channel_info.get(channel).and_then(|i| i.topic.as_ref()) {
+            Some(t) => {
+                self.reply(addr, "332", &format!("{} :{}", channel, t.text));
+                self.reply(addr, "333", &format!("{} {} {}", channel, t.by, t.at));
+            }
+            None => self.reply(addr, "331", &format!("{} :No topic is set", channel)),
+        }
+    }
+
+    /// Why `addr` may not join `channel`, as (numeric, mode letter).
+    fn join_refusal(&self, channel: &str, addr: &SocketAddr, key: Option<&str>) -> Option<(&'static str, char)> {
+        let info = self.channel_info.get(channel)?;
+        if self.role_of(addr).is_superpeer() {
+            return None;
+        }
+        let invited = self.subject(addr).is_some_and(|sub| info.invited.contains(&sub));
+        if info.invite_only && !invited {
+            return Some(("473", 'i'));
+        }
+        if info.key.as_deref().is_some_and(|k| Some(k) != key) && !invited {
+            return Some(("475", 'k'));
+        }
+        let members = self.channels.get(channel).map_or(0, |m| m.len());
+        if info.limit.is_some_and(|l| members >= l) {
+            return Some(("471", 'l'));
+        }
+        None
+    }
+
+    /// Can `addr` send chat to `channel`?  Members only, and only ops and
+    /// voiced users while it is +m.
+    /// Members only; under +m only ops and +v, though a member's
+    /// `signaling` still gets through so calls keep working.
+    fn can_send(&self, channel: &str, addr: &SocketAddr, signaling: bool) -> Result<(), &'static str> {
+        if !self.is_member(channel, addr) {
+            return Err("Cannot send to channel (not joined)");
+        }
+        let moderated = self.channel_info.get(channel).is_some_and(|i| i.moderated);
+        if moderated && !signaling && self.member_prefix(channel, addr).is_empty() {
+            return Err("Cannot send to channel (+m)");
+        }
+        Ok(())
+    }
+
+    /// Invite-only channels are hidden from LIST, NAMES and WHO for anyone
+    /// who isn't in them (ops excepted).
+    fn visible_to(&self, channel: &str, addr: &SocketAddr) -> bool {
+        !self.channel_info.get(channel).is_some_and(|i| i.invite_only)
+            || self.is_member(channel, addr)
+            || self.role_of(addr).is_superpeer()
+    }
+
+    /// Forget `addr` in every channel's per-member state.
+    fn forget_member(&mut self, addr: &SocketAddr) {
+        for info in self.channel_info.values_mut() {
+            info.voiced.remove(addr);
+        }
+    }
+
+    /// What mutes are keyed by: the pubkey once authenticated, so they
+    /// survive a reconnect, otherwise the nick.
+    fn subject(&self, addr: &SocketAddr) -> Option<String> {
+        let c = self.clients.get(addr)?;
+        match (&c.pubkey, c.authenticated) {
+            (Some(pk), true) => Some(pk.clone()),
+            _ => c.nick.as_ref().map(|n| format!("nick:{}", n)),
+        }
+    }
+
+    fn active_mute(&self, addr: &SocketAddr, kind: MuteKind, now: u64) -> Option<&Mute> {
+        let subject = self.subject(addr)?;
+        self.mutes.get(&(kind, subject)).filter(|m| is_active(m.expires, now))
+    }
+
+    fn mute_text(kind: MuteKind, nick: &str, mute: &Mute, now: u64, with_by: bool) -> String {
+        let mut text = format!("VOIRC_MUTE:{}:{}:{}", kind.as_str(), nick, secs_left(mute.expires, now));
+        if with_by {
+            text.push(':');
+            text.push_str(&mute.by);
+        }
+        text
+    }
+
+    fn slow_mode_text(&self, channel: &str, now: u64, by: Option<&str>) -> String {
+        let (interval, left) = self.slow_modes.get(channel)
+            .filter(|sm| is_active(sm.expires, now))
+            .map_or((0, 0), |sm| (sm.interval, secs_left(sm.expires, now)));
+        match by {
+            Some(by) => format!("VOIRC_SLOWMODE:{}:{}:{}", interval, left, by),
+            None => format!("VOIRC_SLOWMODE:{}:{}", interval, left),
+        }
+    }
+
+    /// Send `text` as a server PRIVMSG to everyone in `channel`.
+    fn announce_channel(&self, channel: &str, text: &str) {
+        let Some(members) = self.channels.get(channel) else { return };
+        let line = format!(":{} PRIVMSG {} :{}\r\n", SERVER_NAME, channel, text);
+        for member in members {
+            if let Some(c) = self.clients.get(member) {
+                let _ = c.tx.send(line.clone());
+            }
+        }
+    }
+
+    /// Send `text` to every channel `addr` is in.
+    fn announce(&self, addr: &SocketAddr, text: &str) {
+        for (channel, members) in &self.channels {
+            if members.contains(addr) {
+                self.announce_channel(channel, text);
+            }
+        }
+    }
+
+    /// Refuse channel chat from muted senders and peers posting faster than
+    /// the slow mode allows.  The error is shown to the sender.
+    fn check_chat(&mut self, addr: &SocketAddr, channel: &str, now: u64) -> Result<(), String> {
+        if let Some(mute) = self.active_mute(addr, MuteKind::Chat, now) {
+            return Err(match mute.expires {
+                Some(t) => format!("You are muted ({} left)", format_duration(t.saturating_sub(now).max(1))),
+                None => "You are muted".to_string(),
+            });
+        }
+        let Some(interval) = self.slow_modes.get(channel)
+            .filter(|sm| is_active(sm.expires, now))
+            .map(|sm| sm.interval)
+        else {
+            return Ok(());
+        };
+        if self.role_of(addr).is_superpeer() {
+            return Ok(());
+        }
+        let Some(client) = self.clients.get_mut(addr) else { return Ok(()) };
+        if let Some(last) = client.last_chat.get(channel) {
+            if now < last + interval {
+                return Err(format!("Slow mode: wait {}", format_duration(last + interval - now)));
+            }
+        }
+        client.last_chat.insert(channel.to_string(), now);
+        Ok(())
+    }
+
+    /// Lift mutes and slow modes that have run out, telling the rooms.
+    fn expire_restrictions(&mut self, now: u64) {
+        let expired: Vec<(MuteKind, String)> = self.mutes.iter()
+            .filter(|(_, m)| !is_active(m.expires, now))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            let Some(mute) = self.mutes.remove(&key) else { continue };
+            info!("{} mute on {} expired", key.0.as_str(), mute.nick);
+            let online = self.clients.keys().find(|a| self.subject(a).as_ref() == Some(&key.1)).copied();
+            if let Some(addr) = online {
+                let nick = self.clients[&addr].nick.clone().unwrap_or(mute.nick);
+                self.announce(&addr, &format!("VOIRC_UNMUTE:{}:{}:{}", key.0.as_str(), nick, SERVER_NAME));
+            }
+        }
+
+        let expired: Vec<String> = self.slow_modes.iter()
+            .filter(|(_, sm)| !is_active(sm.expires, now))
+            .map(|(ch, _)| ch.clone())
+            .collect();
+        for channel in expired {
+            self.slow_modes.remove(&channel);
+            self.announce_channel(&channel, &self.slow_mode_text(&channel, now, Some(SERVER_NAME)));
+        }
+    }
+
+    /// Kick `addr` from all its channels in `by`'s name and hang up.
+    fn drop_client(&mut self, addr: &SocketAddr, by: &str, reason: &str) {
+        let Some(client) = self.clients.get(addr) else { return };
+        let mask = match self.find_addr_by_nick(by) {
+            Some(a) if by != SERVER_NAME => self.mask(&a),
+            _ => by.to_string(),
+        };
+        if let Some(nick) = &client.nick {
+            for (channel, members) in self.channels.iter_mut() {
+                if !members.contains(addr) {
+                    continue;
+                }
+                let kick = format!(":{} KICK {} {} :{}\r\n", mask, channel, nick, reason);
+                for member in members.iter() {
+                    if let Some(c) = self.clients.get(member) {
+                        let _ = c.tx.send(kick.clone());
+                    }
+                }
+                members.remove(addr);
+            }
+        }
+        let _ = client.tx.send(format!("ERROR :Closing link: {}\r\n", reason));
+        client.closed.notify_one();
+    }
+
+    /// The authoritative role for a connection.  Unauthenticated clients
+    /// are always peers, whatever they announce.
+    fn role_of(&self, addr: &SocketAddr) -> Role {
+        let Some(pubkey) = self.clients.get(addr)
+            .filter(|c| c.authenticated)
+            .and_then(|c| c.pubkey.as_deref())
+        else {
+            return Role::Peer;
+        };
+        if self.host_pubkey.as_deref() == Some(pubkey) {
+            Role::Host
+        } else if self.mods.contains(pubkey) {
+            Role::Mod
+        } else {
+            Role::Peer
+        }
+    }
+
+    fn role_line(&self, channel: &str, addr: &SocketAddr, by: Option<&str>) -> Option<String> {
+        let nick = self.clients.get(addr)?.nick.as_deref()?;
+        let role = self.role_of(addr).as_str();
+        Some(match by {
+            Some(by) => format!(":{} PRIVMSG {} :VOIRC_ROLE:{}:{}:{}\r\n", SERVER_NAME, channel, nick, role, by),
+            None => format!(":{} PRIVMSG {} :VOIRC_ROLE:{}:{}\r\n", SERVER_NAME, channel, nick, role),
+        })
+    }
+
+    /// Tell every channel `addr` is in what its role is.  `by` names the
+    /// host when this is the result of a grant.
+    fn broadcast_role(&self, addr: &SocketAddr, by: Option<&str>) {
+        for (channel, members) in &self.channels {
+            if !members.contains(addr) {
+                continue;
+            }
+            let Some(line) = self.role_line(channel, addr, by) else { continue };
+            for member in members {
+                if let Some(c) = self.clients.get(member) {
+                    let _ = c.tx.send(line.clone());
+                }
+            }
+        }
+    }
+
+    /// Check a `VOIRC_ROLE_SET:<nick>:<role>:<ts_ms>:<sig>` from the host
+    /// and update the mod table.  Returns the target's address.
+    fn apply_role_grant(&mut self, rest: &str, now_ms: u64) -> Result<SocketAddr, &'static str> {
+        let parts: Vec<&str> = rest.splitn(4, ':').collect();
+        let [target, role, ts, sig] = parts[..] else { return Err("malformed") };
+        let ts: u64 = ts.parse().map_err(|_| "malformed")?;
+        let role = match role {
+            "mod" => Role::Mod,
+            "peer" => Role::Peer,
+            _ => return Err("invalid_role"),
+        };
+        let host_key = self.host_pubkey.as_deref().ok_or("no_host")?;
+        if !verify_signature(host_key, moderation::role_grant_payload(target, role, ts).as_bytes(), sig) {
+            return Err("invalid_signature");
+        }
+        if ts <= self.last_grant_ms || ts.abs_diff(now_ms) > GRANT_MAX_SKEW_MS {
+            return Err("stale");
+        }
+
+        let target_addr = self.find_addr_by_nick(target).ok_or("no_such_nick")?;
+        let target_key = self.clients.get(&target_addr)
+            .filter(|c| c.authenticated)
+            .and_then(|c| c.pubkey.clone())
+            .ok_or("not_authenticated")?;
+        if Some(target_key.as_str()) == self.host_pubkey.as_deref() {
+            return Err("target_is_host");
         }
+
+        self.last_grant_ms = ts;
+        if role == Role::Mod {
+            self.mods.insert(target_key);
+        } else {
+            self.mods.remove(&target_key);
+        }
+        Ok(target_addr)
     }
 
     fn find_addr_by_nick(&self, nick: &str) -> Option<SocketAddr> {
         self.clients.iter()
-            .find(|(_, c)| c.nick.as_deref() == Some(nick))
+            .find(|(_, c)| c.nick.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(nick)))
             .map(|(addr, _)| *addr)
     }
 
@@ -68,26 +879,58 @@ impl ServerState {
 pub struct EmbeddedServer;
 
 impl EmbeddedServer {
-    pub async fn run(port: u16, pow_bits: u8) -> std::io::Result<()> {
-        Self::run_inner(port, None, pow_bits).await
+    /// `host_pubkey` is the hex ed25519 key that gets host rights; with
+    /// `None` nobody can moderate.  `bans` is shared with the relay.
+    pub async fn run(port: u16, pow_bits: u8, host_pubkey: Option<String>, bans: SharedBans) -> std::io::Result<()> {
+        Self::run_inner(port, None, None, pow_bits, host_pubkey, bans).await
     }
 
-    pub async fn run_tls(port: u16, cert_info: &CertInfo, pow_bits: u8) -> std::io::Result<()> {
+    pub async fn run_tls(
+        port: u16,
+        cert_info: &CertInfo,
+        pow_bits: u8,
+        host_pubkey: Option<String>,
+        bans: SharedBans,
+    ) -> std::io::Result<()> {
         let tls_config = crate::tls::server_config(cert_info)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            .map_err(std::io::Error::other)?;
         let acceptor = TlsAcceptor::from(tls_config);
-        Self::run_inner(port, Some(acceptor), pow_bits).await
+        let fingerprint = Some(cert_info.fingerprint.clone());
+        Self::run_inner(port, Some(acceptor), fingerprint, pow_bits, host_pubkey, bans).await
     }
 
-    async fn run_inner(port: u16, acceptor: Option<TlsAcceptor>, pow_bits: u8) -> std::io::Result<()> {
+    async fn run_inner(
+        port: u16,
+        acceptor: Option<TlsAcceptor>,
+        cert_fingerprint: Option<String>,
+        pow_bits: u8,
+        host_pubkey: Option<String>,
+        bans: SharedBans,
+    ) -> std::io::Result<()> {
         let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
         let mode = if acceptor.is_some() { "TLS" } else { "plaintext" };
         info!(
             "Embedded IRC Server listening on 0.0.0.0:{} ({}) pow_required_bits={}",
             port, mode, pow_bits
         );
+        match &host_pubkey {
+            Some(pk) => info!("Host pubkey: {}...", &pk[..8.min(pk.len())]),
+            None => warn!("No host pubkey configured; moderation is disabled"),
+        }
+
+        let mut server_state = ServerState::new(pow_bits, host_pubkey, bans);
+        server_state.cert_fingerprint = cert_fingerprint;
+        let state = Arc::new(RwLock::new(server_state));
 
-        let state = Arc::new(RwLock::new(ServerState::new(pow_bits)));
+        let expiry = Arc::downgrade(&state);
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                tick.tick().await;
+                let Some(state) = expiry.upgrade() else { break };
+                state.write().await.expire_restrictions(now_secs());
+            }
+        });
 
         loop {
             let (socket, addr) = listener.accept().await?;
@@ -103,6 +946,10 @@ impl EmbeddedServer {
                     warn!("Max clients per IP reached for {}", addr.ip());
                     continue;
                 }
+                if s.bans.read().unwrap().find(None, Some(addr.ip()), now_secs()).is_some() {
+                    warn!("Refusing banned address {}", addr.ip());
+                    continue;
+                }
             }
 
             let acc = acceptor.clone();
@@ -144,26 +991,36 @@ where
     let mut reader = BufReader::new(reader);
     let (tx, mut rx) = mpsc::unbounded_channel::<String>();
 
-    {
+    let (closed, caps) = {
         let mut s = state.write().await;
-        s.clients.insert(addr, Client {
-            nick: None,
-            pubkey: None,
-            authenticated: false,
-            tx,
-            ip: addr.ip(),
-        });
-    }
+        let client = Client::new(tx, addr.ip(), s.cloak(addr.ip()));
+        let handles = (Arc::clone(&client.closed), Arc::clone(&client.caps));
+        s.clients.insert(addr, client);
+        handles
+    };
 
-    let writer_handle = tokio::spawn(async move {
+    let line_caps = Arc::clone(&caps);
+    let mut writer_handle = tokio::spawn(async move {
         while let Some(msg) = rx.recv().await {
+            let Some(msg) = render_line(&msg, &caps, chrono::Utc::now()) else { continue };
             if writer.write_all(msg.as_bytes()).await.is_err() { break; }
         }
     });
 
     let mut line = String::new();
-    while reader.read_line(&mut line).await? > 0 {
-        if line.len() > MAX_MSG_LEN {
+    loop {
+        tokio::select! {
+            n = reader.read_line(&mut line) => {
+                if n? == 0 {
+                    break;
+                }
+            }
+            _ = closed.notified() => break,
+        }
+        let body_len = if line.starts_with('@') { line.split_once(' ').map_or(0, |(_, r)| r.len()) } else { line.len() };
+        let max_len = if line_caps.voirc.load(Ordering::Relaxed) { MAX_VOIRC_MSG_LEN } else { MAX_MSG_LEN };
+        if line.len() > max_len + MAX_TAGS_LEN || body_len > max_len {
+            state.read().await.reply(&addr, "417", ":Input line was too long");
             line.clear();
             continue;
         }
@@ -176,7 +1033,9 @@ where
 
     {
         let mut s = state.write().await;
+        let full_mask = s.mask(&addr);
         if let Some(client) = s.clients.remove(&addr) {
+            s.forget_member(&addr);
             if let Some(nick) = client.nick {
                 info!("Client disconnected: {}", nick);
                 s.nick_pubkeys.remove(&nick);
@@ -190,8 +1049,8 @@ where
                     }
                 }
 
-                let full_mask = format!("{}!voirc@127.0.0.1", nick);
-                let quit_msg = format!(":{} QUIT :Connection closed\r\n", full_mask);
+                let reason = client.quit_reason.as_deref().unwrap_or("Connection closed");
+                let quit_msg = format!(":{} QUIT :{}\r\n", full_mask, reason);
                 for peer_addr in peers_to_notify {
                     if let Some(c) = s.clients.get(&peer_addr) {
                         let _ = c.tx.send(quit_msg.clone());
@@ -201,308 +1060,676 @@ where
         }
     }
 
-    writer_handle.abort();
+    // The client's sender is gone with it, so the writer stops once it has
+    // flushed anything left (like a ban's ERROR line).
+    if tokio::time::timeout(Duration::from_secs(2), &mut writer_handle).await.is_err() {
+        writer_handle.abort();
+    }
     Ok(())
 }
 
-async fn process_command(cmd: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+async fn process_command(line: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let (tags, cmd) = split_tags(line);
+    // A source prefix from a client means nothing; drop it.
+    let cmd = match cmd.strip_prefix(':') {
+        Some(prefixed) => prefixed.split_once(' ').map_or("", |(_, rest)| rest.trim_start()),
+        None => cmd,
+    };
     let parts: Vec<&str> = cmd.split_whitespace().collect();
     if parts.is_empty() { return; }
+    let command = parts[0].to_ascii_uppercase();
 
-    match parts[0] {
-        "CAP" => {
-            let s = state.read().await;
-            if let Some(c) = s.clients.get(&addr) {
-                let _ = c.tx.send(":voirc CAP * LS :\r\n".to_string());
-            }
-        }
-        "NICK" => {
-            if parts.len() > 1 {
-                let new_nick = parts[1].to_string();
-                let mut s = state.write().await;
-                let client_pubkey = s.clients.get(&addr).and_then(|c| c.pubkey.clone());
-                if let Some(ref bound_key) = s.nick_pubkeys.get(&new_nick).cloned() {
-                    if client_pubkey.as_deref() != Some(bound_key.as_str()) {
-                        if let Some(c) = s.clients.get(&addr) {
-                            let _ = c.tx.send(format!(
-                                ":voirc 433 * {} :Nickname is already in use\r\n", new_nick
-                            ));
-                        }
-                        return;
-                    }
-                }
-                if let Some(c) = s.clients.get_mut(&addr) {
-                    c.nick = Some(new_nick);
-                }
+    let registered = state.read().await.clients.get(&addr).is_some_and(|c| c.registered);
+    if !registered && !matches!(command.as_str(), "CAP" | "AUTHENTICATE" | "NICK" | "USER" | "PASS" | "PING" | "PONG" | "QUIT") {
+        state.read().await.reply(&addr, "451", ":You have not registered");
+        return;
+    }
+
+    match command.as_str() {
+        "CAP" => handle_cap(cmd, &parts, addr, state).await,
+        "AUTHENTICATE" => match parts.get(1) {
+            Some(arg) => handle_authenticate(arg.trim_start_matches(':'), addr, state).await,
+            None => state.read().await.reply(&addr, "461", "AUTHENTICATE :Not enough parameters"),
+        },
+        "NICK" => match parts.get(1) {
+            Some(nick) => handle_nick(nick.trim_start_matches(':'), addr, state).await,
+            None => state.read().await.reply(&addr, "431", ":No nickname given"),
+        },
+        "USER" => {
+            let mut s = state.write().await;
+            if registered {
+                return s.reply(&addr, "462", ":You may not reregister");
+            }
+            if parts.len() < 5 {
+                return s.reply(&addr, "461", "USER :Not enough parameters");
             }
+            let user: String = parts[1].chars()
+                .filter(|c| c.is_ascii_alphanumeric() || "-_.".contains(*c))
+                .take(10)
+                .collect();
+            if let Some(c) = s.clients.get_mut(&addr) {
+                c.user = Some(if user.is_empty() { "user".to_string() } else { user });
+                c.realname = trailing(cmd, &parts, 4).chars().take(100).collect();
+            }
+            s.try_register(&addr);
         }
-        "USER" => {
-            let s = state.read().await;
-            let nick = s.clients.get(&addr).and_then(|c| c.nick.clone());
-            if let Some(n) = nick {
-                if let Some(c) = s.clients.get(&addr) {
-                    let _ = c.tx.send(format!(":voirc 001 {} :Welcome to Voirc\r\n", n));
-                    let _ = c.tx.send(format!(":voirc 422 {} :MOTD File is missing\r\n", n));
-                    // Advertise current PoW requirement immediately on welcome
-                    let _ = c.tx.send(format!(
-                        ":voirc NOTICE {} :VOIRC_POW_REQUIRED:{}\r\n",
-                        n, s.pow_required_bits
-                    ));
-                }
-            }
-        }
-        "PRIVMSG" => {
-            if parts.len() > 2 {
-                let target = parts[1];
-                let msg_start = cmd.find(':').map(|i| i + 1).unwrap_or(0);
-                let text = if msg_start > 0 { &cmd[msg_start..] } else { "" };
-
-                if target == "voirc" {
-                    if let Some(rest) = text.strip_prefix("VOIRC_HELLO:") {
-                        handle_hello(rest, addr, state).await;
-                        return;
-                    }
-                    if let Some(rest) = text.strip_prefix("VOIRC_POW_SET:") {
-                        handle_pow_set(rest, addr, state).await;
-                        return;
-                    }
-                }
-
-                let s = state.read().await;
-                let sender = s.clients.get(&addr);
-                let sender_nick = sender.and_then(|c| c.nick.clone()).unwrap_or_default();
-                let sender_authed = sender.map(|c| c.authenticated).unwrap_or(false);
-
-                if !sender_nick.is_empty()
-                    && s.nick_pubkeys.contains_key(&sender_nick)
-                    && !sender_authed
-                {
-                    warn!("Dropping PRIVMSG from unauthenticated client claiming nick {}", sender_nick);
-                    return;
+        "PASS" => {
+            // No server passwords; accepted and ignored before registration.
+            if registered {
+                state.read().await.reply(&addr, "462", ":You may not reregister");
+            }
+        }
+        "QUIT" => {
+            let reason = trailing(cmd, &parts, 1);
+            let reason = if reason.is_empty() { "Client Quit".to_string() } else { format!("Quit: {}", reason) };
+            let mut s = state.write().await;
+            if let Some(c) = s.clients.get_mut(&addr) {
+                let _ = c.tx.send(format!("ERROR :Closing Link: {} ({})\r\n", c.host, reason));
+                c.quit_reason = Some(reason);
+                c.closed.notify_one();
+            }
+        }
+        "PRIVMSG" | "NOTICE" | "TAGMSG" => {
+            let notice = command != "PRIVMSG";
+            let Some(targets) = parts.get(1).filter(|t| !t.starts_with(':')) else {
+                if !notice {
+                    state.read().await.reply(&addr, "411", ":No recipient given (PRIVMSG)");
                 }
-
-                let full_mask = format!("{}!voirc@127.0.0.1", sender_nick);
-                let raw_msg = format!(":{} PRIVMSG {} :{}\r\n", full_mask, target, text);
-
-                if let Some(members) = s.channels.get(target) {
-                    for member in members {
-                        if *member != addr {
-                            if let Some(c) = s.clients.get(member) {
-                                let _ = c.tx.send(raw_msg.clone());
-                            }
-                        }
-                    }
-                } else {
-                    for client in s.clients.values() {
-                        if client.nick.as_deref() == Some(target) {
-                            let _ = client.tx.send(raw_msg.clone());
-                            break;
-                        }
-                    }
+                return;
+            };
+            let text = if command == "TAGMSG" { "" } else { trailing(cmd, &parts, 2) };
+            if text.is_empty() && command != "TAGMSG" {
+                if !notice {
+                    state.read().await.reply(&addr, "412", ":No text to send");
                 }
+                return;
+            }
+            for target in targets.split(',') {
+                handle_privmsg(&command, target, text, &tags, addr, state).await;
             }
         }
         "JOIN" => {
-            if parts.len() > 1 {
-                let channel = parts[1].to_string();
-                let mut names_list = Vec::new();
-                let nick_opt = {
-                    let s = state.read().await;
-                    s.clients.get(&addr).and_then(|c| c.nick.clone())
-                };
-                if let Some(n) = nick_opt {
-                    let mut s = state.write().await;
-                    s.channels.entry(channel.clone()).or_default().insert(addr);
-                    let full_mask = format!("{}!voirc@127.0.0.1", n);
-                    let join_msg = format!(":{} JOIN {}\r\n", full_mask, channel);
-                    if let Some(members) = s.channels.get(&channel) {
-                        for member in members {
-                            if let Some(c) = s.clients.get(member) {
-                                let _ = c.tx.send(join_msg.clone());
-                                if let Some(mn) = &c.nick { names_list.push(mn.clone()); }
-                            }
-                        }
+            // JOIN #a,#b key_a,key_b
+            match parts.get(1) {
+                None => state.read().await.reply(&addr, "461", "JOIN :Not enough parameters"),
+                Some(&"0") => {
+                    let joined: Vec<String> = state.read().await.channels.iter()
+                        .filter(|(_, m)| m.contains(&addr))
+                        .map(|(ch, _)| ch.clone())
+                        .collect();
+                    for channel in joined {
+                        handle_part(&channel, "Left all channels", addr, state).await;
                     }
-                    if let Some(c) = s.clients.get(&addr) {
-                        let names_str = names_list.join(" ");
-                        let _ = c.tx.send(format!(":voirc 353 {} = {} :{}\r\n", n, channel, names_str));
-                        let _ = c.tx.send(format!(":voirc 366 {} {} :End of /NAMES list.\r\n", n, channel));
+                }
+                Some(list) => {
+                    let keys: Vec<&str> = parts.get(2).map(|k| k.split(',').collect()).unwrap_or_default();
+                    for (i, channel) in list.split(',').enumerate() {
+                        handle_join(channel, keys.get(i).copied(), addr, state).await;
                     }
                 }
             }
         }
         "KICK" => {
-            if parts.len() >= 3 {
-                let channel = parts[1].to_string();
-                let target_nick = parts[2];
-                let reason = cmd.find(':').map(|i| &cmd[i+1..]).unwrap_or("Kicked");
-                let mut s = state.write().await;
-                let kicker_authed = s.clients.get(&addr).map(|c| c.authenticated).unwrap_or(false);
-                if !kicker_authed {
-                    warn!("Unauthenticated client at {} tried to kick", addr);
-                    return;
-                }
-                let kicker_nick = s.clients.get(&addr).and_then(|c| c.nick.clone()).unwrap_or_default();
-                if let Some(target_addr) = s.find_addr_by_nick(target_nick) {
-                    let full_mask = format!("{}!voirc@127.0.0.1", kicker_nick);
-                    let kick_msg = format!(":{} KICK {} {} :{}\r\n", full_mask, channel, target_nick, reason);
-                    if let Some(members) = s.channels.get(&channel) {
-                        for member in members.iter() {
-                            if let Some(c) = s.clients.get(member) {
-                                let _ = c.tx.send(kick_msg.clone());
-                            }
-                        }
-                    }
-                    if let Some(members) = s.channels.get_mut(&channel) {
-                        members.remove(&target_addr);
-                    }
+            if parts.len() < 3 {
+                return state.read().await.reply(&addr, "461", "KICK :Not enough parameters");
+            }
+            let channel = parts[1].to_string();
+            let target_nick = parts[2];
+            let reason = match trailing(cmd, &parts, 3) {
+                "" => "Kicked",
+                r => r,
+            };
+            let mut s = state.write().await;
+            let kicker_authed = s.clients.get(&addr).map(|c| c.authenticated).unwrap_or(false);
+            if !kicker_authed {
+                warn!("Unauthenticated client at {} tried to kick", addr);
+                return s.reply(&addr, "482", &format!("{} :You're not channel operator", channel));
+            }
+            if !s.channels.contains_key(&channel) {
+                return s.reply(&addr, "403", &format!("{} :No such channel", channel));
+            }
+            if !s.is_member(&channel, &addr) {
+                return s.reply(&addr, "442", &format!("{} :You're not on that channel", channel));
+            }
+            let Some(target_addr) = s.find_addr_by_nick(target_nick).filter(|a| s.is_member(&channel, a)) else {
+                return s.reply(&addr, "441", &format!("{} {} :They aren't on that channel", target_nick, channel));
+            };
+            let kicker_nick = s.nick_of(&addr);
+            let kicker_role = s.role_of(&addr);
+            let target_role = s.role_of(&target_addr);
+            if !may_act_on(kicker_role, target_role) {
+                warn!("{} ({}) may not kick {}", kicker_nick, kicker_role.as_str(), target_nick);
+                return s.reply(&addr, "482", &format!("{} :You're not allowed to kick {}", channel, target_nick));
+            }
+            let target_nick = s.nick_of(&target_addr);
+            let kick_msg = format!(":{} KICK {} {} :{}\r\n", s.mask(&addr), channel, target_nick, reason);
+            if let Some(members) = s.channels.get(&channel) {
+                for member in members.iter() {
+                    if let Some(c) = s.clients.get(member) {
+                        let _ = c.tx.send(kick_msg.clone());
+                    }
+                }
+            }
+            if let Some(members) = s.channels.get_mut(&channel) {
+                members.remove(&target_addr);
+            }
+            if let Some(info) = s.channel_info.get_mut(&channel) {
+                info.voiced.remove(&target_addr);
+            }
+        }
+        "PART" => match parts.get(1) {
+            None => state.read().await.reply(&addr, "461", "PART :Not enough parameters"),
+            Some(list) => {
+                let reason = trailing(cmd, &parts, 2);
+                for channel in list.split(',') {
+                    handle_part(channel, reason, addr, state).await;
                 }
             }
+        },
+        "TOPIC" if parts.len() > 1 => {
+            let topic = parts.get(2).map(|_| trailing(cmd, &parts, 2));
+            handle_topic(parts[1], topic, addr, state).await;
         }
-        "PART" => {
-            if parts.len() > 1 {
-                let channel = parts[1].to_string();
-                let mut s = state.write().await;
-                let nick_opt = s.clients.get(&addr).and_then(|c| c.nick.clone());
-                if let Some(n) = nick_opt {
-                    let full_mask = format!("{}!voirc@127.0.0.1", n);
-                    let part_msg = format!(":{} PART {}\r\n", full_mask, channel);
-                    if let Some(members) = s.channels.get(&channel) {
-                        for member in members.iter() {
-                            if *member != addr {
-                                if let Some(c) = s.clients.get(member) {
-                                    let _ = c.tx.send(part_msg.clone());
-                                }
-                            }
+        "MODE" if parts.len() > 1 => {
+            handle_mode(parts[1], &parts[2..], addr, state).await;
+        }
+        "NAMES" => {
+            let s = state.read().await;
+            match parts.get(1) {
+                Some(list) => {
+                    for channel in list.split(',') {
+                        if s.channels.contains_key(channel) && s.visible_to(channel, &addr) {
+                            s.send_names(channel, &addr);
+                        } else {
+                            s.reply(&addr, "366", &format!("{} :End of /NAMES list.", channel));
                         }
                     }
-                    if let Some(members) = s.channels.get_mut(&channel) {
-                        members.remove(&addr);
+                }
+                None => s.reply(&addr, "366", "* :End of /NAMES list."),
+            }
+        }
+        "WHO" if parts.len() > 1 => {
+            let s = state.read().await;
+            let mask = parts[1];
+            if mask.starts_with('#') {
+                if s.visible_to(mask, &addr) {
+                    for member in s.channels.get(mask).into_iter().flatten() {
+                        s.who_line(&addr, mask, member);
                     }
                 }
+            } else if let Some(member) = s.find_addr_by_nick(mask) {
+                s.who_line(&addr, "*", &member);
+            }
+            s.reply(&addr, "315", &format!("{} :End of /WHO list.", mask));
+        }
+        "WHOIS" if parts.len() > 1 => {
+            // WHOIS [server] nick[,nick]
+            let s = state.read().await;
+            for nick in parts[parts.len() - 1].split(',').take(5) {
+                s.send_whois(&addr, nick);
+            }
+        }
+        "ISON" => {
+            let s = state.read().await;
+            let online: Vec<String> = parts[1..].iter()
+                .map(|n| n.trim_start_matches(':'))
+                .filter_map(|n| s.find_addr_by_nick(n))
+                .map(|a| s.nick_of(&a))
+                .collect();
+            s.reply(&addr, "303", &format!(":{}", online.join(" ")));
+        }
+        "USERHOST" => {
+            let s = state.read().await;
+            let replies: Vec<String> = parts[1..].iter()
+                .take(5)
+                .filter_map(|n| s.find_addr_by_nick(n))
+                .map(|a| {
+                    let op = if s.role_of(&a).is_superpeer() { "*" } else { "" };
+                    let away = if s.clients[&a].away.is_some() { '-' } else { '+' };
+                    let mask = s.mask(&a);
+                    let (nick, userhost) = mask.split_once('!').unwrap_or((&mask, ""));
+                    format!("{}{}={}{}", nick, op, away, userhost)
+                })
+                .collect();
+            s.reply(&addr, "302", &format!(":{}", replies.join(" ")));
+        }
+        "AWAY" => {
+            let message = trailing(cmd, &parts, 1);
+            let mut s = state.write().await;
+            if let Some(c) = s.clients.get_mut(&addr) {
+                c.away = (!message.is_empty()).then(|| message.chars().take(200).collect());
+            }
+            if message.is_empty() {
+                s.reply(&addr, "305", ":You are no longer marked as being away");
+            } else {
+                s.reply(&addr, "306", ":You have been marked as being away");
+            }
+        }
+        "MOTD" => state.read().await.send_motd(&addr),
+        "LIST" => {
+            let s = state.read().await;
+            s.reply(&addr, "321", "Channel :Users  Name");
+            let mut names: Vec<&String> = s.channels.iter()
+                .filter(|(ch, members)| !members.is_empty() && s.visible_to(ch, &addr))
+                .map(|(ch, _)| ch)
+                .collect();
+            names.sort();
+            for channel in names {
+                let info = s.channel_info.get(channel);
+                let topic = info.and_then(|i| i.topic.as_ref()).map_or("", |t| t.text.as_str());
+                let modes = info.map(|i| i.mode_string(false)).filter(|m| m != "+");
+                let text = match modes {
+                    Some(m) => format!("[{}] {}", m, topic),
+                    None => topic.to_string(),
+                };
+                s.reply(&addr, "322", &format!("{} {} :{}", channel, s.channels[channel].len(), text.trim_end()));
             }
+            s.reply(&addr, "323", ":End of /LIST");
+        }
+        "INVITE" if parts.len() > 2 => {
+            handle_invite(parts[1], parts[2], addr, state).await;
         }
         "PING" => {
-            if parts.len() > 1 {
-                let s = state.read().await;
-                if let Some(c) = s.clients.get(&addr) {
-                    let _ = c.tx.send(format!("PONG {}\r\n", parts[1]));
+            let s = state.read().await;
+            match parts.get(1) {
+                Some(token) => {
+                    if let Some(c) = s.clients.get(&addr) {
+                        let _ = c.tx.send(format!(":{0} PONG {0} :{1}\r\n", SERVER_NAME, token.trim_start_matches(':')));
+                    }
                 }
+                None => s.reply(&addr, "409", ":No origin specified"),
             }
         }
-        _ => {}
+        "PONG" => {}
+        "TOPIC" | "MODE" | "WHO" | "WHOIS" | "INVITE" => {
+            state.read().await.reply(&addr, "461", &format!("{} :Not enough parameters", command));
+        }
+        _ => {
+            state.read().await.reply(&addr, "421", &format!("{} :Unknown command", parts[0]));
+        }
     }
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
-// VOIRC_HELLO
+// Registration
 // ─────────────────────────────────────────────────────────────────────────────
 //
-// Format: VOIRC_HELLO:<nick>:<pubkey_hex>:<sig_hex>
-//
-// The sig is ed25519 over nick bytes.  The PoW check is simply:
-//   leading_zero_bits(SHA256(nick || pubkey_hex)) >= pow_required_bits
-//
-// A nick mined at difficulty ≥ current requirement passes instantly and forever
-// (as long as the room doesn't raise the bar above what the nick was mined to).
-// A nick mined below the current requirement gets HELLO_FAILED pow_too_weak:<N>
-// and must re-mine.
+// RFC 2812 order: NICK and USER in either order, then 001–005 and the MOTD.
+// A client that starts CAP negotiation (LS or REQ) isn't registered until
+// CAP END.  Voirc clients REQ `voirc` so they keep getting WRTC:, SIGNED: and
+// VOIRC_* lines verbatim; everyone else gets them rendered or not at all
+// (see `render_line`).
 
-async fn handle_hello(rest: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
-    let parts: Vec<&str> = rest.splitn(3, ':').collect();
-    if parts.len() != 3 {
-        warn!("Malformed VOIRC_HELLO from {}", addr);
-        return;
+async fn handle_cap(cmd: &str, parts: &[&str], addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    let Some(c) = s.clients.get_mut(&addr) else { return };
+    let sub = parts.get(1).map(|p| p.to_ascii_uppercase()).unwrap_or_default();
+    if matches!(sub.as_str(), "LS" | "REQ") && !c.registered {
+        c.cap_negotiating = true;
     }
-    let (hello_nick, pubkey_hex, sig_hex) = (parts[0], parts[1], parts[2]);
+    let caps = Arc::clone(&c.caps);
+    match sub.as_str() {
+        "LS" => {
+            // 302 clients get the mechanism list as the cap's value.
+            let v302 = parts.get(2).and_then(|v| v.parse::<u32>().ok()).is_some_and(|v| v >= 302);
+            let offered: Vec<String> = CAPABILITIES.iter()
+                .map(|&c| match c {
+                    "sasl" if v302 => format!("sasl={}", SASL_MECHANISM),
+                    _ => c.to_string(),
+                })
+                .collect();
+            s.reply(&addr, "CAP", &format!("LS :{}", offered.join(" ")))
+        }
+        "LIST" => s.reply(&addr, "CAP", &format!("LIST :{}", caps.enabled().join(" "))),
+        "REQ" => {
+            let requested = trailing(cmd, parts, 2);
+            let known = requested.split_whitespace()
+                .all(|cap| caps.flag(cap.trim_start_matches('-')).is_some());
+            if known {
+                for cap in requested.split_whitespace() {
+                    let (name, on) = match cap.strip_prefix('-') {
+                        Some(name) => (name, false),
+                        None => (cap, true),
+                    };
+                    if let Some(flag) = caps.flag(name) {
+                        flag.store(on, Ordering::Relaxed);
+                    }
+                }
+                s.reply(&addr, "CAP", &format!("ACK :{}", requested));
+            } else {
+                s.reply(&addr, "CAP", &format!("NAK :{}", requested));
+            }
+        }
+        "END" => {
+            if let Some(c) = s.clients.get_mut(&addr) {
+                c.cap_negotiating = false;
+            }
+            s.try_register(&addr);
+        }
+        _ => s.reply(&addr, "410", &format!("{} :Invalid CAP command", sub)),
+    }
+}
 
-    // Decode + verify ed25519 sig
-    let pubkey_bytes = match hex::decode(pubkey_hex) {
-        Ok(b) if b.len() == 32 => b,
-        _ => { send_notice(state, addr, hello_nick, "HELLO_FAILED invalid_pubkey").await; return; }
-    };
-    let sig_bytes = match hex::decode(sig_hex) {
-        Ok(b) if b.len() == 64 => b,
-        _ => { send_notice(state, addr, hello_nick, "HELLO_FAILED invalid_signature").await; return; }
-    };
+async fn handle_nick(new_nick: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    if !valid_nick(new_nick) || new_nick.eq_ignore_ascii_case(SERVER_NAME) {
+        return s.reply(&addr, "432", &format!("{} :Erroneous nickname", new_nick));
+    }
+    let Some(client) = s.clients.get(&addr) else { return };
+    let old_nick = client.nick.clone();
+    if old_nick.as_deref() == Some(new_nick) {
+        return;
+    }
+    if s.find_addr_by_nick(new_nick).is_some_and(|a| a != addr) {
+        return s.reply(&addr, "433", &format!("{} :Nickname is already in use", new_nick));
+    }
+    // Before registration SASL may still prove the key; `try_register` checks.
+    if let Some(bound_key) = s.nick_pubkeys.get(new_nick).filter(|_| client.registered) {
+        if client.pubkey.as_deref() != Some(bound_key.as_str()) {
+            return s.reply(&addr, "433", &format!("{} :Nickname is already in use", new_nick));
+        }
+    }
+    // SASL bound the nick to the key; other users trust it by that.
+    if client.authenticated {
+        return s.reply(&addr, "484", ":Your nick is bound to your key; reconnect to change it");
+    }
 
-    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
-    let pk_arr: [u8; 32] = pubkey_bytes.try_into().unwrap();
-    let verifying_key = match VerifyingKey::from_bytes(&pk_arr) {
-        Ok(k) => k,
-        Err(_) => { send_notice(state, addr, hello_nick, "HELLO_FAILED invalid_pubkey").await; return; }
-    };
-    let sig_arr: [u8; 64] = sig_bytes.try_into().unwrap();
-    let signature = Signature::from_bytes(&sig_arr);
+    if !client.registered {
+        if let Some(c) = s.clients.get_mut(&addr) {
+            c.nick = Some(new_nick.to_string());
+        }
+        return s.try_register(&addr);
+    }
 
-    if verifying_key.verify(hello_nick.as_bytes(), &signature).is_err() {
-        warn!("VOIRC_HELLO sig failed for {} from {}", hello_nick, addr);
-        send_notice(state, addr, hello_nick, "HELLO_FAILED invalid_signature").await;
-        return;
+    // A guest renaming: tell them and everyone who shares a channel.
+    let line = format!(":{} NICK :{}\r\n", s.mask(&addr), new_nick);
+    let mut notified = HashSet::from([addr]);
+    for members in s.channels.values().filter(|m| m.contains(&addr)) {
+        notified.extend(members.iter().copied());
+    }
+    for member in &notified {
+        if let Some(c) = s.clients.get(member) {
+            let _ = c.tx.send(line.clone());
+        }
+    }
+    // Mutes on guests are kept by nick; they follow the rename.
+    if let Some(old) = old_nick {
+        for kind in [MuteKind::Chat, MuteKind::Voice] {
+            if let Some(mut mute) = s.mutes.remove(&(kind, format!("nick:{}", old))) {
+                mute.nick = new_nick.to_string();
+                s.mutes.insert((kind, format!("nick:{}", new_nick)), mute);
+            }
+        }
+    }
+    if let Some(c) = s.clients.get_mut(&addr) {
+        c.nick = Some(new_nick.to_string());
     }
+    s.publish_holder(&addr);
+}
 
-    // Nick must match what client sent via NICK
-    {
-        let s = state.read().await;
-        if s.clients.get(&addr).and_then(|c| c.nick.as_deref()) != Some(hello_nick) {
-            send_notice(state, addr, hello_nick, "HELLO_FAILED nick_mismatch").await;
+// ─────────────────────────────────────────────────────────────────────────────
+// PRIVMSG / NOTICE / TAGMSG
+// ─────────────────────────────────────────────────────────────────────────────
+
+async fn handle_privmsg(
+    command: &str,
+    target: &str,
+    text: &str,
+    tags: &[String],
+    addr: SocketAddr,
+    state: &Arc<RwLock<ServerState>>,
+) {
+    // NOTICE never gets an error back (RFC 2812 3.3.2).
+    let notice = command != "PRIVMSG";
+
+    if target == SERVER_NAME {
+        if notice {
             return;
         }
+        if let Some(rest) = text.strip_prefix("VOIRC_POW_SET:") {
+            handle_pow_set(rest, addr, state).await;
+        } else if let Some(rest) = text.strip_prefix("VOIRC_ROLE_SET:") {
+            handle_role_set(rest, addr, state).await;
+        } else if let Some(rest) = text.strip_prefix("VOIRC_BAN:") {
+            handle_ban(rest, addr, state).await;
+        } else if let Some(rest) = text.strip_prefix("VOIRC_UNBAN:") {
+            handle_unban(rest.trim(), addr, state).await;
+        } else if text.trim() == "VOIRC_BANLIST" {
+            handle_ban_list(addr, state).await;
+        } else if let Some(rest) = text.strip_prefix("VOIRC_MUTE:") {
+            handle_mute(rest, addr, state).await;
+        } else if let Some(rest) = text.strip_prefix("VOIRC_UNMUTE:") {
+            handle_unmute(rest.trim(), addr, state).await;
+        } else if let Some(rest) = text.strip_prefix("VOIRC_SLOWMODE:") {
+            handle_slow_mode(rest.trim(), addr, state).await;
+        }
+        return;
     }
 
-    // PoW check: verify SHA256(nick || pubkey_hex) has enough leading zero bits.
-    // This is a pure hash check — no grinding at join time.
-    {
-        let s = state.read().await;
-        let required = s.pow_required_bits;
-        if !pow::check_difficulty(hello_nick, pubkey_hex, required) {
-            let actual = pow::leading_zero_bits(&pow::nick_hash(hello_nick, pubkey_hex));
-            warn!(
-                "VOIRC_HELLO PoW too weak for {}: {} bits < {} required",
-                hello_nick, actual, required
-            );
-            let msg = format!("HELLO_FAILED pow_too_weak:{}", required);
-            send_notice(state, addr, hello_nick, &msg).await;
+    if target.starts_with('#') {
+        let mut s = state.write().await;
+        let signaling = command != "TAGMSG" && is_signaling(text);
+        if let Err(why) = s.can_send(target, &addr, signaling) {
+            // Signaling isn't something the user typed; drop it quietly.
+            if !notice && !signaling {
+                s.reply(&addr, "404", &format!("{} :{}", target, why));
+            }
             return;
         }
+        if command != "TAGMSG" && !signaling {
+            if s.channel_info.get(target).is_some_and(|i| i.encrypted) && !is_sealed_chat(text) {
+                if !notice {
+                    s.reply(&addr, "404", &format!("{} :Cannot send to channel (+E, end-to-end encrypted only)", target));
+                }
+                return;
+            }
+            if let Err(why) = s.check_chat(&addr, target, now_secs()) {
+                if !notice {
+                    s.reply(&addr, "NOTICE", &format!(":VOIRC_INFO {}", why));
+                }
+                return;
+            }
+        }
     }
 
-    // Bind nick → pubkey, mark authenticated, broadcast
+    let s = state.read().await;
+    let sender = s.clients.get(&addr);
+    let sender_nick = sender.and_then(|c| c.nick.clone()).unwrap_or_default();
+    let sender_authed = sender.map(|c| c.authenticated).unwrap_or(false);
+
+    if !sender_nick.is_empty()
+        && s.nick_pubkeys.contains_key(&sender_nick)
+        && !sender_authed
     {
-        let mut s = state.write().await;
+        warn!("Dropping {} from unauthenticated client claiming nick {}", command, sender_nick);
+        return;
+    }
 
-        if !s.try_bind_nick_pubkey(hello_nick, pubkey_hex) {
-            warn!("Nick {} claimed by different key, rejecting {}", hello_nick, addr);
-            if let Some(c) = s.clients.get(&addr) {
-                let _ = c.tx.send(format!(
-                    ":voirc 433 * {} :Nickname is already in use (key mismatch)\r\n", hello_nick
-                ));
+    // A client can't say what its own role is; answer with ours.
+    if text.starts_with("VOIRC_ROLE:") {
+        if let (Some(members), Some(line)) = (s.channels.get(target), s.role_line(target, &addr, None)) {
+            for member in members {
+                if let Some(c) = s.clients.get(member) {
+                    let _ = c.tx.send(line.clone());
+                }
             }
+        }
+        return;
+    }
+    if let Some(rest) = text.strip_prefix("VOIRC_MOD:") {
+        let allowed = rest.split_once(':')
+            .and_then(|(action, target)| ModAction::from_wire(action, target))
+            .is_some_and(|action| moderation::check_permission(s.role_of(&addr), &action).is_ok());
+        if !allowed {
+            warn!("Dropping VOIRC_MOD from {} ({}): not permitted", sender_nick, s.role_of(&addr).as_str());
+            s.reply(&addr, "NOTICE", ":VOIRC_DENIED permission");
             return;
         }
+    }
 
-        if let Some(c) = s.clients.get_mut(&addr) {
-            c.pubkey = Some(pubkey_hex.to_string());
-            c.authenticated = true;
+    let tag_prefix = if tags.is_empty() { String::new() } else { format!("@{} ", tags.join(";")) };
+    let raw_msg = match command {
+        "TAGMSG" if tags.is_empty() => return,
+        "TAGMSG" => format!("{}:{} TAGMSG {}\r\n", tag_prefix, s.mask(&addr), target),
+        _ => format!("{}:{} {} {} :{}\r\n", tag_prefix, s.mask(&addr), command, target, text),
+    };
+
+    if let Some(members) = s.channels.get(target) {
+        for member in members {
+            if *member != addr {
+                if let Some(c) = s.clients.get(member) {
+                    let _ = c.tx.send(raw_msg.clone());
+                }
+            }
+        }
+    } else if let Some(to) = s.find_addr_by_nick(target) {
+        let c = &s.clients[&to];
+        let _ = c.tx.send(raw_msg);
+        if let (Some(away), "PRIVMSG") = (&c.away, command) {
+            s.reply(&addr, "301", &format!("{} :{}", s.nick_of(&to), away));
         }
+    } else if !notice {
+        s.reply(&addr, "401", &format!("{} :No such nick/channel", target));
+    }
+}
 
-        let actual_bits = pow::leading_zero_bits(&pow::nick_hash(hello_nick, pubkey_hex));
-        info!("Auth OK: {} pow={} bits pubkey={}...", hello_nick, actual_bits, &pubkey_hex[..8]);
+async fn handle_part(channel: &str, reason: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    if !s.channels.contains_key(channel) {
+        return s.reply(&addr, "403", &format!("{} :No such channel", channel));
+    }
+    if !s.is_member(channel, &addr) {
+        return s.reply(&addr, "442", &format!("{} :You're not on that channel", channel));
+    }
+    let part_msg = match reason {
+        "" => format!(":{} PART {}\r\n", s.mask(&addr), channel),
+        r => format!(":{} PART {} :{}\r\n", s.mask(&addr), channel, r),
+    };
+    for member in s.channels.get(channel).into_iter().flatten() {
+        if let Some(c) = s.clients.get(member) {
+            let _ = c.tx.send(part_msg.clone());
+        }
+    }
+    if let Some(members) = s.channels.get_mut(channel) {
+        members.remove(&addr);
+    }
+    if let Some(info) = s.channel_info.get_mut(channel) {
+        info.voiced.remove(&addr);
+    }
+}
 
+async fn handle_join(channel: &str, key: Option<&str>, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let channel = channel.to_string();
+    let nick_opt = {
+        let s = state.read().await;
+        s.clients.get(&addr).and_then(|c| c.nick.clone())
+    };
+    if let Some(n) = nick_opt {
+        let mut s = state.write().await;
+        if !channel.starts_with('#') || channel.len() > 50 {
+            s.reply(&addr, "403", &format!("{} :No such channel", channel));
+            return;
+        }
+        if s.is_member(&channel, &addr) {
+            return;
+        }
+        if let Some((numeric, mode)) = s.join_refusal(&channel, &addr, key) {
+            s.reply(&addr, numeric, &format!("{} :Cannot join channel (+{})", channel, mode));
+            return;
+        }
+        if let Some(sub) = s.subject(&addr) {
+            if let Some(info) = s.channel_info.get_mut(&channel) {
+                info.invited.remove(&sub);
+            }
+        }
+        s.channels.entry(channel.clone()).or_default().insert(addr);
+        let join_msg = format!(":{} JOIN {}\r\n", s.mask(&addr), channel);
+        // SASL bound the key before we were in any channel; tell the
+        // channel now.
+        let pubkey_msg = s.clients.get(&addr)
+            .and_then(|c| c.pubkey.as_ref())
+            .map(|pk| format!(":voirc PRIVMSG {} :VOIRC_PUBKEY:{}:{}\r\n", channel, n, pk));
+        if let Some(members) = s.channels.get(&channel) {
+            for member in members {
+                if let Some(c) = s.clients.get(member) {
+                    if *member != addr {
+                        if let Some(pm) = &pubkey_msg {
+                            let _ = c.tx.send(pm.clone());
+                        }
+                    }
+                    let _ = c.tx.send(join_msg.clone());
+                }
+            }
+        }
+        if s.channel_info.get(&channel).is_some_and(|i| i.topic.is_some()) {
+            s.send_topic(&channel, &addr);
+        }
+        s.send_names(&channel, &addr);
         if let Some(c) = s.clients.get(&addr) {
-            let _ = c.tx.send(format!(
-                ":voirc NOTICE {} :HELLO_OK pow_bits:{}\r\n", hello_nick, actual_bits
-            ));
-        }
-
-        let broadcast = format!(":voirc PRIVMSG * :VOIRC_PUBKEY:{}:{}\r\n", hello_nick, pubkey_hex);
-        let mut notified = HashSet::new();
-        for members in s.channels.values() {
-            if members.contains(&addr) {
-                for &member_addr in members {
-                    if member_addr != addr && notified.insert(member_addr) {
-                        if let Some(c) = s.clients.get(&member_addr) {
-                            let _ = c.tx.send(broadcast.clone());
+
+            // The joiner's own role, then everyone who isn't a plain peer.
+            if let Some(line) = s.role_line(&channel, &addr, None) {
+                let _ = c.tx.send(line);
+            }
+            if let Some(members) = s.channels.get(&channel) {
+                for member in members {
+                    if *member != addr && s.role_of(member) != Role::Peer {
+                        if let Some(line) = s.role_line(&channel, member, None) {
+                            let _ = c.tx.send(line);
+                        }
+                    }
+                }
+            }
+
+            // Mutes on anyone here (the joiner included) and the
+            // channel's slow mode.
+            let now = now_secs();
+            let mut sync = Vec::new();
+            if let Some(members) = s.channels.get(&channel) {
+                for member in members {
+                    let Some(nick) = s.clients.get(member).and_then(|m| m.nick.as_deref()) else { continue };
+                    for kind in [MuteKind::Chat, MuteKind::Voice] {
+                        if let Some(mute) = s.active_mute(member, kind, now) {
+                            sync.push(ServerState::mute_text(kind, nick, mute, now, false));
+                        }
+                    }
+                }
+            }
+            if s.slow_modes.get(&channel).is_some_and(|sm| is_active(sm.expires, now)) {
+                sync.push(s.slow_mode_text(&channel, now, None));
+            }
+            if s.channel_info.get(&channel).is_some_and(|i| i.encrypted) {
+                sync.push("VOIRC_E2E:1".to_string());
+            }
+            for text in sync {
+                let _ = c.tx.send(format!(":{} PRIVMSG {} :{}\r\n", SERVER_NAME, channel, text));
+            }
+        }
+        // Everyone else needs the joiner's mutes too.
+        let now = now_secs();
+        for kind in [MuteKind::Chat, MuteKind::Voice] {
+            if let Some(mute) = s.active_mute(&addr, kind, now) {
+                let line = format!(
+                    ":{} PRIVMSG {} :{}\r\n",
+                    SERVER_NAME, channel, ServerState::mute_text(kind, &n, mute, now, false)
+                );
+                if let Some(members) = s.channels.get(&channel) {
+                    for member in members {
+                        if *member != addr {
+                            if let Some(c) = s.clients.get(member) {
+                                let _ = c.tx.send(line.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if s.role_of(&addr) != Role::Peer {
+            if let (Some(members), Some(line)) = (s.channels.get(&channel), s.role_line(&channel, &addr, None)) {
+                for member in members {
+                    if *member != addr {
+                        if let Some(c) = s.clients.get(member) {
+                            let _ = c.tx.send(line.clone());
                         }
                     }
                 }
@@ -511,14 +1738,137 @@ async fn handle_hello(rest: &str, addr: SocketAddr, state: &Arc<RwLock<ServerSta
     }
 }
 
+// ─────────────────────────────────────────────────────────────────────────────
+// SASL (VOIRC-ED25519)
+// ─────────────────────────────────────────────────────────────────────────────
+//
+//   C: CAP REQ :voirc sasl
+//   C: AUTHENTICATE VOIRC-ED25519
+//   S: AUTHENTICATE <base64 32-byte nonce>
+//   C: AUTHENTICATE <base64 "<pubkey_hex>:<ts_ms>:<sig_hex>">
+//   S: 900 / 903, or 904 with the reason
+//   C: CAP END
+//
+// The signature covers `sasl_payload`: our cert fingerprint, the nick, the
+// nonce and a timestamp, so it is only good for this nick on this connection
+// to this server.  It all happens before 001: the nick is never usable
+// until the key behind it is known.  The PoW check is simply:
+//   leading_zero_bits(SHA256(nick || pubkey_hex)) >= pow_required_bits
+//
+// A nick mined at difficulty ≥ current requirement passes instantly and forever
+// (as long as the room doesn't raise the bar above what the nick was mined to).
+// A nick mined below the current requirement fails with pow_too_weak:<N> and
+// must re-mine.
+
+/// What signatures say about which server they were made for: the pinned
+/// cert fingerprint, or `plaintext` without TLS.
+pub fn channel_binding(cert_fingerprint: Option<&str>) -> &str {
+    cert_fingerprint.unwrap_or("plaintext")
+}
+
+/// What the client signs for `AUTHENTICATE`.
+pub fn sasl_payload(binding: &str, nick: &str, pubkey_hex: &str, nonce: &[u8], ts: u64) -> String {
+    format!("voirc-sasl:{}:{}:{}:{}:{}", binding, nick, pubkey_hex, hex::encode(nonce), ts)
+}
+
+/// What a peer signs for `VOIRC_HELLO` in `channel`.  `session` is the
+/// nonce from `VOIRC_SESSION`, as hex.
+pub fn hello_payload(binding: &str, session: &str, channel: &str, nick: &str, pubkey_hex: &str, ts: u64) -> String {
+    format!("voirc-hello:{}:{}:{}:{}:{}:{}", binding, session, channel, nick, pubkey_hex, ts)
+}
+
+async fn handle_authenticate(arg: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    use base64::{engine::general_purpose, Engine as _};
+
+    let mut s = state.write().await;
+    let Some(c) = s.clients.get_mut(&addr) else { return };
+    if c.authenticated {
+        return s.reply(&addr, "907", ":You have already authenticated using SASL");
+    }
+    if arg == "*" {
+        c.sasl_nonce = None;
+        return s.reply(&addr, "906", ":SASL authentication aborted");
+    }
+    if c.registered || !c.caps.sasl.load(Ordering::Relaxed) {
+        return s.reply(&addr, "904", ":SASL authentication failed: not_negotiated");
+    }
+
+    let Some(nonce) = c.sasl_nonce.take() else {
+        if !arg.eq_ignore_ascii_case(SASL_MECHANISM) {
+            s.reply(&addr, "908", &format!("{} :are available SASL mechanisms", SASL_MECHANISM));
+            return s.reply(&addr, "904", ":SASL authentication failed: unknown_mechanism");
+        }
+        let nonce: [u8; 32] = rand::random();
+        c.sasl_nonce = Some(nonce);
+        let _ = c.tx.send(format!("AUTHENTICATE {}\r\n", general_purpose::STANDARD.encode(nonce)));
+        return;
+    };
+    let (nick, ip) = (c.nick.clone(), c.ip);
+    if arg.len() > SASL_MAX_RESPONSE {
+        return s.reply(&addr, "905", ":SASL message too long");
+    }
+    let fail = |s: &ServerState, reason: &str| {
+        s.reply(&addr, "904", &format!(":SASL authentication failed: {}", reason));
+    };
+
+    let Some(nick) = nick else { return fail(&s, "no_nick") };
+    let response = general_purpose::STANDARD.decode(arg).ok().and_then(|b| String::from_utf8(b).ok());
+    let parts: Vec<&str> = response.as_deref().map_or(Vec::new(), |r| r.splitn(3, ':').collect());
+    let [pubkey_hex, ts, sig_hex] = parts[..] else { return fail(&s, "malformed") };
+    // Bans, the host key and nick bindings compare keys as text, so only
+    // the one spelling `hex::encode` gives is accepted.
+    if pubkey_hex.len() != 64 || !pubkey_hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return fail(&s, "malformed");
+    }
+    let Ok(ts) = ts.parse::<u64>() else { return fail(&s, "malformed") };
+    if ts.abs_diff(now_ms()) > AUTH_MAX_SKEW_MS {
+        return fail(&s, "stale_timestamp");
+    }
+    let binding = channel_binding(s.cert_fingerprint.as_deref());
+    if !verify_signature(pubkey_hex, sasl_payload(binding, &nick, pubkey_hex, &nonce, ts).as_bytes(), sig_hex) {
+        warn!("SASL signature failed for {} from {}", nick, addr);
+        return fail(&s, "invalid_signature");
+    }
+
+    // Banned keys (or addresses) are refused and disconnected.  The host key
+    // can't be locked out of its own room.
+    let ban = s.bans.read().unwrap().find(Some(pubkey_hex), Some(ip), now_secs()).cloned();
+    if let Some(ban) = ban.filter(|_| s.host_pubkey.as_deref() != Some(pubkey_hex)) {
+        warn!("SASL from banned key {} ({}) at {}", nick, &pubkey_hex[..8], addr);
+        fail(&s, &format!("banned:{}", ban.reason));
+        return s.drop_client(&addr, SERVER_NAME, "Banned");
+    }
+
+    // A pure hash check — no grinding at login time.
+    let required = s.pow_required_bits;
+    if !pow::verify_nick(&nick, pubkey_hex, required) {
+        let actual = pow::leading_zero_bits(&pow::nick_hash(&nick, pubkey_hex));
+        warn!("SASL PoW too weak for {}: {} bits < {} required", nick, actual, required);
+        return fail(&s, &format!("pow_too_weak:{}", required));
+    }
+
+    if !s.try_bind_nick_pubkey(&nick, pubkey_hex) {
+        warn!("Nick {} claimed by different key, rejecting {}", nick, addr);
+        return fail(&s, "nick_bound_to_other_key");
+    }
+    if let Some(c) = s.clients.get_mut(&addr) {
+        c.pubkey = Some(pubkey_hex.to_string());
+        c.authenticated = true;
+    }
+    let actual_bits = pow::leading_zero_bits(&pow::nick_hash(&nick, pubkey_hex));
+    info!("Auth OK: {} pow={} bits pubkey={}...", nick, actual_bits, &pubkey_hex[..8]);
+    let account = &pubkey_hex[..16];
+    s.reply(&addr, "900", &format!("{} {} :You are now logged in as {}", s.mask(&addr), account, account));
+    s.reply(&addr, "903", ":SASL authentication successful");
+}
+
 // ─────────────────────────────────────────────────────────────────────────────
 // VOIRC_POW_SET
 // ─────────────────────────────────────────────────────────────────────────────
 //
 // Format: PRIVMSG voirc :VOIRC_POW_SET:<bits>
 //
-// Only authenticated clients may call this.  The client-side permission check
-// (host/mod role) is the first gate; this is the second.
+// Only the host and mods (per the server's role table) may call this.
 //
 // After changing difficulty, broadcasts VOIRC_POW_REQUIRED:<new_bits> to all
 // clients.  Any client whose current nick no longer meets the new bar will see
@@ -546,7 +1896,15 @@ async fn handle_pow_set(rest: &str, addr: SocketAddr, state: &Arc<RwLock<ServerS
             warn!("Unauthenticated client {} tried VOIRC_POW_SET", addr);
             return;
         }
-        s.clients.get(&addr).and_then(|c| c.nick.clone()).unwrap_or_default()
+        let nick = s.clients.get(&addr).and_then(|c| c.nick.clone()).unwrap_or_default();
+        if !s.role_of(&addr).can_moderate() {
+            warn!("Peer {} tried VOIRC_POW_SET", nick);
+            if let Some(c) = s.clients.get(&addr) {
+                let _ = c.tx.send(format!(":voirc NOTICE {} :VOIRC_POW_SET_FAILED permission\r\n", nick));
+            }
+            return;
+        }
+        nick
     };
 
     let mut s = state.write().await;
@@ -561,12 +1919,1214 @@ async fn handle_pow_set(rest: &str, addr: SocketAddr, state: &Arc<RwLock<ServerS
 }
 
 // ─────────────────────────────────────────────────────────────────────────────
-// Helper
+// VOIRC_ROLE_SET
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Format: PRIVMSG voirc :VOIRC_ROLE_SET:<nick>:<mod|peer>:<ts_ms>:<sig_hex>
+//
+// The sig is the host key over `moderation::role_grant_payload`.  Grants are
+// keyed by the target's pubkey, so a mod keeps the role if they reconnect,
+// for as long as this server runs.  On success the new role is broadcast to
+// the target's channels as VOIRC_ROLE:<nick>:<role>:<host nick>.
+
+async fn handle_role_set(rest: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let now_ms = now_ms();
+    let mut s = state.write().await;
+    let nick = s.clients.get(&addr).and_then(|c| c.nick.clone()).unwrap_or_default();
+    if s.role_of(&addr) != Role::Host {
+        warn!("Non-host {} sent VOIRC_ROLE_SET", nick);
+        if let Some(c) = s.clients.get(&addr) {
+            let _ = c.tx.send(format!(":voirc NOTICE {} :VOIRC_ROLE_SET_FAILED permission\r\n", nick));
+        }
+        return;
+    }
+    match s.apply_role_grant(rest, now_ms) {
+        Ok(target) => {
+            info!("Role change by {}: {}", nick, rest.split(':').take(2).collect::<Vec<_>>().join(" → "));
+            s.broadcast_role(&target, Some(&nick));
+        }
+        Err(reason) => {
+            warn!("VOIRC_ROLE_SET from {} rejected: {}", nick, reason);
+            if let Some(c) = s.clients.get(&addr) {
+                let _ = c.tx.send(format!(":voirc NOTICE {} :VOIRC_ROLE_SET_FAILED {}\r\n", nick, reason));
+            }
+        }
+    }
+}
+
 // ─────────────────────────────────────────────────────────────────────────────
+// VOIRC_BAN / VOIRC_UNBAN / VOIRC_BANLIST
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Format: PRIVMSG voirc :VOIRC_BAN:<nick|pubkey>:<secs, 0 = permanent>:<ip 0|1>:<reason>
+//         PRIVMSG voirc :VOIRC_UNBAN:<nick|pubkey|ip>
+//         PRIVMSG voirc :VOIRC_BANLIST
+//
+// Host and mods only, with the same rank rules as KICK.  The target may be
+// offline if given by full pubkey.  Everyone the new ban covers is kicked
+// from their channels ("Banned: <reason>") and disconnected.  Replies are
+// `VOIRC_INFO <text>` notices to the sender.
+
+async fn handle_ban(rest: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    let nick = s.clients.get(&addr).and_then(|c| c.nick.clone()).unwrap_or_default();
+    let reply = |s: &ServerState, text: &str| {
+        if let Some(c) = s.clients.get(&addr) {
+            let _ = c.tx.send(format!(":voirc NOTICE {} :{}\r\n", nick, text));
+        }
+    };
+    let role = s.role_of(&addr);
+    if !role.can_moderate() {
+        reply(&s, "VOIRC_DENIED permission");
+        return;
+    }
+    let parts: Vec<&str> = rest.splitn(4, ':').collect();
+    let [target, secs, with_ip, reason] = parts[..] else {
+        reply(&s, "VOIRC_DENIED malformed");
+        return;
+    };
+
+    let (pubkey, target_nick, target_ip, target_role) = match s.find_addr_by_nick(target) {
+        // A guest has no key, so the ban goes on their address alone.
+        Some(a) => {
+            let pk = s.clients.get(&a).filter(|c| c.authenticated).and_then(|c| c.pubkey.clone());
+            (pk.unwrap_or_default(), target.to_string(), s.clients.get(&a).map(|c| c.ip), s.role_of(&a))
+        }
+        None if target.len() == 64 && hex::decode(target).is_ok() => {
+            let pk = target.to_ascii_lowercase();
+            let role = if s.host_pubkey.as_deref() == Some(pk.as_str()) {
+                Role::Host
+            } else if s.mods.contains(&pk) {
+                Role::Mod
+            } else {
+                Role::Peer
+            };
+            (pk, target[..8].to_string(), None, role)
+        }
+        None => {
+            reply(&s, "VOIRC_DENIED no_such_nick (ban offline users by pubkey)");
+            return;
+        }
+    };
+    if !may_act_on(role, target_role) {
+        reply(&s, &format!("VOIRC_DENIED cannot ban {} ({})", target_nick, target_role.as_str()));
+        return;
+    }
+
+    // Loopback is where the host itself connects from.
+    let ip = target_ip.filter(|ip| (with_ip == "1" || pubkey.is_empty()) && !ip.is_loopback());
+    if pubkey.is_empty() && ip.is_none() {
+        reply(&s, &format!("VOIRC_DENIED cannot ban {}: a local guest has no key or address to ban", target_nick));
+        return;
+    }
+    let now = now_secs();
+    let secs: u64 = secs.parse().unwrap_or(0);
+    let ban = Ban {
+        pubkey,
+        ip,
+        nick: target_nick,
+        reason: reason.trim().to_string(),
+        by: nick.clone(),
+        by_role: role,
+        created: now,
+        expires: (secs > 0).then_some(now + secs),
+    };
+    // Re-banning replaces the old ban, so it takes the rank to lift it.
+    let held = s.bans.read().unwrap().replaced_by(&ban, now).is_some_and(|b| !may_lift(role, b.by_role));
+    if held {
+        reply(&s, &format!("VOIRC_DENIED cannot change the ban on {} (banned by the host)", ban.nick));
+        return;
+    }
+    info!("Ban by {}: {}", nick, ban.describe(now));
+    if let Err(e) = s.bans.write().unwrap().add(ban.clone()) {
+        error!("Failed to save ban list: {}", e);
+        reply(&s, "VOIRC_INFO Warning: the ban list could not be saved; this ban ends when the server stops");
+    }
+    reply(&s, &format!("VOIRC_INFO Banned {}", ban.describe(now)));
+
+    let kick_reason = if ban.reason.is_empty() {
+        "Banned".to_string()
+    } else {
+        format!("Banned: {}", ban.reason)
+    };
+    // An address ban also covers whoever shares it, but not anyone the
+    // banner couldn't have banned themselves.
+    let (covered, spared): (Vec<SocketAddr>, Vec<SocketAddr>) = s.clients.iter()
+        .filter(|(_, c)| {
+            (!ban.pubkey.is_empty() && c.pubkey.as_deref() == Some(ban.pubkey.as_str())) || ban.ip == Some(c.ip)
+        })
+        .map(|(a, _)| *a)
+        .partition(|a| may_act_on(role, s.role_of(a)));
+    let nicks = |addrs: &[SocketAddr]| -> Vec<String> {
+        addrs.iter().filter_map(|a| s.clients.get(a).and_then(|c| c.nick.clone())).collect()
+    };
+    let (dropped, kept) = (nicks(&covered), nicks(&spared));
+    for a in covered {
+        s.drop_client(&a, &nick, &kick_reason);
+    }
+    if !dropped.is_empty() {
+        reply(&s, &format!("VOIRC_INFO Disconnected {}", dropped.join(", ")));
+    }
+    if !kept.is_empty() {
+        reply(&s, &format!(
+            "VOIRC_INFO Left {} connected from the banned address (they outrank you), but they can't reconnect from it",
+            kept.join(", ")
+        ));
+    }
+}
 
-async fn send_notice(state: &Arc<RwLock<ServerState>>, addr: SocketAddr, nick: &str, msg: &str) {
+async fn handle_unban(query: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
     let s = state.read().await;
+    let nick = s.clients.get(&addr).and_then(|c| c.nick.clone()).unwrap_or_default();
+    let role = s.role_of(&addr);
+    let text = if !role.can_moderate() {
+        "VOIRC_DENIED permission".to_string()
+    } else {
+        let mut bans = s.bans.write().unwrap();
+        // All or nothing, so a mod can't lift the host's ban by IP or prefix.
+        let outranked: Vec<&str> = bans.matching(query)
+            .filter(|b| !may_lift(role, b.by_role))
+            .map(|b| b.nick.as_str())
+            .collect();
+        if !outranked.is_empty() {
+            format!("VOIRC_DENIED cannot unban {} (banned by the host)", outranked.join(", "))
+        } else {
+            match bans.remove(query) {
+                Ok(removed) if removed.is_empty() => format!("VOIRC_INFO No ban matches {}", query),
+                Ok(removed) => {
+                    let names: Vec<&str> = removed.iter().map(|b| b.nick.as_str()).collect();
+                    info!("Unban by {}: {}", nick, names.join(", "));
+                    format!("VOIRC_INFO Unbanned {}", names.join(", "))
+                }
+                Err(e) => {
+                    error!("Failed to save ban list: {}", e);
+                    "VOIRC_INFO Unbanned until the server restarts (the ban list could not be saved)".to_string()
+                }
+            }
+        }
+    };
     if let Some(c) = s.clients.get(&addr) {
-        let _ = c.tx.send(format!(":voirc NOTICE {} :{}\r\n", nick, msg));
+        let _ = c.tx.send(format!(":voirc NOTICE {} :{}\r\n", nick, text));
+    }
+}
+
+async fn handle_ban_list(addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let s = state.read().await;
+    let Some(c) = s.clients.get(&addr) else { return };
+    let nick = c.nick.clone().unwrap_or_default();
+    if !s.role_of(&addr).can_moderate() {
+        let _ = c.tx.send(format!(":voirc NOTICE {} :VOIRC_DENIED permission\r\n", nick));
+        return;
+    }
+    let now = now_secs();
+    let mut bans = s.bans.write().unwrap();
+    bans.prune(now);
+    let lines: Vec<String> = bans.active(now).map(|b| b.describe(now)).collect();
+    if lines.is_empty() {
+        let _ = c.tx.send(format!(":voirc NOTICE {} :VOIRC_INFO No active bans\r\n", nick));
+        return;
+    }
+    let _ = c.tx.send(format!(":voirc NOTICE {} :VOIRC_INFO -- Banned ({}) --\r\n", nick, lines.len()));
+    for line in lines {
+        let _ = c.tx.send(format!(":voirc NOTICE {} :VOIRC_INFO {}\r\n", nick, line));
+    }
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// VOIRC_MUTE / VOIRC_UNMUTE / VOIRC_SLOWMODE
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Format: PRIVMSG voirc :VOIRC_MUTE:<chat|voice>:<nick>:<secs, 0 = until lifted>
+//         PRIVMSG voirc :VOIRC_UNMUTE:<chat|voice>:<nick>
+//         PRIVMSG voirc :VOIRC_SLOWMODE:<#channel>:<interval secs, 0 = off>:<secs, 0 = until lifted>
+//
+// Host and mods only, same rank rules as KICK.  The result is announced to
+// the target's channels as
+//   :voirc PRIVMSG #chan :VOIRC_MUTE:<kind>:<nick>:<secs left>[:<by>]
+//   :voirc PRIVMSG #chan :VOIRC_UNMUTE:<kind>:<nick>:<by>
+//   :voirc PRIVMSG #chan :VOIRC_SLOWMODE:<interval>:<secs left>[:<by>]
+// where `by` is "voirc" when it ran out and missing when syncing a joiner.
+// The server drops chat from chat-muted users itself; voice mutes are
+// enforced by the superpeers, which stop forwarding the audio.
+
+async fn handle_mute(rest: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    let nick = s.clients.get(&addr).and_then(|c| c.nick.clone()).unwrap_or_default();
+    let deny = |s: &ServerState, why: &str| {
+        if let Some(c) = s.clients.get(&addr) {
+            let _ = c.tx.send(format!(":voirc NOTICE {} :VOIRC_DENIED {}\r\n", nick, why));
+        }
+    };
+    let role = s.role_of(&addr);
+    if !role.can_moderate() {
+        deny(&s, "permission");
+        return;
+    }
+    let parts: Vec<&str> = rest.trim().splitn(3, ':').collect();
+    let [kind, target, secs] = parts[..] else { return deny(&s, "malformed") };
+    let (Some(kind), Ok(secs)) = (MuteKind::from_wire(kind), secs.parse::<u64>()) else {
+        return deny(&s, "malformed");
+    };
+    let Some(target_addr) = s.find_addr_by_nick(target) else { return deny(&s, "no_such_nick") };
+    if !may_act_on(role, s.role_of(&target_addr)) {
+        return deny(&s, &format!("cannot mute {}", target));
+    }
+    let Some(subject) = s.subject(&target_addr) else { return };
+    // A new mute replaces the old one, so it takes the rank to lift it.
+    let now = now_secs();
+    if s.active_mute(&target_addr, kind, now).is_some_and(|m| !may_lift(role, m.by_role)) {
+        return deny(&s, &format!("cannot change the mute on {}", target));
+    }
+
+    let mute = Mute {
+        nick: target.to_string(),
+        by: nick.clone(),
+        by_role: role,
+        expires: (secs > 0).then_some(now + secs),
+    };
+    info!("{} {}-muted {} for {}s", nick, kind.as_str(), target, secs);
+    let text = ServerState::mute_text(kind, target, &mute, now, true);
+    s.mutes.insert((kind, subject), mute);
+    s.announce(&target_addr, &text);
+}
+
+async fn handle_unmute(rest: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    let nick = s.clients.get(&addr).and_then(|c| c.nick.clone()).unwrap_or_default();
+    let reply = |s: &ServerState, text: &str| {
+        if let Some(c) = s.clients.get(&addr) {
+            let _ = c.tx.send(format!(":voirc NOTICE {} :{}\r\n", nick, text));
+        }
+    };
+    let role = s.role_of(&addr);
+    if !role.can_moderate() {
+        return reply(&s, "VOIRC_DENIED permission");
+    }
+    let Some((kind, target)) = rest.split_once(':') else { return reply(&s, "VOIRC_DENIED malformed") };
+    let Some(kind) = MuteKind::from_wire(kind) else { return reply(&s, "VOIRC_DENIED malformed") };
+
+    // Online by nick, or an offline subject muted under that nick.
+    let target_addr = s.find_addr_by_nick(target);
+    let subject = target_addr.and_then(|a| s.subject(&a)).or_else(|| {
+        s.mutes.iter()
+            .find(|((k, _), m)| *k == kind && m.nick == target)
+            .map(|((_, subject), _)| subject.clone())
+    });
+    let Some(key) = subject.map(|sub| (kind, sub)).filter(|key| s.mutes.contains_key(key)) else {
+        return reply(&s, &format!("VOIRC_INFO {} is not {}-muted", target, kind.as_str()));
+    };
+    // Same rank rules as setting it, and the setter's rank counts too.
+    let outranked = target_addr.is_some_and(|a| !may_act_on(role, s.role_of(&a)));
+    if outranked || !may_lift(role, s.mutes[&key].by_role) {
+        return reply(&s, &format!("VOIRC_DENIED cannot unmute {}", target));
+    }
+    s.mutes.remove(&key);
+    info!("{} lifted the {} mute on {}", nick, kind.as_str(), target);
+    match target_addr {
+        Some(a) => s.announce(&a, &format!("VOIRC_UNMUTE:{}:{}:{}", kind.as_str(), target, nick)),
+        None => reply(&s, &format!("VOIRC_INFO Unmuted {}", target)),
+    }
+}
+
+async fn handle_slow_mode(rest: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    let nick = s.clients.get(&addr).and_then(|c| c.nick.clone()).unwrap_or_default();
+    let deny = |s: &ServerState, why: &str| {
+        if let Some(c) = s.clients.get(&addr) {
+            let _ = c.tx.send(format!(":voirc NOTICE {} :VOIRC_DENIED {}\r\n", nick, why));
+        }
+    };
+    if !s.role_of(&addr).can_moderate() {
+        return deny(&s, "permission");
+    }
+    let parts: Vec<&str> = rest.splitn(3, ':').collect();
+    let [channel, interval, secs] = parts[..] else { return deny(&s, "malformed") };
+    let (Ok(interval), Ok(secs)) = (interval.parse::<u64>(), secs.parse::<u64>()) else {
+        return deny(&s, "malformed");
+    };
+    if !s.channels.contains_key(channel) {
+        return deny(&s, "no_such_channel");
+    }
+
+    let now = now_secs();
+    if interval == 0 {
+        s.slow_modes.remove(channel);
+    } else {
+        s.slow_modes.insert(channel.to_string(), SlowMode {
+            interval,
+            expires: (secs > 0).then_some(now + secs),
+        });
+    }
+    info!("{} set slow mode in {} to {}s", nick, channel, interval);
+    s.announce_channel(channel, &s.slow_mode_text(channel, now, Some(&nick)));
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// TOPIC / MODE / INVITE
+// ─────────────────────────────────────────────────────────────────────────────
+//
+// Host and mods are the operators of every channel: they set the topic and
+// the i/k/l/m/v modes and may always join.  Op status itself follows the
+// role table, so +o/-o are refused; use VOIRC_ROLE_SET.
+
+async fn handle_topic(channel: &str, topic: Option<&str>, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    if !s.channels.contains_key(channel) || !s.visible_to(channel, &addr) {
+        return s.reply(&addr, "403", &format!("{} :No such channel", channel));
+    }
+    let Some(text) = topic else { return s.send_topic(channel, &addr) };
+    if !s.is_member(channel, &addr) {
+        return s.reply(&addr, "442", &format!("{} :You're not on that channel", channel));
+    }
+    if !s.role_of(&addr).can_moderate() {
+        return s.reply(&addr, "482", &format!("{} :You're not channel operator", channel));
+    }
+
+    let nick = s.nick_of(&addr);
+    let text: String = text.chars().take(300).collect();
+    s.channel_info.entry(channel.to_string()).or_default().topic = (!text.is_empty()).then(|| Topic {
+        text: text.clone(),
+        by: nick.clone(),
+        at: now_secs(),
+    });
+    info!("{} set the topic of {}: {}", nick, channel, text);
+    let line = format!(":{} TOPIC {} :{}\r\n", s.mask(&addr), channel, text);
+    for member in s.channels.get(channel).into_iter().flatten() {
+        if let Some(c) = s.clients.get(member) {
+            let _ = c.tx.send(line.clone());
+        }
+    }
+}
+
+async fn handle_mode(target: &str, args: &[&str], addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    if !target.starts_with('#') {
+        // No user modes to speak of.
+        if target == s.nick_of(&addr) {
+            return s.reply(&addr, "221", "+");
+        }
+        return s.reply(&addr, "502", ":Can't change mode for other users");
+    }
+    let channel = target;
+    if !s.channels.contains_key(channel) || !s.visible_to(channel, &addr) {
+        return s.reply(&addr, "403", &format!("{} :No such channel", channel));
+    }
+    let Some(modes) = args.first() else {
+        let modes = s.channel_info.get(channel)
+            .map_or_else(|| "+".to_string(), |i| i.mode_string(s.is_member(channel, &addr)));
+        return s.reply(&addr, "324", &format!("{} {}", channel, modes));
+    };
+    // Ban-list queries from ordinary clients; bans live in VOIRC_BAN.
+    if modes.trim_start_matches('+') == "b" && args.len() == 1 {
+        return s.reply(&addr, "368", &format!("{} :End of channel ban list", channel));
+    }
+    if !s.role_of(&addr).can_moderate() {
+        return s.reply(&addr, "482", &format!("{} :You're not channel operator", channel));
+    }
+
+    let mut params = args[1..].iter().copied();
+    let mut adding = true;
+    // Applied changes, echoed back to the channel in one MODE line.
+    let mut applied = String::new();
+    let mut applied_args: Vec<String> = Vec::new();
+    let mut last_sign = None;
+    for c in modes.chars() {
+        match c {
+            '+' => { adding = true; continue; }
+            '-' => { adding = false; continue; }
+            _ => {}
+        }
+        let arg = match c {
+            'k' | 'v' => params.next(),
+            'l' if adding => params.next(),
+            _ => None,
+        };
+        let ok = match (c, arg) {
+            ('i', _) => {
+                s.channel_info.entry(channel.to_string()).or_default().invite_only = adding;
+                true
+            }
+            ('m', _) => {
+                s.channel_info.entry(channel.to_string()).or_default().moderated = adding;
+                true
+            }
+            ('E', _) => {
+                s.channel_info.entry(channel.to_string()).or_default().encrypted = adding;
+                true
+            }
+            ('k', Some(key)) => {
+                s.channel_info.entry(channel.to_string()).or_default().key = adding.then(|| key.to_string());
+                true
+            }
+            ('k', None) if !adding => {
+                s.channel_info.entry(channel.to_string()).or_default().key = None;
+                true
+            }
+            ('l', Some(n)) => match n.parse::<usize>() {
+                Ok(n) if n > 0 => {
+                    s.channel_info.entry(channel.to_string()).or_default().limit = Some(n);
+                    true
+                }
+                _ => false,
+            },
+            ('l', None) if !adding => {
+                s.channel_info.entry(channel.to_string()).or_default().limit = None;
+                true
+            }
+            ('v', Some(nick)) => match s.find_addr_by_nick(nick).filter(|a| s.is_member(channel, a)) {
+                Some(a) => {
+                    let info = s.channel_info.entry(channel.to_string()).or_default();
+                    if adding { info.voiced.insert(a); } else { info.voiced.remove(&a); }
+                    true
+                }
+                None => {
+                    s.reply(&addr, "441", &format!("{} {} :They aren't on that channel", nick, channel));
+                    false
+                }
+            },
+            ('o', _) => {
+                params.next();
+                s.reply(&addr, "472", "o :Operators follow roles; the host grants mod with /mod");
+                false
+            }
+            ('k' | 'l' | 'v', None) => {
+                s.reply(&addr, "461", "MODE :Not enough parameters");
+                false
+            }
+            _ => {
+                s.reply(&addr, "472", &format!("{} :is unknown mode char to me", c));
+                false
+            }
+        };
+        if ok {
+            if last_sign != Some(adding) {
+                applied.push(if adding { '+' } else { '-' });
+                last_sign = Some(adding);
+            }
+            applied.push(c);
+            if let Some(a) = arg {
+                applied_args.push(a.to_string());
+            }
+        }
+    }
+    if applied.is_empty() {
+        return;
+    }
+
+    let nick = s.nick_of(&addr);
+    let toggled_e2e = applied.contains('E');
+    let change = std::iter::once(applied).chain(applied_args).collect::<Vec<_>>().join(" ");
+    info!("{} set mode {} on {}", nick, change, channel);
+    let line = format!(":{} MODE {} {}\r\n", s.mask(&addr), channel, change);
+    for member in s.channels.get(channel).into_iter().flatten() {
+        if let Some(c) = s.clients.get(member) {
+            let _ = c.tx.send(line.clone());
+        }
+    }
+    // Voirc clients take +E from the server, as with slow mode.
+    if toggled_e2e {
+        let on = s.channel_info.get(channel).is_some_and(|i| i.encrypted);
+        s.announce_channel(channel, &format!("VOIRC_E2E:{}:{}", on as u8, nick));
+    }
+}
+
+async fn handle_invite(target: &str, channel: &str, addr: SocketAddr, state: &Arc<RwLock<ServerState>>) {
+    let mut s = state.write().await;
+    let Some(target_addr) = s.find_addr_by_nick(target) else {
+        return s.reply(&addr, "401", &format!("{} :No such nick/channel", target));
+    };
+    if s.channels.contains_key(channel) && !s.is_member(channel, &addr) {
+        return s.reply(&addr, "442", &format!("{} :You're not on that channel", channel));
+    }
+    if s.is_member(channel, &target_addr) {
+        return s.reply(&addr, "443", &format!("{} {} :is already on channel", target, channel));
+    }
+    let restricted = s.channel_info.get(channel).is_some_and(|i| i.invite_only || i.key.is_some());
+    if restricted && !s.role_of(&addr).can_moderate() {
+        return s.reply(&addr, "482", &format!("{} :You're not channel operator", channel));
+    }
+    let Some(subject) = s.subject(&target_addr) else { return };
+    s.channel_info.entry(channel.to_string()).or_default().invited.insert(subject);
+
+    let nick = s.nick_of(&addr);
+    info!("{} invited {} to {}", nick, target, channel);
+    s.reply(&addr, "341", &format!("{} {}", target, channel));
+    if let Some(c) = s.clients.get(&target_addr) {
+        let _ = c.tx.send(format!(":{} INVITE {} :{}\r\n", s.mask(&addr), s.nick_of(&target_addr), channel));
+    }
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Helper
+// ─────────────────────────────────────────────────────────────────────────────
+
+fn verify_signature(pubkey_hex: &str, msg: &[u8], sig_hex: &str) -> bool {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+    let Some(pk) = hex::decode(pubkey_hex).ok().and_then(|b| <[u8; 32]>::try_from(b).ok()) else {
+        return false;
+    };
+    let Some(sig) = hex::decode(sig_hex).ok().and_then(|b| <[u8; 64]>::try_from(b).ok()) else {
+        return false;
+    };
+    VerifyingKey::from_bytes(&pk)
+        .is_ok_and(|key| key.verify(msg, &Signature::from_bytes(&sig)).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bans::BanList;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn key(seed: u8) -> (SigningKey, String) {
+        let sk = SigningKey::from_bytes(&[seed; 32]);
+        let pk = hex::encode(sk.verifying_key().to_bytes());
+        (sk, pk)
+    }
+
+    fn state(host_pubkey: Option<String>) -> ServerState {
+        ServerState::new(0, host_pubkey, BanList::new(None).shared())
+    }
+
+    fn add_client(s: &mut ServerState, port: u16, nick: &str, pubkey: Option<&str>) -> SocketAddr {
+        let addr: SocketAddr = format!("10.0.0.{}:{}", port % 250, port).parse().unwrap();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut client = Client::new(tx, addr.ip(), s.cloak(addr.ip()));
+        client.nick = Some(nick.to_string());
+        client.user = Some(nick.to_string());
+        client.registered = true;
+        client.pubkey = pubkey.map(str::to_string);
+        client.authenticated = pubkey.is_some();
+        s.clients.insert(addr, client);
+        addr
+    }
+
+    /// A fresh connection whose output we can read.
+    fn connect(s: &mut ServerState, port: u16) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let addr: SocketAddr = format!("10.0.1.{}:{}", port % 250, port).parse().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        s.clients.insert(addr, Client::new(tx, addr.ip(), s.cloak(addr.ip())));
+        (addr, rx)
+    }
+
+    fn drain(rx: &mut mpsc::UnboundedReceiver<String>) -> Vec<String> {
+        std::iter::from_fn(|| rx.try_recv().ok()).collect()
+    }
+
+    fn grant(sk: &SigningKey, target: &str, role: Role, ts: u64) -> String {
+        let sig = sk.sign(moderation::role_grant_payload(target, role, ts).as_bytes());
+        format!("{}:{}:{}:{}", target, role.as_str(), ts, hex::encode(sig.to_bytes()))
+    }
+
+    #[test]
+    fn test_roles_come_from_bound_keys() {
+        let (_, host_pk) = key(1);
+        let (_, bob_pk) = key(2);
+        let mut s = state(Some(host_pk.clone()));
+        let host = add_client(&mut s, 1000, "alice", Some(&host_pk));
+        let bob = add_client(&mut s, 1001, "bob", Some(&bob_pk));
+        // Claiming the host's nick without its key gets nothing.
+        let mallory = add_client(&mut s, 1002, "mallory", None);
+
+        assert_eq!(s.role_of(&host), Role::Host);
+        assert_eq!(s.role_of(&bob), Role::Peer);
+        assert_eq!(s.role_of(&mallory), Role::Peer);
+
+        s.mods.insert(bob_pk);
+        assert_eq!(s.role_of(&bob), Role::Mod);
+    }
+
+    #[test]
+    fn test_role_grant_requires_host_signature() {
+        let (host_sk, host_pk) = key(1);
+        let (bob_sk, bob_pk) = key(2);
+        let mut s = state(Some(host_pk.clone()));
+        add_client(&mut s, 1000, "alice", Some(&host_pk));
+        let bob = add_client(&mut s, 1001, "bob", Some(&bob_pk));
+        add_client(&mut s, 1002, "carol", None);
+        let now = 1_700_000_000_000;
+
+        assert_eq!(s.apply_role_grant(&grant(&bob_sk, "bob", Role::Mod, now), now), Err("invalid_signature"));
+        assert_eq!(s.apply_role_grant("bob:mod:1", now), Err("malformed"));
+        assert_eq!(s.apply_role_grant(&grant(&host_sk, "bob", Role::Host, now), now), Err("invalid_role"));
+        assert_eq!(s.apply_role_grant(&grant(&host_sk, "carol", Role::Mod, now), now), Err("not_authenticated"));
+        assert_eq!(s.apply_role_grant(&grant(&host_sk, "alice", Role::Peer, now), now), Err("target_is_host"));
+        assert_eq!(s.role_of(&bob), Role::Peer);
+
+        let promote = grant(&host_sk, "bob", Role::Mod, now);
+        assert_eq!(s.apply_role_grant(&promote, now), Ok(bob));
+        assert_eq!(s.role_of(&bob), Role::Mod);
+
+        // Replaying an old grant, or one far from our clock, is refused.
+        let demote = grant(&host_sk, "bob", Role::Peer, now + 1);
+        assert_eq!(s.apply_role_grant(&promote, now), Err("stale"));
+        let far = now + GRANT_MAX_SKEW_MS + 10;
+        assert_eq!(s.apply_role_grant(&grant(&host_sk, "bob", Role::Peer, far), now), Err("stale"));
+        assert_eq!(s.apply_role_grant(&demote, now), Ok(bob));
+        assert_eq!(s.role_of(&bob), Role::Peer);
+    }
+
+    #[test]
+    fn test_no_host_key_means_no_grants() {
+        let (host_sk, _) = key(1);
+        let (_, bob_pk) = key(2);
+        let mut s = state(None);
+        add_client(&mut s, 1001, "bob", Some(&bob_pk));
+        assert_eq!(s.apply_role_grant(&grant(&host_sk, "bob", Role::Mod, 5), 5), Err("no_host"));
+    }
+
+    #[tokio::test]
+    async fn test_ban_is_enforced_and_persisted() {
+        let (_, host_pk) = key(1);
+        let (_, bob_pk) = key(2);
+        let (_, carol_pk) = key(3);
+        let mut s = state(Some(host_pk.clone()));
+        let host = add_client(&mut s, 1000, "alice", Some(&host_pk));
+        let bob = add_client(&mut s, 1001, "bob", Some(&bob_pk));
+        let carol = add_client(&mut s, 1002, "carol", Some(&carol_pk));
+        s.channels.entry("#general".to_string()).or_default().extend([host, bob, carol]);
+        let bans = Arc::clone(&s.bans);
+        let state = Arc::new(RwLock::new(s));
+
+        // Peers can't ban.
+        process_command("PRIVMSG voirc :VOIRC_BAN:bob:0:0:no", carol, &state).await;
+        assert_eq!(bans.read().unwrap().active(0).count(), 0);
+        // Nobody bans the host.
+        process_command(&format!("PRIVMSG voirc :VOIRC_BAN:{}:0:0:x", host_pk), host, &state).await;
+        assert_eq!(bans.read().unwrap().active(0).count(), 0);
+
+        process_command("PRIVMSG voirc :VOIRC_BAN:bob:3600:1:spam: lots", host, &state).await;
+        {
+            let list = bans.read().unwrap();
+            let ban = list.find(Some(&bob_pk), None, now_secs()).expect("bob is banned");
+            assert_eq!(ban.reason, "spam: lots");
+            assert_eq!(ban.ip, Some(bob.ip()));
+            assert!(ban.expires.is_some());
+        }
+        assert!(!state.read().await.channels["#general"].contains(&bob));
+
+        process_command("PRIVMSG voirc :VOIRC_UNBAN:bob", carol, &state).await;
+        assert_eq!(bans.read().unwrap().active(0).count(), 1);
+        // A mod can't lift the host's ban, even by IP; they can lift their own.
+        state.write().await.mods.insert(carol_pk);
+        process_command("PRIVMSG voirc :VOIRC_UNBAN:bob", carol, &state).await;
+        process_command(&format!("PRIVMSG voirc :VOIRC_UNBAN:{}", bob.ip()), carol, &state).await;
+        assert_eq!(bans.read().unwrap().active(0).count(), 1);
+        // Nor shorten it by banning again.
+        process_command(&format!("PRIVMSG voirc :VOIRC_BAN:{}:1:0:oops", bob_pk), carol, &state).await;
+        {
+            let list = bans.read().unwrap();
+            let ban = list.find(Some(&bob_pk), None, now_secs()).expect("bob is still banned");
+            assert_eq!((ban.by_role, ban.reason.as_str()), (Role::Host, "spam: lots"));
+        }
+        process_command("PRIVMSG voirc :VOIRC_UNBAN:bob", host, &state).await;
+        assert_eq!(bans.read().unwrap().active(0).count(), 0);
+
+        process_command(&format!("PRIVMSG voirc :VOIRC_BAN:{}:0:0:x", bob_pk), carol, &state).await;
+        assert_eq!(bans.read().unwrap().active(0).next().map(|b| b.by_role), Some(Role::Mod));
+        process_command(&format!("PRIVMSG voirc :VOIRC_UNBAN:{}", bob_pk), carol, &state).await;
+        assert_eq!(bans.read().unwrap().active(0).count(), 0);
+
+        // A guest without a key is banned by address, asked for or not.
+        let dave = add_client(&mut *state.write().await, 1003, "dave", None);
+        state.write().await.channels.get_mut("#general").unwrap().insert(dave);
+        process_command("PRIVMSG voirc :VOIRC_BAN:dave:0:0:spam", carol, &state).await;
+        {
+            let list = bans.read().unwrap();
+            let ban = list.find(None, Some(dave.ip()), now_secs()).expect("dave's address is banned");
+            assert!(ban.pubkey.is_empty());
+            assert!(list.find(Some(&bob_pk), None, now_secs()).is_none());
+        }
+        assert!(!state.read().await.channels["#general"].contains(&dave));
+
+        // An address ban leaves connected anyone sharing it who outranks the banner.
+        let (_, frank_pk) = key(6);
+        let (erin, frank) = {
+            let mut s = state.write().await;
+            let erin = add_client(&mut s, 1010, "erin", Some(&key(5).1));
+            let frank = add_client(&mut s, 1260, "frank", Some(&frank_pk));
+            s.mods.insert(frank_pk);
+            s.channels.get_mut("#general").unwrap().extend([erin, frank]);
+            (erin, frank)
+        };
+        assert_eq!(erin.ip(), frank.ip());
+        process_command("PRIVMSG voirc :VOIRC_BAN:erin:0:1:spam", carol, &state).await;
+        let s = state.read().await;
+        assert!(!s.channels["#general"].contains(&erin));
+        assert!(s.channels["#general"].contains(&frank));
+    }
+
+    #[tokio::test]
+    async fn test_mutes_and_slow_mode() {
+        let (_, host_pk) = key(1);
+        let (_, bob_pk) = key(2);
+        let mut s = state(Some(host_pk.clone()));
+        let host = add_client(&mut s, 1000, "alice", Some(&host_pk));
+        let bob = add_client(&mut s, 1001, "bob", Some(&bob_pk));
+        let carol = add_client(&mut s, 1002, "carol", None);
+        let (_, dave_pk) = key(4);
+        let dave = add_client(&mut s, 1003, "dave", Some(&dave_pk));
+        s.channels.entry("#general".to_string()).or_default().extend([host, bob, carol, dave]);
+        let state = Arc::new(RwLock::new(s));
+
+        process_command("PRIVMSG voirc :VOIRC_MUTE:chat:bob:60", carol, &state).await;
+        assert!(state.read().await.mutes.is_empty(), "peers can't mute");
+
+        process_command("PRIVMSG voirc :VOIRC_MUTE:chat:bob:60", host, &state).await;
+        process_command("PRIVMSG voirc :VOIRC_MUTE:voice:bob:0", host, &state).await;
+        let now = now_secs();
+        {
+            let mut s = state.write().await;
+            assert!(s.check_chat(&bob, "#general", now).is_err());
+            assert!(s.check_chat(&carol, "#general", now).is_ok());
+            assert!(s.active_mute(&bob, MuteKind::Voice, now).is_some_and(|m| m.expires.is_none()));
+
+            // The chat mute runs out; the voice mute has no expiry.
+            s.expire_restrictions(now + 61);
+            assert!(s.check_chat(&bob, "#general", now + 61).is_ok());
+            assert!(s.active_mute(&bob, MuteKind::Voice, now + 61).is_some());
+        }
+        // A mod can't lift the host's mute, nor a peer any.
+        state.write().await.mods.insert(dave_pk);
+        process_command("PRIVMSG voirc :VOIRC_UNMUTE:voice:bob", dave, &state).await;
+        process_command("PRIVMSG voirc :VOIRC_UNMUTE:voice:bob", carol, &state).await;
+        // Nor shorten it by muting again.
+        process_command("PRIVMSG voirc :VOIRC_MUTE:voice:bob:1", dave, &state).await;
+        assert!(state.read().await.active_mute(&bob, MuteKind::Voice, now).is_some_and(|m| m.expires.is_none()));
+        process_command("PRIVMSG voirc :VOIRC_UNMUTE:voice:bob", host, &state).await;
+        assert!(state.read().await.mutes.is_empty());
+
+        // One the mod set, they can; nobody unmutes the host.
+        process_command("PRIVMSG voirc :VOIRC_MUTE:chat:bob:0", dave, &state).await;
+        process_command("PRIVMSG voirc :VOIRC_UNMUTE:chat:bob", dave, &state).await;
+        assert!(state.read().await.mutes.is_empty());
+        state.write().await.mutes.insert((MuteKind::Chat, host_pk.clone()), Mute {
+            nick: "alice".to_string(), by: "voirc".to_string(), by_role: Role::Peer, expires: None,
+        });
+        process_command("PRIVMSG voirc :VOIRC_UNMUTE:chat:alice", dave, &state).await;
+        assert_eq!(state.read().await.mutes.len(), 1);
+        state.write().await.mutes.clear();
+
+        process_command("PRIVMSG voirc :VOIRC_SLOWMODE:#general:30:0", host, &state).await;
+        let mut s = state.write().await;
+        assert!(s.check_chat(&carol, "#general", now).is_ok());
+        assert!(s.check_chat(&carol, "#general", now + 10).is_err());
+        assert!(s.check_chat(&carol, "#general", now + 30).is_ok());
+        // Host and mods aren't slowed down.
+        assert!(s.check_chat(&host, "#general", now).is_ok());
+        assert!(s.check_chat(&host, "#general", now).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_voirc_text_is_held_to_mutes() {
+        let (_, host_pk) = key(1);
+        let mut s = state(Some(host_pk.clone()));
+        let host = add_client(&mut s, 1000, "alice", Some(&host_pk));
+        let (carol, mut carol_rx) = connect(&mut s, 2001);
+        let (dave, mut dave_rx) = connect(&mut s, 2002);
+        let state = Arc::new(RwLock::new(s));
+        for (addr, nick) in [(carol, "carol"), (dave, "dave")] {
+            process_command(&format!("NICK {}", nick), addr, &state).await;
+            process_command(&format!("USER {} 0 * :{}", nick, nick), addr, &state).await;
+            process_command("JOIN #general", addr, &state).await;
+        }
+        process_command("JOIN #general", host, &state).await;
+        process_command("PRIVMSG voirc :VOIRC_MUTE:chat:carol:60", host, &state).await;
+        drain(&mut carol_rx);
+        drain(&mut dave_rx);
+
+        process_command("PRIVMSG #general :VOIRC_anything", carol, &state).await;
+        assert!(drain(&mut dave_rx).is_empty());
+        assert!(drain(&mut carol_rx).iter().any(|l| l.contains("VOIRC_INFO You are muted")));
+        // Signaling still goes through.
+        process_command("PRIVMSG #general :VOIRC_VOICE:m", carol, &state).await;
+        assert!(drain(&mut dave_rx).iter().any(|l| l.ends_with("PRIVMSG #general :VOIRC_VOICE:m\r\n")));
+
+        process_command("PRIVMSG voirc :VOIRC_UNMUTE:chat:carol", host, &state).await;
+        process_command("PRIVMSG voirc :VOIRC_SLOWMODE:#general:30:0", host, &state).await;
+        drain(&mut carol_rx);
+        drain(&mut dave_rx);
+        process_command("PRIVMSG #general :hi", carol, &state).await;
+        process_command("PRIVMSG #general :VOIRC_again", carol, &state).await;
+        assert_eq!(drain(&mut dave_rx).len(), 1);
+        assert!(drain(&mut carol_rx).iter().any(|l| l.contains("VOIRC_INFO Slow mode")));
+    }
+
+    #[tokio::test]
+    async fn test_channel_modes_topic_and_invites() {
+        let (_, host_pk) = key(1);
+        let (_, bob_pk) = key(2);
+        let (_, carol_pk) = key(3);
+        let mut s = state(Some(host_pk.clone()));
+        let host = add_client(&mut s, 1000, "alice", Some(&host_pk));
+        let bob = add_client(&mut s, 1001, "bob", Some(&bob_pk));
+        let carol = add_client(&mut s, 1002, "carol", Some(&carol_pk));
+        let state = Arc::new(RwLock::new(s));
+
+        process_command("JOIN #ops", host, &state).await;
+        process_command("TOPIC #ops :peers only", bob, &state).await;
+        process_command("MODE #ops +ikl s3cret 2", bob, &state).await;
+        assert!(state.read().await.channel_info.is_empty(), "peers can't set topic or modes");
+
+        process_command("TOPIC #ops :standup at 10", host, &state).await;
+        process_command("MODE #ops +ikl s3cret 2", host, &state).await;
+        {
+            let s = state.read().await;
+            let info = &s.channel_info["#ops"];
+            assert_eq!(info.topic.as_ref().unwrap().text, "standup at 10");
+            assert_eq!(info.mode_string(true), "+ikl s3cret 2");
+            assert_eq!(info.mode_string(false), "+ikl * 2");
+        }
+
+        // +i keeps bob out even with the key, until he is invited.
+        process_command("JOIN #ops s3cret", bob, &state).await;
+        assert!(!state.read().await.is_member("#ops", &bob));
+        process_command("INVITE bob #ops", carol, &state).await;
+        assert!(state.read().await.channel_info["#ops"].invited.is_empty());
+        process_command("INVITE bob #ops", host, &state).await;
+        process_command("JOIN #ops", bob, &state).await;
+        assert!(state.read().await.is_member("#ops", &bob));
+        assert!(state.read().await.channel_info["#ops"].invited.is_empty(), "invites are used up");
+
+        // Full at 2; the limit doesn't hold back an op.
+        process_command("MODE #ops -i", host, &state).await;
+        process_command("JOIN #ops s3cret", carol, &state).await;
+        assert!(!state.read().await.is_member("#ops", &carol));
+        process_command("MODE #ops -kl", host, &state).await;
+        process_command("JOIN #ops", carol, &state).await;
+        assert!(state.read().await.is_member("#ops", &carol));
+
+        // +m: only ops and +v may talk.
+        process_command("MODE #ops +m", host, &state).await;
+        {
+            let s = state.read().await;
+            assert!(s.can_send("#ops", &host, false).is_ok());
+            assert!(s.can_send("#ops", &carol, false).is_err());
+            assert!(s.can_send("#ops", &carol, true).is_ok(), "signaling gets through +m");
+            assert!(s.can_send("#general", &host, true).is_err(), "members only");
+        }
+        process_command("MODE #ops +v carol", host, &state).await;
+        assert!(state.read().await.can_send("#ops", &carol, false).is_ok());
+        assert_eq!(state.read().await.member_prefix("#ops", &carol), "+");
+        process_command("PART #ops", carol, &state).await;
+        assert!(state.read().await.channel_info["#ops"].voiced.is_empty());
+
+        process_command("TOPIC #ops :", host, &state).await;
+        assert!(state.read().await.channel_info["#ops"].topic.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_outsiders_cannot_signal_into_a_channel() {
+        let (_, host_pk) = key(1);
+        let mut s = state(Some(host_pk.clone()));
+        let host = add_client(&mut s, 1000, "alice", Some(&host_pk));
+        let (carol, mut carol_rx) = connect(&mut s, 2001);
+        let (dave, mut dave_rx) = connect(&mut s, 2002);
+        let state = Arc::new(RwLock::new(s));
+        for (addr, nick) in [(carol, "carol"), (dave, "dave")] {
+            process_command(&format!("NICK {}", nick), addr, &state).await;
+            process_command(&format!("USER {} 0 * :{}", nick, nick), addr, &state).await;
+        }
+        process_command("JOIN #ops", host, &state).await;
+        process_command("JOIN #ops", carol, &state).await;
+        process_command("MODE #ops +im", host, &state).await;
+        drain(&mut carol_rx);
+        drain(&mut dave_rx);
+
+        for text in ["VOIRC_VOICE:m", "WRTC:[1/1|x]{}", "VOIRC_HELLO:dave:00:0:00", "hello"] {
+            process_command(&format!("PRIVMSG #ops :{}", text), dave, &state).await;
+        }
+        assert!(drain(&mut carol_rx).is_empty());
+        let refused = drain(&mut dave_rx);
+        assert_eq!(refused.len(), 1, "only the chat gets an answer");
+        assert!(refused[0].contains(" 404 dave #ops :Cannot send to channel (not joined)"));
+
+        // +m silences carol's chat, not her call signaling.
+        process_command("PRIVMSG #ops :VOIRC_VOICE:m", carol, &state).await;
+        assert!(drain(&mut carol_rx).is_empty());
+        process_command("PRIVMSG #ops :VOIRC_whatever", carol, &state).await;
+        assert!(drain(&mut carol_rx)[0].contains(" 404 carol #ops :Cannot send to channel (+m)"));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_channel_takes_only_sealed_chat() {
+        let (_, host_pk) = key(1);
+        let mut s = state(Some(host_pk.clone()));
+        let host = add_client(&mut s, 1000, "alice", Some(&host_pk));
+        let (carol, mut rx) = connect(&mut s, 2001);
+        let state = Arc::new(RwLock::new(s));
+        process_command("NICK carol", carol, &state).await;
+        process_command("USER carol 0 * :Carol", carol, &state).await;
+        process_command("JOIN #ops", host, &state).await;
+        process_command("MODE #ops +E", host, &state).await;
+        assert_eq!(state.read().await.channel_info["#ops"].mode_string(true), "+E");
+
+        // Joiners are told.
+        process_command("JOIN #ops", carol, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines.iter().any(|l| l == ":voirc PRIVMSG #ops :VOIRC_E2E:1\r\n"));
+
+        // Only the E flag itself says anything about encryption, not an
+        // argument that happens to have an E in it.
+        process_command("MODE #ops +k SECRET", host, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines.iter().any(|l| l.ends_with(" MODE #ops +k SECRET\r\n")));
+        assert!(!lines.iter().any(|l| l.contains("VOIRC_E2E")));
+        assert!(state.read().await.channel_info["#ops"].encrypted);
+
+        process_command("PRIVMSG #ops :hello", carol, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].contains(" 404 carol #ops :Cannot send to channel (+E"));
+
+        let plain = serde_json::json!({
+            "id": "1", "author": "carol", "pubkey": "00", "channel": "#ops", "content": "hi",
+            "timestamp": 0, "chain_hash": "", "signature": "",
+        });
+        assert!(!is_sealed_chat(&format!("SIGNED:{}", plain)));
+        let mut sealed = plain.clone();
+        sealed["encrypted"] = true.into();
+        assert!(is_sealed_chat(&format!("SIGNED:{}", sealed)));
+        assert!(!is_sealed_chat("hello"));
+    }
+
+    #[tokio::test]
+    async fn test_registration_waits_for_cap_end() {
+        let mut s = state(None);
+        let (bob, mut rx) = connect(&mut s, 2000);
+        let state = Arc::new(RwLock::new(s));
+
+        process_command("CAP LS 302", bob, &state).await;
+        process_command("NICK bob", bob, &state).await;
+        process_command("USER bob 0 * :Bob Smith", bob, &state).await;
+        process_command("JOIN #general", bob, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].starts_with(":voirc CAP * LS :message-tags server-time voirc"));
+        assert!(lines.iter().any(|l| l.starts_with(":voirc 451 bob ")), "nothing but registration before 001");
+        assert!(!lines.iter().any(|l| l.contains(" 001 ")));
+
+        process_command("CAP REQ :server-time bogus", bob, &state).await;
+        process_command("CAP REQ :server-time", bob, &state).await;
+        process_command("CAP END", bob, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].contains("CAP bob NAK :server-time bogus"));
+        assert!(lines[1].contains("CAP bob ACK :server-time"));
+        let numerics: Vec<&str> = lines[2..].iter().filter_map(|l| l.split(' ').nth(1)).collect();
+        assert_eq!(&numerics[..7], ["001", "002", "003", "004", "005", "375", "372"]);
+        assert!(lines[2].contains("Welcome to the Voirc IRC network bob!~bob@guest/"));
+        assert!(lines.iter().any(|l| l.contains(" 005 bob ") && l.contains("PREFIX=(ov)@+")));
+        assert!(lines.iter().any(|l| l.contains(" 376 bob ")));
+
+        process_command("USER bob 0 * :again", bob, &state).await;
+        process_command("FROB", bob, &state).await;
+        process_command("PRIVMSG nobody :hi", bob, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].contains(" 462 bob "));
+        assert!(lines[1].contains(" 421 bob FROB "));
+        assert!(lines[2].contains(" 401 bob nobody "));
+    }
+
+    #[tokio::test]
+    async fn test_sasl_refuses_a_banned_key_in_uppercase() {
+        use base64::{engine::general_purpose, Engine as _};
+        let (sk, pk) = key(5);
+        let mut s = state(None);
+        s.bans.write().unwrap().add(Ban {
+            pubkey: pk.clone(),
+            ip: None,
+            nick: "mallory".to_string(),
+            reason: String::new(),
+            by: "alice".to_string(),
+            by_role: Role::Host,
+            created: 0,
+            expires: None,
+        }).unwrap();
+        let (mallory, mut rx) = connect(&mut s, 2003);
+        let state = Arc::new(RwLock::new(s));
+        process_command("CAP REQ :sasl", mallory, &state).await;
+        process_command("NICK mallory", mallory, &state).await;
+        process_command("USER mallory 0 * :M", mallory, &state).await;
+        drain(&mut rx);
+
+        for (spelling, reason) in [(pk.to_uppercase(), "malformed"), (pk.clone(), "banned:")] {
+            process_command("AUTHENTICATE VOIRC-ED25519", mallory, &state).await;
+            let challenge = drain(&mut rx).remove(0);
+            let nonce = general_purpose::STANDARD.decode(challenge.trim_end().strip_prefix("AUTHENTICATE ").unwrap()).unwrap();
+            let ts = now_ms();
+            let sig = sk.sign(sasl_payload("plaintext", "mallory", &spelling, &nonce, ts).as_bytes());
+            let response = format!("{}:{}:{}", spelling, ts, hex::encode(sig.to_bytes()));
+            process_command(&format!("AUTHENTICATE {}", general_purpose::STANDARD.encode(response)), mallory, &state).await;
+            let lines = drain(&mut rx);
+            assert!(lines[0].contains(&format!(" 904 mallory :SASL authentication failed: {}", reason)), "{:?}", lines);
+            assert!(!lines.iter().any(|l| l.contains(" 903 ")));
+        }
+        assert!(state.read().await.clients[&mallory].pubkey.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sasl_login_before_registration() {
+        use base64::{engine::general_purpose, Engine as _};
+        let (sk, pk) = key(4);
+        let answer = |line: &str, binding: &str, nick: &str, ts: u64| {
+            let nonce = general_purpose::STANDARD.decode(line.trim_end().strip_prefix("AUTHENTICATE ").unwrap()).unwrap();
+            let sig = sk.sign(sasl_payload(binding, nick, &pk, &nonce, ts).as_bytes());
+            let response = format!("{}:{}:{}", pk, ts, hex::encode(sig.to_bytes()));
+            format!("AUTHENTICATE {}", general_purpose::STANDARD.encode(response))
+        };
+        let mut s = state(None);
+        s.cert_fingerprint = Some("ab12".to_string());
+        let (dave, mut rx) = connect(&mut s, 2002);
+        let state = Arc::new(RwLock::new(s));
+
+        process_command("CAP LS 302", dave, &state).await;
+        process_command("AUTHENTICATE VOIRC-ED25519", dave, &state).await;
+        process_command("CAP REQ :voirc sasl", dave, &state).await;
+        process_command("NICK dave", dave, &state).await;
+        process_command("USER dave 0 * :Dave", dave, &state).await;
+        process_command("AUTHENTICATE PLAIN", dave, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].ends_with(" voirc sasl=VOIRC-ED25519\r\n"));
+        assert!(lines[1].contains(" 904 * :SASL authentication failed: not_negotiated"));
+        assert!(lines[3].contains(" 908 dave VOIRC-ED25519 "));
+        assert!(lines[4].contains(" 904 dave "));
+
+        // A signature over someone else's nick, for another server or from
+        // long ago doesn't log us in, and the nonce is spent either way.
+        let now = now_ms();
+        for (binding, nick, ts, reason) in [
+            ("ab12", "alice", now, "invalid_signature"),
+            ("plaintext", "dave", now, "invalid_signature"),
+            ("ab12", "dave", now - AUTH_MAX_SKEW_MS - 1, "stale_timestamp"),
+        ] {
+            process_command("AUTHENTICATE VOIRC-ED25519", dave, &state).await;
+            let challenge = drain(&mut rx).remove(0);
+            process_command(&answer(&challenge, binding, nick, ts), dave, &state).await;
+            let lines = drain(&mut rx);
+            assert!(lines[0].contains(&format!(" 904 dave :SASL authentication failed: {}", reason)));
+            process_command(&answer(&challenge, "ab12", "dave", now), dave, &state).await;
+            let lines = drain(&mut rx);
+            assert!(lines[0].contains(" 908 dave "));
+            assert!(lines[1].contains(" 904 dave :SASL authentication failed: unknown_mechanism"));
+        }
+
+        process_command("AUTHENTICATE VOIRC-ED25519", dave, &state).await;
+        let challenge = drain(&mut rx).remove(0);
+        process_command(&answer(&challenge, "ab12", "dave", now), dave, &state).await;
+        process_command("AUTHENTICATE VOIRC-ED25519", dave, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].contains(" 900 dave dave!~dave@guest/"));
+        assert!(lines[0].contains(&format!(" {} :You are now logged in", &pk[..16])));
+        assert!(lines[1].contains(" 903 dave "));
+        assert!(lines[2].contains(" 907 dave "));
+        assert!(!lines.iter().any(|l| l.contains(" 001 ")), "no welcome before CAP END");
+        process_command("CAP END", dave, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines.iter().any(|l| l.contains(" 001 dave ")));
+        assert!(lines.iter().any(|l| l.contains(":VOIRC_SESSION:") && l.trim_end().len() > 32));
+        {
+            let s = state.read().await;
+            assert!(s.clients[&dave].authenticated);
+            assert_eq!(s.nick_pubkeys.get("dave"), Some(&pk));
+        }
+
+        // Once bound, the nick can't be registered without the key.
+        state.write().await.clients.remove(&dave);
+        let (eve, mut rx) = {
+            let mut s = state.write().await;
+            connect(&mut s, 2003)
+        };
+        process_command("NICK dave", eve, &state).await;
+        process_command("USER eve 0 * :Eve", eve, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].contains(" 433 * dave :Nickname is already in use"));
+        assert_eq!(lines.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_whois_nick_change_and_quit() {
+        let (_, host_pk) = key(1);
+        let mut s = state(Some(host_pk.clone()));
+        let host = add_client(&mut s, 1000, "alice", Some(&host_pk));
+        let (carol, mut rx) = connect(&mut s, 2001);
+        let state = Arc::new(RwLock::new(s));
+        process_command("NICK carol", carol, &state).await;
+        process_command("USER carol 0 * :Carol", carol, &state).await;
+        process_command("JOIN #general", host, &state).await;
+        process_command("JOIN #general", carol, &state).await;
+        drain(&mut rx);
+
+        process_command("WHOIS ALICE", carol, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].contains(" 311 carol alice ~alice guest/"));
+        assert!(lines.iter().any(|l| l.contains(" 319 carol alice :@#general")));
+        assert!(lines.iter().any(|l| l.contains(" 313 carol alice :is the room host")));
+        assert!(lines.last().unwrap().contains(" 318 "));
+
+        process_command("NICK alice", carol, &state).await;
+        process_command("NICK 9lives", carol, &state).await;
+        process_command("NICK caz", carol, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].contains(" 433 carol alice "));
+        assert!(lines[1].contains(" 432 carol 9lives "));
+        assert!(lines[2].starts_with(":carol!~carol@guest/") && lines[2].ends_with(" NICK :caz\r\n"));
+        process_command("NICK alice2", host, &state).await;
+        assert_eq!(state.read().await.nick_of(&host), "alice", "keyed nicks stay put");
+
+        process_command("QUIT :bye now", carol, &state).await;
+        let lines = drain(&mut rx);
+        assert!(lines[0].starts_with("ERROR :Closing Link: guest/"));
+        assert_eq!(state.read().await.clients[&carol].quit_reason.as_deref(), Some("Quit: bye now"));
+    }
+
+    #[test]
+    fn test_render_line_for_plain_and_voirc_clients() {
+        let now = chrono::DateTime::parse_from_rfc3339("2026-01-02T03:04:05.678Z").unwrap().to_utc();
+        let plain = Caps::default();
+        let voirc = Caps::default();
+        voirc.voirc.store(true, Ordering::Relaxed);
+
+        let wrtc = ":bob!~bob@guest/1 PRIVMSG alice :WRTC:[1/1|ab]offer\r\n";
+        assert_eq!(render_line(wrtc, &plain, now), None);
+        assert_eq!(render_line(wrtc, &voirc, now).as_deref(), Some(wrtc));
+        let dm = ":bob!~bob@guest/1 PRIVMSG alice :E2E:[1/1|ab]c2VhbGVk\r\n";
+        assert_eq!(render_line(dm, &plain, now), None);
+
+        let signed = serde_json::json!({
+            "id": "1", "author": "bob", "pubkey": "00", "channel": "#general", "content": "hello\nthere",
+            "timestamp": 0, "chain_hash": "", "signature": "",
+        });
+        let line = format!(":bob!~bob@guest/1 PRIVMSG #general :SIGNED:{}\r\n", signed);
+        assert_eq!(
+            render_line(&line, &plain, now).as_deref(),
+            Some(":bob!~bob@guest/1 PRIVMSG #general :hello there\r\n"),
+        );
+
+        let mut sealed = signed.clone();
+        sealed["encrypted"] = true.into();
+        let line = format!(":bob!~bob@guest/1 PRIVMSG #general :SIGNED:{}\r\n", sealed);
+        assert_eq!(
+            render_line(&line, &plain, now).as_deref(),
+            Some(":bob!~bob@guest/1 PRIVMSG #general :[end-to-end encrypted message]\r\n"),
+        );
+        assert!(render_line(":voirc PRIVMSG #general :VOIRC_E2E:1\r\n", &plain, now).unwrap()
+            .starts_with(":voirc NOTICE #general :Messages here are end-to-end encrypted"));
+
+        let mute = ":voirc PRIVMSG #general :VOIRC_MUTE:voice:bob:600:alice\r\n";
+        assert_eq!(
+            render_line(mute, &plain, now).as_deref(),
+            Some(":voirc NOTICE #general :bob is voice-muted for 10m by alice\r\n"),
+        );
+        assert_eq!(render_line(":voirc PRIVMSG #general :VOIRC_PUBKEY:bob:00\r\n", &plain, now), None);
+        assert_eq!(render_line(":voirc NOTICE bob :VOIRC_INFO Banned eve\r\n", &plain, now).as_deref(),
+            Some(":voirc NOTICE bob :Banned eve\r\n"));
+
+        // Tags only for those who asked; server-time on everything.
+        let tagged = "@+typing=active :bob!~bob@guest/1 TAGMSG #general\r\n";
+        assert_eq!(render_line(tagged, &plain, now), None);
+        plain.message_tags.store(true, Ordering::Relaxed);
+        plain.server_time.store(true, Ordering::Relaxed);
+        assert_eq!(
+            render_line(tagged, &plain, now).as_deref(),
+            Some("@time=2026-01-02T03:04:05.678Z;+typing=active :bob!~bob@guest/1 TAGMSG #general\r\n"),
+        );
     }
 }
@@ -0,0 +1,299 @@
+// Per-sender jitter buffer.
+//
+// Packets arrive from WebRTC tracks, the TCP relay or a superpeer forwarding
+// someone else's voice, each carrying an RTP sequence number and timestamp.
+// The output callback pulls one 20ms frame per stream at the sound card's
+// pace; this module decides whether that frame is a real packet, an FEC
+// recovery from the following packet, Opus PLC, or nothing because the
+// talker has gone quiet.
+//
+// The sender only transmits frames that pass the VAD, so a gap in time with
+// consecutive sequence numbers is silence, not loss.  The timestamp still
+// advances through silence, which is what the jitter estimate is based on.
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// Samples per RTP timestamp tick: 48kHz clock, same as the codec.
+const CLOCK_RATE: f32 = 48_000.0;
+const FRAME_MS: f32 = 20.0;
+pub const MIN_DEPTH: usize = 1;
+pub const MAX_DEPTH: usize = 10;
+/// After this many concealed frames in a row with nothing queued, assume the
+/// talk spurt ended and go back to buffering.
+const MAX_CONCEAL: u32 = 3;
+
+/// One encoded Opus frame plus the RTP fields the jitter buffer needs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioPacket {
+    pub seq: u16,
+    pub timestamp: u32,
+    /// Original speaker when a superpeer forwards someone else's packet
+    /// (the RTP CSRC).  `None` means the packet is from whoever sent it.
+    pub source: Option<u32>,
+    pub payload: Vec<u8>,
+}
+
+impl AudioPacket {
+    const HEADER_LEN: usize = 10;
+
+    /// Encoding used inside relay frames: seq, timestamp, source (0 = none),
+    /// all big-endian, then the Opus payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::HEADER_LEN + self.payload.len());
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.source.unwrap_or(0).to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    pub fn from_bytes(b: &[u8]) -> Option<Self> {
+        if b.len() <= Self::HEADER_LEN {
+            return None;
+        }
+        let source = u32::from_be_bytes([b[6], b[7], b[8], b[9]]);
+        Some(Self {
+            seq: u16::from_be_bytes([b[0], b[1]]),
+            timestamp: u32::from_be_bytes([b[2], b[3], b[4], b[5]]),
+            source: (source != 0).then_some(source),
+            payload: b[Self::HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+/// Stable 32-bit id for a nick, used as the CSRC on forwarded packets so the
+/// receiver can tell the original speakers apart.  FNV-1a; never 0.
+pub fn source_id(nick: &str) -> u32 {
+    let mut h: u32 = 0x811c_9dc5;
+    for b in nick.bytes() {
+        h ^= b as u32;
+        h = h.wrapping_mul(0x0100_0193);
+    }
+    h.max(1)
+}
+
+/// What to play for the next 20ms of a stream.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Playout {
+    Packet(Vec<u8>),
+    /// The packet for this slot is missing.  `fec` is the following packet,
+    /// whose in-band FEC can rebuild it; otherwise use PLC.
+    Conceal { fec: Option<Vec<u8>> },
+    /// Nothing to play (buffering, or the talker is silent).
+    Idle,
+}
+
+pub struct JitterBuffer {
+    /// Keyed by extended (unwrapped) sequence number.
+    packets: BTreeMap<u64, Vec<u8>>,
+    highest: Option<u64>,
+    next: Option<u64>,
+    playing: bool,
+    concealed_in_row: u32,
+    /// RFC 3550 interarrival jitter, in milliseconds.
+    jitter_ms: f32,
+    last_transit: Option<f32>,
+    epoch: Instant,
+}
+
+impl Default for JitterBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JitterBuffer {
+    pub fn new() -> Self {
+        Self {
+            packets: BTreeMap::new(),
+            highest: None,
+            next: None,
+            playing: false,
+            concealed_in_row: 0,
+            jitter_ms: 0.0,
+            last_transit: None,
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Frames held before playout starts, from the measured jitter.
+    pub fn target_depth(&self) -> usize {
+        let frames = (3.0 * self.jitter_ms / FRAME_MS).ceil() as usize + 1;
+        frames.clamp(MIN_DEPTH, MAX_DEPTH)
+    }
+
+    #[cfg(test)]
+    pub fn jitter_ms(&self) -> f32 {
+        self.jitter_ms
+    }
+
+    fn extend(&self, seq: u16) -> u64 {
+        match self.highest {
+            None => seq as u64 + (1 << 16),
+            Some(h) => {
+                let delta = seq.wrapping_sub(h as u16) as i16 as i64;
+                (h as i64 + delta).max(0) as u64
+            }
+        }
+    }
+
+    pub fn push(&mut self, pkt: AudioPacket, arrival: Instant) {
+        let ext = self.extend(pkt.seq);
+        // The sender numbers straight through silence, so while idle
+        // anything may start the next spurt.
+        if self.playing && self.next.is_some_and(|n| ext < n) {
+            return; // too late, already concealed
+        }
+
+        let arrival_ms = arrival.saturating_duration_since(self.epoch).as_secs_f32() * 1000.0;
+        let transit = arrival_ms - pkt.timestamp as f32 * 1000.0 / CLOCK_RATE;
+        if let Some(last) = self.last_transit {
+            let d = (transit - last).abs();
+            // A long silence resets the sender clock relation; ignore it.
+            if d < 1000.0 {
+                self.jitter_ms += (d - self.jitter_ms) / 16.0;
+            }
+        }
+        self.last_transit = Some(transit);
+
+        self.highest = Some(self.highest.map_or(ext, |h| h.max(ext)));
+        self.packets.insert(ext, pkt.payload);
+
+        // Latency cap: if we've fallen far behind, skip ahead.
+        let limit = (self.target_depth() * 2).max(4);
+        while self.packets.len() > limit {
+            let oldest = *self.packets.keys().next().unwrap();
+            self.packets.remove(&oldest);
+            self.next = Some(oldest + 1);
+        }
+    }
+
+    pub fn pop(&mut self) -> Playout {
+        if !self.playing {
+            if self.packets.len() < self.target_depth() {
+                return Playout::Idle;
+            }
+            self.playing = true;
+            self.concealed_in_row = 0;
+            self.next = self.packets.keys().next().copied();
+        }
+
+        let Some(next) = self.next else { return Playout::Idle };
+        if let Some(payload) = self.packets.remove(&next) {
+            self.next = Some(next + 1);
+            self.concealed_in_row = 0;
+            return Playout::Packet(payload);
+        }
+
+        if self.packets.is_empty() {
+            // Either loss at the end of the queue or the talker stopped.
+            if self.concealed_in_row >= MAX_CONCEAL {
+                self.playing = false;
+                self.next = None;
+                return Playout::Idle;
+            }
+            self.concealed_in_row += 1;
+            self.next = Some(next + 1);
+            return Playout::Conceal { fec: None };
+        }
+
+        self.concealed_in_row += 1;
+        self.next = Some(next + 1);
+        Playout::Conceal { fec: self.packets.get(&(next + 1)).cloned() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn pkt(seq: u16) -> AudioPacket {
+        AudioPacket {
+            seq,
+            timestamp: seq as u32 * 960,
+            source: None,
+            payload: vec![seq as u8],
+        }
+    }
+
+    /// Push packets as if they arrived on time, one per 20ms.
+    fn feed(jb: &mut JitterBuffer, seqs: &[u16]) {
+        let start = jb.epoch;
+        for &s in seqs {
+            jb.push(pkt(s), start + Duration::from_millis(s as u64 * 20));
+        }
+    }
+
+    #[test]
+    fn test_packet_roundtrip() {
+        let p = AudioPacket { seq: 65535, timestamp: 123_456, source: Some(source_id("bob")), payload: vec![1, 2, 3] };
+        assert_eq!(AudioPacket::from_bytes(&p.to_bytes()), Some(p));
+        assert_eq!(AudioPacket::from_bytes(&[0; 10]), None);
+        assert_ne!(source_id("alice"), source_id("bob"));
+    }
+
+    #[test]
+    fn test_reorders_by_sequence() {
+        let mut jb = JitterBuffer::new();
+        feed(&mut jb, &[1, 3, 2, 4]);
+        assert_eq!(jb.pop(), Playout::Packet(vec![1]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![2]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![3]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![4]));
+    }
+
+    #[test]
+    fn test_loss_uses_fec_from_next_packet() {
+        let mut jb = JitterBuffer::new();
+        feed(&mut jb, &[10, 12, 13]);
+        assert_eq!(jb.pop(), Playout::Packet(vec![10]));
+        assert_eq!(jb.pop(), Playout::Conceal { fec: Some(vec![12]) });
+        assert_eq!(jb.pop(), Playout::Packet(vec![12]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![13]));
+    }
+
+    #[test]
+    fn test_silence_after_plc_limit() {
+        let mut jb = JitterBuffer::new();
+        feed(&mut jb, &[1]);
+        assert_eq!(jb.pop(), Playout::Packet(vec![1]));
+        for _ in 0..MAX_CONCEAL {
+            assert_eq!(jb.pop(), Playout::Conceal { fec: None });
+        }
+        assert_eq!(jb.pop(), Playout::Idle);
+
+        // Numbering carries on across the silence: the next spurt starts
+        // with seq 2 even though concealment went past it.
+        feed(&mut jb, &[2, 3]);
+        assert_eq!(jb.pop(), Playout::Packet(vec![2]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![3]));
+    }
+
+    #[test]
+    fn test_sequence_wraparound() {
+        let mut jb = JitterBuffer::new();
+        feed(&mut jb, &[65534, 65535]);
+        jb.push(pkt(0), jb.epoch + Duration::from_millis(40));
+        assert_eq!(jb.pop(), Playout::Packet(vec![254]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![255]));
+        assert_eq!(jb.pop(), Playout::Packet(vec![0]));
+    }
+
+    #[test]
+    fn test_depth_grows_with_jitter() {
+        let mut jb = JitterBuffer::new();
+        assert_eq!(jb.target_depth(), MIN_DEPTH);
+        let start = jb.epoch;
+        for s in 0..50u16 {
+            // Alternate early/late by 30ms.
+            let wobble = if s % 2 == 0 { 0 } else { 30 };
+            jb.push(pkt(s), start + Duration::from_millis(s as u64 * 20 + wobble));
+            let _ = jb.pop();
+        }
+        assert!(jb.jitter_ms() > 10.0);
+        assert!(jb.target_depth() > 2);
+        assert!(jb.target_depth() <= MAX_DEPTH);
+    }
+}
@@ -2,6 +2,8 @@ use anyhow::Result;
 use base64::{engine::general_purpose, Engine};
 use serde::{Deserialize, Serialize};
 
+use crate::config::CodecProfile;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct RawConnectionInfo {
     #[serde(default)]
@@ -20,6 +22,8 @@ struct RawConnectionInfo {
     /// on this server.  0 means disabled.
     #[serde(default)]
     pow_required_bits: u8,
+    #[serde(default)]
+    codec: CodecProfile,
 }
 
 #[derive(Debug, Clone)]
@@ -33,6 +37,8 @@ pub struct ConnectionInfo {
     /// against this before even attempting to connect, and can mine a stronger
     /// nick offline if needed.  0 = no PoW required.
     pub pow_required_bits: u8,
+    /// Opus profile the host picked for the room.
+    pub codec: CodecProfile,
 }
 
 #[derive(Serialize)]
@@ -46,9 +52,12 @@ struct WireFormat<'a> {
     relay_port: Option<u16>,
     #[serde(skip_serializing_if = "is_zero")]
     pow_required_bits: u8,
+    #[serde(skip_serializing_if = "is_default_codec")]
+    codec: CodecProfile,
 }
 
 fn is_zero(v: &u8) -> bool { *v == 0 }
+fn is_default_codec(c: &CodecProfile) -> bool { *c == CodecProfile::default() }
 
 impl ConnectionInfo {
     pub fn new(host: String, port: u16, channels: Vec<String>) -> Self {
@@ -59,6 +68,7 @@ impl ConnectionInfo {
             cert_fingerprint: None,
             relay_port: None,
             pow_required_bits: 0,
+            codec: CodecProfile::default(),
         }
     }
 
@@ -80,6 +90,11 @@ impl ConnectionInfo {
         self
     }
 
+    pub fn with_codec(mut self, codec: CodecProfile) -> Self {
+        self.codec = codec;
+        self
+    }
+
     pub fn to_magic_link(&self) -> Result<String> {
         let wire = WireFormat {
             host: &self.host,
@@ -88,6 +103,7 @@ impl ConnectionInfo {
             cert_fingerprint: self.cert_fingerprint.as_deref(),
             relay_port: self.relay_port,
             pow_required_bits: self.pow_required_bits,
+            codec: self.codec,
         };
         let json = serde_json::to_string(&wire)?;
         let encoded = general_purpose::STANDARD.encode(json.as_bytes());
@@ -96,7 +112,7 @@ impl ConnectionInfo {
 
     pub fn from_magic_link(link: &str) -> Result<Self> {
         let link = link.trim();
-        let encoded = if link.starts_with("voirc://") { &link[8..] } else { link };
+        let encoded = link.strip_prefix("voirc://").unwrap_or(link);
 
         let decoded = general_purpose::STANDARD.decode(encoded)?;
         let json = String::from_utf8(decoded)?;
@@ -117,6 +133,7 @@ impl ConnectionInfo {
             cert_fingerprint: raw.cert_fingerprint,
             relay_port: raw.relay_port,
             pow_required_bits: raw.pow_required_bits,
+            codec: raw.codec,
         })
     }
 
@@ -181,12 +198,29 @@ mod tests {
         assert_eq!(parsed.pow_required_bits, 0);
     }
 
+    #[test]
+    fn test_codec_in_link() {
+        let plain = ConnectionInfo::new("example.com".to_string(), 6667, vec![]);
+        let json = String::from_utf8(
+            base64::engine::general_purpose::STANDARD.decode(&plain.to_magic_link().unwrap()[8..]).unwrap(),
+        ).unwrap();
+        assert!(!json.contains("codec"), "default codec should be omitted: {}", json);
+
+        let link = plain.with_codec(CodecProfile::Music).to_magic_link().unwrap();
+        assert_eq!(ConnectionInfo::from_magic_link(&link).unwrap().codec, CodecProfile::Music);
+
+        // Older links have no codec field.
+        let legacy = "voirc://eyJob3N0IjoiZXhhbXBsZS5jb20iLCJwb3J0Ijo2NjY3LCJjaGFubmVscyI6WyIjZ2VuZXJhbCJdfQ==";
+        assert_eq!(ConnectionInfo::from_magic_link(legacy).unwrap().codec, CodecProfile::Voice);
+    }
+
     #[test]
     fn test_full_roundtrip_with_all_fields() {
         let info = ConnectionInfo::new("192.168.1.1".to_string(), 6667, vec!["#general".to_string()])
             .with_tls("abc123fingerprint".to_string())
             .with_relay(6668)
-            .with_pow(12);
+            .with_pow(12)
+            .with_codec(CodecProfile::LowBandwidth);
 
         let link = info.to_magic_link().unwrap();
         let parsed = ConnectionInfo::from_magic_link(&link).unwrap();
@@ -196,5 +230,6 @@ mod tests {
         assert_eq!(parsed.cert_fingerprint, Some("abc123fingerprint".to_string()));
         assert_eq!(parsed.relay_port, Some(6668));
         assert_eq!(parsed.pow_required_bits, 12);
+        assert_eq!(parsed.codec, CodecProfile::LowBandwidth);
     }
 }
@@ -1,6 +1,8 @@
 mod irc_client;
 mod state;
 mod voice_mixer;
+mod jitter_buffer;
+mod vad;
 mod webrtc_peer;
 mod irc_server;
 mod config;
@@ -9,19 +11,68 @@ mod upnp;
 mod gui;
 mod topology;
 mod moderation;
+mod bans;
+mod contacts;
+mod e2e;
+mod group;
 mod tls;
 mod relay;
 mod persistence;
+mod dag;
+mod search;
 mod pow; // <--- ADD THIS LINE
+mod headless;
+mod call;
+mod tui;
 
 use anyhow::Result;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 use tracing::info;
 
-use crate::config::UserConfig;
+use crate::config::{ServerConfig, UserConfig};
 use crate::gui::VoircApp;
 
+#[derive(Parser)]
+#[command(name = "voirc", version, about = "Voice chat over IRC")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Run the IRC server and audio relay without a GUI and print the magic link
+    Serve {
+        /// Server settings (TOML). Built-in defaults are used when omitted.
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+    },
+    /// Join a room in the terminal, or host one with --host
+    Tui {
+        /// voirc:// magic link to join
+        #[arg(required_unless_present = "host")]
+        link: Option<String>,
+        /// Host a room on this machine instead of joining one
+        #[arg(long, conflicts_with = "link")]
+        host: bool,
+        /// Server settings for --host (TOML)
+        #[arg(short, long, requires = "host")]
+        config: Option<PathBuf>,
+    },
+}
+
+fn load_server_config(path: Option<PathBuf>) -> Result<ServerConfig> {
+    match path {
+        Some(p) => ServerConfig::load(&p),
+        None => Ok(ServerConfig::default()),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     let _ = std::fs::create_dir_all("logs");
     let file_appender = tracing_appender::rolling::daily("logs", "voirc.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
@@ -33,6 +84,22 @@ async fn main() -> Result<()> {
     // Install default rustls crypto provider
     let _ = rustls::crypto::ring::default_provider().install_default();
 
+    match cli.command {
+        Some(Commands::Serve { config }) => {
+            return headless::serve(load_server_config(config)?).await;
+        }
+        Some(Commands::Tui { link, host, config }) => {
+            let user_config = UserConfig::load()?;
+            let target = if host {
+                tui::Target::Host(load_server_config(config)?)
+            } else {
+                tui::Target::Join(link.unwrap_or_default())
+            };
+            return tui::run(user_config, target).await;
+        }
+        None => {}
+    }
+
     let config = UserConfig::load()?;
     info!("Loaded config for user: {}", config.display_name);
 
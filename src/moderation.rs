@@ -1,15 +1,140 @@
 use crate::config::Role;
+use crate::search::SearchQuery;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum ModAction {
     Kick(String),
-    Ban(String),
+    /// Enforced by the server against the target's pubkey; `ip` also bans
+    /// the address they are connected from.  No duration is permanent.
+    Ban { target: String, duration: Option<Duration>, reason: Option<String>, ip: bool },
+    /// A nick, pubkey (or 8+ char prefix) or IP from the ban list.
     Unban(String),
     Promote(String),
     Demote(String),
+    /// Chat mute: the server drops their channel messages.  Voice mute:
+    /// superpeers stop forwarding (and playing) their audio.  No duration
+    /// lasts until lifted.
+    Mute { target: String, kind: MuteKind, duration: Option<Duration> },
+    Unmute { target: String, kind: MuteKind },
+    /// One message per `interval` for peers in the current channel; `None`
+    /// turns it off.
+    SlowMode { interval: Option<Duration>, duration: Option<Duration> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MuteKind {
+    Chat,
+    Voice,
+}
+
+impl MuteKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MuteKind::Chat => "chat",
+            MuteKind::Voice => "voice",
+        }
+    }
+
+    pub fn from_wire(s: &str) -> Option<Self> {
+        match s {
+            "chat" => Some(MuteKind::Chat),
+            "voice" => Some(MuteKind::Voice),
+            _ => None,
+        }
+    }
+}
+
+impl ModAction {
+    /// Parse the `<action>:<target>` of a `VOIRC_MOD:` message.
+    pub fn from_wire(action: &str, target: &str) -> Option<Self> {
+        let target = target.to_string();
+        match action {
+            "kick" => Some(Self::Kick(target)),
+            "ban" => Some(Self::Ban { target, duration: None, reason: None, ip: false }),
+            "unban" => Some(Self::Unban(target)),
+            "promote" => Some(Self::Promote(target)),
+            "demote" => Some(Self::Demote(target)),
+            _ => None,
+        }
+    }
+}
+
+/// `30s`, `10m`, `2h`, `7d`, `1w`; a bare number is minutes.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().ok()?;
+    let secs = match unit {
+        "s" => n,
+        "" | "m" => n * 60,
+        "h" => n * 3600,
+        "d" => n * 86_400,
+        "w" => n * 604_800,
+        _ => return None,
+    };
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Largest whole unit: `45s`, `10m`, `2h`, `3d`.
+pub fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s >= 86_400 => format!("{}d", s / 86_400),
+        s if s >= 3600 => format!("{}h", s / 3600),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// `/ban [--ip] <nick|pubkey> [duration] [reason...]`
+fn parse_ban(arg: &str) -> Option<ModAction> {
+    let mut words = arg.split_whitespace().peekable();
+    let ip = words.next_if(|w| *w == "--ip").is_some();
+    let target = words.next()?.to_string();
+    let duration = words.peek().and_then(|w| parse_duration(w));
+    if duration.is_some() {
+        words.next();
+    }
+    let reason: Vec<&str> = words.collect();
+    let reason = (!reason.is_empty()).then(|| reason.join(" "));
+    Some(ModAction::Ban { target, duration, reason, ip })
+}
+
+/// `/mute <nick> [duration]`, `/voicemute <nick> [duration]`
+fn parse_mute(arg: &str, kind: MuteKind) -> Option<ModAction> {
+    let mut words = arg.split_whitespace();
+    let target = words.next()?.to_string();
+    let duration = match words.next() {
+        Some(w) => Some(parse_duration(w)?),
+        None => None,
+    };
+    Some(ModAction::Mute { target, kind, duration })
+}
+
+/// `/slowmode <secs|off> [duration]`.  The interval is in seconds unless it
+/// has a unit.
+fn parse_slow_mode(arg: &str) -> Option<ModAction> {
+    let mut words = arg.split_whitespace();
+    let interval = match words.next()? {
+        "off" | "0" => None,
+        w if w.bytes().all(|b| b.is_ascii_digit()) => Some(Duration::from_secs(w.parse().ok()?)),
+        w => Some(parse_duration(w)?),
+    };
+    let duration = match words.next() {
+        Some(w) => Some(parse_duration(w)?),
+        None => None,
+    };
+    Some(ModAction::SlowMode { interval, duration })
+}
+
+/// What the host signs to grant or revoke mod: the server only accepts
+/// role changes carrying a valid signature from the host key over this.
+pub fn role_grant_payload(target: &str, role: Role, timestamp_ms: u64) -> String {
+    format!("VOIRC_ROLE_SET:{}:{}:{}", target, role.as_str(), timestamp_ms)
 }
 
 #[derive(Debug, Clone)]
@@ -21,6 +146,30 @@ pub enum Command {
     ListPeers,
     EditCommands,
     Invite,
+    /// Invite a user into the current channel (lets them past +i and +k).
+    InviteUser(String),
+    /// Switch to a channel, creating it if it isn't in our list yet.
+    Join { channel: String, key: Option<String> },
+    /// Show the topic, or set it (empty clears).
+    Topic(Option<String>),
+    /// Channel modes as typed, e.g. `+k secret`; empty shows them.
+    Mode(String),
+    ListChannels,
+    /// End-to-end encrypted message to one user.
+    DirectMessage { to: String, text: String },
+    /// Show the safety number with `nick`, or (`confirm`) mark their key
+    /// as checked.
+    Verify { nick: String, confirm: bool },
+    /// Answer `nick`'s last message here.
+    Reply { nick: String, text: String },
+    /// Replace the text of our last message here.
+    Edit(String),
+    /// Retract our last message, or (mods) `nick`'s.
+    Delete(Option<String>),
+    /// React to the last message here, or `nick`'s last.
+    React { reaction: String, nick: Option<String> },
+    /// Look through every channel's signed log.
+    Search(SearchQuery),
     Reload,
     Diag,
     SetPow(u8),
@@ -42,9 +191,26 @@ pub fn parse_command(text: &str, custom_commands: &CustomCommands, context: &Com
     match cmd.as_str() {
         "/?" | "/help" => Some(Command::Help),
         "/kick" => arg.map(|a| Command::Mod(ModAction::Kick(a))),
-        "/ban" => arg.map(|a| Command::Mod(ModAction::Ban(a))),
+        "/ban" => arg
+            .and_then(|a| parse_ban(&a))
+            .map(Command::Mod)
+            .or(Some(Command::Unknown("/ban [--ip] <nick> [duration] [reason]".to_string()))),
         "/unban" => arg.map(|a| Command::Mod(ModAction::Unban(a))),
         "/listbanned" | "/bans" => Some(Command::ListBanned),
+        "/mute" => arg
+            .and_then(|a| parse_mute(&a, MuteKind::Chat))
+            .map(Command::Mod)
+            .or(Some(Command::Unknown("/mute <nick> [duration]".to_string()))),
+        "/unmute" => arg.map(|a| Command::Mod(ModAction::Unmute { target: a, kind: MuteKind::Chat })),
+        "/voicemute" => arg
+            .and_then(|a| parse_mute(&a, MuteKind::Voice))
+            .map(Command::Mod)
+            .or(Some(Command::Unknown("/voicemute <nick> [duration]".to_string()))),
+        "/voiceunmute" => arg.map(|a| Command::Mod(ModAction::Unmute { target: a, kind: MuteKind::Voice })),
+        "/slowmode" => arg
+            .and_then(|a| parse_slow_mode(&a))
+            .map(Command::Mod)
+            .or(Some(Command::Unknown("/slowmode <secs|off> [duration]".to_string()))),
         "/mod" => arg.map(|a| Command::Mod(ModAction::Promote(a))),
         "/unmod" => arg.map(|a| Command::Mod(ModAction::Demote(a))),
         "/role" => Some(Command::ShowRole),
@@ -57,7 +223,49 @@ pub fn parse_command(text: &str, custom_commands: &CustomCommands, context: &Com
                bits: arg.and_then(|a| a.parse().ok()).unwrap_or(16),
            }),
         "/editcommands" | "/commands" => Some(Command::EditCommands),
-        "/invite" | "/link" => Some(Command::Invite),
+        "/invite" => match arg {
+            Some(nick) => Some(Command::InviteUser(nick)),
+            None => Some(Command::Invite),
+        },
+        "/link" => Some(Command::Invite),
+        "/join" | "/j" => arg
+            .as_deref()
+            .and_then(parse_join)
+            .or(Some(Command::Unknown("/join <#channel> [key]".to_string()))),
+        "/topic" => Some(Command::Topic(arg.map(|t| if t == "-" { String::new() } else { t }))),
+        "/cmode" | "/mode" => Some(Command::Mode(arg.unwrap_or_default())),
+        "/list" | "/channels" => Some(Command::ListChannels),
+        "/msg" | "/dm" => arg
+            .as_deref()
+            .and_then(|a| a.split_once(' '))
+            .map(|(to, text)| Command::DirectMessage { to: to.to_string(), text: text.trim().to_string() })
+            .filter(|c| !matches!(c, Command::DirectMessage { text, .. } if text.is_empty()))
+            .or(Some(Command::Unknown("/msg <nick> <text>".to_string()))),
+        "/verify" => match arg.as_deref().map(|a| a.split_whitespace().collect::<Vec<_>>()).as_deref() {
+            Some([nick]) => Some(Command::Verify { nick: nick.to_string(), confirm: false }),
+            Some([nick, "confirm"]) => Some(Command::Verify { nick: nick.to_string(), confirm: true }),
+            _ => Some(Command::Unknown("/verify <nick> [confirm]".to_string())),
+        },
+        "/reply" | "/re" => arg
+            .as_deref()
+            .and_then(|a| a.split_once(' '))
+            .map(|(nick, text)| Command::Reply { nick: nick.to_string(), text: text.trim().to_string() })
+            .filter(|c| !matches!(c, Command::Reply { text, .. } if text.is_empty()))
+            .or(Some(Command::Unknown("/reply <nick> <text>".to_string()))),
+        "/edit" => arg
+            .filter(|a| !a.is_empty())
+            .map(Command::Edit)
+            .or(Some(Command::Unknown("/edit <new text>".to_string()))),
+        "/delete" | "/del" => Some(Command::Delete(arg.filter(|a| !a.is_empty()))),
+        "/react" => match arg.as_deref().map(|a| a.split_whitespace().collect::<Vec<_>>()).as_deref() {
+            Some([reaction]) => Some(Command::React { reaction: reaction.to_string(), nick: None }),
+            Some([reaction, nick]) => Some(Command::React { reaction: reaction.to_string(), nick: Some(nick.to_string()) }),
+            _ => Some(Command::Unknown("/react <emoji> [nick]".to_string())),
+        },
+        "/search" | "/find" => arg
+            .and_then(|a| SearchQuery::parse(&a).ok())
+            .map(Command::Search)
+            .or(Some(Command::Unknown("/search <words> [from:nick] [in:#chan] [after:YYYY-MM-DD] [before:YYYY-MM-DD]".to_string()))),
         "/reload" => Some(Command::Reload),
         "/diag" | "/diagnostics" => Some(Command::Diag),
         _ => {
@@ -74,13 +282,20 @@ pub fn parse_command(text: &str, custom_commands: &CustomCommands, context: &Com
 
 pub fn check_permission(our_role: Role, action: &ModAction) -> Result<(), &'static str> {
     match action {
-        ModAction::Kick(_) | ModAction::Ban(_) | ModAction::Unban(_) => {
+        ModAction::Kick(_) | ModAction::Ban { .. } | ModAction::Unban(_) => {
             if our_role.can_moderate() {
                 Ok(())
             } else {
                 Err("Only host and mods can kick/ban")
             }
         }
+        ModAction::Mute { .. } | ModAction::Unmute { .. } | ModAction::SlowMode { .. } => {
+            if our_role.can_moderate() {
+                Ok(())
+            } else {
+                Err("Only host and mods can mute or set slow mode")
+            }
+        }
         ModAction::Promote(_) | ModAction::Demote(_) => {
             if our_role.can_promote() {
                 Ok(())
@@ -91,6 +306,17 @@ pub fn check_permission(our_role: Role, action: &ModAction) -> Result<(), &'stat
     }
 }
 
+/// `#chan [key]`; the `#` is optional.
+fn parse_join(arg: &str) -> Option<Command> {
+    let mut words = arg.split_whitespace();
+    let name = words.next()?;
+    if name.trim_start_matches('#').is_empty() {
+        return None;
+    }
+    let channel = if name.starts_with('#') { name.to_string() } else { format!("#{}", name) };
+    Some(Command::Join { channel, key: words.next().map(str::to_string) })
+}
+
 pub fn help_text(our_role: Role, custom_commands: &CustomCommands) -> Vec<String> {
     let mut lines = vec![
         "── Commands ──".to_string(),
@@ -98,6 +324,18 @@ pub fn help_text(our_role: Role, custom_commands: &CustomCommands) -> Vec<String
         "/role           Show your current role".to_string(),
         "/peers, /who    List connected peers".to_string(),
         "/invite, /link  Copy invite link to clipboard".to_string(),
+        "/invite <nick>  Invite a user into this channel".to_string(),
+        "/join <#chan> [key]  Switch to (or create) a channel".to_string(),
+        "/list           List channels on the server".to_string(),
+        "/msg <nick> <text>  Send an end-to-end encrypted DM".to_string(),
+        "/verify <nick> [confirm]  Compare safety numbers; confirm marks the key checked".to_string(),
+        "/reply <nick> <text>  Answer someone's last message".to_string(),
+        "/edit <text>    Correct your last message".to_string(),
+        "/delete         Retract your last message".to_string(),
+        "/react <emoji> [nick]  React to the last message (or nick's)".to_string(),
+        "/search <words> [from:nick] [in:#chan] [after:date] [before:date]  Search the logs".to_string(),
+        "/topic [text]   Show or set the topic (/topic - clears)".to_string(),
+        "/cmode [+ikml...]  Show or set channel modes".to_string(),
         "/diag           Show connection diagnostics".to_string(),
         "/editcommands   Open commands.toml for custom commands".to_string(),
         "/reload         Reload custom commands from disk".to_string(),
@@ -109,9 +347,13 @@ pub fn help_text(our_role: Role, custom_commands: &CustomCommands) -> Vec<String
    
     if our_role.can_moderate() {
         lines.push("/kick <nick>    Disconnect a user".to_string());
-        lines.push("/ban <nick>     Ban a user".to_string());
-        lines.push("/unban <nick>   Unban a user".to_string());
+        lines.push("/ban [--ip] <nick> [30m|2h|7d] [reason]  Ban a user's key (and IP)".to_string());
+        lines.push("/unban <nick|key|ip>  Lift a ban".to_string());
         lines.push("/listbanned     Show ban list".to_string());
+        lines.push("/mute <nick> [10m]       Drop a user's chat (/unmute)".to_string());
+        lines.push("/voicemute <nick> [10m]  Stop relaying a user's voice (/voiceunmute)".to_string());
+        lines.push("/slowmode <secs|off> [1h]  Limit how often peers can post here".to_string());
+        lines.push("/delete <nick>  Retract a user's last message".to_string());
         lines.push("/powset <bits>  Change server PoW requirement (0=off)".to_string());
     }
 
@@ -155,7 +397,7 @@ enum CommandEntryOrString {
 }
 
 impl CommandEntryOrString {
-    fn to_entry(self) -> CommandEntry {
+    fn into_entry(self) -> CommandEntry {
         match self {
             CommandEntryOrString::Full(e) => e,
             CommandEntryOrString::Simple(s) => CommandEntry {
@@ -185,7 +427,7 @@ impl CustomCommands {
                 match toml::from_str::<CommandsFile>(&content) {
                     Ok(file) => {
                         let commands = file.commands.into_iter()
-                            .map(|(k, v)| (k, v.to_entry()))
+                            .map(|(k, v)| (k, v.into_entry()))
                             .collect();
                         Self { commands }
                     }
@@ -314,7 +556,65 @@ mod tests {
             peers: vec![],
         };
         let result = parse_command("/ban spammer", &custom, &ctx);
-        assert!(matches!(result, Some(Command::Mod(ModAction::Ban(nick))) if nick == "spammer"));
+        assert!(matches!(result, Some(Command::Mod(ModAction::Ban { target, duration: None, reason: None, ip: false })) if target == "spammer"));
+
+        let result = parse_command("/ban --ip spammer 2h posting links", &custom, &ctx);
+        match result {
+            Some(Command::Mod(ModAction::Ban { target, duration, reason, ip })) => {
+                assert_eq!(target, "spammer");
+                assert_eq!(duration, Some(Duration::from_secs(7200)));
+                assert_eq!(reason.as_deref(), Some("posting links"));
+                assert!(ip);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // A first word that isn't a duration is part of the reason.
+        let result = parse_command("/ban spammer being rude", &custom, &ctx);
+        assert!(matches!(result, Some(Command::Mod(ModAction::Ban { duration: None, reason: Some(r), .. })) if r == "being rude"));
+    }
+
+    #[test]
+    fn test_parse_and_format_duration() {
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15"), Some(Duration::from_secs(900)));
+        assert_eq!(parse_duration("7d"), Some(Duration::from_secs(7 * 86_400)));
+        assert_eq!(parse_duration("1w"), Some(Duration::from_secs(604_800)));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("rude"), None);
+        assert_eq!(parse_duration("5y"), None);
+        assert_eq!(format_duration(45), "45s");
+        assert_eq!(format_duration(7200), "2h");
+        assert_eq!(format_duration(3 * 86_400 + 5), "3d");
+    }
+
+    #[test]
+    fn test_parse_command_mute_and_slowmode() {
+        let custom = CustomCommands::default();
+        let ctx = CommandContext {
+            nick: "test".to_string(),
+            channel: "#general".to_string(),
+            role: Role::Mod,
+            peers: vec![],
+        };
+        let result = parse_command("/mute troll 10m", &custom, &ctx);
+        assert!(matches!(result, Some(Command::Mod(ModAction::Mute { target, kind: MuteKind::Chat, duration: Some(d) }))
+            if target == "troll" && d == Duration::from_secs(600)));
+        let result = parse_command("/voicemute troll", &custom, &ctx);
+        assert!(matches!(result, Some(Command::Mod(ModAction::Mute { kind: MuteKind::Voice, duration: None, .. }))));
+        let result = parse_command("/voiceunmute troll", &custom, &ctx);
+        assert!(matches!(result, Some(Command::Mod(ModAction::Unmute { kind: MuteKind::Voice, .. }))));
+        assert!(matches!(parse_command("/mute troll forever", &custom, &ctx), Some(Command::Unknown(_))));
+
+        let result = parse_command("/slowmode 30 1h", &custom, &ctx);
+        assert!(matches!(result, Some(Command::Mod(ModAction::SlowMode { interval: Some(i), duration: Some(d) }))
+            if i == Duration::from_secs(30) && d == Duration::from_secs(3600)));
+        let result = parse_command("/slowmode 2m", &custom, &ctx);
+        assert!(matches!(result, Some(Command::Mod(ModAction::SlowMode { interval: Some(i), duration: None }))
+            if i == Duration::from_secs(120)));
+        let result = parse_command("/slowmode off", &custom, &ctx);
+        assert!(matches!(result, Some(Command::Mod(ModAction::SlowMode { interval: None, .. }))));
+        assert!(matches!(parse_command("/slowmode", &custom, &ctx), Some(Command::Unknown(_))));
     }
 
     #[test]
@@ -394,6 +694,98 @@ mod tests {
         assert!(matches!(parse_command("/who", &custom, &ctx), Some(Command::ListPeers)));
     }
 
+    #[test]
+    fn test_parse_command_join() {
+        let custom = CustomCommands::default();
+        let ctx = CommandContext {
+            nick: "test".to_string(),
+            channel: "#general".to_string(),
+            role: Role::Peer,
+            peers: vec![],
+        };
+        assert!(matches!(parse_command("/join #ops", &custom, &ctx), Some(Command::Join { channel, key: None }) if channel == "#ops"));
+        assert!(matches!(parse_command("/j ops", &custom, &ctx), Some(Command::Join { channel, .. }) if channel == "#ops"));
+        assert!(matches!(parse_command("/join #", &custom, &ctx), Some(Command::Unknown(_))));
+        assert!(matches!(
+            parse_command("/join #ops s3cret", &custom, &ctx),
+            Some(Command::Join { channel, key: Some(k) }) if channel == "#ops" && k == "s3cret"
+        ));
+    }
+
+    #[test]
+    fn test_parse_command_channel_settings() {
+        let custom = CustomCommands::default();
+        let ctx = CommandContext {
+            nick: "test".to_string(),
+            channel: "#general".to_string(),
+            role: Role::Peer,
+            peers: vec![],
+        };
+        assert!(matches!(parse_command("/topic", &custom, &ctx), Some(Command::Topic(None))));
+        assert!(matches!(parse_command("/topic hi all", &custom, &ctx), Some(Command::Topic(Some(t))) if t == "hi all"));
+        assert!(matches!(parse_command("/topic -", &custom, &ctx), Some(Command::Topic(Some(t))) if t.is_empty()));
+        assert!(matches!(parse_command("/mode +ik key", &custom, &ctx), Some(Command::Mode(m)) if m == "+ik key"));
+        assert!(matches!(parse_command("/cmode", &custom, &ctx), Some(Command::Mode(m)) if m.is_empty()));
+        assert!(matches!(parse_command("/invite bob", &custom, &ctx), Some(Command::InviteUser(n)) if n == "bob"));
+        assert!(matches!(parse_command("/invite", &custom, &ctx), Some(Command::Invite)));
+        assert!(matches!(parse_command("/list", &custom, &ctx), Some(Command::ListChannels)));
+    }
+
+    #[test]
+    fn test_parse_command_direct_message() {
+        let custom = CustomCommands::default();
+        let ctx = CommandContext {
+            nick: "test".to_string(),
+            channel: "#general".to_string(),
+            role: Role::Peer,
+            peers: vec![],
+        };
+        assert!(matches!(
+            parse_command("/msg bob see you at 10", &custom, &ctx),
+            Some(Command::DirectMessage { to, text }) if to == "bob" && text == "see you at 10"
+        ));
+        assert!(matches!(parse_command("/dm bob hi", &custom, &ctx), Some(Command::DirectMessage { .. })));
+        assert!(matches!(parse_command("/msg bob", &custom, &ctx), Some(Command::Unknown(_))));
+        assert!(matches!(parse_command("/msg bob  ", &custom, &ctx), Some(Command::Unknown(_))));
+
+        assert!(matches!(
+            parse_command("/verify bob", &custom, &ctx),
+            Some(Command::Verify { nick, confirm: false }) if nick == "bob"
+        ));
+        assert!(matches!(parse_command("/verify bob confirm", &custom, &ctx), Some(Command::Verify { confirm: true, .. })));
+        assert!(matches!(parse_command("/verify bob yes", &custom, &ctx), Some(Command::Unknown(_))));
+    }
+
+    #[test]
+    fn test_parse_command_message_events() {
+        let custom = CustomCommands::default();
+        let ctx = CommandContext {
+            nick: "test".to_string(),
+            channel: "#general".to_string(),
+            role: Role::Peer,
+            peers: vec![],
+        };
+        assert!(matches!(parse_command("/edit hello there", &custom, &ctx), Some(Command::Edit(t)) if t == "hello there"));
+        assert!(matches!(parse_command("/edit", &custom, &ctx), Some(Command::Unknown(_))));
+        assert!(matches!(parse_command("/delete", &custom, &ctx), Some(Command::Delete(None))));
+        assert!(matches!(parse_command("/del bob", &custom, &ctx), Some(Command::Delete(Some(n))) if n == "bob"));
+        assert!(matches!(
+            parse_command("/react 👍 bob", &custom, &ctx),
+            Some(Command::React { reaction, nick: Some(n) }) if reaction == "👍" && n == "bob"
+        ));
+        assert!(matches!(parse_command("/react", &custom, &ctx), Some(Command::Unknown(_))));
+        assert!(matches!(
+            parse_command("/reply bob sounds good", &custom, &ctx),
+            Some(Command::Reply { nick, text }) if nick == "bob" && text == "sounds good"
+        ));
+        assert!(matches!(parse_command("/re bob", &custom, &ctx), Some(Command::Unknown(_))));
+        assert!(matches!(
+            parse_command("/search build from:bob", &custom, &ctx),
+            Some(Command::Search(q)) if q.words == ["build"] && q.author.as_deref() == Some("bob")
+        ));
+        assert!(matches!(parse_command("/search after:soon", &custom, &ctx), Some(Command::Unknown(_))));
+    }
+
     #[test]
     fn test_parse_command_unknown() {
         let custom = CustomCommands::default();
@@ -407,25 +799,32 @@ mod tests {
         assert!(matches!(parse_command("/unknowncommand", &custom, &ctx), Some(Command::Unknown(cmd)) if cmd == "/unknowncommand"));
     }
 
+    fn ban(target: &str) -> ModAction {
+        ModAction::Ban { target: target.to_string(), duration: None, reason: None, ip: false }
+    }
+
     #[test]
     fn test_check_permission_kick_ban_host() {
         assert!(check_permission(Role::Host, &ModAction::Kick("user".to_string())).is_ok());
-        assert!(check_permission(Role::Host, &ModAction::Ban("user".to_string())).is_ok());
+        assert!(check_permission(Role::Host, &ban("user")).is_ok());
         assert!(check_permission(Role::Host, &ModAction::Unban("user".to_string())).is_ok());
     }
 
     #[test]
     fn test_check_permission_kick_ban_mod() {
         assert!(check_permission(Role::Mod, &ModAction::Kick("user".to_string())).is_ok());
-        assert!(check_permission(Role::Mod, &ModAction::Ban("user".to_string())).is_ok());
+        assert!(check_permission(Role::Mod, &ban("user")).is_ok());
         assert!(check_permission(Role::Mod, &ModAction::Unban("user".to_string())).is_ok());
     }
 
     #[test]
     fn test_check_permission_kick_ban_peer_fails() {
         assert!(check_permission(Role::Peer, &ModAction::Kick("user".to_string())).is_err());
-        assert!(check_permission(Role::Peer, &ModAction::Ban("user".to_string())).is_err());
+        assert!(check_permission(Role::Peer, &ban("user")).is_err());
         assert!(check_permission(Role::Peer, &ModAction::Unban("user".to_string())).is_err());
+        let mute = ModAction::Mute { target: "user".to_string(), kind: MuteKind::Voice, duration: None };
+        assert!(check_permission(Role::Peer, &mute).is_err());
+        assert!(check_permission(Role::Mod, &mute).is_ok());
     }
 
     #[test]
@@ -440,6 +839,13 @@ mod tests {
         assert!(check_permission(Role::Peer, &ModAction::Demote("user".to_string())).is_err());
     }
 
+    #[test]
+    fn test_mod_action_from_wire() {
+        assert!(matches!(ModAction::from_wire("kick", "bob"), Some(ModAction::Kick(n)) if n == "bob"));
+        assert!(matches!(ModAction::from_wire("promote", "bob"), Some(ModAction::Promote(_))));
+        assert!(ModAction::from_wire("nuke", "bob").is_none());
+    }
+
     #[test]
     fn test_help_text_peer() {
         let custom = CustomCommands::default();
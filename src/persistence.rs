@@ -1,28 +1,36 @@
 // persistence.rs
 //
-// Signed message log with lightweight hash chain.
+// Signed message log, linked into a per-channel DAG.
 //
-// Each message is signed with ed25519. The chain_hash field
-// is SHA256(last N message timestamps), making backdating
-// detectable without requiring full blockchain consensus.
+// Each message is signed with ed25519 and names the hashes of the
+// channel's newest messages as its parents (see `dag`), so history
+// can't be dropped, reordered or forked without it showing.  Logs
+// written before that carry a `chain_hash` of recent timestamps
+// instead; they still verify, they just link to nothing.
 //
-// Sync protocol: on reconnect, peers exchange their log tail
-// and merge by timestamp. Signature verification happens on
-// every received message before it enters the local log.
+// Sync protocol: when someone joins, each member sends them a
+// digest of every channel it is in (one bucket per day: message
+// count and a hash of the ids).  The other side answers with the
+// ids it holds on the days that differ; from that each side knows
+// what the other lacks, pushes its own extras and asks for the
+// rest, in batches of at most SYNC_BATCH_SIZE.  Signature
+// verification happens on every received message before it
+// enters the local log.
 
 use anyhow::{anyhow, Result};
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use rand::rngs::OsRng;
 use ring::digest;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use tracing::{info, warn};
 
-// How many recent timestamps to hash for the chain
-const CHAIN_WINDOW: usize = 5;
+use crate::dag::{ChannelDag, DagCheck};
+use crate::search::{SearchIndex, SearchQuery, SearchResults};
+
 // Tolerance for clock drift before flagging a message (seconds)
 const TIMESTAMP_TOLERANCE_SECS: i64 = 120;
 
@@ -38,21 +46,93 @@ pub struct SignedMessage {
     pub channel: String,
     pub content: String,
     pub timestamp: i64,       // unix seconds
-    pub chain_hash: String,   // hex SHA256 of last N timestamps seen by author
+    /// Hex `hash()`es of the channel heads the author had seen.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parents: Vec<String>,
+    /// Legacy: hex SHA256 of the author's last few timestamps, from
+    /// before `parents`.  Only kept so old logs still verify.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub chain_hash: String,
     pub signature: String,    // hex ed25519 signature over canonical bytes
+    /// `content` is sealed with the author's channel key (see `group`).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+    /// Set when this edits, retracts or reacts to an earlier message
+    /// rather than saying something new.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<MessageEvent>,
+    /// Id of the message this one answers, in the same channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
 }
 
-/// What we send over the wire during sync
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SyncRequest {
-    pub channel: String,
-    pub since: i64, // unix timestamp — "give me everything after this"
+/// What an event message does to its `target` (a message id in the same
+/// channel).  `content` is the new text for an edit, the reaction (usually
+/// one emoji) for a reaction, and unused for a redaction.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MessageEvent {
+    /// Only the target's author may edit it.
+    Edit { target: String },
+    /// The author or a moderator may retract it.
+    Redact { target: String },
+    React { target: String },
+}
+
+impl MessageEvent {
+    pub fn target(&self) -> &str {
+        match self {
+            MessageEvent::Edit { target } | MessageEvent::Redact { target } | MessageEvent::React { target } => target,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SyncResponse {
+/// A message with the events that target it folded in.
+#[derive(Clone, Debug)]
+pub struct MessageView {
+    pub message: SignedMessage,
+    /// The author's latest edit.
+    pub edit: Option<SignedMessage>,
+    pub redaction: Option<SignedMessage>,
+    /// Oldest first; may repeat a reactor, since sealed ones can only be
+    /// told apart once opened.
+    pub reactions: Vec<SignedMessage>,
+}
+
+/// Most messages in one `SyncMessage::Batch`; more go out as several.
+pub const SYNC_BATCH_SIZE: usize = 50;
+/// Most messages one side asks for per channel in one exchange.
+pub const SYNC_MAX_WANT: usize = 2000;
+/// Digest buckets are a day of history each.
+const SYNC_BUCKET_SECS: i64 = 86_400;
+
+/// One day of a channel's log, as compared during sync.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Bucket {
+    /// Unix day (timestamp / 86400).
+    pub day: i64,
+    pub count: usize,
+    /// Hex SHA256 of the day's sorted ids, truncated to 16 chars.
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChannelDigest {
     pub channel: String,
-    pub messages: Vec<SignedMessage>,
+    pub buckets: Vec<Bucket>,
+}
+
+/// What peers send each other as `VOIRC_SYNC:` fragments.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SyncMessage {
+    /// Summaries of every channel the sender is in.
+    Digest { channels: Vec<ChannelDigest> },
+    /// Answer to a digest: the ids the sender holds on the days that differ.
+    Inventory { channel: String, days: Vec<i64>, ids: Vec<String> },
+    /// Ids the sender is missing.
+    Want { channel: String, ids: Vec<String> },
+    Batch { channel: String, messages: Vec<SignedMessage> },
 }
 
 // ---------------------------------------------------------------------------
@@ -62,13 +142,12 @@ pub struct SyncResponse {
 #[derive(Clone)]
 pub struct Identity {
     pub signing_key: Arc<SigningKey>,
-    pub verifying_key: VerifyingKey,
     pub pubkey_hex: String,
 }
 
 impl Identity {
     /// Load from disk or generate fresh
-    pub fn load_or_generate(config_dir: &PathBuf) -> Result<Self> {
+    pub fn load_or_generate(config_dir: &Path) -> Result<Self> {
         let key_path = config_dir.join("identity.key");
 
         let signing_key = if key_path.exists() {
@@ -85,12 +164,10 @@ impl Identity {
             key
         };
 
-        let verifying_key = signing_key.verifying_key();
-        let pubkey_hex = hex::encode(verifying_key.as_bytes());
+        let pubkey_hex = hex::encode(signing_key.verifying_key().as_bytes());
 
         Ok(Self {
             signing_key: Arc::new(signing_key),
-            verifying_key,
             pubkey_hex,
         })
     }
@@ -107,29 +184,139 @@ impl SignedMessage {
         author: &str,
         channel: &str,
         content: &str,
-        recent_timestamps: &[i64],
+        parents: &[String],
     ) -> Result<Self> {
-        let id = uuid::Uuid::new_v4().to_string();
-        let timestamp = chrono::Utc::now().timestamp();
-        let chain_hash = compute_chain_hash(recent_timestamps);
+        Self::draft(identity, author, channel, content, false, parents).signed(identity)
+    }
 
-        let canonical = canonical_bytes(&id, author, channel, content, timestamp, &chain_hash);
-        let signature = identity.signing_key.sign(&canonical);
+    /// Like `create`, for content already sealed with `group::GroupKeys`.
+    /// The signature covers the ciphertext, so it still verifies for
+    /// anyone, including the host, without the key.
+    pub fn create_sealed(
+        identity: &Identity,
+        author: &str,
+        channel: &str,
+        sealed: &str,
+        parents: &[String],
+    ) -> Result<Self> {
+        Self::draft(identity, author, channel, sealed, true, parents).signed(identity)
+    }
 
-        Ok(Self {
-            id,
+    /// A message answering `reply_to`; `content` is sealed if `encrypted`.
+    pub fn create_reply(
+        identity: &Identity,
+        author: &str,
+        channel: &str,
+        reply_to: &str,
+        content: &str,
+        encrypted: bool,
+        parents: &[String],
+    ) -> Result<Self> {
+        Self {
+            reply_to: Some(reply_to.to_string()),
+            ..Self::draft(identity, author, channel, content, encrypted, parents)
+        }.signed(identity)
+    }
+
+    /// An edit, redaction or reaction; `content` is sealed if `encrypted`.
+    pub fn create_event(
+        identity: &Identity,
+        author: &str,
+        channel: &str,
+        event: MessageEvent,
+        content: &str,
+        encrypted: bool,
+        parents: &[String],
+    ) -> Result<Self> {
+        Self {
+            event: Some(event),
+            ..Self::draft(identity, author, channel, content, encrypted, parents)
+        }.signed(identity)
+    }
+
+    /// Everything but the signature.
+    fn draft(
+        identity: &Identity,
+        author: &str,
+        channel: &str,
+        content: &str,
+        encrypted: bool,
+        parents: &[String],
+    ) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
             author: author.to_string(),
             pubkey: identity.pubkey_hex.clone(),
             channel: channel.to_string(),
             content: content.to_string(),
-            timestamp,
-            chain_hash,
-            signature: hex::encode(signature.to_bytes()),
-        })
+            timestamp: chrono::Utc::now().timestamp(),
+            parents: parents.to_vec(),
+            chain_hash: String::new(),
+            signature: String::new(),
+            encrypted,
+            event: None,
+            reply_to: None,
+        }
+    }
+
+    fn signed(mut self, identity: &Identity) -> Result<Self> {
+        let signature = identity.signing_key.sign(&self.canonical_bytes());
+        self.signature = hex::encode(signature.to_bytes());
+        Ok(self)
     }
 
-    /// Verify signature and optionally check chain integrity
-    pub fn verify(&self, known_timestamps: Option<&[i64]>) -> VerifyResult {
+    /// Written before the DAG: links to nothing and can't be checked
+    /// for forks.
+    pub fn is_legacy(&self) -> bool {
+        self.parents.is_empty() && !self.chain_hash.is_empty()
+    }
+
+    /// What children name as a parent: hex SHA256 over everything
+    /// signed plus the key and signature, so it pins the exact message.
+    pub fn hash(&self) -> String {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(b"voirc-msg-v1\0");
+        ctx.update(&self.canonical_bytes());
+        ctx.update(b"\0");
+        ctx.update(self.pubkey.as_bytes());
+        ctx.update(b"\0");
+        ctx.update(self.signature.as_bytes());
+        hex::encode(ctx.finish().as_ref())
+    }
+
+    /// Deterministic canonical form: fields joined with null bytes
+    /// so no field can "bleed" into another.
+    fn canonical_bytes(&self) -> Vec<u8> {
+        // Legacy messages signed their chain hash in the slot the parents
+        // use now.
+        let links = if self.is_legacy() {
+            self.chain_hash.clone()
+        } else {
+            format!("dag:{}", self.parents.join(","))
+        };
+        let mut canonical = format!(
+            "{}\0{}\0{}\0{}\0{}\0{}",
+            self.id, self.author, self.channel, self.content, self.timestamp, links,
+        );
+        match &self.event {
+            Some(MessageEvent::Edit { target }) => canonical.push_str(&format!("\0edit:{}", target)),
+            Some(MessageEvent::Redact { target }) => canonical.push_str(&format!("\0redact:{}", target)),
+            Some(MessageEvent::React { target }) => canonical.push_str(&format!("\0react:{}", target)),
+            None => {}
+        }
+        if let Some(target) = &self.reply_to {
+            canonical.push_str(&format!("\0reply:{}", target));
+        }
+        // Only sealed messages carry the flag, so plain ones sign as before.
+        if self.encrypted {
+            canonical.push_str("\0e2e");
+        }
+        canonical.into_bytes()
+    }
+
+    /// Verify the signature and flag timestamps from the future.  Where the
+    /// message sits in history is `MessageLog::append`'s to judge.
+    pub fn verify(&self) -> VerifyResult {
         // 1. Verify signature
         let pubkey_bytes = match hex::decode(&self.pubkey) {
             Ok(b) => b,
@@ -154,27 +341,11 @@ impl SignedMessage {
         };
         let signature = Signature::from_bytes(&sig_arr);
 
-        let canonical = canonical_bytes(
-            &self.id, &self.author, &self.channel,
-            &self.content, self.timestamp, &self.chain_hash,
-        );
-
-        if verifying_key.verify(&canonical, &signature).is_err() {
+        if verifying_key.verify(&self.canonical_bytes(), &signature).is_err() {
             return VerifyResult::InvalidSignature;
         }
 
-        // 2. Check chain hash if we have context
-        if let Some(timestamps) = known_timestamps {
-            let expected = compute_chain_hash(timestamps);
-            if expected != self.chain_hash {
-                return VerifyResult::ChainMismatch {
-                    expected,
-                    got: self.chain_hash.clone(),
-                };
-            }
-        }
-
-        // 3. Flag suspicious timestamps (far future)
+        // 2. Flag suspicious timestamps (far future)
         let now = chrono::Utc::now().timestamp();
         if self.timestamp > now + TIMESTAMP_TOLERANCE_SECS {
             return VerifyResult::FutureTimestamp;
@@ -182,23 +353,39 @@ impl SignedMessage {
 
         VerifyResult::Ok
     }
+
+    /// For an event, whether its signer may do it to `target`, if we hold
+    /// the target.  Edits are the author's; redactions the author's or a
+    /// moderator's (by pubkey); edits and reactions only apply to plain
+    /// messages.  `Ok` for anything that isn't an event.
+    pub fn verify_event(&self, target: Option<&SignedMessage>, moderators: &HashSet<String>) -> VerifyResult {
+        let Some(event) = &self.event else { return VerifyResult::Ok };
+        let own = target.is_some_and(|t| t.pubkey == self.pubkey);
+        let allowed = match (event, target) {
+            (_, Some(t)) if t.channel != self.channel => false,
+            (MessageEvent::Edit { .. } | MessageEvent::React { .. }, Some(t)) if t.event.is_some() => false,
+            // The fold checks authorship again once the target turns up.
+            (MessageEvent::Edit { .. }, None) => true,
+            (MessageEvent::Edit { .. }, Some(_)) => own,
+            (MessageEvent::Redact { .. }, _) => own || moderators.contains(&self.pubkey),
+            (MessageEvent::React { .. }, _) => true,
+        };
+        if allowed { VerifyResult::Ok } else { VerifyResult::Unauthorized }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum VerifyResult {
     Ok,
     InvalidSignature,
-    ChainMismatch { expected: String, got: String },
     FutureTimestamp,
+    /// An edit or redaction by someone who may not make it.
+    Unauthorized,
 }
 
 impl VerifyResult {
-    pub fn is_ok(&self) -> bool {
-        matches!(self, VerifyResult::Ok)
-    }
-
     pub fn is_suspicious(&self) -> bool {
-        matches!(self, VerifyResult::ChainMismatch { .. } | VerifyResult::FutureTimestamp)
+        matches!(self, VerifyResult::FutureTimestamp)
     }
 }
 
@@ -206,9 +393,22 @@ impl VerifyResult {
 // Local log
 // ---------------------------------------------------------------------------
 
+/// The search index, once someone has searched.
+enum IndexState {
+    Unbuilt,
+    /// Being read from disk; what's appended meanwhile waits here.
+    Building(Vec<SignedMessage>),
+    Ready(Box<SearchIndex>),
+}
+
 pub struct MessageLog {
-    /// channel -> sorted vec of signed messages
-    messages: RwLock<HashMap<String, Vec<SignedMessage>>>,
+    /// channel -> its history
+    messages: RwLock<HashMap<String, ChannelDag>>,
+    /// Built on the first search; see `search`.
+    index: RwLock<IndexState>,
+    /// Held by the one search building the index, so others wait for it
+    /// instead of building their own.
+    index_build: Mutex<()>,
     log_dir: PathBuf,
 }
 
@@ -217,83 +417,230 @@ impl MessageLog {
         std::fs::create_dir_all(&log_dir).ok();
         Arc::new(Self {
             messages: RwLock::new(HashMap::new()),
+            index: RwLock::new(IndexState::Unbuilt),
+            index_build: Mutex::new(()),
             log_dir,
         })
     }
 
-    /// Load persisted messages for a channel from disk
-    pub async fn load_channel(&self, channel: &str) {
-        let path = self.channel_path(channel);
-        if !path.exists() { return; }
-
-        match std::fs::read_to_string(&path) {
-            Ok(content) => {
-                let mut loaded: Vec<SignedMessage> = content
-                    .lines()
-                    .filter(|l| !l.is_empty())
-                    .filter_map(|l| serde_json::from_str(l).ok())
-                    .collect();
-
-                // Sort by timestamp
-                loaded.sort_by_key(|m| m.timestamp);
-
-                let mut messages = self.messages.write().await;
-                messages.insert(channel.to_string(), loaded);
-            }
-            Err(e) => warn!("Failed to load log for {}: {}", channel, e),
+    /// Read a channel's log from disk the first time it's needed.
+    async fn ensure_loaded(&self, channel: &str) {
+        if self.messages.read().await.contains_key(channel) {
+            return;
         }
+        let loaded: Vec<SignedMessage> = match std::fs::read_to_string(self.channel_path(channel)) {
+            Ok(content) => content
+                .lines()
+                .filter(|l| !l.is_empty())
+                .filter_map(|l| serde_json::from_str(l).ok())
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("Failed to load log for {}: {}", channel, e);
+                Vec::new()
+            }
+        };
+        self.messages.write().await
+            .entry(channel.to_string())
+            .or_insert_with(|| ChannelDag::from_messages(loaded));
     }
 
-    /// Append a verified message to the log
-    pub async fn append(&self, msg: SignedMessage) -> Result<()> {
+    /// Append a verified message to the log, and say how it fits the
+    /// history we hold.  Duplicates are dropped and come back clean.
+    pub async fn append(&self, msg: SignedMessage) -> Result<DagCheck> {
         let channel = msg.channel.clone();
-        self.persist_message(&msg)?;
+        self.ensure_loaded(&channel).await;
 
-        let mut messages = self.messages.write().await;
-        let list = messages.entry(channel).or_default();
+        let check = {
+            let mut messages = self.messages.write().await;
+            let dag = messages.entry(channel.clone()).or_default();
+            if dag.contains_id(&msg.id) {
+                return Ok(DagCheck::default());
+            }
+            self.persist_message(&msg)?;
+            dag.insert(msg.clone()).unwrap_or_default()
+        };
+        if check.missing_parents > 0 {
+            info!("{} parent(s) of a message in {} not held yet", check.missing_parents, channel);
+        }
+        match &mut *self.index.write().await {
+            IndexState::Ready(index) => index.add(&msg),
+            IndexState::Building(pending) => pending.push(msg),
+            IndexState::Unbuilt => {}
+        }
+        Ok(check)
+    }
 
-        // Deduplicate by id
-        if list.iter().any(|m| m.id == msg.id) {
-            return Ok(());
+    /// Search every channel's log, building the index first if this is
+    /// the first search.  Appends carry on while it builds.
+    pub async fn search(&self, query: &SearchQuery, limit: usize) -> SearchResults {
+        if let IndexState::Ready(index) = &*self.index.read().await {
+            return index.search(query, limit);
+        }
+        let _building = self.index_build.lock().await;
+        {
+            let mut index = self.index.write().await;
+            if let IndexState::Ready(index) = &*index {
+                return index.search(query, limit);
+            }
+            *index = IndexState::Building(Vec::new());
         }
 
-        list.push(msg);
-        // Keep sorted by timestamp
-        list.sort_by_key(|m| m.timestamp);
+        let dir = self.log_dir.clone();
+        let started = std::time::Instant::now();
+        let mut built = tokio::task::spawn_blocking(move || SearchIndex::build(&dir)).await
+            .unwrap_or_else(|e| {
+                warn!("Building the search index failed: {}", e);
+                SearchIndex::default()
+            });
+        info!("Indexed {} messages for search in {:?}", built.len(), started.elapsed());
+
+        // The build may already have read some of these from disk, which
+        // `add` shrugs off.
+        let mut index = self.index.write().await;
+        if let IndexState::Building(pending) = &*index {
+            for msg in pending {
+                built.add(msg);
+            }
+        }
+        let results = built.search(query, limit);
+        *index = IndexState::Ready(Box::new(built));
+        results
+    }
 
-        Ok(())
+    /// Up to `radius` messages either side of `id`, in display order,
+    /// leaving out edits, redactions and reactions.
+    pub async fn context(&self, channel: &str, id: &str, radius: usize) -> Vec<SignedMessage> {
+        self.ensure_loaded(channel).await;
+        let mut messages = self.messages.write().await;
+        let Some(dag) = messages.get_mut(channel) else { return Vec::new() };
+        let shown: Vec<&SignedMessage> = dag.ordered().iter().filter(|m| m.event.is_none()).collect();
+        let Some(at) = shown.iter().position(|m| m.id == id) else { return Vec::new() };
+        shown[at.saturating_sub(radius)..(at + radius + 1).min(shown.len())]
+            .iter()
+            .map(|m| (*m).clone())
+            .collect()
     }
 
-    /// Get messages after a given timestamp (for sync)
-    pub async fn messages_since(&self, channel: &str, since: i64) -> Vec<SignedMessage> {
-        self.messages.read().await
-            .get(channel)
-            .map(|msgs| msgs.iter().filter(|m| m.timestamp > since).cloned().collect())
-            .unwrap_or_default()
+    /// Per-day buckets of `channel`'s log, for a sync digest.
+    pub async fn digest(&self, channel: &str) -> ChannelDigest {
+        self.ensure_loaded(channel).await;
+        let messages = self.messages.read().await;
+        let mut days: std::collections::BTreeMap<i64, Vec<&str>> = std::collections::BTreeMap::new();
+        for m in messages.get(channel).into_iter().flat_map(ChannelDag::iter) {
+            days.entry(m.timestamp.div_euclid(SYNC_BUCKET_SECS)).or_default().push(&m.id);
+        }
+        let buckets = days.into_iter()
+            .map(|(day, mut ids)| {
+                ids.sort_unstable();
+                let d = digest::digest(&digest::SHA256, ids.join("\n").as_bytes());
+                Bucket { day, count: ids.len(), hash: hex::encode(&d.as_ref()[..8]) }
+            })
+            .collect();
+        ChannelDigest { channel: channel.to_string(), buckets }
     }
 
-    /// Get the last N timestamps for a channel (for chain hash computation)
-    pub async fn recent_timestamps(&self, channel: &str) -> Vec<i64> {
+    /// Ids of `channel`'s messages on the given days.
+    pub async fn ids_on_days(&self, channel: &str, days: &[i64]) -> Vec<String> {
+        self.ensure_loaded(channel).await;
+        let days: HashSet<i64> = days.iter().copied().collect();
         self.messages.read().await
             .get(channel)
             .map(|msgs| {
                 msgs.iter()
-                    .rev()
-                    .take(CHAIN_WINDOW)
-                    .map(|m| m.timestamp)
-                    .collect::<Vec<_>>()
-                    .into_iter()
-                    .rev()
+                    .filter(|m| days.contains(&m.timestamp.div_euclid(SYNC_BUCKET_SECS)))
+                    .map(|m| m.id.clone())
                     .collect()
             })
             .unwrap_or_default()
     }
 
-    /// Get all messages for display
-    pub async fn get_messages(&self, channel: &str) -> Vec<SignedMessage> {
+    /// The messages among `ids` we hold for `channel`, parents first.
+    pub async fn messages_by_id(&self, channel: &str, ids: &[String]) -> Vec<SignedMessage> {
+        self.ensure_loaded(channel).await;
+        let ids: HashSet<&str> = ids.iter().map(String::as_str).collect();
+        self.messages.write().await
+            .get_mut(channel)
+            .map(|dag| dag.ordered().iter().filter(|m| ids.contains(m.id.as_str())).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Parents for the next message we write in `channel`.
+    pub async fn heads(&self, channel: &str) -> Vec<String> {
+        self.ensure_loaded(channel).await;
         self.messages.read().await
             .get(channel)
-            .cloned()
+            .map(ChannelDag::heads)
+            .unwrap_or_default()
+    }
+
+    /// How many messages `channel`'s history names that we don't hold.
+    pub async fn missing_parents(&self, channel: &str) -> usize {
+        self.ensure_loaded(channel).await;
+        self.messages.read().await.get(channel).map_or(0, ChannelDag::missing)
+    }
+
+    pub async fn message(&self, channel: &str, id: &str) -> Option<SignedMessage> {
+        self.ensure_loaded(channel).await;
+        self.messages.read().await.get(channel)?.by_id(id).cloned()
+    }
+
+    /// `id` with its edits, redaction and reactions folded in; `None` if
+    /// we don't hold it or it is itself an event.
+    pub async fn view(&self, channel: &str, id: &str) -> Option<MessageView> {
+        self.ensure_loaded(channel).await;
+        let messages = self.messages.read().await;
+        let dag = messages.get(channel)?;
+        let message = dag.by_id(id).filter(|m| m.event.is_none())?.clone();
+        let mut view = MessageView { message, edit: None, redaction: None, reactions: Vec::new() };
+        let key = |m: &SignedMessage| (m.timestamp, m.hash());
+        for event in dag.events_for(id) {
+            match &event.event {
+                // Edits from anyone else got in before we held the target.
+                Some(MessageEvent::Edit { .. })
+                    if event.pubkey == view.message.pubkey
+                        && view.edit.as_ref().is_none_or(|e| key(e) < key(event)) =>
+                {
+                    view.edit = Some(event.clone());
+                }
+                Some(MessageEvent::Redact { .. }) if view.redaction.as_ref().is_none_or(|r| key(r) > key(event)) => {
+                    view.redaction = Some(event.clone());
+                }
+                Some(MessageEvent::React { .. }) => view.reactions.push(event.clone()),
+                _ => {}
+            }
+        }
+        view.reactions.sort_by_key(key);
+        Some(view)
+    }
+
+    /// The newest message in `channel` that isn't an event or retracted,
+    /// optionally only `pubkey`'s: what `/edit`, `/delete` and `/react`
+    /// act on.
+    pub async fn last_message(&self, channel: &str, pubkey: Option<&str>) -> Option<SignedMessage> {
+        self.ensure_loaded(channel).await;
+        let mut messages = self.messages.write().await;
+        let dag = messages.get_mut(channel)?;
+        dag.ordered();
+        // Sorted now, so `iter` runs in display order.
+        let dag = &*dag;
+        let found = dag.iter().rev()
+            .find(|m| {
+                m.event.is_none()
+                    && pubkey.is_none_or(|k| k == m.pubkey)
+                    && !dag.events_for(&m.id).any(|e| matches!(e.event, Some(MessageEvent::Redact { .. })))
+            })
+            .cloned();
+        found
+    }
+
+    /// Get all messages for display, in DAG order
+    #[cfg(test)]
+    pub async fn get_messages(&self, channel: &str) -> Vec<SignedMessage> {
+        self.ensure_loaded(channel).await;
+        self.messages.write().await
+            .get_mut(channel)
+            .map(|dag| dag.ordered().to_vec())
             .unwrap_or_default()
     }
 
@@ -318,97 +665,96 @@ impl MessageLog {
 // Sync helpers
 // ---------------------------------------------------------------------------
 
-/// Build a sync request to send to a peer on reconnect
-pub fn make_sync_request(channel: &str, since: i64) -> String {
-    let req = SyncRequest { channel: channel.to_string(), since };
-    serde_json::to_string(&req).unwrap_or_default()
+/// Days whose bucket differs between our digest and theirs, including
+/// days only one side has.
+pub fn differing_days(ours: &ChannelDigest, theirs: &[Bucket]) -> Vec<i64> {
+    let ours: HashMap<i64, &Bucket> = ours.buckets.iter().map(|b| (b.day, b)).collect();
+    let theirs: HashMap<i64, &Bucket> = theirs.iter().map(|b| (b.day, b)).collect();
+    let mut days: Vec<i64> = ours.keys()
+        .chain(theirs.keys())
+        .filter(|day| ours.get(day) != theirs.get(day))
+        .copied()
+        .collect();
+    days.sort_unstable();
+    days.dedup();
+    days
+}
+
+/// Split `messages` into `Batch`es of at most `SYNC_BATCH_SIZE`.
+pub fn batches(channel: &str, messages: Vec<SignedMessage>) -> Vec<SyncMessage> {
+    messages
+        .chunks(SYNC_BATCH_SIZE)
+        .map(|chunk| SyncMessage::Batch { channel: channel.to_string(), messages: chunk.to_vec() })
+        .collect()
 }
 
-/// Process incoming sync response — verify all messages and append valid ones
-/// Returns (accepted, rejected) counts
-pub async fn process_sync_response(
+/// Process an incoming sync batch — verify all messages and append valid ones
+/// Returns (accepted, rejected, flagged) counts; flagged ones were accepted
+/// but are reordered or fork their author's history
+pub async fn process_sync_batch(
     log: &Arc<MessageLog>,
-    response: SyncResponse,
-    // known pubkeys: nick -> pubkey_hex (optional trust anchors)
-    trusted_keys: &HashMap<String, String>,
-) -> (usize, usize) {
+    channel: &str,
+    messages: Vec<SignedMessage>,
+    // nick -> every pubkey_hex we've seen it use (known identities plus this session)
+    trusted_keys: &HashMap<String, HashSet<String>>,
+    // pubkeys of the host and mods, who may retract others' messages
+    moderators: &HashSet<String>,
+) -> (usize, usize, usize) {
     let mut accepted = 0;
     let mut rejected = 0;
+    let mut flagged = 0;
 
-    for msg in response.messages {
-        // Optionally check pubkey matches what we know for this nick
-        if let Some(known_key) = trusted_keys.get(&msg.author) {
-            if known_key != &msg.pubkey {
+    for msg in messages {
+        if msg.channel != channel {
+            warn!("Rejected message for {} in a sync batch for {}", msg.channel, channel);
+            rejected += 1;
+            continue;
+        }
+        // A nick we know can't bring history signed by a key it never used
+        if let Some(known_keys) = trusted_keys.get(&msg.author) {
+            if !known_keys.contains(&msg.pubkey) {
                 warn!(
-                    "Pubkey mismatch for {}: expected {}, got {}",
-                    msg.author, known_key, msg.pubkey
+                    "Pubkey mismatch for {}: got {}, not one of its {} known keys",
+                    msg.author, msg.pubkey, known_keys.len()
                 );
                 rejected += 1;
                 continue;
             }
         }
 
-        let result = msg.verify(None);
-
-        match result {
-            VerifyResult::Ok => {
-                if log.append(msg).await.is_ok() {
-                    accepted += 1;
-                }
+        let result = msg.verify();
+        if result == VerifyResult::InvalidSignature {
+            warn!("Rejected message with invalid signature from {}", msg.author);
+            rejected += 1;
+            continue;
+        }
+        if let Some(event) = &msg.event {
+            let target = log.message(channel, event.target()).await;
+            if msg.verify_event(target.as_ref(), moderators) == VerifyResult::Unauthorized {
+                warn!("Rejected {:?} by {}: not theirs to make", event, msg.author);
+                rejected += 1;
+                continue;
             }
-            VerifyResult::ChainMismatch { .. } | VerifyResult::FutureTimestamp => {
-                // Suspicious but not necessarily forged — log and accept with warning
-                warn!("Suspicious message from {}: {:?}", msg.author, result);
-                if log.append(msg).await.is_ok() {
-                    accepted += 1; // still accept, just flag
+        }
+        if result.is_suspicious() {
+            // Suspicious but not necessarily forged — log and accept with warning
+            warn!("Suspicious message from {}: {:?}", msg.author, result);
+        }
+        let author = msg.author.clone();
+        match log.append(msg).await {
+            Ok(check) => {
+                accepted += 1;
+                if check.is_suspicious() {
+                    // Still accept: dropping it would hide the fork, not fix it
+                    warn!("Message from {} doesn't fit the history: {:?}", author, check);
+                    flagged += 1;
                 }
             }
-            VerifyResult::InvalidSignature => {
-                warn!("Rejected message with invalid signature from {}", msg.author);
-                rejected += 1;
-            }
+            Err(e) => warn!("Failed to store synced message: {}", e),
         }
     }
 
-    (accepted, rejected)
-}
-
-// ---------------------------------------------------------------------------
-// Internals
-// ---------------------------------------------------------------------------
-
-fn canonical_bytes(
-    id: &str,
-    author: &str,
-    channel: &str,
-    content: &str,
-    timestamp: i64,
-    chain_hash: &str,
-) -> Vec<u8> {
-    // Deterministic canonical form: join fields with null bytes
-    // so no field can "bleed" into another
-    format!("{}\0{}\0{}\0{}\0{}\0{}", id, author, channel, content, timestamp, chain_hash)
-        .into_bytes()
-}
-
-fn compute_chain_hash(timestamps: &[i64]) -> String {
-    let tail: Vec<i64> = timestamps
-        .iter()
-        .rev()
-        .take(CHAIN_WINDOW)
-        .cloned()
-        .collect::<Vec<_>>()
-        .into_iter()
-        .rev()
-        .collect();
-
-    let input = tail.iter()
-        .map(|t| t.to_string())
-        .collect::<Vec<_>>()
-        .join(",");
-
-    let d = digest::digest(&digest::SHA256, input.as_bytes());
-    hex::encode(d.as_ref())
+    (accepted, rejected, flagged)
 }
 
 // ---------------------------------------------------------------------------
@@ -420,6 +766,7 @@ mod tests {
     use super::*;
     use tempfile::tempdir;
 
+    #[allow(clippy::ptr_arg)]
     fn make_identity(dir: &PathBuf) -> Identity {
         Identity::load_or_generate(dir).unwrap()
     }
@@ -433,7 +780,7 @@ mod tests {
             &identity, "alice", "#general", "hello world", &[],
         ).unwrap();
 
-        assert_eq!(msg.verify(None), VerifyResult::Ok);
+        assert_eq!(msg.verify(), VerifyResult::Ok);
     }
 
     #[test]
@@ -446,34 +793,79 @@ mod tests {
         ).unwrap();
 
         msg.content = "tampered content".to_string();
-        assert_eq!(msg.verify(None), VerifyResult::InvalidSignature);
+        assert_eq!(msg.verify(), VerifyResult::InvalidSignature);
     }
 
     #[test]
-    fn test_chain_hash_mismatch_detected() {
+    fn test_encrypted_flag_is_signed() {
         let dir = tempdir().unwrap();
         let identity = make_identity(&dir.path().to_path_buf());
 
-        let msg = SignedMessage::create(
-            &identity, "alice", "#general", "hello", &[1000, 2000, 3000],
+        let mut msg = SignedMessage::create_sealed(
+            &identity, "alice", "#ops", "0a1b2c3d:c2VhbGVk", &[],
         ).unwrap();
+        assert!(msg.encrypted);
+        assert_eq!(msg.verify(), VerifyResult::Ok);
 
-        // Verify against different timestamps — should flag chain mismatch
-        let result = msg.verify(Some(&[9000, 9001]));
-        assert!(matches!(result, VerifyResult::ChainMismatch { .. }));
+        msg.encrypted = false;
+        assert_eq!(msg.verify(), VerifyResult::InvalidSignature);
     }
 
     #[test]
-    fn test_chain_hash_matches() {
+    fn test_parents_are_signed() {
         let dir = tempdir().unwrap();
         let identity = make_identity(&dir.path().to_path_buf());
 
-        let timestamps = vec![1000i64, 2000, 3000];
-        let msg = SignedMessage::create(
-            &identity, "alice", "#general", "hello", &timestamps,
+        let first = SignedMessage::create(&identity, "alice", "#general", "hello", &[]).unwrap();
+        let mut msg = SignedMessage::create(
+            &identity, "alice", "#general", "again", &[first.hash()],
         ).unwrap();
+        assert_eq!(msg.verify(), VerifyResult::Ok);
+        assert_ne!(msg.hash(), first.hash());
 
-        assert_eq!(msg.verify(Some(&timestamps)), VerifyResult::Ok);
+        // Rewiring the history breaks the signature.
+        msg.parents.clear();
+        assert_eq!(msg.verify(), VerifyResult::InvalidSignature);
+    }
+
+    #[test]
+    fn test_reply_to_is_signed() {
+        let dir = tempdir().unwrap();
+        let identity = make_identity(&dir.path().to_path_buf());
+
+        let mut reply = SignedMessage::create_reply(
+            &identity, "alice", "#general", "some-id", "agreed", false, &[],
+        ).unwrap();
+        assert_eq!(reply.verify(), VerifyResult::Ok);
+        reply.reply_to = Some("another-id".to_string());
+        assert_eq!(reply.verify(), VerifyResult::InvalidSignature);
+    }
+
+    #[test]
+    fn test_legacy_chain_hash_still_verifies() {
+        let dir = tempdir().unwrap();
+        let identity = make_identity(&dir.path().to_path_buf());
+
+        // How messages were signed before the DAG.
+        let canonical = ["legacy-id", "alice", "#general", "hello", "1000", "c0ffee"].join("\0");
+        let msg = SignedMessage {
+            id: "legacy-id".to_string(),
+            author: "alice".to_string(),
+            pubkey: identity.pubkey_hex.clone(),
+            channel: "#general".to_string(),
+            content: "hello".to_string(),
+            timestamp: 1000,
+            parents: Vec::new(),
+            chain_hash: "c0ffee".to_string(),
+            signature: hex::encode(identity.signing_key.sign(canonical.as_bytes()).to_bytes()),
+            encrypted: false,
+            event: None,
+            reply_to: None,
+        };
+        assert!(msg.is_legacy());
+        assert_eq!(msg.verify(), VerifyResult::Ok);
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains("chain_hash") && !json.contains("parents"));
     }
 
     #[test]
@@ -492,7 +884,7 @@ mod tests {
 
         // Signature won't match due to changed timestamp, so we get InvalidSignature
         // The FutureTimestamp check comes after signature verification
-        assert_eq!(msg.verify(None), VerifyResult::InvalidSignature);
+        assert_eq!(msg.verify(), VerifyResult::InvalidSignature);
     }
 
     #[tokio::test]
@@ -530,27 +922,135 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_messages_since() {
+    async fn test_appends_dont_wait_for_the_index_build() {
         let dir = tempdir().unwrap();
-        let log = MessageLog::new(dir.path().to_path_buf());
+        let log = MessageLog::new(dir.path().join("logs"));
         let identity = make_identity(&dir.path().to_path_buf());
+        let query = SearchQuery::parse("deploy").unwrap();
+        log.append(SignedMessage::create(&identity, "alice", "#ops", "deploy one", &[]).unwrap()).await.unwrap();
+
+        // Mid-build, an append queues instead of waiting for the index.
+        *log.index.write().await = IndexState::Building(Vec::new());
+        let second = SignedMessage::create(&identity, "alice", "#ops", "deploy two", &[]).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), log.append(second)).await.unwrap().unwrap();
+        assert!(matches!(&*log.index.read().await, IndexState::Building(pending) if pending.len() == 1));
+
+        assert_eq!(log.search(&query, 10).await.total, 2);
+        let third = SignedMessage::create(&identity, "alice", "#ops", "deploy three", &[]).unwrap();
+        log.append(third).await.unwrap();
+        assert_eq!(log.search(&query, 10).await.total, 3);
+    }
 
-        for i in 0..5 {
-            let mut msg = SignedMessage::create(
-                &identity, "alice", "#general", &format!("msg {}", i), &[],
-            ).unwrap();
-            msg.timestamp = 1000 + i;
-            // Re-sign with correct timestamp
-            let msg = SignedMessage::create(
-                &identity, "alice", "#general", &format!("msg {}", i), &[],
-            ).unwrap();
-            log.append(msg).await.unwrap();
+    #[tokio::test]
+    async fn test_digest_reconciliation() {
+        let (dir_a, dir_b) = (tempdir().unwrap(), tempdir().unwrap());
+        let (ours, theirs) = (MessageLog::new(dir_a.path().join("logs")), MessageLog::new(dir_b.path().join("logs")));
+        let identity = make_identity(&dir_a.path().to_path_buf());
+
+        let mut shared = Vec::new();
+        for (i, day) in [10, 10, 11].into_iter().enumerate() {
+            let mut msg = SignedMessage::create(&identity, "alice", "#general", &format!("msg {}", i), &[]).unwrap();
+            msg.timestamp = day * SYNC_BUCKET_SECS + i as i64;
+            shared.push(msg);
         }
+        for msg in &shared {
+            ours.append(msg.clone()).await.unwrap();
+            theirs.append(msg.clone()).await.unwrap();
+        }
+        let mut only_ours = shared[0].clone();
+        only_ours.id = "extra".to_string();
+        ours.append(only_ours).await.unwrap();
+        let mut only_theirs = shared[2].clone();
+        only_theirs.id = "new day".to_string();
+        only_theirs.timestamp = 12 * SYNC_BUCKET_SECS;
+        theirs.append(only_theirs).await.unwrap();
+
+        let days = differing_days(&ours.digest("#general").await, &theirs.digest("#general").await.buckets);
+        assert_eq!(days, vec![10, 12]);
+        let mut theirs_ids = theirs.ids_on_days("#general", &days).await;
+        theirs_ids.sort();
+        let mut expected = vec!["new day".to_string(), shared[0].id.clone(), shared[1].id.clone()];
+        expected.sort();
+        assert_eq!(theirs_ids, expected);
+        assert_eq!(ours.messages_by_id("#general", &["extra".to_string()]).await.len(), 1);
+
+        // Logs are read back from disk, without duplicates.
+        ours.append(shared[0].clone()).await.unwrap();
+        let reloaded = MessageLog::new(dir_a.path().join("logs"));
+        assert_eq!(reloaded.get_messages("#general").await.len(), 4);
+        assert_eq!(reloaded.digest("#general").await, ours.digest("#general").await);
+
+        let msgs: Vec<SignedMessage> = (0..SYNC_BATCH_SIZE + 1).map(|_| shared[0].clone()).collect();
+        assert_eq!(batches("#general", msgs).len(), 2);
+    }
 
-        // We can't easily control timestamps in tests without exposing internals,
-        // so just verify the since=0 case returns everything
-        let all = log.messages_since("#general", 0).await;
-        assert_eq!(all.len(), 5);
+    #[tokio::test]
+    async fn test_edits_redactions_and_reactions() {
+        let (dir_a, dir_b, dir_c) = (tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap());
+        let log = MessageLog::new(dir_a.path().join("logs"));
+        let (alice, bob, carol) = (make_identity(&dir_a.path().to_path_buf()), make_identity(&dir_b.path().to_path_buf()), make_identity(&dir_c.path().to_path_buf()));
+        let original = SignedMessage::create(&alice, "alice", "#general", "helo", &[]).unwrap();
+        log.append(original.clone()).await.unwrap();
+        let parents = vec![original.hash()];
+        let event = |who: &Identity, nick: &str, event: MessageEvent, content: &str| {
+            SignedMessage::create_event(who, nick, "#general", event, content, false, &parents).unwrap()
+        };
+        let none = HashSet::new();
+
+        let target = original.id.clone();
+        let edit = |who, nick, text| event(who, nick, MessageEvent::Edit { target: target.clone() }, text);
+
+        // The target is signed: pointing an edit elsewhere breaks it.
+        let mut moved = edit(&alice, "alice", "hello");
+        moved.event = Some(MessageEvent::Edit { target: "other".to_string() });
+        assert_eq!(moved.verify(), VerifyResult::InvalidSignature);
+
+        let mut first = edit(&alice, "alice", "hullo");
+        first.timestamp -= 10;
+        let batch = vec![
+            first,
+            edit(&bob, "bob", "pwned"),
+            edit(&alice, "alice", "hello"),
+            event(&bob, "bob", MessageEvent::React { target: target.clone() }, "👍"),
+            event(&bob, "bob", MessageEvent::Redact { target: target.clone() }, ""),
+        ];
+        // First edit fails its signature after the timestamp change.
+        assert_eq!(process_sync_batch(&log, "#general", batch, &HashMap::new(), &none).await, (2, 3, 0));
+        let view = log.view("#general", &target).await.unwrap();
+        assert_eq!(view.edit.unwrap().content, "hello");
+        assert_eq!(view.reactions.len(), 1);
+        assert!(view.redaction.is_none());
+        assert_eq!(log.last_message("#general", None).await.unwrap().id, target);
+
+        // A moderator may retract it.
+        let moderators = HashSet::from([carol.pubkey_hex.clone()]);
+        let redact = event(&carol, "carol", MessageEvent::Redact { target: target.clone() }, "");
+        assert_eq!(process_sync_batch(&log, "#general", vec![redact], &HashMap::new(), &moderators).await, (1, 0, 0));
+        assert_eq!(log.view("#general", &target).await.unwrap().redaction.unwrap().author, "carol");
+        assert!(log.last_message("#general", None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sync_rejects_keys_a_known_nick_never_used() {
+        let (dir_a, dir_b) = (tempdir().unwrap(), tempdir().unwrap());
+        let log = MessageLog::new(dir_a.path().join("logs"));
+        let (bob, impostor) = (make_identity(&dir_a.path().to_path_buf()), make_identity(&dir_b.path().to_path_buf()));
+        let trusted = HashMap::from([("bob".to_string(), HashSet::from([bob.pubkey_hex.clone()]))]);
+
+        let messages = vec![
+            SignedMessage::create(&bob, "bob", "#general", "real", &[]).unwrap(),
+            SignedMessage::create(&impostor, "bob", "#general", "fake", &[]).unwrap(),
+            SignedMessage::create(&impostor, "carol", "#general", "new face", &[]).unwrap(),
+        ];
+        assert_eq!(process_sync_batch(&log, "#general", messages.clone(), &trusted, &HashSet::new()).await, (2, 1, 0));
+        // Nothing for another channel rides along in a batch.
+        assert_eq!(process_sync_batch(&log, "#ops", messages, &trusted, &HashSet::new()).await, (0, 3, 0));
+        assert!(log.get_messages("#general").await.iter().all(|m| m.content != "fake"));
+
+        // A nick whose only keys are unverified changes trusts none.
+        let flagged = HashMap::from([("dave".to_string(), HashSet::new())]);
+        let takeover = SignedMessage::create(&impostor, "dave", "#general", "it's me", &[]).unwrap();
+        assert_eq!(process_sync_batch(&log, "#general", vec![takeover], &flagged, &HashSet::new()).await, (0, 1, 0));
     }
 
     #[test]
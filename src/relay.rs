@@ -1,32 +1,51 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, RwLock};
-use tracing::{error, info};
+use tokio::sync::{mpsc, oneshot, RwLock};
+use tracing::{error, info, warn};
+
+use crate::bans::{now_secs, SharedBans};
+use crate::jitter_buffer::AudioPacket;
 
 // Wire format: [1 byte nick_len][nick bytes][2 bytes payload_len BE][payload]
 // Total overhead per packet: 3 + nick_len bytes
+// The payload is opaque to the relay; clients put `AudioPacket::to_bytes` in it.
+//
+// There is no authentication here, so bans are matched on the IP and on
+// whoever the IRC server has under the claimed nick (see `bans`).
+// They're checked on connect and again every `BAN_RECHECK` so a ban made on
+// the IRC side also cuts the relay stream.
+
+const BAN_RECHECK: Duration = Duration::from_secs(5);
 
 struct RelayClient {
-    nick: String,
     tx: mpsc::UnboundedSender<Vec<u8>>,
 }
 
 struct RelayState {
     clients: HashMap<SocketAddr, RelayClient>,
+    bans: SharedBans,
+}
+
+impl RelayState {
+    fn is_banned(&self, nick: &str, addr: &SocketAddr) -> bool {
+        self.bans.read().unwrap().find_relay(nick, addr.ip(), now_secs()).is_some()
+    }
 }
 
 pub struct AudioRelay;
 
 impl AudioRelay {
-    pub async fn run(port: u16) -> std::io::Result<()> {
+    pub async fn run(port: u16, bans: SharedBans) -> std::io::Result<()> {
         let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).await?;
         info!("Audio relay listening on 0.0.0.0:{}", port);
 
         let state = Arc::new(RwLock::new(RelayState {
             clients: HashMap::new(),
+            bans,
         }));
 
         loop {
@@ -56,6 +75,10 @@ async fn handle_relay_client(
     socket.read_exact(&mut nick_buf).await?;
     let nick = String::from_utf8_lossy(&nick_buf).to_string();
 
+    if state.read().await.is_banned(&nick, &addr) {
+        warn!("Refusing banned relay client {} ({})", nick, addr);
+        return Ok(());
+    }
     info!("Relay client connected: {} ({})", nick, addr);
 
     let (reader, mut writer) = socket.into_split();
@@ -63,7 +86,7 @@ async fn handle_relay_client(
 
     {
         let mut s = state.write().await;
-        s.clients.insert(addr, RelayClient { nick: nick.clone(), tx });
+        s.clients.insert(addr, RelayClient { tx });
     }
 
     // Writer task: send relay frames to this client
@@ -75,23 +98,39 @@ async fn handle_relay_client(
         }
     });
 
+    // Ban watch: fires once if this client gets banned.  Only then is a
+    // frame read cut short, and the connection goes with it.
+    let (banned_tx, mut banned) = oneshot::channel::<()>();
+    let watch_handle = tokio::spawn({
+        let state = Arc::clone(&state);
+        let nick = nick.clone();
+        async move {
+            let mut recheck = tokio::time::interval(BAN_RECHECK);
+            recheck.tick().await;
+            loop {
+                recheck.tick().await;
+                if state.read().await.is_banned(&nick, &addr) {
+                    let _ = banned_tx.send(());
+                    break;
+                }
+            }
+        }
+    });
+
     // Reader: receive frames and forward to all other clients
     let mut reader = tokio::io::BufReader::new(reader);
     loop {
-        // Read payload length (2 bytes BE)
-        let payload_len = match reader.read_u16().await {
-            Ok(len) => len as usize,
-            Err(_) => break,
+        let payload = tokio::select! {
+            payload = read_payload(&mut reader) => match payload {
+                Ok(Some(payload)) => payload,
+                _ => break,
+            },
+            Ok(()) = &mut banned => {
+                warn!("Dropping banned relay client {} ({})", nick, addr);
+                break;
+            }
         };
-
-        if payload_len == 0 || payload_len > 4096 {
-            break;
-        }
-
-        let mut payload = vec![0u8; payload_len];
-        if reader.read_exact(&mut payload).await.is_err() {
-            break;
-        }
+        let payload_len = payload.len();
 
         // Build relay frame: [nick_len][nick][payload_len][payload]
         let mut frame = Vec::with_capacity(1 + nick.len() + 2 + payload_len);
@@ -115,10 +154,23 @@ async fn handle_relay_client(
         s.clients.remove(&addr);
     }
     write_handle.abort();
+    watch_handle.abort();
     info!("Relay client disconnected: {} ({})", nick, addr);
     Ok(())
 }
 
+/// One client frame: [2 bytes payload_len BE][payload].  `None` for a bad
+/// length.
+async fn read_payload<R: AsyncReadExt + Unpin>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let payload_len = reader.read_u16().await? as usize;
+    if payload_len == 0 || payload_len > 4096 {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; payload_len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
 // Client-side relay connection
 pub struct RelayConnection {
     writer: Arc<tokio::sync::Mutex<tokio::net::tcp::OwnedWriteHalf>>,
@@ -128,7 +180,7 @@ impl RelayConnection {
     pub async fn connect(
         addr: &str,
         nick: &str,
-        audio_rx_tx: mpsc::UnboundedSender<(String, Vec<u8>)>,
+        audio_rx_tx: mpsc::UnboundedSender<(String, AudioPacket)>,
     ) -> anyhow::Result<Self> {
         let mut socket = TcpStream::connect(addr).await?;
 
@@ -143,12 +195,9 @@ impl RelayConnection {
         // Reader task: parse incoming relay frames
         tokio::spawn(async move {
             let mut reader = tokio::io::BufReader::new(reader);
-            loop {
-                // Read sender nick
-                let nick_len = match reader.read_u8().await {
-                    Ok(len) => len as usize,
-                    Err(_) => break,
-                };
+            // Read sender nick
+            while let Ok(len) = reader.read_u8().await {
+                let nick_len = len as usize;
                 if nick_len == 0 || nick_len > 64 { break; }
 
                 let mut nick_buf = vec![0u8; nick_len];
@@ -165,7 +214,9 @@ impl RelayConnection {
                 let mut payload = vec![0u8; payload_len];
                 if reader.read_exact(&mut payload).await.is_err() { break; }
 
-                let _ = audio_rx_tx.send((sender, payload));
+                if let Some(packet) = AudioPacket::from_bytes(&payload) {
+                    let _ = audio_rx_tx.send((sender, packet));
+                }
             }
         });
 
@@ -173,10 +224,11 @@ impl RelayConnection {
         Ok(Self { writer })
     }
 
-    pub async fn send_audio(&self, data: &[u8]) -> anyhow::Result<()> {
+    pub async fn send_audio(&self, packet: &AudioPacket) -> anyhow::Result<()> {
+        let data = packet.to_bytes();
         let mut writer = self.writer.lock().await;
         writer.write_u16(data.len() as u16).await?;
-        writer.write_all(data).await?;
+        writer.write_all(&data).await?;
         Ok(())
     }
 }
@@ -0,0 +1,366 @@
+// Full-text search over the signed logs.
+//
+// An inverted index: every word of every message maps to the (ascending)
+// numbers of the documents holding it, kept in a BTreeMap so a query word
+// matches as a prefix ("deploy" finds "deployed").  A query intersects the
+// posting lists of its words, then filters by author, channel and date, so
+// its cost follows the matches rather than the size of the logs.
+//
+// The index lives in memory.  It is built from the `signed_logs` files the
+// first time anyone searches and then kept up to date by `MessageLog::append`.
+// An edit replaces the text indexed for the message it edits; redacted
+// messages drop out of results.  Sealed (+E) messages are left out: the index would be a
+// plaintext copy of them.
+
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use tracing::warn;
+
+use crate::persistence::{MessageEvent, SignedMessage};
+
+/// Longer "words" are pasted keys, hashes and the like; not indexed.
+const MAX_WORD_LEN: usize = 40;
+
+/// What to look for: every word (as a prefix), optionally narrowed down.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub words: Vec<String>,
+    /// Author nick, any case.
+    pub author: Option<String>,
+    pub channel: Option<String>,
+    /// Unix seconds, inclusive.
+    pub after: Option<i64>,
+    /// Unix seconds, exclusive.
+    pub before: Option<i64>,
+}
+
+impl SearchQuery {
+    /// `words... [from:nick] [in:#chan] [after:YYYY-MM-DD] [before:YYYY-MM-DD]`,
+    /// days in local time.  `after` includes its day, `before` doesn't.
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut query = Self::default();
+        for part in input.split_whitespace() {
+            match part.split_once(':') {
+                Some(("from", nick)) if !nick.is_empty() => query.author = Some(nick.to_string()),
+                Some(("in", ch)) if !ch.is_empty() => {
+                    query.channel = Some(if ch.starts_with('#') { ch.to_string() } else { format!("#{}", ch) });
+                }
+                Some(("after", day)) => query.after = Some(day_start(day)?),
+                Some(("before", day)) => query.before = Some(day_start(day)?),
+                _ => query.words.extend(words(part)),
+            }
+        }
+        if query.is_empty() {
+            return Err(anyhow!("nothing to search for"));
+        }
+        Ok(query)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.author.is_none() && self.channel.is_none()
+            && self.after.is_none() && self.before.is_none()
+    }
+}
+
+fn day_start(day: &str) -> Result<i64> {
+    let date = chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .map_err(|_| anyhow!("bad date {:?}, expected YYYY-MM-DD", day))?;
+    date.and_hms_opt(0, 0, 0)
+        .and_then(|t| t.and_local_timezone(chrono::Local).earliest())
+        .map(|t| t.timestamp())
+        .ok_or_else(|| anyhow!("no such local time: {}", day))
+}
+
+/// Lowercased runs of letters and digits.
+pub fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty() && w.len() <= MAX_WORD_LEN)
+        .map(str::to_lowercase)
+}
+
+/// `words` of `text`, sorted, each once.
+fn distinct_words(text: &str) -> Vec<String> {
+    let mut found: Vec<String> = words(text).collect();
+    found.sort_unstable();
+    found.dedup();
+    found
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit {
+    pub channel: String,
+    /// The message to show in context; for a matching edit, the one edited.
+    pub id: String,
+    pub author: String,
+    pub timestamp: i64,
+    /// The text that matched: the latest edit's, if any.
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SearchResults {
+    /// Newest first.
+    pub hits: Vec<SearchHit>,
+    /// Messages matched, of which `hits` are the newest.
+    pub total: usize,
+}
+
+struct Document {
+    channel: u32,
+    id: String,
+    author: String,
+    /// The author's pubkey, interned.
+    key: u32,
+    /// Of the latest version.
+    timestamp: i64,
+    text: String,
+}
+
+/// Channels and pubkeys are stored once and referred to by number.
+#[derive(Default)]
+struct Names {
+    names: Vec<String>,
+    numbers: HashMap<String, u32>,
+}
+
+impl Names {
+    fn intern(&mut self, name: &str) -> u32 {
+        if let Some(&n) = self.numbers.get(name) {
+            return n;
+        }
+        let n = self.names.len() as u32;
+        self.names.push(name.to_string());
+        self.numbers.insert(name.to_string(), n);
+        n
+    }
+}
+
+#[derive(Default)]
+pub struct SearchIndex {
+    documents: Vec<Document>,
+    postings: BTreeMap<String, Vec<u32>>,
+    channels: Names,
+    keys: Names,
+    /// (channel, id) of indexed messages → their document.
+    messages: HashMap<(u32, String), u32>,
+    /// Ids of indexed edits, so a log read twice isn't indexed twice.
+    edits: HashSet<String>,
+    /// Channel → ids of redacted messages.
+    redacted: HashMap<u32, HashSet<String>>,
+}
+
+impl SearchIndex {
+    /// Index every `*.jsonl` log in `log_dir`.
+    pub fn build(log_dir: &Path) -> Self {
+        let mut index = Self::default();
+        let entries = match std::fs::read_dir(log_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read {}: {}", log_dir.display(), e);
+                return index;
+            }
+        };
+        for path in entries.flatten().map(|e| e.path()) {
+            if path.extension().is_none_or(|ext| ext != "jsonl") {
+                continue;
+            }
+            let file = match std::fs::File::open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Could not index {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                if let Ok(msg) = serde_json::from_str::<SignedMessage>(&line) {
+                    index.add(&msg);
+                }
+            }
+        }
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    /// Index `msg` if it's searchable and new.
+    pub fn add(&mut self, msg: &SignedMessage) {
+        if msg.encrypted {
+            return;
+        }
+        let channel = self.channels.intern(&msg.channel);
+        let key = self.keys.intern(&msg.pubkey);
+        match &msg.event {
+            None if self.messages.contains_key(&(channel, msg.id.clone())) => {}
+            None => {
+                let n = self.documents.len() as u32;
+                for word in distinct_words(&msg.content) {
+                    self.postings.entry(word).or_default().push(n);
+                }
+                self.messages.insert((channel, msg.id.clone()), n);
+                self.documents.push(Document {
+                    channel,
+                    id: msg.id.clone(),
+                    author: msg.author.clone(),
+                    key,
+                    timestamp: msg.timestamp,
+                    text: msg.content.clone(),
+                });
+            }
+            Some(MessageEvent::Redact { target }) => {
+                self.redacted.entry(channel).or_default().insert(target.clone());
+            }
+            // Only the author's own edits count, and we can only tell for
+            // messages already indexed.  The latest edit wins.
+            Some(MessageEvent::Edit { target }) => {
+                let Some(&n) = self.messages.get(&(channel, target.clone())) else { return };
+                let doc = &self.documents[n as usize];
+                if doc.key != key || msg.timestamp < doc.timestamp || !self.edits.insert(msg.id.clone()) {
+                    return;
+                }
+                self.replace(n, msg);
+            }
+            Some(MessageEvent::React { .. }) => {}
+        }
+    }
+
+    /// Swap document `n`'s text, and its postings, for the edit `msg`'s.
+    fn replace(&mut self, n: u32, msg: &SignedMessage) {
+        for word in distinct_words(&self.documents[n as usize].text) {
+            if let Some(docs) = self.postings.get_mut(&word) {
+                if let Ok(i) = docs.binary_search(&n) {
+                    docs.remove(i);
+                }
+                if docs.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+        for word in distinct_words(&msg.content) {
+            let docs = self.postings.entry(word).or_default();
+            if let Err(i) = docs.binary_search(&n) {
+                docs.insert(i, n);
+            }
+        }
+        let doc = &mut self.documents[n as usize];
+        doc.author = msg.author.clone();
+        doc.timestamp = msg.timestamp;
+        doc.text = msg.content.clone();
+    }
+
+    /// Documents holding a word starting with `prefix`, ascending.
+    fn prefixed(&self, prefix: &str) -> Vec<u32> {
+        let mut docs: Vec<u32> = self.postings.range(prefix.to_string()..)
+            .take_while(|(word, _)| word.starts_with(prefix))
+            .flat_map(|(_, docs)| docs.iter().copied())
+            .collect();
+        docs.sort_unstable();
+        docs.dedup();
+        docs
+    }
+
+    /// The newest `limit` matches for `query`, one per message.
+    pub fn search(&self, query: &SearchQuery, limit: usize) -> SearchResults {
+        let candidates: Vec<u32> = if query.words.is_empty() {
+            (0..self.documents.len() as u32).collect()
+        } else {
+            let mut lists: Vec<Vec<u32>> = query.words.iter().map(|w| self.prefixed(w)).collect();
+            lists.sort_unstable_by_key(Vec::len);
+            let mut docs = lists.remove(0);
+            for list in &lists {
+                docs.retain(|d| list.binary_search(d).is_ok());
+            }
+            docs
+        };
+
+        let channel = match &query.channel {
+            Some(name) => match self.channels.names.iter().position(|c| c.eq_ignore_ascii_case(name)) {
+                Some(n) => Some(n as u32),
+                None => return SearchResults::default(),
+            },
+            None => None,
+        };
+        let mut matched: Vec<(u32, &Document)> = candidates.into_iter()
+            .map(|n| (n, &self.documents[n as usize]))
+            .filter(|(_, d)| channel.is_none_or(|c| d.channel == c))
+            .filter(|(_, d)| query.author.as_ref().is_none_or(|a| d.author.eq_ignore_ascii_case(a)))
+            .filter(|(_, d)| query.after.is_none_or(|t| d.timestamp >= t))
+            .filter(|(_, d)| query.before.is_none_or(|t| d.timestamp < t))
+            .filter(|(_, d)| !self.redacted.get(&d.channel).is_some_and(|ids| ids.contains(&d.id)))
+            .collect();
+        // Newest first.
+        matched.sort_unstable_by(|(m, a), (n, b)| (b.timestamp, n).cmp(&(a.timestamp, m)));
+
+        let mut results = SearchResults::default();
+        for (_, d) in matched {
+            results.total += 1;
+            if results.hits.len() < limit {
+                results.hits.push(SearchHit {
+                    channel: self.channels.names[d.channel as usize].clone(),
+                    id: d.id.clone(),
+                    author: d.author.clone(),
+                    timestamp: d.timestamp,
+                    text: d.text.clone(),
+                });
+            }
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::Identity;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_query() {
+        let q = SearchQuery::parse("Deploy FAILED from:alice in:ops after:2026-01-02").unwrap();
+        assert_eq!(q.words, vec!["deploy", "failed"]);
+        assert_eq!(q.author.as_deref(), Some("alice"));
+        assert_eq!(q.channel.as_deref(), Some("#ops"));
+        assert!(q.after.is_some() && q.before.is_none());
+        assert!(SearchQuery::parse("after:yesterday").is_err());
+        assert!(SearchQuery::parse("  ").is_err());
+    }
+
+    #[test]
+    fn test_search_words_filters_edits_and_redactions() {
+        let (dir_a, dir_b) = (tempdir().unwrap(), tempdir().unwrap());
+        let alice = Identity::load_or_generate(dir_a.path()).unwrap();
+        let bob = Identity::load_or_generate(dir_b.path()).unwrap();
+        let mut index = SearchIndex::default();
+
+        let deploy = SignedMessage::create(&alice, "alice", "#ops", "Deployed the new build", &[]).unwrap();
+        let other = SignedMessage::create(&bob, "bob", "#general", "deploy tomorrow?", &[]).unwrap();
+        let typo = SignedMessage::create(&bob, "bob", "#ops", "the bulid is red", &[]).unwrap();
+        let fix = SignedMessage::create_event(&bob, "bob", "#ops", MessageEvent::Edit { target: typo.id.clone() }, "the build is red", false, &[]).unwrap();
+        let forged = SignedMessage::create_event(&alice, "alice", "#ops", MessageEvent::Edit { target: typo.id.clone() }, "forged build", false, &[]).unwrap();
+        for msg in [&deploy, &other, &typo, &fix, &forged, &deploy] {
+            index.add(msg);
+        }
+
+        let ids = |index: &SearchIndex, q: &str| -> Vec<String> {
+            index.search(&SearchQuery::parse(q).unwrap(), 10).hits.into_iter().map(|h| h.id).collect()
+        };
+        assert_eq!(ids(&index, "deploy").len(), 2);
+        assert_eq!(ids(&index, "deploy in:#ops"), vec![deploy.id.clone()]);
+        assert_eq!(ids(&index, "DEPLOY from:Bob"), vec![other.id.clone()]);
+        // The edit finds the message it corrected; the forged one doesn't count.
+        assert_eq!(ids(&index, "build red"), vec![typo.id.clone()]);
+        assert!(ids(&index, "forged").is_empty());
+        // Nor does the text the edit replaced.
+        assert!(ids(&index, "bulid").is_empty());
+        assert_eq!(index.len(), 3);
+        assert!(ids(&index, "deploy before:2000-01-01").is_empty());
+
+        let redact = SignedMessage::create_event(&alice, "alice", "#ops", MessageEvent::Redact { target: deploy.id.clone() }, "", false, &[]).unwrap();
+        index.add(&redact);
+        assert_eq!(ids(&index, "deploy"), vec![other.id.clone()]);
+        assert_eq!(index.search(&SearchQuery::parse("in:#ops").unwrap(), 1).total, 1);
+    }
+}
@@ -1,13 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use webrtc::peer_connection::RTCPeerConnection;
 
-use crate::config::{ConnState, NetDiagnostics, Role};
-use crate::persistence::{Identity, MessageLog};
+use crate::call::CallEvent;
+use crate::config::{ConnState, NetDiagnostics, Role, VoiceStatus};
+use crate::bans::now_secs;
+use crate::contacts::{KnownIdentities, Sighting};
+use crate::e2e::ReplayGuard;
+use crate::group::GroupKeys;
+use crate::persistence::{Identity, MessageLog, SignedMessage};
 
 #[derive(Clone, Debug)]
 pub struct PeerState {
@@ -17,6 +22,80 @@ pub struct PeerState {
     pub role: Role,
     pub conn_state: ConnState,
     pub conn_started: Option<Instant>,
+    /// Their mute/deafen flags from `VOIRC_VOICE:`.
+    pub voice: VoiceStatus,
+}
+
+/// How far a chat line can be trusted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verification {
+    /// Plain IRC text, a system line, or from the plaintext log.
+    Unsigned,
+    /// Signed, and fits the channel's history.
+    Verified,
+    /// Signed, but from the future, reordered or forking its author's
+    /// history.
+    Suspicious,
+}
+
+/// One line of a channel's scrollback.
+#[derive(Clone, Debug)]
+pub struct ChatEntry {
+    /// The signed message's id; `None` if it wasn't signed.
+    pub id: Option<String>,
+    /// `None` for system lines.
+    pub author: Option<String>,
+    pub text: String,
+    /// Unix seconds.
+    pub timestamp: i64,
+    pub status: Verification,
+    /// Id of the message this one answers.
+    pub reply_to: Option<String>,
+}
+
+impl ChatEntry {
+    /// A system line, or `<nick> text` as chat.
+    pub fn from_line(line: String) -> Self {
+        let chat = line.strip_prefix('<')
+            .and_then(|rest| rest.split_once("> "))
+            .filter(|(nick, _)| !nick.is_empty() && !nick.contains(' '))
+            .map(|(nick, text)| (nick.to_string(), text.to_string()));
+        let (author, text) = match chat {
+            Some((nick, text)) => (Some(nick), text),
+            None => (None, line),
+        };
+        Self {
+            id: None,
+            author,
+            text,
+            timestamp: chrono::Utc::now().timestamp(),
+            status: Verification::Unsigned,
+            reply_to: None,
+        }
+    }
+
+    /// As shown in plain text: `<nick> text`, marked `[?]` if suspicious.
+    pub fn line(&self) -> String {
+        let mark = if self.status == Verification::Suspicious { "[?] " } else { "" };
+        match &self.author {
+            Some(nick) => format!("<{}> {}{}", nick, mark, self.text),
+            None => self.text.clone(),
+        }
+    }
+}
+
+/// `entries` as text lines, replies marked with whom they answer.
+pub fn chat_lines(entries: &[ChatEntry]) -> Vec<String> {
+    entries.iter()
+        .map(|e| {
+            let Some(target) = &e.reply_to else { return e.line() };
+            let to = entries.iter()
+                .find(|t| t.id.as_ref() == Some(target))
+                .and_then(|t| t.author.as_deref())
+                .unwrap_or("an earlier message");
+            ChatEntry { text: format!("↪ {}: {}", to, e.text), ..e.clone() }.line()
+        })
+        .collect()
 }
 
 #[derive(Clone, Debug)]
@@ -30,7 +109,10 @@ pub struct SharedFile {
 pub struct AppState {
     pub peers: RwLock<HashMap<String, Arc<RTCPeerConnection>>>,
     pub peer_states: RwLock<HashMap<String, PeerState>>,
-    pub messages: RwLock<HashMap<String, Vec<String>>>,
+    pub messages: RwLock<HashMap<String, Vec<ChatEntry>>>,
+    /// Peer nick → decrypted DM lines, both directions.  Memory only; they
+    /// never touch the channel logs.
+    pub direct_messages: RwLock<HashMap<String, Vec<String>>>,
     pub last_audio: RwLock<HashMap<String, Instant>>,
     pub received_files: RwLock<Vec<SharedFile>>,
     pub our_role: RwLock<Role>,
@@ -38,37 +120,72 @@ pub struct AppState {
     pub message_log: Arc<MessageLog>,
     // Store known public keys for verification: Nick -> PubkeyHex
     pub known_pubkeys: RwLock<HashMap<String, String>>,
+    /// Every key we've seen, across sessions; see `contacts`.
+    pub contacts: RwLock<KnownIdentities>,
+    /// Nicks the server has voice-muted; their audio isn't played or
+    /// forwarded.
+    pub voice_muted: RwLock<HashSet<String>>,
+    /// Channel → topic, as last told by the server.
+    pub topics: RwLock<HashMap<String, String>>,
+    /// Channels the server reports as `+E`: chat there goes out sealed.
+    pub encrypted_channels: RwLock<HashSet<String>>,
+    /// Channel → keys we've been in it together with this session.  History
+    /// sync only answers a peer for channels listed here.
+    pub channel_members: RwLock<HashMap<String, HashSet<String>>>,
+    /// Our sender keys and the ones members shared with us.
+    pub group_keys: RwLock<GroupKeys>,
+    /// Sealed DMs and key shares opened lately, to refuse them played back.
+    pub sealed_seen: RwLock<ReplayGuard>,
     pub identity: Option<Identity>,
+    /// Typed notifications for frontends and scripts; see `CallSession::subscribe`.
+    pub events: broadcast::Sender<CallEvent>,
     log_dir: PathBuf,
 }
 
 impl AppState {
     pub fn new(identity: Option<Identity>) -> Arc<Self> {
-        let log_dir = dirs::data_dir()
+        let data_dir = dirs::data_dir()
             .unwrap_or_else(|| PathBuf::from("."))
-            .join("voirc")
-            .join("logs");
+            .join("voirc");
+        Self::with_data_dir(identity, &data_dir)
+    }
+
+    /// Like `new`, but keeps the plaintext and signed logs under `data_dir`.
+    pub fn with_data_dir(identity: Option<Identity>, data_dir: &Path) -> Arc<Self> {
+        let log_dir = data_dir.join("logs");
         let _ = std::fs::create_dir_all(&log_dir);
-        let signed_log_dir = dirs::data_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join("voirc")
-            .join("signed_logs");
+        let signed_log_dir = data_dir.join("signed_logs");
+        let (events, _) = broadcast::channel(256);
 
         Arc::new(Self {
             peers: RwLock::new(HashMap::new()),
             peer_states: RwLock::new(HashMap::new()),
             messages: RwLock::new(HashMap::new()),
+            direct_messages: RwLock::new(HashMap::new()),
             last_audio: RwLock::new(HashMap::new()),
             received_files: RwLock::new(Vec::new()),
             our_role: RwLock::new(Role::Peer),
             diagnostics: RwLock::new(NetDiagnostics::default()),
             message_log: MessageLog::new(signed_log_dir),
             known_pubkeys: RwLock::new(HashMap::new()),
+            contacts: RwLock::new(KnownIdentities::load(&data_dir.join("known_identities.toml"))),
+            voice_muted: RwLock::new(HashSet::new()),
+            topics: RwLock::new(HashMap::new()),
+            encrypted_channels: RwLock::new(HashSet::new()),
+            channel_members: RwLock::new(HashMap::new()),
+            group_keys: RwLock::new(GroupKeys::default()),
+            sealed_seen: RwLock::new(ReplayGuard::default()),
             identity,
+            events,
             log_dir,
         })
     }
 
+    pub fn emit(&self, event: CallEvent) {
+        // Err just means nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
     pub async fn set_our_role(&self, role: Role) {
         *self.our_role.write().await = role;
     }
@@ -77,11 +194,125 @@ impl AppState {
         *self.our_role.read().await
     }
 
+    /// A system line, or `<nick> text` for unsigned chat.
     pub async fn add_message(&self, channel: &str, msg: String) {
-        self.persist_message(channel, &msg);
+        self.add_entry(channel, ChatEntry::from_line(msg)).await;
+    }
+
+    pub async fn add_entry(&self, channel: &str, entry: ChatEntry) {
+        let line = entry.line();
+        self.persist_message(channel, &line);
+        self.emit(CallEvent::Line { channel: channel.to_string(), text: line });
         let mut messages = self.messages.write().await;
         let list = messages.entry(channel.to_string()).or_default();
-        list.push(msg);
+        list.push(entry);
+        if list.len() > 1000 {
+            list.drain(0..100);
+        }
+    }
+
+    /// Replace the text of signed message `id`, if it's still in the
+    /// scrollback.
+    pub async fn update_signed_message(&self, channel: &str, id: &str, text: String) -> bool {
+        let mut messages = self.messages.write().await;
+        let entry = messages.get_mut(channel)
+            .and_then(|list| list.iter_mut().rev().find(|e| e.id.as_deref() == Some(id)));
+        match entry {
+            Some(entry) => {
+                entry.text = text;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The text of `msg`, opened if it's sealed.
+    pub async fn open_content(&self, msg: &SignedMessage) -> String {
+        if !msg.encrypted {
+            return msg.content.clone();
+        }
+        self.group_keys.read().await.open(&msg.channel, &msg.pubkey, &msg.content)
+            .unwrap_or_else(|e| {
+                tracing::warn!("Could not open sealed message from {}: {}", msg.author, e);
+                format!("[encrypted - no key from {} yet]", msg.author)
+            })
+    }
+
+    /// Author and current text of signed message `id`: the latest edit,
+    /// "(edited)", reaction counts, or a note that it was retracted.
+    pub async fn render_signed(&self, channel: &str, id: &str) -> Option<(String, String)> {
+        let view = self.message_log.view(channel, id).await?;
+        let author = view.message.author.clone();
+        if let Some(redaction) = &view.redaction {
+            let text = if redaction.pubkey == view.message.pubkey {
+                "[message deleted]".to_string()
+            } else {
+                format!("[message removed by {}]", redaction.author)
+            };
+            return Some((author, text));
+        }
+        let mut text = match &view.edit {
+            Some(edit) => format!("{} (edited)", self.open_content(edit).await),
+            None => self.open_content(&view.message).await,
+        };
+        // One per reactor and reaction, counted in the order first seen.
+        let mut seen = HashSet::new();
+        let mut counts: Vec<(String, usize)> = Vec::new();
+        for reaction in &view.reactions {
+            let emoji = self.open_content(reaction).await;
+            if !seen.insert((reaction.pubkey.clone(), emoji.clone())) {
+                continue;
+            }
+            match counts.iter_mut().find(|(e, _)| *e == emoji) {
+                Some((_, n)) => *n += 1,
+                None => counts.push((emoji, 1)),
+            }
+        }
+        if !counts.is_empty() {
+            let counts: Vec<String> = counts.iter().map(|(e, n)| format!("{} {}", e, n)).collect();
+            text = format!("{}  [{}]", text, counts.join(" · "));
+        }
+        Some((author, text))
+    }
+
+    /// The signed messages around `id` as they read now, for showing a
+    /// search hit in context.
+    pub async fn message_context(&self, channel: &str, id: &str, radius: usize) -> Vec<ChatEntry> {
+        let mut entries = Vec::new();
+        for msg in self.message_log.context(channel, id, radius).await {
+            let Some((author, text)) = self.render_signed(channel, &msg.id).await else { continue };
+            entries.push(ChatEntry {
+                id: Some(msg.id),
+                author: Some(author),
+                text,
+                timestamp: msg.timestamp,
+                status: Verification::Verified,
+                reply_to: msg.reply_to,
+            });
+        }
+        entries
+    }
+
+    /// Pubkeys of the host and mods, as far as we know them: who may
+    /// retract other people's messages.
+    pub async fn moderator_keys(&self) -> HashSet<String> {
+        let mut keys: HashSet<String> = HashSet::new();
+        let known = self.known_pubkeys.read().await;
+        for (nick, peer) in self.peer_states.read().await.iter() {
+            if peer.role.can_moderate() {
+                keys.extend(known.get(nick).cloned());
+            }
+        }
+        if self.our_role().await.can_moderate() {
+            keys.extend(self.identity.as_ref().map(|i| i.pubkey_hex.clone()));
+        }
+        keys
+    }
+
+    pub async fn add_direct_message(&self, peer: &str, line: String) {
+        let mut dms = self.direct_messages.write().await;
+        let list = dms.entry(peer.to_string()).or_default();
+        list.push(line);
         if list.len() > 1000 {
             list.drain(0..100);
         }
@@ -108,11 +339,17 @@ impl AppState {
 
             if list.is_empty() {
                 for line in &lines[start..] {
-                    if let Some(end_bracket) = line.find("] ") {
-                        list.push(line[end_bracket + 2..].to_string());
-                    } else {
-                        list.push(line.to_string());
+                    let (when, text) = match line.split_once("] ") {
+                        Some((stamp, text)) => (stamp.strip_prefix('['), text),
+                        None => (None, *line),
+                    };
+                    let mut entry = ChatEntry::from_line(text.to_string());
+                    if let Some(ts) = when.and_then(|w| chrono::NaiveDateTime::parse_from_str(w, "%Y-%m-%d %H:%M:%S").ok())
+                        .and_then(|t| t.and_local_timezone(chrono::Local).earliest())
+                    {
+                        entry.timestamp = ts.timestamp();
                     }
+                    list.push(entry);
                 }
             }
         }
@@ -122,10 +359,10 @@ impl AppState {
         let mut states = self.peer_states.write().await;
         
         // Extract values first to end the immutable borrow of `states`
-        let (existing_role, existing_conn_state, existing_started) = if let Some(s) = states.get(&nick) {
-            (s.role, s.conn_state, s.conn_started)
+        let (existing_role, existing_conn_state, existing_started, existing_voice) = if let Some(s) = states.get(&nick) {
+            (s.role, s.conn_state, s.conn_started, s.voice)
         } else {
-            (Role::Peer, ConnState::Connecting, None)
+            (Role::Peer, ConnState::Connecting, None, VoiceStatus::default())
         };
 
         let conn_state = if connected {
@@ -141,6 +378,7 @@ impl AppState {
             role: existing_role,
             conn_state,
             conn_started: existing_started,
+            voice: existing_voice,
         });
     }
 
@@ -157,6 +395,7 @@ impl AppState {
                 role: Role::Peer,
                 conn_state: ConnState::Connecting,
                 conn_started: Some(Instant::now()),
+                voice: VoiceStatus::default(),
             });
         }
     }
@@ -168,6 +407,7 @@ impl AppState {
             if state == ConnState::Connected {
                 ps.connected = true;
             }
+            self.emit(CallEvent::PeerConnState { nick: nick.to_string(), state });
         }
     }
 
@@ -183,10 +423,18 @@ impl AppState {
                 role,
                 conn_state: ConnState::Connecting,
                 conn_started: None,
+                voice: VoiceStatus::default(),
             });
         }
     }
 
+    pub async fn set_peer_voice(&self, nick: &str, voice: VoiceStatus) {
+        if let Some(ps) = self.peer_states.write().await.get_mut(nick) {
+            ps.voice = voice;
+            self.emit(CallEvent::PeerVoice { nick: nick.to_string(), status: voice });
+        }
+    }
+
     pub async fn get_peer_role(&self, nick: &str) -> Role {
         self.peer_states.read().await
             .get(nick)
@@ -227,11 +475,62 @@ impl AppState {
         }
     }
 
+    pub async fn set_voice_muted(&self, nick: &str, muted: bool) {
+        let mut set = self.voice_muted.write().await;
+        if muted {
+            set.insert(nick.to_string());
+        } else {
+            set.remove(nick);
+        }
+    }
+
+    pub async fn is_voice_muted(&self, nick: &str) -> bool {
+        self.voice_muted.read().await.contains(nick)
+    }
+
+    /// `None` clears it.
+    pub async fn set_topic(&self, channel: &str, topic: Option<String>) {
+        let mut topics = self.topics.write().await;
+        match topic {
+            Some(t) => topics.insert(channel.to_string(), t),
+            None => topics.remove(channel),
+        };
+    }
+
+    pub async fn topic(&self, channel: &str) -> Option<String> {
+        self.topics.read().await.get(channel).cloned()
+    }
+
+    pub async fn set_channel_encrypted(&self, channel: &str, on: bool) {
+        let mut set = self.encrypted_channels.write().await;
+        if on {
+            set.insert(channel.to_string());
+        } else {
+            set.remove(channel);
+        }
+    }
+
+    pub async fn is_channel_encrypted(&self, channel: &str) -> bool {
+        self.encrypted_channels.read().await.contains(channel)
+    }
+
+    pub async fn note_channel_member(&self, channel: &str, pubkey: &str) {
+        self.channel_members.write().await
+            .entry(channel.to_string()).or_default()
+            .insert(pubkey.to_string());
+    }
+
+    pub async fn was_channel_member(&self, channel: &str, pubkey: &str) -> bool {
+        self.channel_members.read().await.get(channel).is_some_and(|m| m.contains(pubkey))
+    }
+
     pub async fn remove_peer(&self, nick: &str) {
         self.peers.write().await.remove(nick);
         self.peer_states.write().await.remove(nick);
         self.last_audio.write().await.remove(nick);
         self.known_pubkeys.write().await.remove(nick);
+        // The server re-sends any mutes when they (or we) join again.
+        self.voice_muted.write().await.remove(nick);
     }
 
     pub async fn clear_peers(&self) {
@@ -239,6 +538,7 @@ impl AppState {
         self.peer_states.write().await.clear();
         self.last_audio.write().await.clear();
         self.known_pubkeys.write().await.clear();
+        self.voice_muted.write().await.clear();
     }
 
     pub async fn add_received_file(&self, from: String, name: String, size: usize, path: PathBuf) {
@@ -247,22 +547,47 @@ impl AppState {
 
     // --- Key Management Methods ---
 
-    pub async fn register_peer_pubkey(&self, nick: String, pubkey: String) -> bool {
+    /// Take `pubkey` as `nick`'s key for this session and record it in the
+    /// known identities.  `None` if the nick already has another key this
+    /// session.  Only the server's `VOIRC_PUBKEY` and signed hellos get
+    /// here.
+    ///
+    /// Lock order: `known_pubkeys`, then `contacts`.
+    pub async fn register_peer_pubkey(&self, nick: String, pubkey: String) -> Option<Sighting> {
         let mut keys = self.known_pubkeys.write().await;
         if let Some(existing) = keys.get(&nick) {
             if existing != &pubkey {
-                return false; // Conflict
+                return None; // Conflict
             }
         }
+        let ours = self.identity.as_ref().is_some_and(|id| id.pubkey_hex == pubkey);
+        let sighting = if ours {
+            Sighting::Known
+        } else {
+            self.contacts.write().await.observe(&nick, &pubkey, now_secs())
+        };
         keys.insert(nick, pubkey);
-        true
+        Some(sighting)
     }
 
     pub async fn pubkey_for_nick(&self, nick: &str) -> Option<String> {
         self.known_pubkeys.read().await.get(nick).cloned()
     }
 
-    pub async fn trusted_keys_snapshot(&self) -> HashMap<String, String> {
-        self.known_pubkeys.read().await.clone()
+    /// Nick → the keys it has been seen with, this session or before, for
+    /// checking synced history.  Keys flagged as a changed identity and
+    /// not yet verified don't count, so a nick taken over by another key
+    /// can't bring history in the old owner's name.
+    pub async fn trusted_keys_snapshot(&self) -> HashMap<String, HashSet<String>> {
+        let session = self.known_pubkeys.read().await.clone();
+        let contacts = self.contacts.read().await;
+        let mut keys = contacts.nick_keys();
+        for (nick, key) in session.iter() {
+            let trusted = keys.entry(nick.clone()).or_default();
+            if !contacts.needs_attention(nick, key) {
+                trusted.insert(key.clone());
+            }
+        }
+        keys
     }
 }
@@ -71,7 +71,7 @@ mod tests {
 
     // Helper is now async and does not start its own runtime
     async fn make_state_with_peers(peers: Vec<(&'static str, Role)>) -> Arc<AppState> {
-        let state = AppState::new();
+        let state = AppState::new(None);
         for (nick, role) in peers {
             state.set_peer_role(nick, role).await;
         }
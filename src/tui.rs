@@ -0,0 +1,534 @@
+// Terminal frontend.
+//
+// A ratatui/crossterm view over the same CallSession the GUI drives: channels
+// on the left, chat in the middle, peers on the right, input at the bottom.
+// Slash commands are handled by the call engine (moderation::parse_command),
+// so they behave exactly like they do in the GUI.
+
+use anyhow::Result;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use std::io::Stdout;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, RwLock};
+use tracing::info;
+
+use crate::call::{self, CallCommand, CallEvent, CallSession, SessionOptions};
+use crate::config::{ConnState, Role, ServerConfig, TransmitMode, UserConfig, VoiceStatus};
+use crate::headless::HostedRoom;
+use crate::magic_link::ConnectionInfo;
+use crate::state::{chat_lines, PeerState};
+
+type Term = Terminal<CrosstermBackend<Stdout>>;
+
+pub enum Target {
+    Join(String),
+    Host(ServerConfig),
+}
+
+pub async fn run(mut config: UserConfig, target: Target) -> Result<()> {
+    let mut room = None;
+    let mut invite = None;
+    let (conn_info, is_host) = match target {
+        Target::Host(mut server_config) => {
+            // We're the one hosting, so we're the host unless told otherwise.
+            if server_config.host_pubkey.is_none() {
+                let identity = crate::persistence::Identity::load_or_generate(&UserConfig::tls_cert_dir())?;
+                server_config.host_pubkey = Some(identity.pubkey_hex);
+            }
+            let r = HostedRoom::start(&server_config).await?;
+            let info = r.local_info.clone();
+            invite = Some(r.public_link.clone());
+            room = Some(r);
+            (info, true)
+        }
+        Target::Join(link) => {
+            let info = ConnectionInfo::from_magic_link(&link)?;
+            ensure_pow(&mut config, info.pow_required_bits).await?;
+            (info, false)
+        }
+    };
+
+    call::remember_server(&mut config, &conn_info, is_host);
+    let invite_link = Arc::new(RwLock::new(invite));
+    let mut opts = SessionOptions::from_config(&config, conn_info, is_host)
+        .with_invite_link(invite_link);
+    if opts.transmit.mode == TransmitMode::PushToTalk {
+        opts.transmit.mode = TransmitMode::VoiceActivity;
+    }
+    let handle = CallSession::start(opts)?;
+
+    let mut terminal = setup_terminal()?;
+    let result = ui_loop(&mut terminal, &handle, room.as_mut()).await;
+    restore_terminal(&mut terminal)?;
+
+    handle.send(CallCommand::Shutdown);
+    if let Some(r) = room {
+        r.shutdown().await;
+    }
+    result
+}
+
+/// Mine a stronger nick before connecting if the room demands more PoW than
+/// our current one has.  The GUI does this in the background; here we just
+/// block with a message on stderr since the TUI isn't up yet.
+async fn ensure_pow(config: &mut UserConfig, required: u8) -> Result<()> {
+    if required == 0 {
+        return Ok(());
+    }
+    let identity = crate::persistence::Identity::load_or_generate(&UserConfig::tls_cert_dir())?;
+    if crate::pow::check_difficulty(&config.display_name, &identity.pubkey_hex, required) {
+        return Ok(());
+    }
+
+    eprintln!(
+        "This server requires {} PoW bits. Mining a new nick (~{})…",
+        required,
+        crate::pow::time_estimate(required)
+    );
+    let base = crate::pow::base_name(&config.display_name).to_string();
+    let pubkey = identity.pubkey_hex.clone();
+    let mined = tokio::task::spawn_blocking(move || {
+        crate::pow::mine_nick(&base, &pubkey, required, 200_000_000)
+    })
+    .await?
+    .ok_or_else(|| anyhow::anyhow!("Mining failed (limit reached)"))?;
+
+    eprintln!("New nick '{}' ({} bits)", mined.nick, mined.bits);
+    config.display_name = mined.nick;
+    config.save()?;
+    Ok(())
+}
+
+fn setup_terminal() -> Result<Term> {
+    enable_raw_mode()?;
+    let mut stdout = std::io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    Ok(Terminal::new(CrosstermBackend::new(stdout))?)
+}
+
+fn restore_terminal(terminal: &mut Term) -> Result<()> {
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    Ok(())
+}
+
+/// crossterm's blocking reader, moved off the runtime onto its own thread.
+fn spawn_input_reader() -> mpsc::UnboundedReceiver<Event> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || loop {
+        match event::poll(Duration::from_millis(200)) {
+            Ok(true) => match event::read() {
+                Ok(ev) => {
+                    if tx.send(ev).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            },
+            Ok(false) => {
+                if tx.is_closed() {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    });
+    rx
+}
+
+async fn room_stopped(room: Option<&mut HostedRoom>) -> Result<()> {
+    match room {
+        Some(r) => r.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// UI state
+// ─────────────────────────────────────────────────────────────────────────────
+
+#[derive(Default)]
+struct TuiState {
+    input: String,
+    /// Chat lines scrolled up from the bottom.
+    scroll: usize,
+    show_invite: bool,
+    /// The latest call event that doesn't show up in the chat.
+    status: Option<String>,
+}
+
+/// Everything a frame needs, copied out of the async locks once per tick.
+struct View {
+    nickname: String,
+    current_channel: String,
+    topic: Option<String>,
+    encrypted: bool,
+    channels: Vec<String>,
+    our_role: Role,
+    our_voice: VoiceStatus,
+    transmit: TransmitMode,
+    peers: Vec<PeerState>,
+    messages: Vec<String>,
+    invite: Option<String>,
+}
+
+impl View {
+    async fn capture(handle: &CallSession) -> Self {
+        let current_channel = handle.current_channel.read().await.clone();
+        let mut peers: Vec<PeerState> = handle.state.peer_states.read().await.values().cloned().collect();
+        peers.sort_by(|a, b| a.nickname.cmp(&b.nickname));
+        let messages = handle.state.messages.read().await
+            .get(&current_channel)
+            .map(|entries| chat_lines(entries))
+            .unwrap_or_default();
+        Self {
+            nickname: handle.nickname.clone(),
+            channels: handle.channels.read().await.clone(),
+            our_role: handle.state.our_role().await,
+            our_voice: handle.voice.status(),
+            transmit: handle.voice.mode(),
+            invite: handle.invite_link.read().await.clone(),
+            topic: handle.state.topic(&current_channel).await,
+            encrypted: handle.state.is_channel_encrypted(&current_channel).await,
+            current_channel,
+            peers,
+            messages,
+        }
+    }
+}
+
+async fn ui_loop(terminal: &mut Term, handle: &CallSession, mut room: Option<&mut HostedRoom>) -> Result<()> {
+    let mut ui = TuiState::default();
+    let mut input_rx = spawn_input_reader();
+    let mut events = handle.subscribe();
+    let mut tick = tokio::time::interval(Duration::from_millis(100));
+
+    loop {
+        handle.state.refresh_speaking().await;
+        let view = View::capture(handle).await;
+        terminal.draw(|f| draw(f, &view, &ui))?;
+
+        tokio::select! {
+            _ = tick.tick() => {}
+            Some(ev) = input_rx.recv() => {
+                if !handle_event(ev, &mut ui, handle, &view) {
+                    info!("TUI quit");
+                    return Ok(());
+                }
+            }
+            Ok(ev) = events.recv() => match ev {
+                // Already in the scrollback.
+                CallEvent::Line { .. } | CallEvent::Chat { .. } => {}
+                ev => ui.status = Some(ev.to_string()),
+            },
+            r = room_stopped(room.as_deref_mut()) => return r,
+        }
+    }
+}
+
+/// Returns false when the user asked to quit.
+fn handle_event(ev: Event, ui: &mut TuiState, handle: &CallSession, view: &View) -> bool {
+    let Event::Key(key) = ev else { return true };
+    if key.kind != KeyEventKind::Press {
+        return true;
+    }
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+    match key.code {
+        KeyCode::Char('c') | KeyCode::Char('q') if ctrl => return false,
+        KeyCode::Char('n') if ctrl => switch_relative(handle, view, 1),
+        KeyCode::Char('p') if ctrl => switch_relative(handle, view, -1),
+        KeyCode::F(2) => ui.show_invite = !ui.show_invite,
+        KeyCode::F(3) => handle.send(CallCommand::SetMuted(!view.our_voice.muted)),
+        KeyCode::F(4) => handle.send(CallCommand::SetDeafened(!view.our_voice.deafened)),
+        // Terminals don't report key release, so push-to-talk isn't offered.
+        KeyCode::F(5) => handle.voice.set_mode(match view.transmit {
+            TransmitMode::AlwaysOn => TransmitMode::VoiceActivity,
+            _ => TransmitMode::AlwaysOn,
+        }),
+        KeyCode::Esc => ui.show_invite = false,
+        KeyCode::PageUp => ui.scroll = (ui.scroll + 10).min(view.messages.len()),
+        KeyCode::PageDown => ui.scroll = ui.scroll.saturating_sub(10),
+        KeyCode::Enter => {
+            let text = ui.input.trim().to_string();
+            if !text.is_empty() {
+                handle.send(CallCommand::SendMessage(text));
+                ui.scroll = 0;
+            }
+            ui.input.clear();
+        }
+        KeyCode::Backspace => {
+            ui.input.pop();
+        }
+        KeyCode::Char(c) if !ctrl => ui.input.push(c),
+        _ => {}
+    }
+    true
+}
+
+fn switch_relative(handle: &CallSession, view: &View, step: isize) {
+    let n = view.channels.len() as isize;
+    if n < 2 {
+        return;
+    }
+    let cur = view.channels.iter().position(|c| *c == view.current_channel).unwrap_or(0) as isize;
+    let next = (cur + step).rem_euclid(n) as usize;
+    handle.send(CallCommand::SwitchChannel(view.channels[next].clone()));
+}
+
+// ─────────────────────────────────────────────────────────────────────────────
+// Rendering
+// ─────────────────────────────────────────────────────────────────────────────
+
+fn draw(f: &mut Frame, view: &View, ui: &TuiState) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(1), Constraint::Min(3), Constraint::Length(3)])
+        .split(f.area());
+    let cols = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Length(18), Constraint::Min(20), Constraint::Length(28)])
+        .split(rows[1]);
+
+    draw_header(f, rows[0], view);
+    draw_channels(f, cols[0], view);
+    draw_chat(f, cols[1], view, ui.scroll);
+    draw_peers(f, cols[2], view);
+
+    let mut block = Block::default().borders(Borders::ALL).title("Message (/? for commands)");
+    if let Some(status) = &ui.status {
+        block = block.title_bottom(Line::from(status.as_str()).style(Style::default().fg(Color::DarkGray)));
+    }
+    let input = Paragraph::new(ui.input.as_str()).block(block);
+    f.render_widget(input, rows[2]);
+    let cursor_x = rows[2].x + 1 + (ui.input.chars().count() as u16).min(rows[2].width.saturating_sub(3));
+    f.set_cursor_position((cursor_x, rows[2].y + 1));
+
+    if ui.show_invite {
+        draw_invite(f, view);
+    }
+}
+
+fn draw_header(f: &mut Frame, area: Rect, view: &View) {
+    let badge = match view.our_role {
+        Role::Host => Span::styled(" [HOST]", Style::default().fg(Color::Yellow)),
+        Role::Mod => Span::styled(" [MOD]", Style::default().fg(Color::LightBlue)),
+        Role::Peer => Span::raw(""),
+    };
+    let line = Line::from(vec![
+        Span::styled(" V ", Style::default().add_modifier(Modifier::BOLD)),
+        Span::styled(view.current_channel.clone(), Style::default().add_modifier(Modifier::BOLD)),
+        badge,
+        Span::styled(if view.encrypted { " [E2E]" } else { "" }, Style::default().fg(Color::LightGreen)),
+        Span::styled(
+            if view.transmit == TransmitMode::AlwaysOn { " [open mic]" } else { "" },
+            Style::default().fg(Color::LightRed),
+        ),
+        Span::styled(
+            view.topic.as_ref().map_or(String::new(), |t| format!(" — {}", t)),
+            Style::default().fg(Color::Gray),
+        ),
+        Span::styled(
+            "   F2 invite · F3 mute · F4 deafen · F5 open mic · Ctrl-N/P channel · PgUp/PgDn scroll · Ctrl-C quit",
+            Style::default().fg(Color::DarkGray),
+        ),
+    ]);
+    f.render_widget(Paragraph::new(line), area);
+}
+
+fn draw_channels(f: &mut Frame, area: Rect, view: &View) {
+    let items: Vec<ListItem> = view.channels.iter().map(|ch| {
+        if *ch == view.current_channel {
+            ListItem::new(format!("> {}", ch)).style(Style::default().fg(Color::White).add_modifier(Modifier::BOLD))
+        } else {
+            ListItem::new(format!("  {}", ch)).style(Style::default().fg(Color::Gray))
+        }
+    }).collect();
+    f.render_widget(List::new(items).block(Block::default().borders(Borders::ALL).title("Channels")), area);
+}
+
+fn role_tag(role: Role) -> &'static str {
+    match role {
+        Role::Host => " *",
+        Role::Mod => " +",
+        Role::Peer => "",
+    }
+}
+
+fn draw_peers(f: &mut Frame, area: Rect, view: &View) {
+    let online = view.peers.iter().filter(|p| p.connected).count();
+    let mut items = vec![ListItem::new(format!(
+        "    {} (you){}{}", view.nickname, role_tag(view.our_role), view.our_voice.tag(),
+    ))];
+
+    for peer in &view.peers {
+        let (icon, color) = match peer.conn_state {
+            ConnState::Connected if peer.speaking => (">> ", Color::Green),
+            ConnState::Connected => ("-- ", Color::Gray),
+            ConnState::Connecting => ("...", Color::Yellow),
+            ConnState::NatIssue => ("!! ", Color::LightRed),
+            ConnState::Relayed => ("~> ", Color::LightBlue),
+            ConnState::Failed => ("XX ", Color::Red),
+        };
+        let name_color = if peer.connected { Color::White } else { Color::Gray };
+        let mut lines = vec![Line::from(vec![
+            Span::styled(icon, Style::default().fg(color)),
+            Span::raw(" "),
+            Span::styled(format!("{}{}", peer.nickname, role_tag(peer.role)), Style::default().fg(name_color)),
+            Span::styled(peer.voice.tag(), Style::default().fg(Color::DarkGray)),
+        ])];
+        if peer.conn_state != ConnState::Connected {
+            lines.push(Line::styled(format!("     {}", peer.conn_state), Style::default().fg(Color::DarkGray)));
+        }
+        items.push(ListItem::new(lines));
+    }
+
+    let title = format!("Voice - {} online", online);
+    f.render_widget(List::new(items).block(Block::default().borders(Borders::ALL).title(title)), area);
+}
+
+/// Split `text` into rows of at most `width` chars so scrolling can be done
+/// by row count.
+fn wrap_chars(text: &str, width: usize) -> Vec<String> {
+    if width == 0 {
+        return vec![];
+    }
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars.chunks(width).map(|c| c.iter().collect()).collect()
+}
+
+fn chat_line(row: String, first: bool, msg: &str) -> Line<'static> {
+    // Same convention as the GUI: "<nick> text" is chat, anything else is a
+    // system line.
+    if first {
+        if let Some(rest) = msg.strip_prefix('<') {
+            if let Some(close) = rest.find('>') {
+                let nick_len = close + 2;
+                if row.len() >= nick_len && row.is_char_boundary(nick_len) {
+                    let (nick, text) = row.split_at(nick_len);
+                    return Line::from(vec![
+                        Span::styled(nick.to_string(), Style::default().fg(Color::LightBlue).add_modifier(Modifier::BOLD)),
+                        Span::raw(text.to_string()),
+                    ]);
+                }
+            }
+        }
+    }
+    if msg.starts_with('<') {
+        Line::raw(row)
+    } else {
+        Line::styled(row, Style::default().fg(Color::Gray).add_modifier(Modifier::ITALIC))
+    }
+}
+
+fn draw_chat(f: &mut Frame, area: Rect, view: &View, scroll: usize) {
+    let width = area.width.saturating_sub(2) as usize;
+    let height = area.height.saturating_sub(2) as usize;
+
+    let mut rows: Vec<Line> = Vec::new();
+    for msg in &view.messages {
+        for (i, row) in wrap_chars(msg, width).into_iter().enumerate() {
+            rows.push(chat_line(row, i == 0, msg));
+        }
+    }
+    let end = rows.len().saturating_sub(scroll);
+    let start = end.saturating_sub(height);
+    let visible: Vec<Line> = rows.drain(start..end).collect();
+
+    let title = if scroll > 0 {
+        format!("{} (scrolled)", view.current_channel)
+    } else {
+        view.current_channel.clone()
+    };
+    f.render_widget(
+        Paragraph::new(visible).block(Block::default().borders(Borders::ALL).title(title)),
+        area,
+    );
+}
+
+fn draw_invite(f: &mut Frame, view: &View) {
+    let area = f.area();
+    let popup = Rect {
+        x: area.width / 8,
+        y: area.height / 3,
+        width: area.width * 3 / 4,
+        height: 7.min(area.height),
+    };
+    let text = view.invite.clone()
+        .unwrap_or_else(|| "No invite link available yet (external IP still resolving)".to_string());
+    f.render_widget(Clear, popup);
+    f.render_widget(
+        Paragraph::new(text)
+            .wrap(Wrap { trim: false })
+            .block(Block::default().borders(Borders::ALL).title("Invite link (F2/Esc to close)")),
+        popup,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+
+    fn sample_view() -> View {
+        View {
+            nickname: "alice".to_string(),
+            current_channel: "#general".to_string(),
+            topic: Some("standup at 10".to_string()),
+            encrypted: true,
+            channels: vec!["#general".to_string(), "#ops".to_string()],
+            our_role: Role::Host,
+            our_voice: VoiceStatus { muted: false, deafened: true },
+            transmit: TransmitMode::AlwaysOn,
+            peers: vec![PeerState {
+                nickname: "bob".to_string(),
+                connected: true,
+                speaking: true,
+                role: Role::Peer,
+                conn_state: ConnState::Connected,
+                conn_started: None,
+                voice: VoiceStatus { muted: true, deafened: false },
+            }],
+            messages: vec!["Connected as alice (TLS)".to_string(), "<bob> hi there".to_string()],
+            invite: Some("voirc://abc".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_wrap_chars() {
+        assert_eq!(wrap_chars("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(wrap_chars("", 4), vec![""]);
+        assert!(wrap_chars("abc", 0).is_empty());
+    }
+
+    #[test]
+    fn test_draw_shows_channels_peers_and_chat() {
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        let ui = TuiState { show_invite: true, status: Some("bob joined (peer)".to_string()), ..Default::default() };
+        terminal.draw(|f| draw(f, &sample_view(), &ui)).unwrap();
+
+        let buffer = terminal.backend().buffer();
+        let text: String = buffer.content().iter().map(|c| c.symbol()).collect();
+        assert!(text.contains("#general [HOST] [E2E] [open mic] — standup at 10"));
+        assert!(text.contains("> #general"));
+        assert!(text.contains("#ops"));
+        assert!(text.contains("alice (you) * [deaf]"));
+        assert!(text.contains(">>  bob [muted]"));
+        assert!(text.contains("<bob> hi there"));
+        assert!(text.contains("voirc://abc"));
+        assert!(text.contains("bob joined (peer)"));
+    }
+}
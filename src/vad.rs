@@ -0,0 +1,182 @@
+// Voice activity detection for the mic path.
+//
+// Runs on each 20ms frame after the AEC in `CaptureHalf`.  A frame counts as
+// voiced when its level clears both the user's threshold and a margin above
+// the noise floor.  The floor follows the quietest recent frames (fast down,
+// slow up), so steady fan or hum eventually stops opening the gate while the
+// short dips between syllables keep it from creeping up during speech.
+//
+// Opening needs `ATTACK_FRAMES` voiced frames in a row; the caller keeps a
+// short pre-roll so the start of the word is still sent.  Once open, the gate
+// holds for `HANGOVER_FRAMES` after the last voiced frame so word endings and
+// brief pauses aren't chopped.
+
+/// Levels are clamped here so digital silence doesn't drag the floor to -inf.
+pub const MIN_DB: f32 = -90.0;
+pub const ATTACK_FRAMES: u32 = 2;
+pub const HANGOVER_FRAMES: u32 = 15;
+/// Frames kept while the gate is closed and sent when it opens.
+pub const PRE_ROLL_FRAMES: usize = ATTACK_FRAMES as usize + 1;
+/// How far above the noise floor speech must be.
+const FLOOR_MARGIN_DB: f32 = 10.0;
+const FLOOR_FALL: f32 = 0.3;
+/// dB per frame; about 2.5 dB/s.
+const FLOOR_RISE_DB: f32 = 0.05;
+
+/// RMS level of a frame in dBFS.
+pub fn frame_level_db(frame: &[f32]) -> f32 {
+    if frame.is_empty() {
+        return MIN_DB;
+    }
+    let rms = (frame.iter().map(|&x| x * x).sum::<f32>() / frame.len() as f32).sqrt();
+    (20.0 * rms.log10()).max(MIN_DB)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gate {
+    Closed,
+    /// Just opened on this frame: flush the pre-roll first.
+    Opening,
+    Open,
+}
+
+impl Gate {
+    pub fn is_open(self) -> bool {
+        self != Gate::Closed
+    }
+}
+
+pub struct Vad {
+    noise_floor_db: f32,
+    voiced_run: u32,
+    hangover: u32,
+    open: bool,
+}
+
+impl Default for Vad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vad {
+    pub fn new() -> Self {
+        Self { noise_floor_db: -60.0, voiced_run: 0, hangover: 0, open: false }
+    }
+
+    #[cfg(test)]
+    pub fn noise_floor_db(&self) -> f32 {
+        self.noise_floor_db
+    }
+
+    /// The level a frame must exceed to count as speech.
+    pub fn gate_db(&self, threshold_db: f32) -> f32 {
+        threshold_db.max(self.noise_floor_db + FLOOR_MARGIN_DB)
+    }
+
+    pub fn process(&mut self, level_db: f32, threshold_db: f32) -> Gate {
+        let voiced = level_db > self.gate_db(threshold_db);
+
+        if level_db < self.noise_floor_db {
+            self.noise_floor_db += (level_db - self.noise_floor_db) * FLOOR_FALL;
+        } else {
+            self.noise_floor_db = (self.noise_floor_db + FLOOR_RISE_DB).min(level_db);
+        }
+
+        if voiced {
+            self.voiced_run += 1;
+            self.hangover = HANGOVER_FRAMES;
+        } else {
+            self.voiced_run = 0;
+        }
+
+        if self.open {
+            if !voiced {
+                if self.hangover == 0 {
+                    self.open = false;
+                    return Gate::Closed;
+                }
+                self.hangover -= 1;
+            }
+            Gate::Open
+        } else if self.voiced_run >= ATTACK_FRAMES {
+            self.open = true;
+            Gate::Opening
+        } else {
+            Gate::Closed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: f32 = -45.0;
+
+    #[test]
+    fn test_frame_level() {
+        assert_eq!(frame_level_db(&[0.0; 960]), MIN_DB);
+        let full = frame_level_db(&[1.0; 960]);
+        assert!(full.abs() < 0.01);
+        let tenth = frame_level_db(&[0.1; 960]);
+        assert!((tenth + 20.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_attack_and_hangover() {
+        let mut vad = Vad::new();
+        assert_eq!(vad.process(-70.0, THRESHOLD), Gate::Closed);
+
+        // A single click doesn't open the gate.
+        assert_eq!(vad.process(-20.0, THRESHOLD), Gate::Closed);
+        assert_eq!(vad.process(-70.0, THRESHOLD), Gate::Closed);
+
+        assert_eq!(vad.process(-20.0, THRESHOLD), Gate::Closed);
+        assert_eq!(vad.process(-20.0, THRESHOLD), Gate::Opening);
+        assert_eq!(vad.process(-20.0, THRESHOLD), Gate::Open);
+
+        // Stays open through the hangover, then closes.
+        for _ in 0..HANGOVER_FRAMES {
+            assert_eq!(vad.process(-70.0, THRESHOLD), Gate::Open);
+        }
+        assert_eq!(vad.process(-70.0, THRESHOLD), Gate::Closed);
+    }
+
+    #[test]
+    fn test_steady_noise_is_gated_out() {
+        let mut vad = Vad::new();
+        // A fan well above the threshold opens the gate at first...
+        let mut gate = Gate::Closed;
+        for _ in 0..ATTACK_FRAMES {
+            gate = vad.process(-35.0, THRESHOLD);
+        }
+        assert!(gate.is_open());
+
+        // ...but the floor catches up within a few seconds.
+        for _ in 0..50 * 10 {
+            gate = vad.process(-35.0, THRESHOLD);
+        }
+        assert_eq!(gate, Gate::Closed);
+        assert!(vad.noise_floor_db() > -46.0);
+
+        // Speech over the fan still gets through.
+        vad.process(-15.0, THRESHOLD);
+        assert_eq!(vad.process(-15.0, THRESHOLD), Gate::Opening);
+    }
+
+    #[test]
+    fn test_threshold_is_respected_in_quiet_room() {
+        let mut vad = Vad::new();
+        for _ in 0..100 {
+            assert_eq!(vad.process(-50.0, THRESHOLD), Gate::Closed);
+        }
+        let mut vad = Vad::new();
+        for _ in 0..100 {
+            vad.process(-80.0, -30.0);
+        }
+        for _ in 0..10 {
+            assert_eq!(vad.process(-35.0, -30.0), Gate::Closed);
+        }
+    }
+}
@@ -2,21 +2,36 @@ use anyhow::Result;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{Device, Stream, StreamConfig};
 use crossbeam::queue::ArrayQueue;
-use opus::{Application, Channels, Decoder, Encoder};
+use opus::{Application, Bitrate, Channels, Decoder, Encoder};
 use std::collections::{HashMap, VecDeque};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
+use std::sync::mpsc::{self as std_mpsc, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tracing::{error, info, warn};
 use webrtc_audio_processing::{
-    Config, EchoCancellation, EchoCancellationSuppressionLevel,
+    Config, EchoCancellation, EchoCancellationSuppressionLevel, GainControl, GainControlMode,
     InitializationConfig, NoiseSuppression, NoiseSuppressionLevel, Processor,
 };
 
+use crate::config::{
+    AudioProcessing, CodecProfile, NoiseSuppressionSetting, PeerAudioSettings, TransmitMode, TransmitSettings, VoiceStatus,
+};
+use crate::jitter_buffer::{AudioPacket, JitterBuffer, Playout};
+use crate::vad::{self, Gate, Vad};
+
 const SAMPLE_RATE: u32 = 48000;
 const CHANNELS_COUNT: u16 = 1;
+/// Speakers are opened in stereo so peers can be panned; mono is the fallback.
+const OUTPUT_CHANNELS: u16 = 2;
 const FRAME_SIZE: usize = 960;  // 20ms @ 48kHz — Opus frame
 const AEC_FRAME: usize = webrtc_audio_processing::NUM_SAMPLES_PER_FRAME as usize;
-const RING_CAPACITY: usize = 64;
+const RING_CAPACITY: usize = 256;
+/// Forget a sender's jitter buffer and decoder after this long without audio.
+const STREAM_IDLE: Duration = Duration::from_secs(5);
+/// How often to look for a preferred device that was missing.
+const DEVICE_POLL: Duration = Duration::from_secs(2);
 
 // ---------------------------------------------------------------------------
 // AEC internals
@@ -76,34 +91,48 @@ impl CaptureHalf {
     }
 }
 
-fn build_aec() -> Result<(RenderHalf, CaptureHalf)> {
-    // From compiler: InitializationConfig fields are only
-    // enable_experimental_agc and enable_intelligibility_enhancer.
-    // Sample rate and channel count are not configurable here in 0.5.x;
-    // the processor always operates at 48kHz mono when built bundled.
-    let init = InitializationConfig {
-        ..Default::default()
-    };
-    let mut processor = Processor::new(&init)?;
-
+/// Processor config for our settings.  Echo cancellation is always on.
+fn processing_config(settings: &AudioProcessing) -> Config {
     // From compiler: Config fields are echo_cancellation, gain_control,
     // noise_suppression, voice_detection, enable_transient_suppressor,
     // enable_high_pass_filter. EchoCancellation and NoiseSuppression do
     // not implement Default — construct them field-by-field.
-    let cfg = Config {
+    let suppression_level = match settings.noise_suppression {
+        NoiseSuppressionSetting::Off => None,
+        NoiseSuppressionSetting::Low => Some(NoiseSuppressionLevel::Low),
+        NoiseSuppressionSetting::Moderate => Some(NoiseSuppressionLevel::Moderate),
+        NoiseSuppressionSetting::High => Some(NoiseSuppressionLevel::High),
+        NoiseSuppressionSetting::VeryHigh => Some(NoiseSuppressionLevel::VeryHigh),
+    };
+    Config {
         echo_cancellation: Some(EchoCancellation {
             suppression_level: EchoCancellationSuppressionLevel::High,
             enable_delay_agnostic: true,
             enable_extended_filter: true,
             stream_delay_ms: None,
         }),
-        noise_suppression: Some(NoiseSuppression {
-            suppression_level: NoiseSuppressionLevel::High,
+        noise_suppression: suppression_level.map(|suppression_level| NoiseSuppression { suppression_level }),
+        gain_control: settings.gain_control.then(|| GainControl {
+            mode: GainControlMode::AdaptiveDigital,
+            target_level_dbfs: settings.agc_target_dbfs.clamp(0, 31),
+            compression_gain_db: settings.agc_max_gain_db.clamp(0, 90),
+            enable_limiter: true,
         }),
-        enable_high_pass_filter: true,
+        enable_high_pass_filter: settings.high_pass_filter,
+        ..Default::default()
+    }
+}
+
+fn build_aec(settings: &AudioProcessing) -> Result<(Arc<Mutex<AecState>>, RenderHalf, CaptureHalf)> {
+    // From compiler: InitializationConfig fields are only
+    // enable_experimental_agc and enable_intelligibility_enhancer.
+    // Sample rate and channel count are not configurable here in 0.5.x;
+    // the processor always operates at 48kHz mono when built bundled.
+    let init = InitializationConfig {
         ..Default::default()
     };
-    processor.set_config(cfg);
+    let mut processor = Processor::new(&init)?;
+    processor.set_config(processing_config(settings));
 
     let shared = Arc::new(Mutex::new(AecState { processor }));
 
@@ -116,192 +145,890 @@ fn build_aec() -> Result<(RenderHalf, CaptureHalf)> {
         buffer: VecDeque::with_capacity(AEC_FRAME * 4),
     };
 
-    Ok((render, capture))
+    Ok((shared, render, capture))
 }
 
 // ---------------------------------------------------------------------------
-// Public API — identical signatures to original voice_mixer.rs
+// Mute / deafen / transmit mode / per-peer levels
 // ---------------------------------------------------------------------------
 
-pub struct VoiceMixer {
-    input_device: Option<Device>,
-    output_device: Device,
-    config: StreamConfig,
-    net_ring: Arc<ArrayQueue<Vec<f32>>>,
-    render_half: Mutex<Option<RenderHalf>>,
-    capture_half: Mutex<Option<CaptureHalf>>,
+/// Switches read from the audio callbacks on every frame and written by the
+/// frontend, so they are plain atomics rather than anything that can block.
+/// The per-peer table is only ever `try_read` from the output callback.
+/// The input callback writes back the mic level and gate state for meters.
+pub struct VoiceControls {
+    muted: AtomicBool,
+    deafened: AtomicBool,
+    mode: AtomicU8,
+    ptt_held: AtomicBool,
+    /// f32 bits, dBFS.
+    vad_threshold: AtomicU32,
+    input_level: AtomicU32,
+    gate_open: AtomicBool,
+    peers: RwLock<HashMap<String, PeerAudioSettings>>,
 }
 
-pub struct PeerDecoders {
-    decoders: HashMap<String, Decoder>,
+impl Default for VoiceControls {
+    fn default() -> Self {
+        let c = Self {
+            muted: AtomicBool::new(false),
+            deafened: AtomicBool::new(false),
+            mode: AtomicU8::new(0),
+            ptt_held: AtomicBool::new(false),
+            vad_threshold: AtomicU32::new(0),
+            input_level: AtomicU32::new(vad::MIN_DB.to_bits()),
+            gate_open: AtomicBool::new(false),
+            peers: RwLock::new(HashMap::new()),
+        };
+        c.apply_transmit(TransmitSettings::default());
+        c
+    }
 }
 
-impl PeerDecoders {
-    pub fn new() -> Self {
-        Self { decoders: HashMap::new() }
+impl VoiceControls {
+    pub fn apply_transmit(&self, settings: TransmitSettings) {
+        self.set_mode(settings.mode);
+        self.set_vad_threshold_db(settings.vad_threshold_db);
+    }
+
+    pub fn transmit_settings(&self) -> TransmitSettings {
+        TransmitSettings { mode: self.mode(), vad_threshold_db: self.vad_threshold_db() }
+    }
+
+    pub fn set_muted(&self, on: bool) {
+        self.muted.store(on, Ordering::Relaxed);
+    }
+
+    pub fn set_deafened(&self, on: bool) {
+        self.deafened.store(on, Ordering::Relaxed);
+    }
+
+    /// In push-to-talk mode the mic is only sent while `set_ptt_held(true)`.
+    pub fn set_mode(&self, mode: TransmitMode) {
+        let i = TransmitMode::ALL.iter().position(|m| *m == mode).unwrap_or(0);
+        self.mode.store(i as u8, Ordering::Relaxed);
+    }
+
+    pub fn mode(&self) -> TransmitMode {
+        TransmitMode::ALL
+            .get(self.mode.load(Ordering::Relaxed) as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set_vad_threshold_db(&self, db: f32) {
+        self.vad_threshold.store(db.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn vad_threshold_db(&self) -> f32 {
+        f32::from_bits(self.vad_threshold.load(Ordering::Relaxed))
+    }
+
+    /// Last mic frame level in dBFS, measured after echo cancellation.
+    pub fn input_level_db(&self) -> f32 {
+        f32::from_bits(self.input_level.load(Ordering::Relaxed))
+    }
+
+    /// Whether the VAD currently hears speech (regardless of mode).
+    pub fn voice_detected(&self) -> bool {
+        self.gate_open.load(Ordering::Relaxed)
+    }
+
+    fn set_input_meter(&self, level_db: f32, open: bool) {
+        self.input_level.store(level_db.to_bits(), Ordering::Relaxed);
+        self.gate_open.store(open, Ordering::Relaxed);
+    }
+
+    pub fn set_ptt_held(&self, held: bool) {
+        self.ptt_held.store(held, Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn is_deafened(&self) -> bool {
+        self.deafened.load(Ordering::Relaxed)
+    }
+
+    /// Whether mic frames may be sent at all; in voice-activity mode the VAD
+    /// decides frame by frame on top of this.  Deafen implies mute: talking
+    /// into a room you can't hear is never what anyone wants.
+    pub fn transmitting(&self) -> bool {
+        if self.is_muted() || self.is_deafened() {
+            return false;
+        }
+        self.mode() != TransmitMode::PushToTalk || self.ptt_held.load(Ordering::Relaxed)
+    }
+
+    /// What peers see; push-to-talk gating is not announced.
+    pub fn status(&self) -> VoiceStatus {
+        VoiceStatus { muted: self.is_muted(), deafened: self.is_deafened() }
+    }
+
+    pub fn set_peer(&self, nick: &str, settings: PeerAudioSettings) {
+        let mut peers = self.peers.write().unwrap();
+        if settings.is_default() {
+            peers.remove(nick);
+        } else {
+            peers.insert(nick.to_string(), settings);
+        }
     }
 
-    pub fn decode(&mut self, nick: &str, packet: &[u8]) -> Option<Vec<f32>> {
-        let decoder = self
-            .decoders
-            .entry(nick.to_string())
-            .or_insert_with(|| Decoder::new(SAMPLE_RATE, Channels::Mono).unwrap());
+    pub fn peer(&self, nick: &str) -> PeerAudioSettings {
+        self.peers.read().unwrap().get(nick).copied().unwrap_or_default()
+    }
+}
 
-        let mut output = vec![0.0f32; FRAME_SIZE];
-        match decoder.decode_float(packet, &mut output, false) {
-            Ok(len) => {
-                output.truncate(len);
-                Some(output)
+/// Add one peer's mono frame into an interleaved output buffer.
+fn mix_into(out: &mut [f32], channels: usize, pcm: &[f32], settings: &PeerAudioSettings) {
+    let (left, right) = settings.channel_gains();
+    if left == 0.0 && right == 0.0 {
+        return;
+    }
+    for (frame, &sample) in out.chunks_mut(channels).zip(pcm) {
+        match frame {
+            [l, r, ..] => {
+                *l += sample * left;
+                *r += sample * right;
             }
-            Err(e) => {
-                error!("Opus decode error from {}: {}", nick, e);
-                None
+            [m] => *m += sample * (left + right) * 0.5,
+            [] => {}
+        }
+    }
+}
+
+/// Like `mix_into` for an interleaved stereo frame: pan and gain are
+/// applied to each side rather than placing a mono source.
+fn mix_stereo_into(out: &mut [f32], channels: usize, pcm: &[f32], settings: &PeerAudioSettings) {
+    let (left, right) = settings.channel_gains();
+    if left == 0.0 && right == 0.0 {
+        return;
+    }
+    for (frame, src) in out.chunks_mut(channels).zip(pcm.chunks_exact(2)) {
+        match frame {
+            [l, r, ..] => {
+                *l += src[0] * left;
+                *r += src[1] * right;
             }
+            [m] => *m += (src[0] * left + src[1] * right) * 0.5,
+            [] => {}
         }
     }
 }
 
-impl VoiceMixer {
-    pub fn new() -> Result<Self> {
-        let host = cpal::default_host();
+fn opus_channels(channels: usize) -> Channels {
+    if channels == 2 { Channels::Stereo } else { Channels::Mono }
+}
 
-        let input_device = match host.default_input_device() {
-            Some(dev) => {
-                info!("Input device: {:?}", dev.name());
-                Some(dev)
-            }
-            None => {
-                warn!("No input device found - running in listen-only mode");
-                None
-            }
-        };
+// ---------------------------------------------------------------------------
+// Mixer
+// ---------------------------------------------------------------------------
 
-        let output_device = host
-            .default_output_device()
-            .ok_or_else(|| anyhow::anyhow!("No output device"))?;
+/// Everything that outlives a particular pair of sound card streams: the
+/// receive ring, the controls, processing settings and the RTP numbering.
+/// `open` builds streams (and a fresh AEC) against it; `AudioIo` decides
+/// when to do that.
+pub struct VoiceMixer {
+    config: StreamConfig,
+    codec: CodecProfile,
+    /// Encoded packets tagged with the sender's nick and arrival time; the
+    /// output callback sorts them into per-sender jitter buffers.
+    net_ring: Arc<ArrayQueue<(String, AudioPacket, Instant)>>,
+    /// Processor shared with the halves of the current streams; used to
+    /// change settings mid-call.
+    aec: Mutex<Option<Arc<Mutex<AecState>>>>,
+    processing: Mutex<AudioProcessing>,
+    /// RTP numbering carried across stream rebuilds, so receivers never see
+    /// the sequence jump backwards after a device change.
+    next_seq: Arc<AtomicU16>,
+    next_timestamp: Arc<AtomicU32>,
+    controls: Arc<VoiceControls>,
+}
+
+/// Streams from one `VoiceMixer::open`.  Dropping them releases the devices.
+struct AudioStreams {
+    _input: Option<Stream>,
+    _output: Stream,
+    active: ActiveDevices,
+}
+
+/// Playout state for one sender, owned by the output callback.
+struct PeerStream {
+    jitter: JitterBuffer,
+    decoder: Decoder,
+    /// Interleaved when `channels` is 2.
+    pcm: VecDeque<f32>,
+    channels: usize,
+    last_packet: Instant,
+}
+
+impl PeerStream {
+    fn new(channels: usize) -> Result<Self> {
+        Ok(Self {
+            jitter: JitterBuffer::new(),
+            decoder: Decoder::new(SAMPLE_RATE, opus_channels(channels))?,
+            pcm: VecDeque::with_capacity(FRAME_SIZE * 2 * channels),
+            channels,
+            last_packet: Instant::now(),
+        })
+    }
+
+    /// Decode frames from the jitter buffer until `samples` per channel are
+    /// ready or the sender has nothing more to play right now.
+    fn fill(&mut self, nick: &str, samples: usize) {
+        let mut buf = [0.0f32; FRAME_SIZE * 2];
+        let frame = &mut buf[..FRAME_SIZE * self.channels];
+        while self.pcm.len() < samples * self.channels {
+            let decoded = match self.jitter.pop() {
+                Playout::Idle => break,
+                Playout::Packet(payload) => self.decoder.decode_float(&payload, frame, false),
+                // The following packet carries a low-bitrate copy of this one.
+                Playout::Conceal { fec: Some(next) } => self.decoder.decode_float(&next, frame, true),
+                // Empty input asks the decoder for loss concealment.
+                Playout::Conceal { fec: None } => self.decoder.decode_float(&[], frame, false),
+            };
+            match decoded {
+                Ok(len) => self.pcm.extend(&frame[..len * self.channels]),
+                Err(e) => {
+                    error!("Opus decode error from {}: {}", nick, e);
+                    self.pcm.extend(frame.iter().map(|_| 0.0));
+                }
+            }
+        }
+    }
+}
 
+impl VoiceMixer {
+    pub fn new(controls: Arc<VoiceControls>, processing: &AudioProcessing, codec: CodecProfile) -> Self {
         let config = StreamConfig {
             channels: CHANNELS_COUNT,
             sample_rate: cpal::SampleRate(SAMPLE_RATE),
             buffer_size: cpal::BufferSize::Default,
         };
 
+        Self {
+            config,
+            codec,
+            net_ring: Arc::new(ArrayQueue::new(RING_CAPACITY)),
+            aec: Mutex::new(None),
+            processing: Mutex::new(*processing),
+            next_seq: Arc::new(AtomicU16::new(0)),
+            next_timestamp: Arc::new(AtomicU32::new(0)),
+            controls,
+        }
+    }
+
+    /// Change noise suppression, AGC and high-pass settings without
+    /// rebuilding the streams.  Also used for any later rebuild.
+    pub fn set_processing(&self, settings: &AudioProcessing) {
+        *self.processing.lock().unwrap() = *settings;
+        if let Some(aec) = self.aec.lock().unwrap().as_ref() {
+            match aec.lock() {
+                Ok(mut s) => s.processor.set_config(processing_config(settings)),
+                Err(_) => warn!("Audio processor lock poisoned; settings not applied"),
+            }
+        }
+    }
+
+    /// Open the named devices (the system defaults when `None` or missing)
+    /// with a fresh AEC.  No mic is not an error; no speakers is.
+    fn open(
+        &self,
+        input_name: Option<&str>,
+        output_name: Option<&str>,
+        mic_tx: &mpsc::UnboundedSender<AudioPacket>,
+        failed: &std_mpsc::Sender<DeviceCommand>,
+    ) -> Result<AudioStreams> {
+        let host = cpal::default_host();
+
+        let input_device = find_device(host.input_devices().ok(), input_name)
+            .or_else(|| host.default_input_device());
+        if input_device.is_none() {
+            warn!("No input device found - running in listen-only mode");
+        }
+        let output_device = find_device(host.output_devices().ok(), output_name)
+            .or_else(|| host.default_output_device())
+            .ok_or_else(|| anyhow::anyhow!("No output device"))?;
+
         // AEC is best-effort — if it fails (e.g. unsupported sample rate),
         // fall back gracefully. Voice still works, just without echo cancellation.
-        let (render, capture) = match build_aec() {
-            Ok((r, c)) => {
+        // The music profile skips it altogether: the speech processing would
+        // mangle instruments and it only handles mono.
+        let processing = *self.processing.lock().unwrap();
+        let built = if self.codec.is_music() {
+            info!("Music profile: echo cancellation and noise suppression bypassed");
+            Err(None)
+        } else {
+            build_aec(&processing).map_err(Some)
+        };
+        let (aec, render, capture) = match built {
+            Ok((a, r, c)) => {
                 info!("AEC initialized");
-                (Some(r), Some(c))
+                (Some(a), Some(r), Some(c))
             }
-            Err(e) => {
+            Err(Some(e)) => {
                 warn!("AEC init failed ({}), running without echo cancellation", e);
-                (None, None)
+                (None, None, None)
             }
+            Err(None) => (None, None, None),
         };
+        *self.aec.lock().unwrap() = aec;
 
-        Ok(Self {
-            input_device,
-            output_device,
-            config,
-            net_ring: Arc::new(ArrayQueue::new(RING_CAPACITY)),
-            render_half: Mutex::new(render),
-            capture_half: Mutex::new(capture),
-        })
+        let output = self.start_output(&output_device, render, failed.clone())?;
+        let input = match &input_device {
+            Some(dev) => match self.start_input(dev, capture, mic_tx.clone(), failed.clone()) {
+                Ok(stream) => Some(stream),
+                Err(e) => {
+                    error!("Input stream: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let active = ActiveDevices {
+            input: input.as_ref().and(input_device.as_ref()).and_then(|d| d.name().ok()),
+            output: output_device.name().ok(),
+        };
+        info!("Input device: {:?}, output device: {:?}", active.input, active.output);
+        Ok(AudioStreams { _input: input, _output: output, active })
     }
 
     pub fn soft_clip(sample: f32) -> f32 {
         (sample * 1.5).tanh()
     }
 
-    pub fn has_input(&self) -> bool {
-        self.input_device.is_some()
-    }
-
-    pub fn start_input(&self, audio_tx: mpsc::UnboundedSender<Vec<u8>>) -> Result<Option<Stream>> {
-        let input_device = match &self.input_device {
-            Some(dev) => dev,
-            None => {
-                info!("No mic - skipping input stream");
-                return Ok(None);
+    fn start_input(
+        &self,
+        input_device: &Device,
+        capture_half: Option<CaptureHalf>,
+        audio_tx: mpsc::UnboundedSender<AudioPacket>,
+        failed: std_mpsc::Sender<DeviceCommand>,
+    ) -> Result<Stream> {
+        if self.codec.channels() == 2 {
+            let stereo = StreamConfig { channels: 2, ..self.config.clone() };
+            match self.build_input(input_device, &stereo, None, audio_tx.clone(), failed.clone()) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => warn!("Stereo input failed ({}), capturing mono", e),
             }
-        };
-
-        let mut capture_half_opt = self.capture_half.lock().unwrap().take();
-
+        }
+        self.build_input(input_device, &self.config, capture_half, audio_tx, failed)
+    }
 
-        let config = self.config.clone();
-        let mut encoder = Encoder::new(SAMPLE_RATE, Channels::Mono, Application::Voip)?;
+    fn build_input(
+        &self,
+        input_device: &Device,
+        config: &StreamConfig,
+        mut capture_half_opt: Option<CaptureHalf>,
+        audio_tx: mpsc::UnboundedSender<AudioPacket>,
+        failed: std_mpsc::Sender<DeviceCommand>,
+    ) -> Result<Stream> {
+        let codec = self.codec;
+        let channels = codec.channels() as usize;
+        // A mono mic on the stereo profile is sent as dual mono.
+        let upmix = channels == 2 && config.channels == 1;
+        let application = if codec.is_music() { Application::Audio } else { Application::Voip };
+        let mut encoder = Encoder::new(SAMPLE_RATE, opus_channels(channels), application)?;
+        encoder.set_bitrate(Bitrate::Bits(codec.bitrate()))?;
+        encoder.set_complexity(codec.complexity())?;
         encoder.set_inband_fec(true)?;
         encoder.set_dtx(true)?;
-        let mut opus_acc: Vec<f32> = Vec::with_capacity(FRAME_SIZE * 2);
+        let frame_len = FRAME_SIZE * channels;
+        let mut opus_acc: Vec<f32> = Vec::with_capacity(frame_len * 2);
+        let mut processed: Vec<f32> = Vec::new();
+        let controls = Arc::clone(&self.controls);
+        let mut vad = Vad::new();
+        // Frames heard while the gate was closed, sent when it opens so
+        // the attack time doesn't clip the start of a word.
+        let mut pre_roll: VecDeque<(u32, Vec<f32>)> = VecDeque::with_capacity(vad::PRE_ROLL_FRAMES + 1);
+        // RTP numbering: seq counts packets sent, timestamp counts samples
+        // captured, so receivers can tell silence from loss.
+        let next_seq = Arc::clone(&self.next_seq);
+        let next_timestamp = Arc::clone(&self.next_timestamp);
+
+        let send = move |encoder: &mut Encoder, frame: &[f32], frame_ts: u32| {
+            let mut out = [0u8; 4000];
+            match encoder.encode_float(frame, &mut out) {
+                Ok(len) => {
+                    let _ = audio_tx.send(AudioPacket {
+                        seq: next_seq.fetch_add(1, Ordering::Relaxed),
+                        timestamp: frame_ts,
+                        source: None,
+                        payload: out[..len].to_vec(),
+                    });
+                }
+                Err(e) => error!("Opus encode error: {}", e),
+            }
+        };
 
         let stream = input_device.build_input_stream(
-            &config,
+            config,
             move |data: &[f32], _: &cpal::InputCallbackInfo| {
-                if let Some(ref mut ch) = capture_half_opt {
-                    ch.process(data, &mut opus_acc);
+                let data = match capture_half_opt {
+                    Some(ref mut ch) => {
+                        processed.clear();
+                        ch.process(data, &mut processed);
+                        &processed[..]
+                    }
+                    None => data,
+                };
+                if upmix {
+                    opus_acc.extend(data.iter().flat_map(|&s| [s, s]));
                 } else {
                     opus_acc.extend_from_slice(data);
                 }
 
-                while opus_acc.len() >= FRAME_SIZE {
-                    let frame: Vec<f32> = opus_acc.drain(..FRAME_SIZE).collect();
-                    let rms = (frame.iter().map(|&x| x * x).sum::<f32>() / FRAME_SIZE as f32)
-                        .sqrt();
-                    if rms > 0.01 {
-                        let mut out = [0u8; 4000];
-                        match encoder.encode_float(&frame, &mut out) {
-                            Ok(len) => {
-                                let _ = audio_tx.send(out[..len].to_vec());
+                // The timestamp counts samples per channel.
+                while opus_acc.len() >= frame_len {
+                    let frame: Vec<f32> = opus_acc.drain(..frame_len).collect();
+                    let frame_ts = next_timestamp.fetch_add(FRAME_SIZE as u32, Ordering::Relaxed);
+
+                    // The VAD and meter run even while muted so the noise
+                    // floor stays current, like the AEC above.
+                    let level = vad::frame_level_db(&frame);
+                    let gate = vad.process(level, controls.vad_threshold_db());
+                    controls.set_input_meter(level, gate.is_open());
+
+                    if !controls.transmitting() {
+                        pre_roll.clear();
+                        continue;
+                    }
+                    if controls.mode() == TransmitMode::VoiceActivity {
+                        match gate {
+                            Gate::Closed => {
+                                pre_roll.push_back((frame_ts, frame));
+                                if pre_roll.len() > vad::PRE_ROLL_FRAMES {
+                                    pre_roll.pop_front();
+                                }
+                                continue;
+                            }
+                            Gate::Opening => {
+                                for (ts, f) in pre_roll.drain(..) {
+                                    send(&mut encoder, &f, ts);
+                                }
                             }
-                            Err(e) => error!("Opus encode error: {}", e),
+                            Gate::Open => {}
                         }
                     }
+                    send(&mut encoder, &frame, frame_ts);
                 }
             },
-            |err| error!("Mic error: {}", err),
+            move |err| {
+                error!("Mic error: {}", err);
+                let _ = failed.send(DeviceCommand::StreamFailed);
+            },
             None,
         )?;
         stream.play()?;
-        Ok(Some(stream))
+        Ok(stream)
     }
 
-    pub fn start_output(&self) -> Result<Stream> {
-        let config = self.config.clone();
-        let net_ring = Arc::clone(&self.net_ring);
+    fn start_output(
+        &self,
+        device: &Device,
+        render_half: Option<RenderHalf>,
+        failed: std_mpsc::Sender<DeviceCommand>,
+    ) -> Result<Stream> {
+        let render_half = Arc::new(Mutex::new(render_half));
 
-        let mut render_half_opt = self.render_half.lock().unwrap().take();
+        let stereo = StreamConfig { channels: OUTPUT_CHANNELS, ..self.config.clone() };
+        match self.build_output(device, &stereo, Arc::clone(&render_half), failed.clone()) {
+            Ok(stream) => Ok(stream),
+            Err(e) => {
+                warn!("Stereo output failed ({}), falling back to mono", e);
+                self.build_output(device, &self.config, render_half, failed)
+            }
+        }
+    }
 
+    fn build_output(
+        &self,
+        device: &Device,
+        config: &StreamConfig,
+        render_half: Arc<Mutex<Option<RenderHalf>>>,
+        failed: std_mpsc::Sender<DeviceCommand>,
+    ) -> Result<Stream> {
+        let channels = config.channels as usize;
+        let stream_channels = self.codec.channels() as usize;
+        let net_ring = Arc::clone(&self.net_ring);
+        let controls = Arc::clone(&self.controls);
+        // Last table we managed to read, used when the frontend holds the lock.
+        let mut levels: HashMap<String, PeerAudioSettings> = HashMap::new();
+        let mut streams: HashMap<String, PeerStream> = HashMap::new();
+        let mut pcm: Vec<f32> = Vec::new();
+        let mut mono: Vec<f32> = Vec::new();
 
-        let stream = self.output_device.build_output_stream(
-            &config,
+        let stream = device.build_output_stream(
+            config,
             move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
                 data.fill(0.0);
 
-                while let Some(buf) = net_ring.pop() {
-                    for (i, s) in data.iter_mut().enumerate() {
-                        if i < buf.len() {
-                            *s += buf[i];
+                if controls.is_deafened() {
+                    // Drop anything queued before deafen took effect.
+                    while net_ring.pop().is_some() {}
+                    streams.clear();
+                }
+                if let Ok(peers) = controls.peers.try_read() {
+                    levels.clone_from(&peers);
+                }
+                while let Some((nick, packet, arrival)) = net_ring.pop() {
+                    if !streams.contains_key(&nick) {
+                        match PeerStream::new(stream_channels) {
+                            Ok(ps) => { streams.insert(nick.clone(), ps); }
+                            Err(e) => { error!("Opus decoder for {}: {}", nick, e); continue; }
                         }
                     }
+                    let ps = streams.get_mut(&nick).unwrap();
+                    ps.jitter.push(packet, arrival);
+                    ps.last_packet = arrival;
                 }
 
+                let samples = data.len() / channels;
+                for (nick, ps) in streams.iter_mut() {
+                    ps.fill(nick, samples);
+                    let n = (samples * ps.channels).min(ps.pcm.len());
+                    pcm.clear();
+                    pcm.extend(ps.pcm.drain(..n));
+                    let settings = levels.get(nick).copied().unwrap_or_default();
+                    if ps.channels == 2 {
+                        mix_stereo_into(data, channels, &pcm, &settings);
+                    } else {
+                        mix_into(data, channels, &pcm, &settings);
+                    }
+                }
+                streams.retain(|_, ps| ps.last_packet.elapsed() < STREAM_IDLE);
+
                 for s in data.iter_mut() {
                     *s = Self::soft_clip(*s);
                 }
 
-                if let Some(ref mut rh) = render_half_opt {
-                    rh.queue(data);
+                // The AEC wants the mono signal that reached the speakers.
+                if let Ok(mut guard) = render_half.try_lock() {
+                    if let Some(rh) = guard.as_mut() {
+                        mono.clear();
+                        mono.extend(data.chunks(channels).map(|f| f.iter().sum::<f32>() / channels as f32));
+                        rh.queue(&mono);
+                    }
                 }
             },
-            |err| error!("Speaker error: {}", err),
+            move |err| {
+                error!("Speaker error: {}", err);
+                let _ = failed.send(DeviceCommand::StreamFailed);
+            },
             None,
         )?;
         stream.play()?;
+        info!("Output stream: {} channel(s)", channels);
         Ok(stream)
     }
 
-    pub fn queue_audio(&self, pcm: Vec<f32>) {
-        let _ = self.net_ring.push(pcm);
+    /// Hand a received packet to the output callback.  `nick` keys the
+    /// jitter buffer and the per-peer levels.
+    pub fn queue_packet(&self, nick: &str, packet: AudioPacket) {
+        if self.controls.is_deafened() {
+            return;
+        }
+        let _ = self.net_ring.push((nick.to_string(), packet, Instant::now()));
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Device selection and hot-swap
+// ---------------------------------------------------------------------------
+
+/// Names of the available input and output devices, for settings pickers.
+pub fn list_devices() -> (Vec<String>, Vec<String>) {
+    let host = cpal::default_host();
+    fn names(devices: Option<impl Iterator<Item = Device>>) -> Vec<String> {
+        devices.into_iter().flatten().filter_map(|d| d.name().ok()).collect()
+    }
+    (names(host.input_devices().ok()), names(host.output_devices().ok()))
+}
+
+fn find_device<I: Iterator<Item = Device>>(devices: Option<I>, name: Option<&str>) -> Option<Device> {
+    let name = name?;
+    let found = devices?.find(|d| d.name().is_ok_and(|n| n == name));
+    if found.is_none() {
+        warn!("Audio device {:?} not found, using the default", name);
+    }
+    found
+}
+
+enum DeviceCommand {
+    Select { input: Option<String>, output: Option<String> },
+    StreamFailed,
+    /// Sent on drop; the stream error callbacks hold senders too, so the
+    /// channel never disconnects on its own.
+    Stop,
+}
+
+/// The devices actually in use, which may differ from the preferred ones
+/// when those are missing.  `input` is `None` in listen-only mode.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ActiveDevices {
+    pub input: Option<String>,
+    pub output: Option<String>,
+}
+
+/// Owns the sound card streams on a dedicated thread (cpal streams can't
+/// move between threads on every platform) and reopens them, with a fresh
+/// AEC, when a device errors out, the selection changes, or a preferred
+/// device that was missing shows up again.  The call carries on throughout:
+/// the mixer, its ring and the mic channel are untouched.
+pub struct AudioIo {
+    mixer: Arc<VoiceMixer>,
+    commands: std_mpsc::Sender<DeviceCommand>,
+    active: Arc<Mutex<ActiveDevices>>,
+}
+
+impl AudioIo {
+    /// Open the preferred devices (or the defaults).  `on_change` runs on
+    /// the audio thread after every reopen, not for the first open.
+    pub fn start(
+        mixer: Arc<VoiceMixer>,
+        mic_tx: mpsc::UnboundedSender<AudioPacket>,
+        input: Option<String>,
+        output: Option<String>,
+        on_change: impl Fn(&ActiveDevices) + Send + 'static,
+    ) -> Result<Self> {
+        let (commands, rx) = std_mpsc::channel();
+        let (ready_tx, ready_rx) = std_mpsc::channel::<Result<ActiveDevices>>();
+        let active = Arc::new(Mutex::new(ActiveDevices::default()));
+
+        let mixer_t = Arc::clone(&mixer);
+        let failed = commands.clone();
+        let active_t = Arc::clone(&active);
+        std::thread::Builder::new().name("voirc-audio".to_string()).spawn(move || {
+            let mut want = (input, output);
+            let mut streams = match mixer_t.open(want.0.as_deref(), want.1.as_deref(), &mic_tx, &failed) {
+                Ok(s) => {
+                    *active_t.lock().unwrap() = s.active.clone();
+                    let _ = ready_tx.send(Ok(s.active.clone()));
+                    Some(s)
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            loop {
+                let reopen = match rx.recv_timeout(DEVICE_POLL) {
+                    Ok(DeviceCommand::Select { input, output }) => {
+                        want = (input, output);
+                        true
+                    }
+                    Ok(DeviceCommand::StreamFailed) => {
+                        // Give the OS a moment to settle after an unplug.
+                        std::thread::sleep(Duration::from_millis(300));
+                        true
+                    }
+                    Err(RecvTimeoutError::Timeout) => match &streams {
+                        None => true,
+                        Some(s) => preferred_is_back(&want, &s.active),
+                    },
+                    Ok(DeviceCommand::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if !reopen {
+                    continue;
+                }
+                // Coalesce a burst of errors (both streams fail on unplug).
+                let mut stop = false;
+                while let Ok(cmd) = rx.try_recv() {
+                    match cmd {
+                        DeviceCommand::Select { input, output } => want = (input, output),
+                        DeviceCommand::Stop => stop = true,
+                        DeviceCommand::StreamFailed => {}
+                    }
+                }
+                if stop {
+                    break;
+                }
+
+                streams = None;
+                let now = match mixer_t.open(want.0.as_deref(), want.1.as_deref(), &mic_tx, &failed) {
+                    Ok(s) => {
+                        let now = s.active.clone();
+                        streams = Some(s);
+                        now
+                    }
+                    Err(e) => {
+                        warn!("Could not reopen audio devices: {}", e);
+                        ActiveDevices::default()
+                    }
+                };
+                let changed = *active_t.lock().unwrap() != now;
+                *active_t.lock().unwrap() = now.clone();
+                if changed {
+                    on_change(&now);
+                }
+            }
+            info!("Audio thread stopped");
+        })?;
+
+        let first = ready_rx.recv().map_err(|_| anyhow::anyhow!("Audio thread exited"))??;
+        *active.lock().unwrap() = first;
+        Ok(Self { mixer, commands, active })
+    }
+
+    pub fn mixer(&self) -> &Arc<VoiceMixer> {
+        &self.mixer
+    }
+
+    /// Switch devices mid-call.  `None` means the system default.
+    pub fn select(&self, input: Option<String>, output: Option<String>) {
+        let _ = self.commands.send(DeviceCommand::Select { input, output });
+    }
+
+    pub fn active(&self) -> ActiveDevices {
+        self.active.lock().unwrap().clone()
+    }
+}
+
+impl Drop for AudioIo {
+    fn drop(&mut self) {
+        let _ = self.commands.send(DeviceCommand::Stop);
+    }
+}
+
+/// Whether a preferred device we had to replace is available again.  Only
+/// enumerates devices when we're actually on a fallback.
+fn preferred_is_back(want: &(Option<String>, Option<String>), active: &ActiveDevices) -> bool {
+    let missing_in = want.0.as_ref().filter(|n| active.input.as_ref() != Some(n));
+    let missing_out = want.1.as_ref().filter(|n| active.output.as_ref() != Some(n));
+    if missing_in.is_none() && missing_out.is_none() {
+        return false;
+    }
+    let (inputs, outputs) = list_devices();
+    missing_in.is_some_and(|n| inputs.contains(n)) || missing_out.is_some_and(|n| outputs.contains(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_voice_controls_gating() {
+        let c = VoiceControls::default();
+        assert!(c.transmitting());
+
+        c.set_muted(true);
+        assert!(!c.transmitting());
+        c.set_muted(false);
+
+        c.set_deafened(true);
+        assert!(!c.transmitting());
+        assert_eq!(c.status(), VoiceStatus { muted: false, deafened: true });
+        c.set_deafened(false);
+
+        c.set_mode(TransmitMode::PushToTalk);
+        assert!(!c.transmitting());
+        c.set_ptt_held(true);
+        assert!(c.transmitting());
+        c.set_muted(true);
+        assert!(!c.transmitting());
+        c.set_muted(false);
+
+        c.set_ptt_held(false);
+        c.set_mode(TransmitMode::AlwaysOn);
+        assert!(c.transmitting());
+    }
+
+    #[test]
+    fn test_transmit_settings_apply() {
+        let c = VoiceControls::default();
+        assert_eq!(c.transmit_settings(), TransmitSettings::default());
+        let custom = TransmitSettings { mode: TransmitMode::PushToTalk, vad_threshold_db: -30.5 };
+        c.apply_transmit(custom);
+        assert_eq!(c.transmit_settings(), custom);
+        assert_eq!(c.input_level_db(), vad::MIN_DB);
+    }
+
+    #[test]
+    fn test_processing_config() {
+        let cfg = processing_config(&AudioProcessing::default());
+        assert!(cfg.echo_cancellation.is_some());
+        assert!(matches!(cfg.noise_suppression, Some(NoiseSuppression { suppression_level: NoiseSuppressionLevel::High })));
+        assert!(cfg.gain_control.is_some());
+        assert!(cfg.enable_high_pass_filter);
+
+        let off = AudioProcessing {
+            noise_suppression: NoiseSuppressionSetting::Off,
+            gain_control: false,
+            high_pass_filter: false,
+            ..Default::default()
+        };
+        let cfg = processing_config(&off);
+        assert!(cfg.echo_cancellation.is_some());
+        assert!(cfg.noise_suppression.is_none());
+        assert!(cfg.gain_control.is_none());
+        assert!(!cfg.enable_high_pass_filter);
+
+        let loud = AudioProcessing { agc_target_dbfs: 99, agc_max_gain_db: -5, ..Default::default() };
+        let agc = processing_config(&loud).gain_control.unwrap();
+        assert_eq!((agc.target_level_dbfs, agc.compression_gain_db), (31, 0));
+    }
+
+    #[test]
+    fn test_preferred_device_check() {
+        let active = ActiveDevices { input: Some("USB Headset".into()), output: Some("Speakers".into()) };
+        // Nothing to wait for: no device enumeration needed.
+        assert!(!preferred_is_back(&(None, None), &active));
+        assert!(!preferred_is_back(&(Some("USB Headset".into()), Some("Speakers".into())), &active));
+        assert!(find_device(None::<std::iter::Empty<Device>>, Some("USB Headset")).is_none());
+        assert!(find_device(Some(std::iter::empty::<Device>()), None).is_none());
+    }
+
+    #[test]
+    fn test_peer_levels_table() {
+        let c = VoiceControls::default();
+        let quiet = PeerAudioSettings { gain: 0.5, ..Default::default() };
+        c.set_peer("bob", quiet);
+        assert_eq!(c.peer("bob"), quiet);
+        assert_eq!(c.peer("carol"), PeerAudioSettings::default());
+        c.set_peer("bob", PeerAudioSettings::default());
+        assert!(c.peers.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_mix_into_stereo_and_mono() {
+        let pcm = [0.5, -0.5];
+        let right = PeerAudioSettings { pan: 1.0, ..Default::default() };
+
+        let mut stereo = [0.0; 4];
+        mix_into(&mut stereo, 2, &pcm, &right);
+        assert_eq!(stereo, [0.0, 0.5, 0.0, -0.5]);
+
+        // A second peer at half volume sums on top.
+        let half = PeerAudioSettings { gain: 0.5, ..Default::default() };
+        mix_into(&mut stereo, 2, &pcm, &half);
+        assert_eq!(stereo, [0.25, 0.75, -0.25, -0.75]);
+
+        let mut mono = [0.0; 2];
+        mix_into(&mut mono, 1, &pcm, &right);
+        assert_eq!(mono, [0.25, -0.25]);
+
+        let mut silent = [0.0; 4];
+        mix_into(&mut silent, 2, &pcm, &PeerAudioSettings { muted: true, ..Default::default() });
+        assert_eq!(silent, [0.0; 4]);
+    }
+
+    #[test]
+    fn test_mix_stereo_into() {
+        // Two frames: hard left, then hard right.
+        let pcm = [0.5, 0.0, 0.0, 0.5];
+        let mut stereo = [0.0; 4];
+        mix_stereo_into(&mut stereo, 2, &pcm, &PeerAudioSettings::default());
+        assert_eq!(stereo, [0.5, 0.0, 0.0, 0.5]);
+
+        // Panning right attenuates the left side rather than moving it.
+        let mut panned = [0.0; 4];
+        mix_stereo_into(&mut panned, 2, &pcm, &PeerAudioSettings { pan: 1.0, ..Default::default() });
+        assert_eq!(panned, [0.0, 0.0, 0.0, 0.5]);
+
+        let mut mono = [0.0; 2];
+        mix_stereo_into(&mut mono, 1, &pcm, &PeerAudioSettings::default());
+        assert_eq!(mono, [0.25, 0.25]);
     }
 }
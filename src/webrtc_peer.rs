@@ -21,7 +21,8 @@ use webrtc::rtp_transceiver::rtp_codec::{
 use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
 
-use crate::config::{ConnState, TurnServer};
+use crate::config::{CodecProfile, ConnState, TurnServer};
+use crate::jitter_buffer::AudioPacket;
 use crate::state::AppState;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -129,22 +130,26 @@ impl WebRtcPeer {
     pub async fn new(
         nickname: String,
         state: Arc<AppState>,
-        mixer_tx: mpsc::UnboundedSender<(String, Vec<u8>)>,
+        mixer_tx: mpsc::UnboundedSender<(String, AudioPacket)>,
         ice_tx: mpsc::UnboundedSender<InternalSignal>,
         file_tx: mpsc::UnboundedSender<ReceivedFile>,
         turn_servers: Vec<TurnServer>,
+        codec: CodecProfile,
     ) -> Result<Self> {
         let mut media_engine = MediaEngine::default();
 
+        // RFC 7587: Opus is always opus/48000/2 in the rtpmap; whether the
+        // stream is actually stereo, and at what rate, goes in the fmtp.
+        let opus = RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_owned(),
+            clock_rate: 48000,
+            channels: 2,
+            sdp_fmtp_line: codec.fmtp(),
+            rtcp_feedback: vec![],
+        };
         media_engine.register_codec(
             RTCRtpCodecParameters {
-                capability: RTCRtpCodecCapability {
-                    mime_type: MIME_TYPE_OPUS.to_owned(),
-                    clock_rate: 48000,
-                    channels: 1,
-                    sdp_fmtp_line: "".to_owned(),
-                    rtcp_feedback: vec![],
-                },
+                capability: opus.clone(),
                 payload_type: 111,
                 ..Default::default()
             },
@@ -200,7 +205,12 @@ impl WebRtcPeer {
                     match track.read(&mut buf).await {
                         Ok((pkt, _)) => {
                             if !pkt.payload.is_empty() {
-                                let _ = tx.send((nick.clone(), pkt.payload.to_vec()));
+                                let _ = tx.send((nick.clone(), AudioPacket {
+                                    seq: pkt.header.sequence_number,
+                                    timestamp: pkt.header.timestamp,
+                                    source: pkt.header.csrc.first().copied(),
+                                    payload: pkt.payload.to_vec(),
+                                }));
                             }
                         }
                         Err(e) => {
@@ -261,12 +271,7 @@ impl WebRtcPeer {
 
         // Local audio track
         let local_track = Arc::new(TrackLocalStaticRTP::new(
-            RTCRtpCodecCapability {
-                mime_type: MIME_TYPE_OPUS.to_owned(),
-                clock_rate: 48000,
-                channels: 1,
-                ..Default::default()
-            },
+            opus,
             "audio".to_owned(),
             "voice-irc".to_owned(),
         ));
@@ -316,8 +321,21 @@ impl WebRtcPeer {
         Ok(())
     }
 
-    pub async fn send_audio(&self, data: &[u8]) -> Result<()> {
-        self.local_audio_track.write(data).await?;
+    /// Send one Opus frame.  The track fills in SSRC and payload type; the
+    /// sequence number and timestamp are the sender's, so a forwarded packet
+    /// keeps the original speaker's numbering (and their id as CSRC).
+    pub async fn send_audio(&self, packet: &AudioPacket) -> Result<()> {
+        let rtp = webrtc::rtp::packet::Packet {
+            header: webrtc::rtp::header::Header {
+                version: 2,
+                sequence_number: packet.seq,
+                timestamp: packet.timestamp,
+                csrc: packet.source.into_iter().collect(),
+                ..Default::default()
+            },
+            payload: bytes::Bytes::copy_from_slice(&packet.payload),
+        };
+        self.local_audio_track.write_rtp(&rtp).await?;
         Ok(())
     }
 